@@ -87,6 +87,12 @@ pub struct LimitsRoom {
     /// the maximum bitrate for voice channels
     #[serde(default = "default_max_bitrate")]
     pub max_bitrate: u32,
+
+    /// the maximum number of bytes of media that can be linked to a room
+    ///
+    /// unlimited if unset
+    #[serde(default)]
+    pub max_media_storage: Option<u64>,
 }
 
 fn default_max_roles() -> u16 {
@@ -129,6 +135,7 @@ impl Default for LimitsRoom {
             max_channel_webhooks: default_max_channel_webhooks(),
             max_total_webhooks: default_max_total_webhooks(),
             max_bitrate: default_max_bitrate(),
+            max_media_storage: None,
         }
     }
 }
@@ -148,6 +155,12 @@ pub struct LimitsUser {
     /// ie. connections that are not `ConnectionVisibility::Private`
     #[serde(default = "default_max_public_connections")]
     pub max_public_connections: u8,
+
+    /// the maximum number of bytes of media a user can upload
+    ///
+    /// unlimited if unset
+    #[serde(default)]
+    pub max_media_storage: Option<u64>,
 }
 
 fn default_max_emails() -> u8 {
@@ -166,6 +179,7 @@ impl Default for LimitsUser {
             max_emails: default_max_emails(),
             max_room_joins: default_max_room_joins(),
             max_public_connections: default_max_public_connections(),
+            max_media_storage: None,
         }
     }
 }
@@ -15,7 +15,7 @@ use url::Url;
 use crate::{
     Error, Result,
     config::{limits::Limits, secret::Secret},
-    types::health::HealthcheckIssue,
+    types::{health::HealthcheckIssue, media::MediaPaths},
 };
 
 use common::v1::types::federation::Hostname;
@@ -170,10 +170,81 @@ pub struct ConfigOauthProvider {
     pub autoregister: bool,
 }
 
-// TODO: remove
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConfigUrlPreview {
-    // does this need anything?
+    #[serde(default)]
+    pub oembed: ConfigOEmbed,
+
+    /// whether to respect robots.txt
+    #[serde(default = "default_true")]
+    pub robots: bool,
+
+    /// the maximum number of parallel requests to a single host
+    #[serde(default = "default_url_preview_host_concurrency")]
+    pub host_concurrency: usize,
+
+    /// the minimum time between starting requests to a single host, in milliseconds
+    #[serde(default = "default_url_preview_host_interval")]
+    pub host_interval: u64,
+
+    /// the maximum number of unfurled urls to cache
+    #[serde(default = "default_url_preview_cache_size")]
+    pub cache_size: u64,
+}
+
+impl Default for ConfigUrlPreview {
+    fn default() -> Self {
+        Self {
+            oembed: ConfigOEmbed::default(),
+            robots: true,
+            host_concurrency: default_url_preview_host_concurrency(),
+            host_interval: default_url_preview_host_interval(),
+            cache_size: default_url_preview_cache_size(),
+        }
+    }
+}
+
+fn default_url_preview_host_concurrency() -> usize {
+    2
+}
+
+fn default_url_preview_host_interval() -> u64 {
+    250
+}
+
+fn default_url_preview_cache_size() -> u64 {
+    10_000
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigOEmbed {
+    /// whether to look for oembed links in html pages
+    #[serde(default = "default_true")]
+    pub discovery: bool,
+
+    /// providers to query directly for matching urls, without fetching the page first
+    #[serde(default)]
+    pub providers: Vec<ConfigOEmbedProvider>,
+}
+
+impl Default for ConfigOEmbed {
+    fn default() -> Self {
+        Self {
+            discovery: true,
+            providers: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigOEmbedProvider {
+    pub name: String,
+
+    /// url patterns this provider handles, where `*` matches anything
+    pub schemes: Vec<String>,
+
+    /// the oembed api endpoint
+    pub endpoint: Url,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -270,13 +341,59 @@ pub struct ConfigMedia {
     #[serde(default = "default_thumb_sizes")]
     pub thumb_sizes: Vec<u32>,
 
+    /// the video heights to transcode hls streams to
+    ///
+    /// heights larger than the source video are skipped
+    #[serde(default = "default_stream_heights")]
+    pub stream_heights: Vec<u32>,
+
     /// the maximum size of media in bytes (default 8MiB)
     #[serde(default = "default_max_media_size")]
     pub max_size: u64,
 
+    /// how long an unfinished upload can go without receiving data before it's
+    /// discarded, in seconds (default 1 hour)
+    ///
+    /// unfinished uploads only exist in the memory of the instance that created
+    /// them, so they're lost on restart regardless of this setting
+    #[serde(default = "default_upload_expiry")]
+    pub upload_expiry: u64,
+
+    /// the key used to sign image transform urls
+    ///
+    /// if None, image transforms are disabled
+    pub transform_key: Option<Secret>,
+
+    /// the maximum number of transformed variants stored for each piece of media
+    #[serde(default = "default_max_transforms")]
+    pub max_transforms: usize,
+
+    /// the key used to sign urls for media in private channels and dms
+    ///
+    /// if set, the media proxy refuses to serve private media without a valid,
+    /// unexpired signature. if None, anyone who knows a media id can fetch it.
+    pub url_key: Option<Secret>,
+
+    /// how long signed media urls are valid for, in seconds (default 12 hours)
+    ///
+    /// signatures are reused for half of this, so clients can cache urls
+    #[serde(default = "default_url_expiry")]
+    pub url_expiry: u64,
+
     /// media scanners
     #[serde(default)]
     pub scanners: Vec<ConfigMediaScanner>,
+
+    /// the prefix for media files in blob storage (default "media/")
+    #[serde(default = "default_media_path_prefix")]
+    pub path_prefix: String,
+}
+
+impl ConfigMedia {
+    /// get the paths for media files in blob storage
+    pub fn paths(&self) -> MediaPaths {
+        MediaPaths::new(&self.path_prefix)
+    }
 }
 
 fn default_cache_media() -> u64 {
@@ -291,18 +408,45 @@ fn default_thumb_sizes() -> Vec<u32> {
     vec![64, 320, 640]
 }
 
+fn default_stream_heights() -> Vec<u32> {
+    vec![360, 720, 1080]
+}
+
 fn default_max_media_size() -> u64 {
     8 * 1024 * 1024 // 8 MiB
 }
 
+fn default_upload_expiry() -> u64 {
+    60 * 60
+}
+
+fn default_max_transforms() -> usize {
+    32
+}
+
+fn default_url_expiry() -> u64 {
+    60 * 60 * 12
+}
+
+fn default_media_path_prefix() -> String {
+    "media/".to_owned()
+}
+
 impl Default for ConfigMedia {
     fn default() -> Self {
         ConfigMedia {
             cache_media: default_cache_media(),
             cache_emoji: default_cache_emoji(),
             thumb_sizes: default_thumb_sizes(),
+            stream_heights: default_stream_heights(),
             max_size: default_max_media_size(),
+            upload_expiry: default_upload_expiry(),
+            transform_key: None,
+            max_transforms: default_max_transforms(),
+            url_key: None,
+            url_expiry: default_url_expiry(),
             scanners: Vec::new(),
+            path_prefix: default_media_path_prefix(),
         }
     }
 }
@@ -324,6 +468,11 @@ pub struct ConfigVoice {
     /// defaults to the number of cpu cores
     pub workers: Option<u8>,
 
+    /// the maximum bandwidth this sfu can use in bits per second
+    ///
+    /// defaults to being unknown
+    pub bandwidth_max: Option<u64>,
+
     /// the udp port to use for media traffic
     ///
     /// defaults to a random port
@@ -337,10 +486,15 @@ pub struct ConfigVoice {
     #[serde(default)]
     pub quic_port: u16,
 
-    /// the udp port that the builtin stun server should listen on (TODO)
+    /// the udp port that the builtin stun/turn server should listen on
     ///
     /// defaults to being disabled
     pub stun_port: Option<u16>,
+
+    /// the shared secret used to mint turn credentials
+    ///
+    /// the builtin server only answers stun binding requests if this isn't set
+    pub turn_secret: Option<Secret>,
 }
 
 impl Default for ConfigScripts {
@@ -533,6 +687,28 @@ pub struct ConfigMediaScanner {
     /// This version is stored alongside scan results to track which scanner
     /// version was used for each scan.
     pub version: u16,
+
+    /// The URL to GET the scanner's capabilities from.
+    ///
+    /// If set, media the scanner doesn't accept is skipped, and files are
+    /// streamed to `stream_url` if the scanner supports it.
+    #[serde(default)]
+    pub info_url: Option<Url>,
+
+    /// The URL to POST raw file bodies to, for scanners that don't share a
+    /// filesystem with the backend.
+    #[serde(default)]
+    pub stream_url: Option<Url>,
+
+    /// How long to wait for a scan result (in seconds)
+    ///
+    /// defaults to 15 seconds
+    #[serde(default = "default_scanner_timeout")]
+    pub timeout: u64,
+}
+
+fn default_scanner_timeout() -> u64 {
+    15
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
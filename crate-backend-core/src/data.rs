@@ -744,6 +744,9 @@ pub trait DataApplication {
         owner_id: UserId,
         q: PaginationQuery<ApplicationId>,
     ) -> Result<PaginationResponse<Application>>;
+
+    /// list every application that has registered unfurl patterns
+    async fn application_list_unfurlers(&mut self) -> Result<Vec<Application>>;
 }
 
 #[async_trait]
@@ -984,6 +987,12 @@ pub trait DataAdmin {
     /// gets candidates for media garbage collection sweep
     async fn gc_media_get_sweep_candidates(&mut self, limit: u32) -> Result<Vec<MediaId>>;
 
+    /// release the blob referenced by a piece of media that is being swept
+    ///
+    /// returns the media that owns the blob and how many references remain, or
+    /// None if the media doesn't reference a shared blob
+    async fn gc_media_release_blob(&mut self, media_id: MediaId) -> Result<Option<(MediaId, u64)>>;
+
     /// deletes media that has been swept
     async fn gc_media_delete_swept(&mut self, ids: &[MediaId]) -> Result<u64>;
 
@@ -0,0 +1,82 @@
+//! audio analysis for waveforms and loudness normalization
+
+/// the sample rate audio is decoded at for waveforms
+///
+/// this is much lower than anything used for playback, but is more than enough
+/// resolution for a few hundred buckets
+pub const WAVEFORM_SAMPLE_RATE: u32 = 4000;
+
+/// the result of analyzing an audio track
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioAnalysis {
+    /// peak amplitude per bucket, from 0 to 255
+    pub waveform: Vec<u8>,
+
+    /// integrated loudness in LUFS
+    pub loudness: Option<f64>,
+}
+
+/// downsample mono s16le pcm into peaks
+///
+/// returns fewer buckets if there are fewer samples than buckets
+pub fn waveform(pcm: &[u8], buckets: usize) -> Vec<u8> {
+    let samples: Vec<i16> = pcm
+        .chunks_exact(2)
+        .map(|s| i16::from_le_bytes([s[0], s[1]]))
+        .collect();
+    if samples.is_empty() || buckets == 0 {
+        return vec![];
+    }
+
+    let buckets = buckets.min(samples.len());
+    (0..buckets)
+        .map(|i| {
+            let start = i * samples.len() / buckets;
+            let end = (i + 1) * samples.len() / buckets;
+            let peak = samples[start..end]
+                .iter()
+                .map(|s| s.unsigned_abs() as u32)
+                .max()
+                .unwrap_or(0);
+            (peak * 255 / 32768) as u8
+        })
+        .collect()
+}
+
+/// parse the integrated loudness from the summary the ebur128 filter logs
+pub fn parse_loudness(stderr: &str) -> Option<f64> {
+    let summary = &stderr[stderr.rfind("Integrated loudness:")?..];
+    summary
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("I:"))
+        .and_then(|l| l.split_whitespace().next())
+        .and_then(|l| l.parse::<f64>().ok())
+        .filter(|l| l.is_finite())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waveform_peaks() {
+        let pcm: Vec<u8> = [0i16, 100, -32768, 5, 16384, 0, 0, 0]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        assert_eq!(waveform(&pcm, 4), vec![0, 255, 127, 0]);
+        assert_eq!(waveform(&pcm, 100).len(), 8);
+        assert!(waveform(&[], 256).is_empty());
+    }
+
+    #[test]
+    fn loudness_summary() {
+        let stderr = "[Parsed_ebur128_0 @ 0x0] Summary:\n\n  Integrated loudness:\n    I:         -16.4 LUFS\n    Threshold: -26.7 LUFS\n\n  Loudness range:\n    LRA:         5.2 LU\n";
+        assert_eq!(parse_loudness(stderr), Some(-16.4));
+        assert_eq!(parse_loudness("nothing here"), None);
+        assert_eq!(
+            parse_loudness("Integrated loudness:\n    I:         -inf LUFS\n"),
+            None
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! previews and text extraction for pdfs and office documents
+//!
+//! ffmpeg can't read documents, so these shell out to poppler and libreoffice
+
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use tokio::process::Command;
+use tracing::error;
+
+use crate::ffmpeg::{Ffmpeg, FfmpegError};
+
+/// the maximum number of bytes of text extracted from a document
+pub const DOCUMENT_TEXT_LIMIT: usize = 1024 * 1024;
+
+/// the width and height the first page is rendered to fit inside
+pub const DOCUMENT_POSTER_SIZE: u32 = 1280;
+
+/// whether this is a document that libreoffice can convert into a pdf
+pub fn is_office_document(content_type: &str) -> bool {
+    content_type == "application/msword"
+        || content_type == "application/rtf"
+        || content_type == "application/vnd.ms-excel"
+        || content_type == "application/vnd.ms-powerpoint"
+        || content_type.starts_with("application/vnd.openxmlformats-officedocument.")
+        || content_type.starts_with("application/vnd.oasis.opendocument.")
+}
+
+/// parse the page count from the output of pdfinfo
+pub fn parse_page_count(stdout: &str) -> Option<u64> {
+    stdout
+        .lines()
+        .find_map(|l| l.strip_prefix("Pages:"))
+        .and_then(|l| l.trim().parse().ok())
+}
+
+/// truncate text to at most `limit` bytes without splitting a character
+pub fn truncate_text(mut text: String, limit: usize) -> String {
+    if text.len() > limit {
+        let mut end = limit;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+    text
+}
+
+impl Ffmpeg {
+    /// convert an office document into a pdf in `out_dir`
+    ///
+    /// returns the path to the converted pdf
+    pub async fn document_to_pdf(
+        &self,
+        in_path: &Path,
+        out_dir: &Path,
+    ) -> Result<PathBuf, FfmpegError> {
+        let output = tokio::time::timeout(
+            Duration::from_secs(120),
+            Command::new("soffice")
+                // separate profiles so multiple conversions can run at once
+                .arg(format!(
+                    "-env:UserInstallation=file://{}",
+                    out_dir.join("profile").display()
+                ))
+                .args([
+                    "--headless",
+                    "--norestore",
+                    "--convert-to",
+                    "pdf",
+                    "--outdir",
+                ])
+                .arg(out_dir)
+                .arg(in_path)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await
+        .map_err(|_| FfmpegError::TimedOut)??;
+
+        let stem = in_path.file_stem().ok_or(FfmpegError::Other)?;
+        let pdf_path = out_dir.join(stem).with_extension("pdf");
+        if output.status.success() && pdf_path.exists() {
+            Ok(pdf_path)
+        } else {
+            error!(
+                stderr = String::from_utf8_lossy(&output.stderr).to_string(),
+                "document conversion failed"
+            );
+            Err(FfmpegError::Other)
+        }
+    }
+
+    /// get the number of pages in a pdf
+    pub async fn pdf_page_count(&self, path: &Path) -> Result<u64, FfmpegError> {
+        let output = tokio::time::timeout(
+            Duration::from_secs(10),
+            Command::new("pdfinfo")
+                .arg(path)
+                .stdin(Stdio::null())
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await
+        .map_err(|_| FfmpegError::TimedOut)??;
+
+        if output.status.success() {
+            parse_page_count(&String::from_utf8_lossy(&output.stdout)).ok_or(FfmpegError::Other)
+        } else {
+            Err(FfmpegError::Other)
+        }
+    }
+
+    /// render the first page of a pdf to a png
+    pub async fn pdf_render_first_page(&self, path: &Path) -> Result<Vec<u8>, FfmpegError> {
+        let output = tokio::time::timeout(
+            Duration::from_secs(30),
+            Command::new("pdftoppm")
+                .args(["-q", "-png", "-singlefile", "-f", "1", "-l", "1"])
+                .args(["-scale-to", &DOCUMENT_POSTER_SIZE.to_string()])
+                .arg(path)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await
+        .map_err(|_| FfmpegError::TimedOut)??;
+
+        if output.status.success() && !output.stdout.is_empty() {
+            Ok(output.stdout)
+        } else {
+            error!(
+                stderr = String::from_utf8_lossy(&output.stderr).to_string(),
+                "pdf rendering failed"
+            );
+            Err(FfmpegError::Other)
+        }
+    }
+
+    /// extract up to [`DOCUMENT_TEXT_LIMIT`] bytes of text from a pdf
+    pub async fn pdf_extract_text(&self, path: &Path) -> Result<String, FfmpegError> {
+        let output = tokio::time::timeout(
+            Duration::from_secs(30),
+            Command::new("pdftotext")
+                .args(["-q", "-enc", "UTF-8", "-nopgbrk"])
+                .arg(path)
+                .arg("-")
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await
+        .map_err(|_| FfmpegError::TimedOut)??;
+
+        if output.status.success() {
+            let text = String::from_utf8_lossy(&output.stdout).into_owned();
+            Ok(truncate_text(text, DOCUMENT_TEXT_LIMIT))
+        } else {
+            Err(FfmpegError::Other)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_count() {
+        let stdout = "Producer:        LibreOffice 7.6\nTagged:          no\nPages:           12\nEncrypted:       no\n";
+        assert_eq!(parse_page_count(stdout), Some(12));
+        assert_eq!(parse_page_count("Pages: lots\n"), None);
+        assert_eq!(parse_page_count(""), None);
+    }
+
+    #[test]
+    fn truncate() {
+        assert_eq!(truncate_text("hello".to_owned(), 10), "hello");
+        assert_eq!(truncate_text("hello".to_owned(), 3), "hel");
+        assert_eq!(truncate_text("héllo".to_owned(), 2), "h");
+    }
+
+    #[test]
+    fn office_documents() {
+        assert!(is_office_document(
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        ));
+        assert!(is_office_document(
+            "application/vnd.oasis.opendocument.text"
+        ));
+        assert!(!is_office_document("application/pdf"));
+        assert!(!is_office_document("application/zip"));
+    }
+}
@@ -24,6 +24,8 @@ pub struct Stream {
     pub codec_type: MediaType,
     pub width: Option<u64>,
     pub height: Option<u64>,
+    pub channels: Option<u64>,
+    pub channel_layout: Option<String>,
     pub disposition: Disposition,
     #[serde(default)]
     pub tags: HashMap<String, String>,
@@ -71,6 +73,11 @@ impl MediaMetadata {
         self.get_main(MediaType::Video)
     }
 
+    /// get the default or first audio stream
+    pub fn get_main_audio(&self) -> Option<&Stream> {
+        self.get_main(MediaType::Audio)
+    }
+
     pub fn width(&self) -> Option<u64> {
         self.get_main_video().and_then(|v| v.width)
     }
@@ -3,12 +3,22 @@ use std::{
     process::Stdio,
 };
 
+use common::v2::types::media::proxy::{StreamFormat, StreamKind};
 use thiserror::Error;
 use tokio::process::Command;
 use tracing::error;
 
-use crate::{config::Config, ffmpeg::metadata::MediaMetadata};
+use crate::{
+    config::Config,
+    ffmpeg::{
+        audio::{AudioAnalysis, WAVEFORM_SAMPLE_RATE},
+        metadata::MediaMetadata,
+    },
+    types::media::{ImageTransform, ThumbFormat},
+};
 
+pub mod audio;
+pub mod document;
 pub mod metadata;
 
 #[derive(Debug, Default)]
@@ -28,6 +38,10 @@ pub enum FfmpegError {
     #[error("timed out")]
     TimedOut,
 
+    /// the command exited unsuccessfully
+    #[error("command failed: {0}")]
+    Failed(String),
+
     // TODO: better errors
     /// other
     #[error("other")]
@@ -116,33 +130,28 @@ impl Ffmpeg {
         in_path: &Path,
         out_path: &Path,
         size: u32,
+        format: ThumbFormat,
         animate: bool,
     ) -> Result<(), FfmpegError> {
         let mut cmd = Command::new(self.resolved_ffmpeg_path());
         cmd.args(["-v", "quiet", "-y", "-i"]).arg(in_path);
+        cmd.args([
+            "-vf",
+            &format!("scale={size}:{size}:force_original_aspect_ratio=decrease"),
+        ]);
 
         if animate {
-            // Generate animated WebP for thumbnails
-            cmd.args([
-                "-vf",
-                &format!("scale={size}:{size}:force_original_aspect_ratio=decrease"),
-                "-loop",
-                "0",
-                "-f",
-                "webp",
-            ]);
+            cmd.args(["-loop", "0"]);
         } else {
-            // Generate static AVIF (first frame)
-            cmd.args([
-                "-vf",
-                &format!("scale={size}:{size}:force_original_aspect_ratio=decrease"),
-                "-frames:v",
-                "1",
-                "-f",
-                "avif",
-            ]);
+            // first frame only
+            cmd.args(["-frames:v", "1"]);
+        }
+
+        if format == ThumbFormat::Jpeg {
+            cmd.args(["-q:v", "3"]);
         }
 
+        cmd.args(["-f", format.ffmpeg_format()]);
         cmd.arg(out_path);
 
         let output = cmd
@@ -164,6 +173,204 @@ impl Ffmpeg {
         }
     }
 
+    /// resize, crop, or convert an image
+    pub async fn transform_image(
+        &self,
+        in_path: &Path,
+        out_path: &Path,
+        transform: &ImageTransform,
+        format: ThumbFormat,
+    ) -> Result<(), FfmpegError> {
+        let mut cmd = Command::new(self.resolved_ffmpeg_path());
+        cmd.args(["-v", "error", "-y", "-i"]).arg(in_path);
+        if let Some(filter) = transform.ffmpeg_filter() {
+            cmd.args(["-vf", &filter]);
+        }
+        cmd.args(["-frames:v", "1"]);
+
+        // map quality (1-100) to each encoder's scale
+        let quality = transform.quality.map(u32::from);
+        match format {
+            ThumbFormat::Jpeg => {
+                let q = quality.map(|q| 2 + (100 - q) * 29 / 99).unwrap_or(3);
+                cmd.args(["-q:v", &q.to_string()]);
+            }
+            ThumbFormat::Webp => {
+                if let Some(q) = quality {
+                    cmd.args(["-quality", &q.to_string()]);
+                }
+            }
+            ThumbFormat::Avif => {
+                if let Some(q) = quality {
+                    cmd.args(["-crf", &((100 - q) * 63 / 100).to_string()]);
+                }
+            }
+        }
+
+        cmd.args(["-f", format.ffmpeg_format()]);
+        cmd.arg(out_path);
+
+        let output = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            error!(stderr, "image transform failed");
+            Err(FfmpegError::Failed(stderr))
+        }
+    }
+
+    /// transcode a video or audio file into a single hls stream
+    ///
+    /// writes `index.m3u8` and numbered segments into `out_dir`. segment uris in
+    /// the playlist are `base_url` followed by the segment number.
+    pub async fn transcode_to_hls(
+        &self,
+        in_path: &Path,
+        out_dir: &Path,
+        format: &StreamFormat,
+        bitrate: u64,
+        base_url: &str,
+    ) -> Result<(), FfmpegError> {
+        let mut cmd = Command::new(self.resolved_ffmpeg_path());
+        cmd.args(["-v", "quiet", "-y", "-i"]).arg(in_path);
+
+        match format.kind {
+            StreamKind::Video => {
+                let width = format.width.unwrap_or(0);
+                let height = format.height.unwrap_or(0);
+                cmd.args([
+                    "-map",
+                    "0:v:0",
+                    "-map",
+                    "0:a:0?",
+                    "-c:v",
+                    "libx264",
+                    "-preset",
+                    "veryfast",
+                    "-vf",
+                    &format!("scale={width}:{height}"),
+                    "-b:v",
+                    &bitrate.to_string(),
+                    "-maxrate",
+                    &bitrate.to_string(),
+                    "-bufsize",
+                    &(bitrate * 2).to_string(),
+                    "-sc_threshold",
+                    "0",
+                    "-c:a",
+                    "aac",
+                    "-b:a",
+                    "128k",
+                    "-ac",
+                    "2",
+                ]);
+            }
+            StreamKind::Audio => {
+                let channels = format.channels.unwrap_or(2);
+                cmd.args([
+                    "-map",
+                    "0:a:0",
+                    "-vn",
+                    "-c:a",
+                    "aac",
+                    "-b:a",
+                    &bitrate.to_string(),
+                    "-ac",
+                    &channels.to_string(),
+                ]);
+            }
+        }
+
+        cmd.args([
+            "-f",
+            "hls",
+            "-hls_time",
+            "6",
+            "-hls_playlist_type",
+            "vod",
+            "-hls_base_url",
+            base_url,
+            "-hls_segment_filename",
+        ])
+        .arg(out_dir.join("%d"))
+        .arg(out_dir.join("index.m3u8"));
+
+        let output = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            error!(
+                stderr = String::from_utf8_lossy(&output.stderr).to_string(),
+                stdout = String::from_utf8_lossy(&output.stdout).to_string(),
+                "hls transcode failed",
+            );
+            Err(FfmpegError::Other)
+        }
+    }
+
+    /// generate trickplay sheets from a video
+    ///
+    /// takes a frame every `interval` milliseconds, scales it to fit `tile` and
+    /// tiles them into a `grid`. sheets are written to `out_dir` as `0.jpg`, `1.jpg`, etc.
+    pub async fn generate_trickplay(
+        &self,
+        in_path: &Path,
+        out_dir: &Path,
+        interval: u64,
+        tile: (u32, u32),
+        grid: (u32, u32),
+    ) -> Result<(), FfmpegError> {
+        let (w, h) = tile;
+        let filter = format!(
+            "fps=1000/{interval},scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,tile={}x{}",
+            grid.0, grid.1,
+        );
+        let output = Command::new(self.resolved_ffmpeg_path())
+            .args(["-v", "quiet", "-y", "-i"])
+            .arg(in_path)
+            .args([
+                "-map",
+                "0:v:0",
+                "-an",
+                "-vf",
+                &filter,
+                "-q:v",
+                "5",
+                "-start_number",
+                "0",
+            ])
+            .arg(out_dir.join("%d.jpg"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            error!(
+                stderr = String::from_utf8_lossy(&output.stderr).to_string(),
+                stdout = String::from_utf8_lossy(&output.stdout).to_string(),
+                "generate trickplay failed",
+            );
+            Err(FfmpegError::Other)
+        }
+    }
+
     pub async fn extract_attachment(
         &self,
         path: &Path,
@@ -278,6 +485,151 @@ impl Ffmpeg {
         }
     }
 
+    /// copy every audio, video, and subtitle stream into a new file without any metadata
+    ///
+    /// data streams are dropped, since phones use them for location tracks
+    pub async fn remux_without_metadata(
+        &self,
+        in_path: &Path,
+        out_path: &Path,
+        format: &str,
+    ) -> Result<(), FfmpegError> {
+        let output = Command::new(self.resolved_ffmpeg_path())
+            .args(["-v", "quiet", "-y", "-i"])
+            .arg(in_path)
+            .args([
+                "-map",
+                "0",
+                "-dn",
+                "-c",
+                "copy",
+                // global, per stream, and chapter metadata
+                "-map_metadata",
+                "-1",
+                "-map_metadata:s",
+                "-1",
+                "-map_chapters",
+                "-1",
+                "-fflags",
+                "+bitexact",
+                "-f",
+                format,
+            ])
+            .arg(out_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            error!(
+                stderr = String::from_utf8_lossy(&output.stderr).to_string(),
+                stdout = String::from_utf8_lossy(&output.stdout).to_string(),
+                "remux failed",
+            );
+            Err(FfmpegError::Other)
+        }
+    }
+
+    /// mix several audio files into a single opus track
+    ///
+    /// each input is delayed by its offset in milliseconds, so that inputs that
+    /// started at different times line up
+    pub async fn mix_audio(
+        &self,
+        inputs: &[(&Path, u64)],
+        out_path: &Path,
+    ) -> Result<(), FfmpegError> {
+        let mut cmd = Command::new(self.resolved_ffmpeg_path());
+        cmd.args(["-v", "quiet", "-y"]);
+        for (path, _) in inputs {
+            cmd.arg("-i").arg(path);
+        }
+
+        let mut filter = String::new();
+        for (i, (_, offset)) in inputs.iter().enumerate() {
+            filter.push_str(&format!("[{i}:a]adelay={offset}:all=1[a{i}];"));
+        }
+        for i in 0..inputs.len() {
+            filter.push_str(&format!("[a{i}]"));
+        }
+        filter.push_str(&format!(
+            "amix=inputs={}:duration=longest:normalize=0[out]",
+            inputs.len()
+        ));
+
+        let output = cmd
+            .args([
+                "-filter_complex",
+                &filter,
+                "-map",
+                "[out]",
+                "-c:a",
+                "libopus",
+                "-b:a",
+                "96k",
+                "-f",
+                "ogg",
+            ])
+            .arg(out_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            error!(
+                stderr = String::from_utf8_lossy(&output.stderr).to_string(),
+                stdout = String::from_utf8_lossy(&output.stdout).to_string(),
+                "mix audio failed",
+            );
+            Err(FfmpegError::Other)
+        }
+    }
+
+    /// decode the main audio track to calculate a waveform and its loudness
+    pub async fn analyze_audio(
+        &self,
+        path: &Path,
+        buckets: usize,
+    ) -> Result<AudioAnalysis, FfmpegError> {
+        // ebur128 passes audio through unchanged, so the same decode can be
+        // used for the waveform. its summary is logged at the info level.
+        let output = tokio::time::timeout(
+            std::time::Duration::from_secs(60),
+            Command::new(self.resolved_ffmpeg_path())
+                .args(["-v", "info", "-nostats", "-hide_banner", "-i"])
+                .arg(path)
+                .args(["-map", "0:a:0", "-af", "ebur128=framelog=quiet", "-ac", "1"])
+                .args(["-ar", &WAVEFORM_SAMPLE_RATE.to_string()])
+                .args(["-f", "s16le", "-"])
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await
+        .map_err(|_| FfmpegError::TimedOut)??;
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if output.status.success() {
+            Ok(AudioAnalysis {
+                waveform: audio::waveform(&output.stdout, buckets),
+                loudness: audio::parse_loudness(&stderr),
+            })
+        } else {
+            error!(stderr = stderr.to_string(), "audio analysis failed");
+            Err(FfmpegError::Other)
+        }
+    }
+
     pub async fn extract_metadata(&self, path: &Path) -> Result<MediaMetadata, FfmpegError> {
         let out = tokio::time::timeout(
             std::time::Duration::from_secs(10),
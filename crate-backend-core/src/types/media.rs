@@ -1,4 +1,13 @@
-use common::v2::types::MediaId;
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use common::v2::types::{
+    MediaId,
+    media::{
+        MediaMetadata,
+        proxy::{MediaSigned, TransformFit, TransformFormat, TransformQuery},
+    },
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
 /// media path calculator
 pub struct MediaPaths {
@@ -12,6 +21,11 @@ impl MediaPaths {
         }
     }
 
+    /// get the directory containing all of a piece of media's files
+    pub fn dir(&self, media_id: MediaId) -> String {
+        format!("{}/", self.base(media_id))
+    }
+
     /// get the path for the file itself
     pub fn file(&self, media_id: MediaId) -> String {
         format!("{}/file", self.base(media_id))
@@ -22,6 +36,11 @@ impl MediaPaths {
         format!("{}/poster", self.base(media_id))
     }
 
+    /// get the path for the text extracted from a document
+    pub fn text(&self, media_id: MediaId) -> String {
+        format!("{}/text", self.base(media_id))
+    }
+
     /// get the path for the file's (potentially animated) generated thumbnail of a specific size
     pub fn thumb(&self, media_id: MediaId, size: u32, ext: &str) -> String {
         format!("{}/thumb/{}x{}.{}", self.base(media_id), size, size, ext)
@@ -43,10 +62,569 @@ impl MediaPaths {
         format!("{}/gifv", self.base(media_id))
     }
 
+    /// get the path for the hls playlist of a single stream
+    pub fn stream_playlist(&self, media_id: MediaId, stream_id: u64) -> String {
+        format!("{}/stream/{}/index.m3u8", self.base(media_id), stream_id)
+    }
+
+    /// get the path for a single hls segment of a stream
+    pub fn stream_segment(&self, media_id: MediaId, stream_id: u64, segment: usize) -> String {
+        format!(
+            "{}/stream/{}/{}.ts",
+            self.base(media_id),
+            stream_id,
+            segment
+        )
+    }
+
+    /// get the path for the index of a set of trickplay sheets
+    ///
+    /// `tile` is the size of each thumbnail and `grid` is the number of thumbnails in each sheet
+    pub fn trickplay_index(&self, media_id: MediaId, tile: (u32, u32), grid: (u32, u32)) -> String {
+        format!("{}/index.json", self.trickplay_base(media_id, tile, grid))
+    }
+
+    /// get the path for a single trickplay sheet
+    pub fn trickplay_sheet(
+        &self,
+        media_id: MediaId,
+        tile: (u32, u32),
+        grid: (u32, u32),
+        sheet: u32,
+    ) -> String {
+        format!(
+            "{}/{}.jpg",
+            self.trickplay_base(media_id, tile, grid),
+            sheet
+        )
+    }
+
+    /// get the path for a transformed variant of an image
+    pub fn transform(
+        &self,
+        media_id: MediaId,
+        transform: &ImageTransform,
+        format: ThumbFormat,
+    ) -> String {
+        format!(
+            "{}{}.{}",
+            self.transform_dir(media_id),
+            transform.key(),
+            format.ext()
+        )
+    }
+
+    /// get the directory containing every transformed variant of an image
+    pub fn transform_dir(&self, media_id: MediaId) -> String {
+        format!("{}/transform/", self.base(media_id))
+    }
+
+    fn trickplay_base(&self, media_id: MediaId, tile: (u32, u32), grid: (u32, u32)) -> String {
+        format!(
+            "{}/thumb/trickplay/{}x{}_{}x{}",
+            self.base(media_id),
+            tile.0,
+            tile.1,
+            grid.0,
+            grid.1
+        )
+    }
+
     fn base(&self, media_id: MediaId) -> String {
         format!("{}{}", self.prefix, media_id)
     }
+}
+
+/// videos longer than this many milliseconds only get static thumbnails
+pub const MAX_ANIMATED_THUMB_DURATION: u64 = 30_000;
+
+/// whether a piece of media should have animated thumbnails
+pub fn can_animate_thumb(content_type: &str, metadata: &MediaMetadata) -> bool {
+    match metadata {
+        MediaMetadata::Video { duration, .. } => {
+            content_type.starts_with("video/") && *duration <= MAX_ANIMATED_THUMB_DURATION
+        }
+        _ => content_type == "image/gif",
+    }
+}
+
+/// an image format that thumbnails can be generated in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ThumbFormat {
+    Avif,
+    Webp,
+    Jpeg,
+}
+
+impl ThumbFormat {
+    /// every format, in order of preference
+    pub const ALL: [ThumbFormat; 3] = [ThumbFormat::Avif, ThumbFormat::Webp, ThumbFormat::Jpeg];
+
+    /// every format that can be animated, in order of preference
+    pub const ANIMATED: [ThumbFormat; 2] = [ThumbFormat::Webp, ThumbFormat::Avif];
+
+    pub fn ext(&self) -> &'static str {
+        match self {
+            ThumbFormat::Avif => "avif",
+            ThumbFormat::Webp => "webp",
+            ThumbFormat::Jpeg => "jpg",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ThumbFormat::Avif => "image/avif",
+            ThumbFormat::Webp => "image/webp",
+            ThumbFormat::Jpeg => "image/jpeg",
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.mime() == mime)
+    }
+
+    /// the ffmpeg muxer for this format
+    pub fn ffmpeg_format(&self) -> &'static str {
+        match self {
+            ThumbFormat::Avif => "avif",
+            ThumbFormat::Webp => "webp",
+            ThumbFormat::Jpeg => "mjpeg",
+        }
+    }
+
+    /// pick the best format for an `Accept` header
+    ///
+    /// if no animated format is acceptable, returns a static format and `false`
+    pub fn negotiate(accept: Option<&str>, animated: bool) -> (ThumbFormat, bool) {
+        let Some(accept) = accept else {
+            return if animated {
+                (ThumbFormat::Webp, true)
+            } else {
+                (ThumbFormat::Avif, false)
+            };
+        };
+
+        let pick = |formats: &[ThumbFormat]| {
+            let mut best: Option<(ThumbFormat, f32)> = None;
+            for format in formats {
+                let q = accept_quality(accept, format.mime());
+                if q > 0.0 && best.is_none_or(|(_, b)| q > b) {
+                    best = Some((*format, q));
+                }
+            }
+            best.map(|(f, _)| f)
+        };
+
+        if animated && let Some(format) = pick(&Self::ANIMATED) {
+            return (format, true);
+        }
+
+        // jpeg is always supported, even if the client doesn't say so
+        (pick(&Self::ALL).unwrap_or(ThumbFormat::Jpeg), false)
+    }
+}
+
+impl From<TransformFormat> for ThumbFormat {
+    fn from(value: TransformFormat) -> Self {
+        match value {
+            TransformFormat::Avif => ThumbFormat::Avif,
+            TransformFormat::Webp => ThumbFormat::Webp,
+            TransformFormat::Jpeg => ThumbFormat::Jpeg,
+        }
+    }
+}
+
+/// the largest width or height an image can be transformed to
+pub const MAX_TRANSFORM_SIZE: u32 = 4096;
+
+/// a validated on-the-fly image transform
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ImageTransform {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: TransformFit,
+
+    /// x, y, width, height
+    pub crop: Option<(u32, u32, u32, u32)>,
+
+    /// the output format, or None to negotiate it
+    pub format: Option<TransformFormat>,
+    pub quality: Option<u8>,
+}
+
+impl ImageTransform {
+    /// validate a transform query, returning the reason it's invalid if it is
+    pub fn from_query(query: &TransformQuery) -> Result<Self, &'static str> {
+        let size_ok = |s: Option<u32>| s.is_none_or(|s| (1..=MAX_TRANSFORM_SIZE).contains(&s));
+        if !size_ok(query.w) || !size_ok(query.h) {
+            return Err("width and height must be between 1 and 4096");
+        }
+
+        if query.fit == TransformFit::Cover && (query.w.is_none() || query.h.is_none()) {
+            return Err("cover needs both a width and height");
+        }
+
+        if query.quality.is_some_and(|q| !(1..=100).contains(&q)) {
+            return Err("quality must be between 1 and 100");
+        }
+
+        let crop = match &query.crop {
+            Some(crop) => {
+                let parts: Vec<u32> = crop
+                    .split(',')
+                    .map(|p| p.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| "crop must be x,y,width,height")?;
+                match parts[..] {
+                    [x, y, w, h] if w > 0 && h > 0 => Some((x, y, w, h)),
+                    _ => return Err("crop must be x,y,width,height"),
+                }
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            width: query.w,
+            height: query.h,
+            fit: query.fit,
+            crop,
+            format: query.format,
+            quality: query.quality,
+        })
+    }
+
+    /// a canonical name for this transform, excluding the format
+    pub fn key(&self) -> String {
+        let mut parts = vec![];
+        if let Some((x, y, w, h)) = self.crop {
+            parts.push(format!("c{x}-{y}-{w}-{h}"));
+        }
+        if let Some(w) = self.width {
+            parts.push(format!("w{w}"));
+        }
+        if let Some(h) = self.height {
+            parts.push(format!("h{h}"));
+        }
+        parts.push(
+            match self.fit {
+                TransformFit::Cover => "cover",
+                TransformFit::Contain => "contain",
+            }
+            .to_owned(),
+        );
+        if let Some(q) = self.quality {
+            parts.push(format!("q{q}"));
+        }
+        parts.join("_")
+    }
+
+    /// the query parameters for a url with this transform
+    pub fn query_pairs(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = vec![];
+        if let Some(w) = self.width {
+            pairs.push(("w", w.to_string()));
+        }
+        if let Some(h) = self.height {
+            pairs.push(("h", h.to_string()));
+        }
+        if self.fit == TransformFit::Cover {
+            pairs.push(("fit", "cover".to_owned()));
+        }
+        if let Some((x, y, w, h)) = self.crop {
+            pairs.push(("crop", format!("{x},{y},{w},{h}")));
+        }
+        if let Some(format) = self.format {
+            let format = match format {
+                TransformFormat::Avif => "avif",
+                TransformFormat::Webp => "webp",
+                TransformFormat::Jpeg => "jpeg",
+            };
+            pairs.push(("format", format.to_owned()));
+        }
+        if let Some(q) = self.quality {
+            pairs.push(("quality", q.to_string()));
+        }
+        pairs
+    }
+
+    /// the ffmpeg filter graph that applies this transform
+    pub fn ffmpeg_filter(&self) -> Option<String> {
+        let mut filters = vec![];
+        if let Some((x, y, w, h)) = self.crop {
+            // clamp to the source image so oversized crops don't fail
+            filters.push(format!(
+                "crop='min({w},iw-min({x},iw-1))':'min({h},ih-min({y},ih-1))':'min({x},iw-1)':'min({y},ih-1)'"
+            ));
+        }
+        match (self.width, self.height, self.fit) {
+            (Some(w), Some(h), TransformFit::Cover) => {
+                filters.push(format!(
+                    "scale={w}:{h}:force_original_aspect_ratio=increase,crop={w}:{h}"
+                ));
+            }
+            (Some(w), Some(h), TransformFit::Contain) => {
+                filters.push(format!(
+                    "scale={w}:{h}:force_original_aspect_ratio=decrease"
+                ));
+            }
+            (Some(w), None, _) => filters.push(format!("scale={w}:-1")),
+            (None, Some(h), _) => filters.push(format!("scale=-1:{h}")),
+            (None, None, _) => {}
+        }
+        (!filters.is_empty()).then(|| filters.join(","))
+    }
+
+    /// sign this transform so the media server will generate it
+    ///
+    /// transforms of private media should have an expiry, so leaked links stop working
+    pub fn sign(&self, media_id: MediaId, expires: Option<u64>, key: &[u8]) -> String {
+        let mac = self.mac(media_id, expires, key).finalize().into_bytes();
+        BASE64_URL_SAFE_NO_PAD.encode(mac)
+    }
+
+    /// check if a signature for this transform is valid
+    ///
+    /// this doesn't check whether the signature has expired
+    pub fn verify(&self, media_id: MediaId, expires: Option<u64>, key: &[u8], sig: &str) -> bool {
+        let Ok(sig) = BASE64_URL_SAFE_NO_PAD.decode(sig) else {
+            return false;
+        };
+        self.mac(media_id, expires, key).verify_slice(&sig).is_ok()
+    }
+
+    fn mac(&self, media_id: MediaId, expires: Option<u64>, key: &[u8]) -> Hmac<Sha256> {
+        let format = self
+            .format
+            .map(|f| ThumbFormat::from(f).ext())
+            .unwrap_or("auto");
+        let expires = expires.map(|e| e.to_string()).unwrap_or_default();
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+        mac.update(format!("transform:{media_id}:{}.{format}:{expires}", self.key()).as_bytes());
+        mac
+    }
+}
+
+/// a file the media proxy serves for a piece of media
+///
+/// signed urls are bound to a variant, so a signature for a thumbnail can't be
+/// used to fetch the original file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MediaVariant {
+    Media,
+    Thumb,
+    Gifv,
+    Stream,
+    Trickplay,
+}
+
+impl MediaVariant {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MediaVariant::Media => "media",
+            MediaVariant::Thumb => "thumb",
+            MediaVariant::Gifv => "gifv",
+            MediaVariant::Stream => "stream",
+            MediaVariant::Trickplay => "trickplay",
+        }
+    }
+
+    /// sign a url for this variant that's valid until `expires` (in seconds since the unix epoch)
+    pub fn sign(&self, media_id: MediaId, expires: u64, key: &[u8]) -> String {
+        let mac = self.mac(media_id, expires, key).finalize().into_bytes();
+        BASE64_URL_SAFE_NO_PAD.encode(mac)
+    }
+
+    /// check if a signature for this variant is valid
+    ///
+    /// this doesn't check whether the signature has expired
+    pub fn verify(&self, media_id: MediaId, expires: u64, key: &[u8], sig: &str) -> bool {
+        let Ok(sig) = BASE64_URL_SAFE_NO_PAD.decode(sig) else {
+            return false;
+        };
+        self.mac(media_id, expires, key).verify_slice(&sig).is_ok()
+    }
+
+    /// sign urls for every variant of a piece of media
+    pub fn sign_all(media_id: MediaId, expires: u64, key: &[u8]) -> MediaSigned {
+        MediaSigned {
+            expires,
+            media: MediaVariant::Media.sign(media_id, expires, key),
+            thumb: MediaVariant::Thumb.sign(media_id, expires, key),
+            gifv: MediaVariant::Gifv.sign(media_id, expires, key),
+            stream: MediaVariant::Stream.sign(media_id, expires, key),
+            trickplay: MediaVariant::Trickplay.sign(media_id, expires, key),
+        }
+    }
+
+    fn mac(&self, media_id: MediaId, expires: u64, key: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+        mac.update(format!("media:{media_id}:{}:{expires}", self.name()).as_bytes());
+        mac
+    }
+}
+
+/// get the quality value for a mime type in an `Accept` header
+fn accept_quality(accept: &str, mime: &str) -> f32 {
+    let (ty, _) = mime.split_once('/').unwrap_or((mime, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let range = parts.next().unwrap_or_default().trim();
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        // more specific ranges take precedence
+        let specificity = if range.eq_ignore_ascii_case(mime) {
+            2
+        } else if range
+            .strip_suffix("/*")
+            .is_some_and(|t| t.eq_ignore_ascii_case(ty))
+        {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+
+        if best.is_none_or(|(s, _)| specificity > s) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map(|(_, q)| q).unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_no_accept() {
+        assert_eq!(
+            ThumbFormat::negotiate(None, false),
+            (ThumbFormat::Avif, false)
+        );
+        assert_eq!(
+            ThumbFormat::negotiate(None, true),
+            (ThumbFormat::Webp, true)
+        );
+    }
+
+    #[test]
+    fn negotiate_browser() {
+        let accept = "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8";
+        assert_eq!(
+            ThumbFormat::negotiate(Some(accept), false),
+            (ThumbFormat::Avif, false)
+        );
+        assert_eq!(
+            ThumbFormat::negotiate(Some(accept), true),
+            (ThumbFormat::Webp, true)
+        );
+    }
+
+    #[test]
+    fn transform_query() {
+        let query = TransformQuery {
+            w: Some(320),
+            h: Some(180),
+            fit: TransformFit::Cover,
+            crop: Some("10,20,300,200".to_owned()),
+            format: Some(TransformFormat::Webp),
+            quality: Some(80),
+        };
+        let t = ImageTransform::from_query(&query).unwrap();
+        assert_eq!(t.key(), "c10-20-300-200_w320_h180_cover_q80");
+        assert_eq!(
+            t.query_pairs(),
+            vec![
+                ("w", "320".to_owned()),
+                ("h", "180".to_owned()),
+                ("fit", "cover".to_owned()),
+                ("crop", "10,20,300,200".to_owned()),
+                ("format", "webp".to_owned()),
+                ("quality", "80".to_owned()),
+            ]
+        );
+
+        let bad = |q: TransformQuery| ImageTransform::from_query(&q).is_err();
+        assert!(bad(TransformQuery {
+            w: Some(10_000),
+            ..Default::default()
+        }));
+        assert!(bad(TransformQuery {
+            w: Some(100),
+            fit: TransformFit::Cover,
+            ..Default::default()
+        }));
+        assert!(bad(TransformQuery {
+            crop: Some("1,2,3".to_owned()),
+            ..Default::default()
+        }));
+        assert!(bad(TransformQuery {
+            quality: Some(0),
+            ..Default::default()
+        }));
+    }
 
-    // TODO: stream
-    // TODO: trickplay
+    #[test]
+    fn transform_signature() {
+        let media_id = MediaId::new();
+        let t = ImageTransform::from_query(&TransformQuery {
+            w: Some(64),
+            ..Default::default()
+        })
+        .unwrap();
+        let sig = t.sign(media_id, None, b"secret");
+        assert!(t.verify(media_id, None, b"secret", &sig));
+        assert!(!t.verify(media_id, None, b"other", &sig));
+        assert!(!t.verify(MediaId::new(), None, b"secret", &sig));
+        assert!(!t.verify(media_id, Some(1000), b"secret", &sig));
+
+        let mut other = t.clone();
+        other.width = Some(128);
+        assert!(!other.verify(media_id, None, b"secret", &sig));
+
+        let sig = t.sign(media_id, Some(1000), b"secret");
+        assert!(t.verify(media_id, Some(1000), b"secret", &sig));
+        assert!(!t.verify(media_id, Some(1001), b"secret", &sig));
+        assert!(!t.verify(media_id, None, b"secret", &sig));
+    }
+
+    #[test]
+    fn media_signature() {
+        let media_id = MediaId::new();
+        let sig = MediaVariant::Thumb.sign(media_id, 1000, b"secret");
+        assert!(MediaVariant::Thumb.verify(media_id, 1000, b"secret", &sig));
+        assert!(!MediaVariant::Thumb.verify(media_id, 1001, b"secret", &sig));
+        assert!(!MediaVariant::Thumb.verify(media_id, 1000, b"other", &sig));
+        assert!(!MediaVariant::Thumb.verify(MediaId::new(), 1000, b"secret", &sig));
+        assert!(!MediaVariant::Media.verify(media_id, 1000, b"secret", &sig));
+
+        let signed = MediaVariant::sign_all(media_id, 1000, b"secret");
+        assert_eq!(signed.thumb, sig);
+        assert!(MediaVariant::Media.verify(media_id, 1000, b"secret", &signed.media));
+    }
+
+    #[test]
+    fn negotiate_fallback() {
+        assert_eq!(
+            ThumbFormat::negotiate(Some("image/jpeg"), true),
+            (ThumbFormat::Jpeg, false)
+        );
+        assert_eq!(
+            ThumbFormat::negotiate(Some("image/webp;q=0.5,image/avif;q=0.9"), false),
+            (ThumbFormat::Avif, false)
+        );
+        assert_eq!(
+            ThumbFormat::negotiate(Some("image/avif;q=0,image/*"), true),
+            (ThumbFormat::Webp, true)
+        );
+        assert_eq!(
+            ThumbFormat::negotiate(Some("text/html"), false),
+            (ThumbFormat::Jpeg, false)
+        );
+    }
 }
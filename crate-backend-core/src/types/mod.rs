@@ -10,3 +10,4 @@ pub mod health;
 pub mod media;
 pub mod permission;
 pub mod search;
+pub mod turn;
@@ -0,0 +1,37 @@
+//! credentials for the builtin turn server
+//!
+//! uses the same scheme as coturn's `use-auth-secret`: the username contains
+//! an expiry timestamp and the password is an hmac of the username.
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use common::v2::types::UserId;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+/// time limited credentials for a turn server
+#[derive(Debug, Clone)]
+pub struct TurnCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl TurnCredentials {
+    /// mint credentials for a user that are valid until `expires` (in seconds since the unix epoch)
+    pub fn mint(user_id: UserId, expires: u64, secret: &[u8]) -> Self {
+        let username = format!("{expires}:{user_id}");
+        let password = Self::password(&username, secret);
+        Self { username, password }
+    }
+
+    /// get the password for a username
+    pub fn password(username: &str, secret: &[u8]) -> String {
+        let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac accepts any key length");
+        mac.update(username.as_bytes());
+        BASE64_STANDARD.encode(mac.finalize().into_bytes())
+    }
+
+    /// get when a username expires (in seconds since the unix epoch)
+    pub fn expires(username: &str) -> Option<u64> {
+        username.split_once(':')?.0.parse().ok()
+    }
+}
@@ -0,0 +1,11 @@
+-- files shared between deduplicated media, keyed by their sha512/256 hash
+create table media_blob (
+    hash bytea primary key,
+    -- the media whose directory holds the files
+    media_id uuid not null,
+    size bigint not null,
+    ref_count bigint not null
+);
+
+alter table media add column blob_hash bytea references media_blob(hash) on delete set null;
+create index media_blob_hash on media (blob_hash);
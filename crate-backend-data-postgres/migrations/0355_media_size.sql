@@ -0,0 +1,3 @@
+-- the size of each piece of media, for storage quotas
+alter table media add column size bigint generated always as ((data->>'size')::bigint) stored;
+create index media_user_id on media (user_id) where deleted_at is null;
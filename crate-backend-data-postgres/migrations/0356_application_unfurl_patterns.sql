@@ -0,0 +1 @@
+alter table application add column unfurl_patterns jsonb not null default '[]';
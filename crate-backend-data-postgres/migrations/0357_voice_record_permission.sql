@@ -0,0 +1 @@
+alter type permission add value 'VoiceRecord';
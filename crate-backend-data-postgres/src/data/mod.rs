@@ -25,7 +25,7 @@ use common::v1::types::{
 use common::v1::types::{ChannelSeq, RoomFeature};
 use common::v2::types::HarvestId;
 use common::v2::types::embed::Embed;
-use common::v2::types::media::{Media, MediaPatch};
+use common::v2::types::media::{Media, MediaPatch, MediaUsage};
 use lamprey_backend_core::data::DataScript;
 pub use lamprey_backend_core::data::{
     DataAdmin, DataApplication, DataAuditLogs, DataAutomod, DataCalendar, DataConfigInternal,
@@ -190,6 +190,9 @@ pub trait DataMedia {
         link_type: MediaLinkType,
     ) -> Result<()>;
     async fn media_link_select(&mut self, media_id: MediaId) -> Result<Vec<MediaLink>>;
+
+    /// get the channels containing the messages and documents a piece of media is linked to
+    async fn media_link_channels(&mut self, media_id: MediaId) -> Result<Vec<ChannelId>>;
     async fn media_link_delete(&mut self, target_id: Uuid, link_type: MediaLinkType) -> Result<()>;
     async fn media_link_delete_all(&mut self, target_id: Uuid) -> Result<()>;
     async fn media_link_create_exclusive(
@@ -209,6 +212,36 @@ pub trait DataMedia {
         hostname: &Hostname,
         origin_id: Uuid,
     ) -> Result<Option<Media>>;
+
+    /// get the media that owns the blob with this hash
+    async fn media_blob_select(&mut self, hash: &[u8]) -> Result<Option<MediaId>>;
+
+    /// reference an existing blob with this hash, returning the media that owns it
+    ///
+    /// only call this once the media is done processing, so failed media never holds a reference
+    async fn media_blob_ref(&mut self, media_id: MediaId, hash: &[u8]) -> Result<Option<MediaId>>;
+
+    /// register this media's file as a blob other media can reference
+    ///
+    /// returns false if a blob with this hash already exists
+    async fn media_blob_insert(
+        &mut self,
+        media_id: MediaId,
+        hash: &[u8],
+        size: u64,
+    ) -> Result<bool>;
+
+    /// get how much storage a user's media uses
+    async fn media_usage_user(&mut self, user_id: UserId) -> Result<MediaUsage>;
+
+    /// get how much storage the media linked to a room uses
+    async fn media_usage_room(&mut self, room_id: RoomId) -> Result<MediaUsage>;
+
+    /// lock a user's media usage until this transaction ends, so concurrent quota checks can't race
+    async fn media_usage_user_lock(&mut self, user_id: UserId) -> Result<()>;
+
+    /// lock a room's media usage until this transaction ends, so concurrent quota checks can't race
+    async fn media_usage_room_lock(&mut self, room_id: RoomId) -> Result<()>;
 }
 
 #[async_trait]
@@ -123,6 +123,41 @@ impl DataAdmin for Postgres {
         Ok(rows.into_iter().map(|r| r.id.into()).collect())
     }
 
+    async fn gc_media_release_blob(&mut self, media_id: MediaId) -> Result<Option<(MediaId, u64)>> {
+        let mut conn = self.acquire().await?;
+        let row = query!(
+            r#"
+            with released as (
+                update media set blob_hash = null
+                from (select id, blob_hash from media where id = $1 for update) as old
+                where media.id = old.id and old.blob_hash is not null
+                returning old.blob_hash
+            )
+            update media_blob set ref_count = ref_count - 1
+            where hash = (select blob_hash from released)
+            returning hash, media_id, ref_count
+        "#,
+            *media_id
+        )
+        .fetch_optional(conn.ext())
+        .await?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        if row.ref_count <= 0 {
+            query!(
+                "delete from media_blob where hash = $1 and ref_count <= 0",
+                row.hash
+            )
+            .execute(conn.ext())
+            .await?;
+        }
+
+        Ok(Some((row.media_id.into(), row.ref_count.max(0) as u64)))
+    }
+
     async fn gc_media_delete_swept(&mut self, ids: &[MediaId]) -> Result<u64> {
         if ids.is_empty() {
             return Ok(0);
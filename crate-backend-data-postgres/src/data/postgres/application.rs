@@ -21,8 +21,8 @@ impl DataApplication for Postgres {
         let mut tx = self.begin_tx().await?;
         query!(
             r#"
-            insert into application (id, owner_id, name, description, public, oauth_secret, oauth_redirect_uris, oauth_confidential)
-            values ($1, $2, $3, $4, $5, $6, $7, $8)
+            insert into application (id, owner_id, name, description, public, oauth_secret, oauth_redirect_uris, oauth_confidential, unfurl_patterns)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9)
             "#,
             *app.id,
             *app.owner_id,
@@ -32,6 +32,7 @@ impl DataApplication for Postgres {
             app.oauth_secret,
             serde_json::to_value(app.oauth_redirect_uris).unwrap(),
             app.oauth_confidential,
+            serde_json::to_value(app.unfurl_patterns).unwrap(),
         )
         .execute(tx.ext())
         .await?;
@@ -65,7 +66,8 @@ impl DataApplication for Postgres {
                 public = $4,
                 oauth_secret = $5,
                 oauth_redirect_uris = $6,
-                oauth_confidential = $7
+                oauth_confidential = $7,
+                unfurl_patterns = $8
             where id = $1
             "#,
             *app.id,
@@ -75,6 +77,7 @@ impl DataApplication for Postgres {
             app.oauth_secret,
             serde_json::to_value(app.oauth_redirect_uris).unwrap(),
             app.oauth_confidential,
+            serde_json::to_value(app.unfurl_patterns).unwrap(),
         )
         .execute(tx.ext())
         .await?;
@@ -125,7 +128,7 @@ impl DataApplication for Postgres {
         let app = query!(
             r#"
         	SELECT
-                a.id, a.owner_id, a.name, a.description, a.public, a.oauth_secret, a.oauth_redirect_uris, a.oauth_confidential,
+                a.id, a.owner_id, a.name, a.description, a.public, a.oauth_secret, a.oauth_redirect_uris, a.oauth_confidential, a.unfurl_patterns,
                 b.application_id as "bridge_id?", b.platform_name, b.platform_url, b.platform_description
             FROM application a
             LEFT JOIN application_bridge b ON a.id = b.application_id
@@ -163,6 +166,7 @@ impl DataApplication for Postgres {
             oauth_redirect_uris: serde_json::from_value(app.oauth_redirect_uris)
                 .unwrap_or_default(),
             oauth_confidential: app.oauth_confidential,
+            unfurl_patterns: serde_json::from_value(app.unfurl_patterns).unwrap_or_default(),
         })
     }
 
@@ -178,7 +182,7 @@ impl DataApplication for Postgres {
             query!(
                 r#"
             	SELECT
-                    a.id, a.owner_id, a.name, a.description, a.public, a.oauth_secret, a.oauth_redirect_uris, a.oauth_confidential,
+                    a.id, a.owner_id, a.name, a.description, a.public, a.oauth_secret, a.oauth_redirect_uris, a.oauth_confidential, a.unfurl_patterns,
                     b.application_id as "bridge_id?", b.platform_name, b.platform_url, b.platform_description
                 FROM application a
                 LEFT JOIN application_bridge b ON a.id = b.application_id
@@ -217,9 +221,57 @@ impl DataApplication for Postgres {
                     oauth_redirect_uris: serde_json::from_value(row.oauth_redirect_uris)
                         .unwrap_or_default(),
                     oauth_confidential: row.oauth_confidential,
+                    unfurl_patterns: serde_json::from_value(row.unfurl_patterns)
+                        .unwrap_or_default(),
                 }
             },
             |i: &Application| i.id.to_string()
         )
     }
+
+    async fn application_list_unfurlers(&mut self) -> Result<Vec<Application>> {
+        let mut conn = self.acquire().await?;
+        let rows = query!(
+            r#"
+        	SELECT
+                a.id, a.owner_id, a.name, a.description, a.public, a.oauth_secret, a.oauth_redirect_uris, a.oauth_confidential, a.unfurl_patterns,
+                b.application_id as "bridge_id?", b.platform_name, b.platform_url, b.platform_description
+            FROM application a
+            LEFT JOIN application_bridge b ON a.id = b.application_id
+        	WHERE a.deleted_at IS NULL AND a.unfurl_patterns != '[]'
+            "#
+        )
+        .fetch_all(conn.ext())
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let bridge = if row.bridge_id.is_some() {
+                    Some(common::v1::types::application::Bridge {
+                        platform_name: row.platform_name,
+                        platform_url: row.platform_url,
+                        platform_description: row.platform_description,
+                    })
+                } else {
+                    None
+                };
+
+                Application {
+                    id: row.id.into(),
+                    owner_id: row.owner_id.into(),
+                    name: row.name,
+                    description: row.description,
+                    bridge,
+                    public: row.public,
+                    oauth_secret: row.oauth_secret,
+                    oauth_redirect_uris: serde_json::from_value(row.oauth_redirect_uris)
+                        .unwrap_or_default(),
+                    oauth_confidential: row.oauth_confidential,
+                    unfurl_patterns: serde_json::from_value(row.unfurl_patterns)
+                        .unwrap_or_default(),
+                }
+            })
+            .collect())
+    }
 }
@@ -24,6 +24,7 @@ struct DbConnection {
     app_oauth_secret: Option<String>,
     app_oauth_redirect_uris: serde_json::Value,
     app_oauth_confidential: bool,
+    app_unfurl_patterns: serde_json::Value,
     bridge_platform_name: Option<String>,
     bridge_platform_url: Option<String>,
     bridge_platform_description: Option<String>,
@@ -53,6 +54,8 @@ impl From<DbConnection> for Connection {
                 oauth_redirect_uris: serde_json::from_value(val.app_oauth_redirect_uris)
                     .unwrap_or_default(),
                 oauth_confidential: val.app_oauth_confidential,
+                unfurl_patterns: serde_json::from_value(val.app_unfurl_patterns)
+                    .unwrap_or_default(),
             },
             scopes: serde_json::from_value(val.scopes).unwrap_or_default(),
             created_at: val.created_at.into(),
@@ -99,6 +102,7 @@ impl DataConnection for Postgres {
                 a.owner_id as app_owner_id, a.name as app_name, a.description as app_description,
                 a.public as app_public, a.oauth_secret as app_oauth_secret,
                 a.oauth_redirect_uris as app_oauth_redirect_uris, a.oauth_confidential as app_oauth_confidential,
+                a.unfurl_patterns as app_unfurl_patterns,
                 b.platform_name as "bridge_platform_name?", b.platform_url as "bridge_platform_url?", b.platform_description as "bridge_platform_description?"
             from connection c
             join application a on c.application_id = a.id
@@ -131,6 +135,7 @@ impl DataConnection for Postgres {
                     a.owner_id as app_owner_id, a.name as app_name, a.description as app_description,
                     a.public as app_public, a.oauth_secret as app_oauth_secret,
                     a.oauth_redirect_uris as app_oauth_redirect_uris, a.oauth_confidential as app_oauth_confidential,
+                a.unfurl_patterns as app_unfurl_patterns,
                     b.platform_name as "bridge_platform_name?", b.platform_url as "bridge_platform_url?", b.platform_description as "bridge_platform_description?"
                 from connection c
                 join application a on c.application_id = a.id
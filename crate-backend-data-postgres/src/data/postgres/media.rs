@@ -3,16 +3,16 @@ use common::v1::types::error::{ApiError, ErrorCode};
 use common::v1::types::federation::{FederationEpoch, Hostname, Remote};
 use common::v1::types::{MediaTrack as MediaTrackV1, MediaV0 as MediaV1};
 use common::v2::types::media::{
-    Media as MediaV2, MediaErrorReason, MediaPatch as MediaPatchV2, MediaStatus,
+    Media as MediaV2, MediaErrorReason, MediaPatch as MediaPatchV2, MediaStatus, MediaUsage,
 };
 use serde::{Deserialize, Serialize};
-use sqlx::{query, query_as};
+use sqlx::{query, query_as, query_scalar};
 use time::PrimitiveDateTime;
 use tracing::{info, warn};
 use uuid::Uuid;
 
 use crate::error::{Error, Result};
-use crate::types::{MediaId, MediaLink, MediaLinkType, MediaVerId, UserId};
+use crate::types::{ChannelId, MediaId, MediaLink, MediaLinkType, MediaVerId, RoomId, UserId};
 
 use crate::data::DataMedia;
 
@@ -376,6 +376,146 @@ impl DataMedia for Postgres {
         Ok(())
     }
 
+    async fn media_blob_select(&mut self, hash: &[u8]) -> Result<Option<MediaId>> {
+        let mut conn = self.acquire().await?;
+        let owner_id = query_scalar!("select media_id from media_blob where hash = $1", hash)
+            .fetch_optional(conn.ext())
+            .await?;
+        Ok(owner_id.map(Into::into))
+    }
+
+    async fn media_blob_ref(&mut self, media_id: MediaId, hash: &[u8]) -> Result<Option<MediaId>> {
+        let mut tx = self.begin_tx().await?;
+        let owner_id = query_scalar!(
+            "update media_blob set ref_count = ref_count + 1 where hash = $1 returning media_id",
+            hash
+        )
+        .fetch_optional(tx.ext())
+        .await?;
+
+        if owner_id.is_some() {
+            query!(
+                "update media set blob_hash = $2 where id = $1",
+                *media_id,
+                hash
+            )
+            .execute(tx.ext())
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(owner_id.map(Into::into))
+    }
+
+    async fn media_blob_insert(
+        &mut self,
+        media_id: MediaId,
+        hash: &[u8],
+        size: u64,
+    ) -> Result<bool> {
+        let mut tx = self.begin_tx().await?;
+        let inserted = query!(
+            r#"
+            insert into media_blob (hash, media_id, size, ref_count)
+            values ($1, $2, $3, 1)
+            on conflict (hash) do nothing
+        "#,
+            hash,
+            *media_id,
+            size as i64
+        )
+        .execute(tx.ext())
+        .await?
+        .rows_affected()
+            > 0;
+
+        if inserted {
+            query!(
+                "update media set blob_hash = $2 where id = $1",
+                *media_id,
+                hash
+            )
+            .execute(tx.ext())
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(inserted)
+    }
+
+    async fn media_usage_user(&mut self, user_id: UserId) -> Result<MediaUsage> {
+        let mut conn = self.acquire().await?;
+        let row = query!(
+            r#"
+            select coalesce(sum(size), 0)::bigint as "bytes!", count(*) as "count!"
+            from media
+            where user_id = $1 and deleted_at is null and data->>'status' is distinct from 'Errored'
+        "#,
+            *user_id,
+        )
+        .fetch_one(conn.ext())
+        .await?;
+        Ok(MediaUsage {
+            bytes: row.bytes as u64,
+            count: row.count as u64,
+            quota: None,
+        })
+    }
+
+    async fn media_usage_room(&mut self, room_id: RoomId) -> Result<MediaUsage> {
+        let mut conn = self.acquire().await?;
+        let row = query!(
+            r#"
+            with room_media as (
+                select l.media_id from media_link l
+                join message msg on msg.id = l.target_id
+                join channel c on c.id = msg.channel_id
+                where l.link_type = 'Message' and l.deleted_at is null
+                    and msg.deleted_at is null and c.room_id = $1
+                union
+                select l.media_id from media_link l
+                join channel c on c.id = l.target_id
+                where l.link_type in ('ChannelIcon', 'Document') and l.deleted_at is null
+                    and c.room_id = $1
+                union
+                select l.media_id from media_link l
+                where l.link_type in ('RoomIcon', 'RoomBanner', 'CustomEmoji')
+                    and l.deleted_at is null and l.target_id = $1
+                union
+                select media_id from custom_emoji where room_id = $1 and deleted_at is null
+            )
+            select coalesce(sum(m.size), 0)::bigint as "bytes!", count(*) as "count!"
+            from media m
+            join room_media rm on rm.media_id = m.id
+            where m.deleted_at is null
+        "#,
+            *room_id,
+        )
+        .fetch_one(conn.ext())
+        .await?;
+        Ok(MediaUsage {
+            bytes: row.bytes as u64,
+            count: row.count as u64,
+            quota: None,
+        })
+    }
+
+    async fn media_usage_user_lock(&mut self, user_id: UserId) -> Result<()> {
+        let mut conn = self.acquire().await?;
+        query!("select from usr where id = $1 for update", *user_id)
+            .execute(conn.ext())
+            .await?;
+        Ok(())
+    }
+
+    async fn media_usage_room_lock(&mut self, room_id: RoomId) -> Result<()> {
+        let mut conn = self.acquire().await?;
+        query!("select from room where id = $1 for update", *room_id)
+            .execute(conn.ext())
+            .await?;
+        Ok(())
+    }
+
     async fn media_delete(&mut self, media_id: MediaId) -> Result<()> {
         let mut conn = self.acquire().await?;
         query!(
@@ -430,6 +570,31 @@ impl DataMedia for Postgres {
         Ok(links)
     }
 
+    async fn media_link_channels(&mut self, media_id: MediaId) -> Result<Vec<ChannelId>> {
+        let mut conn = self.acquire().await?;
+        let channel_ids = query_scalar!(
+            r#"
+            SELECT DISTINCT CASE
+                WHEN l.link_type = 'Document' THEN l.target_id
+                ELSE m.channel_id
+            END AS "channel_id!"
+            FROM media_link l
+            LEFT JOIN message_version v
+              ON l.link_type = 'MessageVersion' AND v.version_id = l.target_id
+            LEFT JOIN message m
+              ON l.link_type IN ('Message', 'MessageVersion')
+              AND m.id = COALESCE(v.message_id, l.target_id)
+            WHERE l.media_id = $1
+              AND l.link_type IN ('Message', 'MessageVersion', 'Document')
+              AND (l.link_type = 'Document' OR m.channel_id IS NOT NULL)
+        "#,
+            *media_id,
+        )
+        .fetch_all(conn.ext())
+        .await?;
+        Ok(channel_ids.into_iter().map(Into::into).collect())
+    }
+
     async fn media_link_delete(&mut self, target_id: Uuid, link_type: MediaLinkType) -> Result<()> {
         let mut conn = self.acquire().await?;
         query!(
@@ -602,3 +767,103 @@ impl DataMedia for Postgres {
         Ok(Some(media.parse()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::data::DataAdmin;
+
+    /// run in a transaction that is rolled back when dropped
+    async fn data() -> Postgres {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let pool = PgPool::connect(&url).await.unwrap();
+        let txn = pool.begin().await.unwrap();
+        Postgres {
+            pool,
+            txn: Some(txn),
+            use_legacy_behavior: false,
+        }
+    }
+
+    async fn insert_user(data: &mut Postgres) -> Uuid {
+        let id = Uuid::now_v7();
+        sqlx::query(
+            "insert into usr (id, name, can_fork, totp_enabled, version_id) values ($1, 'test', false, false, $1)",
+        )
+        .bind(id)
+        .execute(&mut **data.txn.as_mut().unwrap())
+        .await
+        .unwrap();
+        id
+    }
+
+    async fn insert_media(data: &mut Postgres, user_id: Uuid, size: i64) -> MediaId {
+        let id = Uuid::now_v7();
+        sqlx::query(
+            "insert into media (id, user_id, data, version_id) values ($1, $2, jsonb_build_object('size', $3::bigint), $1)",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(size)
+        .execute(&mut **data.txn.as_mut().unwrap())
+        .await
+        .unwrap();
+        id.into()
+    }
+
+    #[tokio::test]
+    async fn test_usage_user() {
+        let mut data = data().await;
+        let user_id = insert_user(&mut data).await;
+        let usage = data.media_usage_user(user_id.into()).await.unwrap();
+        assert_eq!((usage.bytes, usage.count), (0, 0));
+
+        insert_media(&mut data, user_id, 10).await;
+        let deleted = insert_media(&mut data, user_id, 20).await;
+        let errored = insert_media(&mut data, user_id, 40).await;
+        data.media_delete(deleted).await.unwrap();
+        sqlx::query("update media set data = data || '{\"status\": \"Errored\"}' where id = $1")
+            .bind(*errored)
+            .execute(&mut **data.txn.as_mut().unwrap())
+            .await
+            .unwrap();
+
+        // the lock is held by this transaction, so the usage can still be read
+        data.media_usage_user_lock(user_id.into()).await.unwrap();
+        let usage = data.media_usage_user(user_id.into()).await.unwrap();
+        assert_eq!((usage.bytes, usage.count), (10, 1));
+    }
+
+    #[tokio::test]
+    async fn test_blob_refs() {
+        let mut data = data().await;
+        let user_id = insert_user(&mut data).await;
+        let owner = insert_media(&mut data, user_id, 10).await;
+        let copy = insert_media(&mut data, user_id, 10).await;
+        let hash = Uuid::now_v7().into_bytes();
+
+        assert_eq!(data.media_blob_select(&hash).await.unwrap(), None);
+        assert!(data.media_blob_insert(owner, &hash, 10).await.unwrap());
+        assert!(!data.media_blob_insert(copy, &hash, 10).await.unwrap());
+
+        // finding a blob to dedupe against doesn't reference it
+        assert_eq!(data.media_blob_select(&hash).await.unwrap(), Some(owner));
+        assert_eq!(data.gc_media_release_blob(copy).await.unwrap(), None);
+        assert_eq!(data.media_blob_ref(copy, &hash).await.unwrap(), Some(owner));
+
+        // the blob is kept until every reference is released
+        assert_eq!(
+            data.gc_media_release_blob(owner).await.unwrap(),
+            Some((owner, 1))
+        );
+        assert_eq!(data.gc_media_release_blob(owner).await.unwrap(), None);
+        assert_eq!(data.media_blob_select(&hash).await.unwrap(), Some(owner));
+        assert_eq!(
+            data.gc_media_release_blob(copy).await.unwrap(),
+            Some((owner, 0))
+        );
+        assert_eq!(data.media_blob_select(&hash).await.unwrap(), None);
+    }
+}
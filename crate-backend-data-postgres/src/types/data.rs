@@ -698,6 +698,7 @@ impl_perms!(
     RoomJoinForce,
     ScriptManage,
     ScriptInspect,
+    VoiceRecord,
 );
 
 pub struct DbInvite {
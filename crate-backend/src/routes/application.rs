@@ -51,6 +51,15 @@ async fn app_create(
             return Err(ApiError::from_code(ErrorCode::PlatformNameRequiredForBridge).into());
         }
     }
+    if !json
+        .unfurl_patterns
+        .iter()
+        .all(|p| is_valid_unfurl_pattern(p))
+    {
+        return Err(Error::BadStatic(
+            "unfurl patterns must start with http:// or https:// and be at most 256 bytes",
+        ));
+    }
 
     let mut data = s.data();
     let user = data
@@ -75,6 +84,7 @@ async fn app_create(
         oauth_secret: None,
         oauth_redirect_uris: vec![],
         oauth_confidential: false,
+        unfurl_patterns: json.unfurl_patterns,
     };
     data.application_insert(app.clone()).await?;
     al.commit_success(AuditLogEntryType::ApplicationCreate {
@@ -84,12 +94,18 @@ async fn app_create(
             .add("description", &app.description)
             .add("bridge", &app.bridge)
             .add("public", &app.public)
+            .add("unfurl_patterns", &app.unfurl_patterns)
             .build(),
     })
     .await?;
     Ok((StatusCode::CREATED, Json(app)))
 }
 
+/// unfurl patterns can only match http urls
+fn is_valid_unfurl_pattern(pattern: &str) -> bool {
+    pattern.len() <= 256 && (pattern.starts_with("https://") || pattern.starts_with("http://"))
+}
+
 /// App list
 #[handler(routes::app_list)]
 async fn app_list(
@@ -154,6 +170,13 @@ async fn app_patch(
             return Err(ApiError::from_code(ErrorCode::PlatformNameRequiredForBridge).into());
         }
     }
+    if let Some(patterns) = &patch.unfurl_patterns
+        && !patterns.iter().all(|p| is_valid_unfurl_pattern(p))
+    {
+        return Err(Error::BadStatic(
+            "unfurl patterns must start with http:// or https:// and be at most 256 bytes",
+        ));
+    }
 
     if !patch.changes(&start) {
         return Ok(Json(start));
@@ -166,6 +189,7 @@ async fn app_patch(
     app.public = patch.public.unwrap_or(app.public);
     app.oauth_redirect_uris = patch.oauth_redirect_uris.unwrap_or(app.oauth_redirect_uris);
     app.oauth_confidential = patch.oauth_confidential.unwrap_or(app.oauth_confidential);
+    app.unfurl_patterns = patch.unfurl_patterns.unwrap_or(app.unfurl_patterns);
 
     data.application_update(app.clone()).await?;
 
@@ -186,6 +210,11 @@ async fn app_patch(
                 &start.oauth_confidential,
                 &app.oauth_confidential,
             )
+            .change(
+                "unfurl_patterns",
+                &start.unfurl_patterns,
+                &app.unfurl_patterns,
+            )
             .build(),
     })
     .await?;
@@ -1,10 +1,18 @@
 use std::sync::Arc;
 
 use axum::{
-    extract::{State, ws::WebSocketUpgrade},
+    body::Body,
+    extract::{DefaultBodyLimit, Path, Query, State, ws::WebSocketUpgrade},
     response::IntoResponse,
+    routing,
 };
-use http::HeaderMap;
+use common::v1::types::ChannelId;
+use common::v1::types::error::{ApiError, ErrorCode};
+use common::v1::types::voice::internal::RecordingUploadParams;
+use futures_util::StreamExt;
+use http::{HeaderMap, StatusCode, header::CONTENT_LENGTH};
+use kerosene_services::services::media::Import;
+use kerosene_services::services::voice::recording::RecordedTrack;
 use tracing::error;
 use utoipa_axum::{router::OpenApiRouter, routes};
 
@@ -23,6 +31,87 @@ async fn internal_rpc(
     headers: HeaderMap,
     State(s): State<Arc<ServerState>>,
 ) -> Result<impl IntoResponse> {
+    ensure_server_auth(&s, &headers)?;
+
+    Ok(ws.on_upgrade(move |socket| async move {
+        let srv = s.services();
+        if let Err(e) = srv.voice.sfu_handle_connect(socket).await {
+            error!("Failed to connect to SFU: {:?}", e);
+            // NOTE: do i need to destroy the sfu here?
+            return;
+        }
+    }))
+}
+
+/// Internal recording upload
+///
+/// Upload a track that a sfu recorded. The track is added to the call's
+/// recording, which is posted once every sfu has finished uploading.
+async fn internal_recording_upload(
+    Path(channel_id): Path<ChannelId>,
+    Query(params): Query<RecordingUploadParams>,
+    headers: HeaderMap,
+    State(s): State<Arc<ServerState>>,
+    body: Body,
+) -> Result<impl IntoResponse> {
+    ensure_server_auth(&s, &headers)?;
+
+    let size: u64 = headers
+        .get(CONTENT_LENGTH)
+        .ok_or(Error::BadHeader)?
+        .to_str()?
+        .parse()?;
+    if size > s.config.media.max_size {
+        return Err(Error::TooBig);
+    }
+
+    let srv = s.services();
+    let recording =
+        srv.voice
+            .recording_get(channel_id)
+            .ok_or(Error::ApiError(ApiError::from_code(
+                ErrorCode::NotRecording,
+            )))?;
+
+    let mut import = Import::new(recording.started_by);
+    import.filename = Some(params.filename);
+    import.max_size = Some(size);
+    let item = srv.media.import_from_upload(import).await?;
+    let media_id = item.media().id;
+
+    let mut up =
+        srv.media
+            .upload_get(media_id)
+            .await
+            .ok_or(Error::ApiError(ApiError::from_code(
+                ErrorCode::UnknownMedia,
+            )))?;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        up.write(&chunk?).await?;
+    }
+
+    // drop the lock before calling upload_done
+    drop(up);
+    srv.media.upload_done(media_id).await?;
+
+    srv.voice
+        .recording_track_add(
+            channel_id,
+            RecordedTrack {
+                user_id: params.user_id,
+                kind: params.kind,
+                offset: params.offset,
+                media: item,
+            },
+        )
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// check that a request was made by a sfu
+fn ensure_server_auth(s: &ServerState, headers: &HeaderMap) -> Result<()> {
     let Some(v) = &s.config.voice else {
         return Err(Error::Unimplemented);
     };
@@ -36,16 +125,16 @@ async fn internal_rpc(
         return Err(Error::MissingAuth);
     }
 
-    Ok(ws.on_upgrade(move |socket| async move {
-        let srv = s.services();
-        if let Err(e) = srv.voice.sfu_handle_connect(socket).await {
-            error!("Failed to connect to SFU: {:?}", e);
-            // NOTE: do i need to destroy the sfu here?
-            return;
-        }
-    }))
+    Ok(())
 }
 
 pub fn routes() -> OpenApiRouter<Arc<ServerState>> {
-    OpenApiRouter::new().routes(routes!(internal_rpc))
+    OpenApiRouter::new()
+        .routes(routes!(internal_rpc))
+        // recordings can be much larger than the default body limit, the size
+        // is checked against the media limit instead
+        .route(
+            "/internal/recording/{channel_id}",
+            routing::put(internal_recording_upload).layer(DefaultBodyLimit::disable()),
+        )
 }
@@ -4,15 +4,21 @@ use axum::{
     Json,
     body::Body,
     extract::{Path, State},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+    },
+    response::{IntoResponse, Response},
     routing,
 };
 use common::v1::{
     routes,
-    types::{federation::RemoteReq, misc::MediaIdReq},
+    types::{
+        federation::RemoteReq,
+        misc::{MediaIdReq, UserIdReq},
+    },
 };
-use common::v2::types::media::{MediaCreateSource, MediaCreated};
+use common::v2::types::media::{MediaCreateSource, MediaCreated, proxy::TransformSigned};
 use common::{
     v1::types::error::{ApiError, ErrorCode},
     v1::types::{Permission, SERVER_ROOM_ID, application::Scope},
@@ -28,7 +34,10 @@ use common::v1::types::MediaId;
 use kerosene_services::services::{media::Import, search::SearchMediaVisibility};
 
 use super::util::Auth;
-use lamprey_backend_core::types::permission::{CheckPermissions, Permissions2};
+use lamprey_backend_core::types::{
+    media::ImageTransform,
+    permission::{CheckPermissions, Permissions2},
+};
 
 #[handler(routes::media_create)]
 async fn media_create(
@@ -199,74 +208,156 @@ async fn media_clone(
     Ok(Error::Unimplemented)
 }
 
+#[handler(routes::media_transform_sign)]
+async fn media_transform_sign(
+    auth: Auth,
+    State(s): State<Arc<ServerState>>,
+    req: routes::media_transform_sign::Request,
+) -> Result<impl IntoResponse> {
+    let Some(key) = &s.config().media.transform_key else {
+        return Err(Error::Unimplemented);
+    };
+
+    let transform = ImageTransform::from_query(&req.body).map_err(Error::BadStatic)?;
+    let srv = s.services();
+    let media = srv.media.get(req.media_id).await?.media();
+    if media.deleted_at.is_some() {
+        return Err(Error::ApiError(ApiError::from_code(
+            ErrorCode::UnknownMedia,
+        )));
+    }
+
+    let private = srv.media.ensure_visible(auth.user.id, &media).await?;
+    let expires = private.then(|| srv.media.url_expires());
+    let sig = transform.sign(req.media_id, expires, key.load()?.as_bytes());
+    let mut url = s
+        .config()
+        .cdn_url
+        .join(&format!("transform/{}", req.media_id))?;
+    {
+        let mut query = url.query_pairs_mut();
+        query.extend_pairs(transform.query_pairs());
+        if let Some(expires) = expires {
+            query.append_pair("expires", &expires.to_string());
+        }
+        query.append_pair("sig", &sig);
+    }
+
+    Ok(Json(TransformSigned { url }))
+}
+
+/// the version of the tus resumable upload protocol the upload url implements
+const TUS_VERSION: &str = "1.0.0";
+
+/// the tus extensions the upload url supports
+///
+/// uploads are created with `media_create` instead of the creation extension
+const TUS_EXTENSIONS: &str = "expiration,termination";
+
+/// the content type tus clients use for PATCH requests
+const TUS_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+/// headers included in every response from the upload url
+fn tus_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+    headers
+}
+
+/// reject requests from tus clients using an unsupported version
+fn tus_version_mismatch(headers: &HeaderMap) -> Option<Response> {
+    match headers.get("tus-resumable") {
+        Some(v) if v != TUS_VERSION => {
+            let mut headers = tus_headers();
+            headers.insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+            Some((StatusCode::PRECONDITION_FAILED, headers).into_response())
+        }
+        _ => None,
+    }
+}
+
 /// Media upload
 ///
+/// Upload a chunk of a piece of media. Compatible with the tus 1.0 resumable
+/// upload protocol.
+///
+/// Unfinished uploads are kept by the server instance that created them, and are
+/// lost if it restarts. Once an upload is unknown, restart it with a new
+/// `media_create` request instead of resuming.
+///
 /// Always returns immediately, but will automatically begin processing media in
 /// the background.
 async fn media_upload(
     Path(media_id): Path<MediaId>,
-    _auth: Auth,
+    auth: Auth,
     State(s): State<Arc<ServerState>>,
     headers_req: HeaderMap,
     body: Body,
-) -> Result<impl IntoResponse> {
-    // TODO: check auth
+) -> Result<Response> {
     let srv = s.services();
 
+    if let Some(res) = tus_version_mismatch(&headers_req) {
+        return Ok(res);
+    }
+
+    // tus clients must send the correct content type, but keep accepting
+    // anything from older clients
+    if headers_req.contains_key("tus-resumable")
+        && headers_req
+            .get(CONTENT_TYPE)
+            .is_none_or(|ct| ct != TUS_CONTENT_TYPE)
+    {
+        return Ok((StatusCode::UNSUPPORTED_MEDIA_TYPE, tus_headers()).into_response());
+    }
+
     let current_off: u64 = headers_req
         .get("upload-offset")
         .ok_or(Error::BadHeader)?
         .to_str()?
         .parse()?;
-    let _part_length: u64 = headers_req
-        .get("content-length")
-        .ok_or(Error::BadHeader)?
-        .to_str()?
-        .parse()?;
-
-    // TODO: ensure body length == part_length? (minor issue)
 
-    {
-        let mut up =
-            srv.media
-                .upload_get(media_id)
-                .await
-                .ok_or(Error::ApiError(ApiError::from_code(
-                    ErrorCode::UnknownMedia,
-                )))?;
-        // TODO: return these for headers_res
-        up.offset();
-        up.expected_size();
-
-        let current_size = up.offset();
-        if current_size != current_off {
-            return Err(Error::CantOverwrite);
-        }
+    let mut up = srv
+        .media
+        .upload_get(media_id)
+        .await
+        .filter(|up| up.user_id() == auth.user.id)
+        .ok_or(Error::ApiError(ApiError::from_code(
+            ErrorCode::UnknownMedia,
+        )))?;
 
-        let mut stream = body.into_data_stream();
-        while let Some(chunk) = stream.next().await {
-            up.write(&chunk?).await?;
-        }
+    if up.offset() != current_off {
+        return Err(Error::CantOverwrite);
+    }
 
-        if !up.expects_more() {
-            // drop the lock before calling upload_done
-            drop(up);
-            srv.media.upload_done(media_id).await?;
-        }
+    // everything written before the connection drops is kept, so the client
+    // can resume from the last offset
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        up.write(&chunk?).await?;
     }
 
-    let item = srv.media.get(media_id).await?;
-    let media = item.media();
+    let mut headers_res = tus_headers();
+    headers_res.insert("upload-offset", up.offset().into());
+    headers_res.insert("upload-length", up.expected_size().into());
 
-    let mut headers_res = HeaderMap::new();
-    headers_res.insert("upload-offset", media.size.into()); // FIXME: should be up.offset()
-    headers_res.insert("upload-length", media.size.into()); // FIXME: should be up.expected_size()
-    Ok((StatusCode::NO_CONTENT, headers_res))
+    if up.expects_more() {
+        headers_res.insert(
+            "upload-expires",
+            HeaderValue::from_str(&httpdate::fmt_http_date(up.expires_at()))?,
+        );
+    } else {
+        // drop the lock before calling upload_done
+        drop(up);
+        srv.media.upload_done(media_id).await?;
+    }
+
+    Ok((StatusCode::NO_CONTENT, headers_res).into_response())
 }
 
 /// Media check
 ///
-/// Get headers useful for resuming an upload
+/// Get headers useful for resuming an upload. Returns `UnknownMedia` if the
+/// upload expired or was lost, see `media_upload`.
 async fn media_check(
     Path(media_id): Path<MediaId>,
     auth: Auth,
@@ -277,20 +368,55 @@ async fn media_check(
         .media
         .upload_get(media_id)
         .await
+        .filter(|up| up.user_id() == auth.user.id)
         .ok_or(Error::ApiError(ApiError::from_code(
             ErrorCode::UnknownMedia,
         )))?;
 
-    if up.user_id() == auth.user.id {
-        let mut headers = HeaderMap::new();
-        headers.insert("upload-offset", up.offset().into());
-        headers.insert("upload-length", up.expected_size().into());
-        Ok((StatusCode::NO_CONTENT, headers))
-    } else {
-        Err(Error::ApiError(ApiError::from_code(
+    let mut headers = tus_headers();
+    headers.insert("upload-offset", up.offset().into());
+    headers.insert("upload-length", up.expected_size().into());
+    headers.insert(
+        "upload-expires",
+        HeaderValue::from_str(&httpdate::fmt_http_date(up.expires_at()))?,
+    );
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Ok((StatusCode::NO_CONTENT, headers))
+}
+
+/// Media upload options
+///
+/// Get the tus protocol versions and extensions the upload url supports
+async fn media_upload_options(State(s): State<Arc<ServerState>>) -> impl IntoResponse {
+    let mut headers = tus_headers();
+    headers.insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+    headers.insert("tus-extension", HeaderValue::from_static(TUS_EXTENSIONS));
+    headers.insert("tus-max-size", s.config.media.max_size.into());
+    (StatusCode::NO_CONTENT, headers)
+}
+
+/// Media upload terminate
+///
+/// Cancel an unfinished upload
+async fn media_upload_terminate(
+    Path(media_id): Path<MediaId>,
+    auth: Auth,
+    State(s): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse> {
+    let srv = s.services();
+    let up = srv
+        .media
+        .upload_get(media_id)
+        .await
+        .filter(|up| up.user_id() == auth.user.id)
+        .ok_or(Error::ApiError(ApiError::from_code(
             ErrorCode::UnknownMedia,
-        )))
-    }
+        )))?;
+
+    // drop the lock before deleting
+    drop(up);
+    srv.media.delete(auth.user.id, media_id).await?;
+    Ok((StatusCode::NO_CONTENT, tus_headers()))
 }
 
 /// Media search
@@ -320,6 +446,53 @@ async fn media_search(
     Ok(Json(results))
 }
 
+/// Media usage user
+///
+/// Get how much media storage a user is using. Only admins can view other users' usage.
+#[handler(routes::media_usage_user)]
+async fn media_usage_user(
+    auth: Auth,
+    State(s): State<Arc<ServerState>>,
+    req: routes::media_usage_user::Request,
+) -> Result<impl IntoResponse> {
+    let srv = s.services();
+    let user_id = match req.user_id {
+        UserIdReq::UserSelf => auth.user.id,
+        UserIdReq::UserId(user_id) if user_id == auth.user.id => user_id,
+        UserIdReq::UserId(user_id) => {
+            srv.perms
+                .for_room3(Some(auth.user.id), SERVER_ROOM_ID)
+                .await?
+                .ensure_view()?
+                .needs(Permission::Admin)
+                .check()?;
+            user_id
+        }
+        UserIdReq::RemoteUser(..) => return Err(Error::Unimplemented),
+    };
+    let usage = srv.media.usage_user(user_id).await?;
+    Ok(Json(usage))
+}
+
+/// Media usage room
+///
+/// Get how much media storage is linked to a room.
+#[handler(routes::media_usage_room)]
+async fn media_usage_room(
+    auth: Auth,
+    State(s): State<Arc<ServerState>>,
+    req: routes::media_usage_room::Request,
+) -> Result<impl IntoResponse> {
+    let srv = s.services();
+    srv.perms
+        .for_room3(Some(auth.user.id), req.room_id)
+        .await?
+        .ensure_view()?
+        .check()?;
+    let usage = srv.media.usage_room(req.room_id).await?;
+    Ok(Json(usage))
+}
+
 pub fn routes() -> OpenApiRouter<Arc<ServerState>> {
     OpenApiRouter::new()
         .routes(routes2!(media_create))
@@ -328,10 +501,16 @@ pub fn routes() -> OpenApiRouter<Arc<ServerState>> {
         .routes(routes2!(media_delete))
         .routes(routes2!(media_done))
         .routes(routes2!(media_clone))
+        .routes(routes2!(media_transform_sign))
         .routes(routes2!(media_search))
+        .routes(routes2!(media_usage_user))
+        .routes(routes2!(media_usage_room))
         // TODO: move these to cdn?
         .route(
             "/internal/media-upload/{media_id}",
-            routing::patch(media_upload).head(media_check),
+            routing::patch(media_upload)
+                .head(media_check)
+                .options(media_upload_options)
+                .delete(media_upload_terminate),
         )
 }
@@ -552,6 +552,57 @@ async fn voice_call_get(
     Ok(Json(call.call().clone()))
 }
 
+/// Voice call recording start
+#[handler(routes::voice_call_recording_start)]
+async fn voice_call_recording_start(
+    auth: Auth,
+    State(s): State<Arc<ServerState>>,
+    req: routes::voice_call_recording_start::Request,
+) -> Result<impl IntoResponse> {
+    auth.ensure_scopes(&[Scope::Full])?;
+    auth.user.ensure_unsuspended()?;
+
+    s.services()
+        .perms
+        .for_channel3(Some(auth.user.id), req.channel_id)
+        .await?
+        .ensure_view()?
+        .needs(Permission::VoiceRecord)
+        .check()?;
+
+    let call_handle = s
+        .services()
+        .voice
+        .call_recording_start(req.channel_id, auth.user.id)
+        .await?;
+    Ok((StatusCode::OK, Json(call_handle.call().clone())))
+}
+
+/// Voice call recording stop
+#[handler(routes::voice_call_recording_stop)]
+async fn voice_call_recording_stop(
+    auth: Auth,
+    State(s): State<Arc<ServerState>>,
+    req: routes::voice_call_recording_stop::Request,
+) -> Result<impl IntoResponse> {
+    auth.ensure_scopes(&[Scope::Full])?;
+    auth.user.ensure_unsuspended()?;
+
+    s.services()
+        .perms
+        .for_channel3(Some(auth.user.id), req.channel_id)
+        .await?
+        .ensure_view()?
+        .needs(Permission::VoiceRecord)
+        .check()?;
+
+    s.services()
+        .voice
+        .call_recording_stop(req.channel_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 /// Voice ring eligibility
 #[handler(routes::voice_ring_eligibility)]
 async fn voice_ring_eligibility(
@@ -607,6 +658,8 @@ pub fn routes() -> OpenApiRouter<Arc<ServerState>> {
         .routes(routes2!(voice_call_delete))
         .routes(routes2!(voice_call_patch))
         .routes(routes2!(voice_call_get))
+        .routes(routes2!(voice_call_recording_start))
+        .routes(routes2!(voice_call_recording_stop))
         .routes(routes2!(voice_ring_start))
         .routes(routes2!(voice_ring_stop))
         .routes(routes2!(voice_ring_eligibility))
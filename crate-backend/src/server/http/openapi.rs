@@ -189,8 +189,10 @@ components(schemas(
     common::v2::types::media::MediaStatus,
     common::v2::types::media::MediaMetadata,
     common::v2::types::media::MediaScan,
+    common::v2::types::media::scanner::ScanVerdict,
     common::v2::types::media::MediaQuarantine,
     common::v2::types::media::MediaCreate,
+    common::v2::types::media::MediaUsage,
     // interactions
     common::v1::types::interactions::InteractionCreate,
     common::v1::types::interactions::InteractionCreateType,
@@ -3,18 +3,33 @@ use tower_http::cors::CorsLayer;
 
 const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
 const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const TUS_VERSION: HeaderName = HeaderName::from_static("tus-version");
+const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+const TUS_MAX_SIZE: HeaderName = HeaderName::from_static("tus-max-size");
 const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
 const REASON: HeaderName = HeaderName::from_static("x-reason");
 const PUPPET_ID: HeaderName = HeaderName::from_static("x-puppet-id");
 
 pub fn cors() -> CorsLayer {
     CorsLayer::very_permissive()
-        .expose_headers([CONTENT_TYPE, UPLOAD_OFFSET, UPLOAD_LENGTH])
+        .expose_headers([
+            CONTENT_TYPE,
+            UPLOAD_OFFSET,
+            UPLOAD_LENGTH,
+            UPLOAD_EXPIRES,
+            TUS_RESUMABLE,
+            TUS_VERSION,
+            TUS_EXTENSION,
+            TUS_MAX_SIZE,
+        ])
         .allow_headers([
             AUTHORIZATION,
             CONTENT_TYPE,
             UPLOAD_OFFSET,
             UPLOAD_LENGTH,
+            TUS_RESUMABLE,
             IDEMPOTENCY_KEY,
             REASON,
             PUPPET_ID,
@@ -151,6 +151,37 @@ pub mod media_clone {
     }
 }
 
+/// Media transform sign
+///
+/// Get a signed url for resizing, cropping, or converting an image.
+///
+/// Urls for private media expire, like other signed media urls.
+#[endpoint(
+    post,
+    path = "/media/{media_id}/transform",
+    tags = ["media"],
+    response(OK, body = TransformSigned, description = "Media transform sign success"),
+)]
+pub mod media_transform_sign {
+    use crate::{
+        v1::types::MediaId,
+        v2::types::media::proxy::{TransformQuery, TransformSigned},
+    };
+
+    pub struct Request {
+        #[path]
+        pub media_id: MediaId,
+
+        #[json]
+        pub body: TransformQuery,
+    }
+
+    pub struct Response {
+        #[json]
+        pub signed: TransformSigned,
+    }
+}
+
 /// Media search
 #[endpoint(
     post,
@@ -172,6 +203,52 @@ pub mod media_search {
     }
 }
 
+/// Media usage user
+///
+/// Get how much media storage a user is using. Only admins can view other users' usage.
+#[endpoint(
+    get,
+    path = "/user/{user_id}/media-usage",
+    tags = ["media"],
+    response(OK, body = MediaUsage, description = "Media usage success"),
+)]
+pub mod media_usage_user {
+    use crate::{v1::types::misc::UserIdReq, v2::types::media::MediaUsage};
+
+    pub struct Request {
+        #[path]
+        pub user_id: UserIdReq,
+    }
+
+    pub struct Response {
+        #[json]
+        pub usage: MediaUsage,
+    }
+}
+
+/// Media usage room
+///
+/// Get how much media storage is linked to a room.
+#[endpoint(
+    get,
+    path = "/room/{room_id}/media-usage",
+    tags = ["media"],
+    response(OK, body = MediaUsage, description = "Media usage success"),
+)]
+pub mod media_usage_room {
+    use crate::{v1::types::RoomId, v2::types::media::MediaUsage};
+
+    pub struct Request {
+        #[path]
+        pub room_id: RoomId,
+    }
+
+    pub struct Response {
+        #[json]
+        pub usage: MediaUsage,
+    }
+}
+
 /// Media upload (internal)
 ///
 /// Upload a chunk of a piece of media.
@@ -370,3 +370,66 @@ pub mod stream_head {
 
     pub struct Response {}
 }
+
+/// Fetch transform
+///
+/// resize, crop, or convert an image. the url must be signed by the api with
+/// `media_transform_sign`.
+#[endpoint(
+    get,
+    path = "/transform/{media_id}",
+    tags = ["cdn"],
+    response(OK, description = "success"),
+)]
+pub mod transform_get {
+    use crate::{
+        v1::types::MediaId,
+        v2::types::media::proxy::{MediaQuery, SignedQuery, TransformQuery},
+    };
+
+    pub struct Request {
+        #[path]
+        pub media_id: MediaId,
+
+        #[query]
+        pub query: TransformQuery,
+
+        #[query]
+        pub signed: SignedQuery,
+
+        #[query]
+        pub media_query: MediaQuery,
+    }
+
+    pub struct Response {}
+}
+
+/// Head transform
+#[endpoint(
+    head,
+    path = "/transform/{media_id}",
+    tags = ["cdn"],
+    response(OK, description = "success"),
+)]
+pub mod transform_head {
+    use crate::{
+        v1::types::MediaId,
+        v2::types::media::proxy::{MediaQuery, SignedQuery, TransformQuery},
+    };
+
+    pub struct Request {
+        #[path]
+        pub media_id: MediaId,
+
+        #[query]
+        pub query: TransformQuery,
+
+        #[query]
+        pub signed: SignedQuery,
+
+        #[query]
+        pub media_query: MediaQuery,
+    }
+
+    pub struct Response {}
+}
@@ -284,6 +284,52 @@ pub mod voice_call_get {
     }
 }
 
+/// Voice call recording start
+///
+/// start recording the call in this channel. the recording is posted to the channel once it stops.
+#[endpoint(
+    post,
+    path = "/voice/{channel_id}/call/recording",
+    tags = ["voice"],
+    scopes = [Full],
+    permissions = [VoiceRecord],
+    response(OK, body = Call, description = "ok"),
+)]
+pub mod voice_call_recording_start {
+    use crate::v1::types::ChannelId;
+    use crate::v1::types::voice::Call;
+
+    pub struct Request {
+        #[path]
+        pub channel_id: ChannelId,
+    }
+
+    pub struct Response {
+        #[json]
+        pub call: Call,
+    }
+}
+
+/// Voice call recording stop
+#[endpoint(
+    delete,
+    path = "/voice/{channel_id}/call/recording",
+    tags = ["voice"],
+    scopes = [Full],
+    permissions = [VoiceRecord],
+    response(NO_CONTENT, description = "ok"),
+)]
+pub mod voice_call_recording_stop {
+    use crate::v1::types::ChannelId;
+
+    pub struct Request {
+        #[path]
+        pub channel_id: ChannelId,
+    }
+
+    pub struct Response {}
+}
+
 // ========== ringing ==========
 
 /// Voice ring start
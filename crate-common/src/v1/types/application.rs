@@ -34,6 +34,14 @@ pub struct Application {
 
     /// oauth whether this client can keep secrets confidential
     pub oauth_confidential: bool,
+
+    /// url patterns this application generates previews for, where `*` matches anything
+    ///
+    /// links matching these in channels the application's bot can see create Unfurl interactions
+    #[schema(required = false, max_length = 16)]
+    #[validate(length(max = 16))]
+    #[serde(default)]
+    pub unfurl_patterns: Vec<String>,
     // do i really need all these urls properties, or can i get away with a vec?
     // url_terms_of_service: Option<Url>,
     // url_privacy_policy: Option<Url>,
@@ -54,7 +62,6 @@ pub struct Application {
     pub oauth_client: Option<ApplicationOauthClient>,
     // TODO: add these
     // interactions_url: Option<Url>,
-    // unfurl_domains: Vec<String>,
 }
 
 #[record]
@@ -138,6 +145,12 @@ pub struct ApplicationCreate {
 
     #[serde(default)]
     pub oauth_confidential: Option<bool>,
+
+    /// url patterns this application generates previews for, where `*` matches anything
+    #[schema(required = false, max_length = 16)]
+    #[validate(length(max = 16))]
+    #[serde(default)]
+    pub unfurl_patterns: Vec<String>,
 }
 
 #[record]
@@ -161,6 +174,11 @@ pub struct ApplicationPatch {
     #[validate(length(max = 8))]
     pub oauth_redirect_uris: Option<Vec<String>>,
     pub oauth_confidential: Option<bool>,
+
+    /// url patterns this application generates previews for, where `*` matches anything
+    #[schema(required = false, max_length = 16)]
+    #[validate(length(max = 16))]
+    pub unfurl_patterns: Option<Vec<String>>,
 }
 
 /// an application that is authorized to a user
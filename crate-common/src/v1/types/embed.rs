@@ -1,5 +1,9 @@
 use crate::{
-    v1::types::{EmbedId, misc::Color, util::truncate::truncate_with_ellipsis},
+    v1::types::{
+        EmbedId,
+        misc::Color,
+        util::{Time, truncate::truncate_with_ellipsis},
+    },
     v2::types::media::{Media, MediaReference},
 };
 
@@ -64,9 +68,24 @@ pub struct Embed {
 
     /// aka favicon
     pub site_avatar: Option<Media>,
+
+    /// an embeddable player for this url, eg. for videos. clients should load this in a sandboxed iframe.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub player: Option<EmbedPlayer>,
+
+    /// when the linked article, post, or video was published
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_at: Option<Time>,
+
+    /// the price of the linked product
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price: Option<EmbedPrice>,
+
+    /// when and where the linked event is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<EmbedEvent>,
     // /// what kind of thing this is
     // pub kind: UrlTargetKind,
-    // pub timestamp: Option<Time>,
     // pub footer: Option<String>,
 
     // // discord compatibility? these aren't really used for url embeds though, and
@@ -75,6 +94,48 @@ pub struct Embed {
     // pub field: Vec<name, value, inline?>
 }
 
+/// an embeddable player, loaded in an iframe
+#[record]
+#[derive(PartialEq, Eq)]
+pub struct EmbedPlayer {
+    /// the url to load in the iframe
+    pub url: Url,
+
+    /// the width of the player in pixels
+    pub width: Option<u32>,
+
+    /// the height of the player in pixels
+    pub height: Option<u32>,
+}
+
+/// the price of a product
+#[record]
+#[derive(PartialEq, Eq)]
+pub struct EmbedPrice {
+    /// the price as a decimal string, eg. `12.50`
+    #[schema(max_length = 64)]
+    #[validate(length(max = 64))]
+    pub amount: String,
+
+    /// the iso 4217 currency code, eg. `USD`
+    #[schema(max_length = 16)]
+    #[validate(length(max = 16))]
+    pub currency: Option<String>,
+}
+
+/// an event, like a concert or meetup
+#[record]
+#[derive(PartialEq, Eq)]
+pub struct EmbedEvent {
+    pub start_at: Option<Time>,
+    pub end_at: Option<Time>,
+
+    /// the name or address of the venue, or a url for online events
+    #[schema(max_length = 256)]
+    #[validate(length(max = 256))]
+    pub location: Option<String>,
+}
+
 // TODO: rename to EmbedGenerate
 #[record]
 #[derive(PartialEq, Eq)]
@@ -621,6 +621,14 @@ pub enum ErrorCode {
     #[error("cannot move to thread in different room")]
     CannotMoveToThreadInDifferentRoom,
 
+    /// this call is already being recorded
+    #[error("this call is already being recorded")]
+    AlreadyRecording,
+
+    /// this call isn't being recorded
+    #[error("this call isn't being recorded")]
+    NotRecording,
+
     /// cannot close default branch
     #[error("cannot close default branch")]
     CannotCloseDefaultBranch,
@@ -758,6 +766,10 @@ pub enum ErrorCode {
     #[error("media is too big")]
     MediaTooBig,
 
+    /// media storage quota exceeded
+    #[error("media storage quota exceeded")]
+    QuotaExceeded,
+
     /// message slowmode in effect
     #[error("message slowmode in effect")]
     SlowmodeMessage,
@@ -143,6 +143,8 @@ impl ErrorCode {
             ErrorCode::CannotMoveToThreadWithoutVoice => StatusCode::BAD_REQUEST,
             ErrorCode::NotConnectedToAnyThread => StatusCode::BAD_REQUEST,
             ErrorCode::CannotMoveToThreadInDifferentRoom => StatusCode::BAD_REQUEST,
+            ErrorCode::AlreadyRecording => StatusCode::CONFLICT,
+            ErrorCode::NotRecording => StatusCode::BAD_REQUEST,
             ErrorCode::CannotCloseDefaultBranch => StatusCode::BAD_REQUEST,
             ErrorCode::CannotMergeDefaultBranch => StatusCode::BAD_REQUEST,
             ErrorCode::BranchHasNoParent => StatusCode::BAD_REQUEST,
@@ -180,6 +182,9 @@ impl ErrorCode {
 
             // "payload too large" is specifically for the request body, so "bad request" is used instead
             ErrorCode::MediaTooBig => StatusCode::BAD_REQUEST,
+
+            // the upload (or attaching it) is what pushes usage over the quota
+            ErrorCode::QuotaExceeded => StatusCode::PAYLOAD_TOO_LARGE,
         }
     }
 }
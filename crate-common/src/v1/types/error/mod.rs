@@ -49,6 +49,10 @@ pub struct ApiError {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ratelimit: Option<Ratelimit>,
 
+    /// storage quota that you ran into
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<QuotaExceeded>,
+
     /// errors with your script
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub script: Vec<RedexError>,
@@ -166,6 +170,33 @@ pub struct Ratelimit {
     pub global: bool,
 }
 
+/// a storage quota that would be exceeded
+#[record]
+pub struct QuotaExceeded {
+    /// whose quota would be exceeded
+    pub scope: QuotaScope,
+
+    /// how many bytes are currently used
+    pub used: u64,
+
+    /// the maximum number of bytes allowed
+    pub quota: u64,
+
+    /// how many more bytes this request needs
+    pub requested: u64,
+}
+
+/// whose storage quota this is
+#[record]
+#[derive(Copy, PartialEq, Eq)]
+pub enum QuotaScope {
+    /// the user uploading the media
+    User,
+
+    /// the room the media is being added to
+    Room,
+}
+
 impl ApiError {
     // TODO: pub fn with_message<S: Into<String>>(code: ErrorCode, message: S) -> Self {
     #[inline]
@@ -188,10 +219,24 @@ impl ApiError {
             warnings: vec![],
             automod_message: None,
             ratelimit: None,
+            quota: None,
             script: vec![],
         }
     }
 
+    /// construct a `QuotaExceeded` error
+    pub fn quota_exceeded(quota: QuotaExceeded) -> Self {
+        let message = match quota.scope {
+            QuotaScope::User => "user media storage quota exceeded",
+            QuotaScope::Room => "room media storage quota exceeded",
+        };
+        Self {
+            message: message.to_owned(),
+            quota: Some(quota),
+            ..Self::from_code(ErrorCode::QuotaExceeded)
+        }
+    }
+
     /// prefix all fields with a path for nested validation
     pub fn nested(self, path: &[String]) -> Self {
         Self {
@@ -1,4 +1,5 @@
 use lamprey_macros::record;
+use url::Url;
 
 use crate::v1::types::{
     ApplicationId, Channel, ChannelId, Embed, InteractionId, Message, MessageCreate, MessageId,
@@ -78,7 +79,12 @@ pub enum InteractionType {
     },
 
     /// unfurl a url
+    ///
+    /// respond with [`InteractionResponseCreateType::Unfurl`] within a few seconds
     Unfurl {
+        /// the url to generate a preview for
+        url: Url,
+
         /// the room this interaction was created in
         room: Option<Room>,
 
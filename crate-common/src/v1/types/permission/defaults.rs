@@ -68,6 +68,7 @@ pub const ADMIN_ROOM: &[Permission] = &[
     Permission::VoiceSpeak,
     Permission::VoiceVad,
     Permission::VoiceVideo,
+    Permission::VoiceRecord,
 ];
 
 /// Which permissions are granted to someone with Admin in a thread
@@ -127,6 +128,7 @@ pub const ADMIN_THREAD: &[Permission] = &[
     Permission::VoiceSpeak,
     Permission::VoiceVad,
     Permission::VoiceVideo,
+    Permission::VoiceRecord,
 ];
 
 /// Default permissions for everyone in a trusted room (eg. with friends)
@@ -227,6 +229,7 @@ pub const MODERATOR: &[Permission] = &[
     Permission::VoicePriority,
     Permission::VoiceVad,
     Permission::VoiceBroadcast,
+    Permission::VoiceRecord,
     Permission::MessageCreateThread,
     Permission::ChannelSlowmodeBypass,
     Permission::CallUpdate,
@@ -302,6 +302,9 @@ define_permissions! {
     /// can view redex logs, traces, metrics, and other debugging info
     // TODO: rename to RedexInspect
     ScriptInspect,
+
+    /// can start and stop recording calls in voice channels
+    VoiceRecord,
     // TODO: maybe add new EmojiCreate permission
     // like discord's expression create permission
 }
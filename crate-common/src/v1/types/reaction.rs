@@ -91,7 +91,13 @@ pub enum ReactionKeyField {
 }
 
 impl ReactionKeyField {
-    // pub fn a(&self) {}
+    /// get this key as a ReactionKeyParam string
+    pub fn to_key_str(&self) -> String {
+        match self {
+            ReactionKeyField::Param(p) => p.to_string(),
+            ReactionKeyField::Key(k) => k.to_key_str(),
+        }
+    }
 }
 
 #[cfg(feature = "utoipa")]
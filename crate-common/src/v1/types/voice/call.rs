@@ -5,7 +5,7 @@ use utoipa::IntoParams;
 
 #[cfg(feature = "serde")]
 use crate::v1::types::util::some_option;
-use crate::v1::types::{ChannelId, RoomId, misc::Time};
+use crate::v1::types::{ChannelId, RoomId, UserId, misc::Time};
 
 /// a currently active voice session
 #[record]
@@ -25,6 +25,20 @@ pub struct CallMetadata {
     /// suppressed) are sent.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub audience_count: Option<u64>,
+
+    /// the recording in progress for this call, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recording: Option<CallRecording>,
+}
+
+/// a recording of a call
+#[record]
+pub struct CallRecording {
+    /// the user who started this recording
+    pub started_by: UserId,
+
+    /// when this recording was started
+    pub started_at: Time,
 }
 
 /// a currently active voice session, with ids
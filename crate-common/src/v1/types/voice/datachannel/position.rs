@@ -8,12 +8,13 @@ use bytes::{Buf, BufMut};
 ///
 /// Sent by both the client and server to indicate that a media's position changed. Clients can only send this for their own tracks.
 ///
+/// A position applies to every track in the same stream (ie. with the same `TrackKey`), and the position of a user's `user` stream is where they are listening from. The server only sends positions of nearby tracks, and stops forwarding media from tracks that are too far away.
+///
 /// ## binary
 ///
 /// - 8 byte track id
 /// - 1 byte tag (0 = Position, 1 = Direction)
 /// - 12 bytes coordinates (x: f32, y: f32, z: f32)
-// TODO: tie position to track groups/streams
 #[derive(Debug, Clone)]
 pub struct PositionDatagram {
     pub track_id: TrackId,
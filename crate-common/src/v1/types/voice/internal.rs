@@ -9,7 +9,7 @@ use thiserror::Error;
 use crate::v1::types::{
     ConnectionId, SessionId, UserId,
     misc::Time,
-    voice::{VoiceState, VoiceStateUpdate},
+    voice::{MediaKind, VoiceState, VoiceStateUpdate},
 };
 
 /// smaller voice state for sfus
@@ -66,6 +66,23 @@ pub struct VoiceConfig {
     // TODO: video resolution
 }
 
+/// query params for a recorded track that an sfu is uploading
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RecordingUploadParams {
+    /// the user who published this track
+    pub user_id: UserId,
+
+    /// what kind of media this track contains
+    pub kind: MediaKind,
+
+    /// the filename for this track
+    pub filename: String,
+
+    /// how many milliseconds after the recording started this track begins
+    pub offset: u64,
+}
+
 /// errors that occur when converting from an api voice state to an sfu voice state
 #[derive(Debug, Clone, Error)]
 pub enum SfuVoiceStateConversionError {
@@ -12,8 +12,8 @@ use utoipa::ToSchema;
 use crate::v1::types::{
     ChannelId, SfuId, UserId,
     voice::{
-        IceCandidate, SessionDescription, SfuStats, SubscriptionUpdate, TrackAnnouncement,
-        TrackCreate, TrackId, TrackMapping, VoiceErrorCode, VoiceStateUpdate,
+        IceCandidate, IceServer, SessionDescription, SfuStats, SubscriptionUpdate,
+        TrackAnnouncement, TrackCreate, TrackId, TrackMapping, VoiceErrorCode, VoiceStateUpdate,
         internal::{SfuVoiceState, VoiceConfig},
     },
 };
@@ -67,6 +67,9 @@ pub enum SfuCommand {
 
         /// the address to connect to
         addr: SocketAddr,
+
+        /// the hex encoded sha-256 hash of the target sfu's certificate
+        fingerprint: String,
     },
 
     /// whenever media is sent to this channel, forward it to all of these sfus
@@ -89,6 +92,14 @@ pub enum SfuCommand {
     /// upsert channel config
     Channel { id: ChannelId, config: VoiceConfig },
 
+    /// start recording media published to this sfu in a call
+    RecordingStart { channel_id: ChannelId },
+
+    /// stop recording a call
+    ///
+    /// uploads every recorded track then sends `RecordingFinished`
+    RecordingStop { channel_id: ChannelId },
+
     /// a remote peer wants a keyframe for this media
     // FIXME: keyframe generation between sfus
     // (this command specifically may not be needed)
@@ -156,6 +167,9 @@ pub enum SfuEvent {
 
         /// the address to connect to
         addr: SocketAddr,
+
+        /// the hex encoded sha-256 hash of our certificate
+        fingerprint: String,
     },
 
     // TODO: CascadeDisconnected
@@ -172,6 +186,14 @@ pub enum SfuEvent {
         channel_id: ChannelId,
         update: VoiceStateUpdate,
     },
+
+    /// a recording was stopped and all of its tracks have been uploaded
+    RecordingFinished { channel_id: ChannelId },
+
+    /// the builtin stun and turn servers for this sfu
+    ///
+    /// sent after `Init`. turn urls are only included if a turn secret is configured.
+    IceServers { urls: Vec<String> },
 }
 
 /// an event sent from the peer's sync connection to the master
@@ -211,6 +233,12 @@ pub enum SignallingEvent {
         ///
         /// internal; for debugging.
         sfu_id: SfuId,
+
+        /// extra ice servers to use for this connection
+        ///
+        /// turn credentials are only valid for this voice state
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        ice_servers: Vec<IceServer>,
     },
 
     /// disconnected
@@ -1,5 +1,4 @@
 //! routing logic
-// TODO: remove? or move to utils
 
 use std::collections::{HashMap, HashSet};
 
@@ -36,18 +35,77 @@ impl Default for VoiceRouterConfig {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct VoiceTopology {
     pub links: Vec<VoiceLink>,
+
+    /// sfus without any users that were added to shorten slow links
+    pub relays: Vec<SfuId>,
 }
 
 /// voice links are bidirectional
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct VoiceLink {
     pub src: SfuId,
     pub dest: SfuId,
 }
 
+/// move all users from one sfu to another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoiceMerge {
+    pub from: SfuId,
+    pub into: SfuId,
+}
+
+impl VoiceTopology {
+    /// every sfu that is part of this topology
+    pub fn nodes(&self) -> HashSet<SfuId> {
+        self.links.iter().flat_map(|l| [l.src, l.dest]).collect()
+    }
+
+    /// the sfus directly linked to this one
+    pub fn neighbors(&self, sfu_id: SfuId) -> Vec<SfuId> {
+        self.links
+            .iter()
+            .filter_map(|l| {
+                if l.src == sfu_id {
+                    Some(l.dest)
+                } else if l.dest == sfu_id {
+                    Some(l.src)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// the sfus reachable from `start` without using the link at `skip`
+    fn component(&self, start: SfuId, skip: usize) -> HashSet<SfuId> {
+        let mut seen = HashSet::from([start]);
+        let mut stack = vec![start];
+        while let Some(node) = stack.pop() {
+            for (i, link) in self.links.iter().enumerate() {
+                if i == skip {
+                    continue;
+                }
+
+                let next = if link.src == node {
+                    link.dest
+                } else if link.dest == node {
+                    link.src
+                } else {
+                    continue;
+                };
+
+                if seen.insert(next) {
+                    stack.push(next);
+                }
+            }
+        }
+        seen
+    }
+}
+
 // A simple Union-Find for Kruskal's Algorithm
 struct DisjointSet {
     parent: HashMap<SfuId, SfuId>,
@@ -121,7 +179,7 @@ impl VoiceRouter {
     /// calculate minimum spanning tree for a channel's active nodes
     pub fn calculate_topology(&self, active_nodes: &HashSet<SfuId>) -> VoiceTopology {
         if active_nodes.len() < 2 {
-            return VoiceTopology { links: vec![] };
+            return VoiceTopology::default();
         }
 
         // 1. Generate all unique edges between active nodes
@@ -154,6 +212,242 @@ impl VoiceRouter {
             }
         }
 
-        VoiceTopology { links: mst }
+        VoiceTopology {
+            links: mst,
+            relays: vec![],
+        }
+    }
+
+    /// calculate the topology for a channel
+    ///
+    /// `available_nodes` are all sfus that may be used as relays for links that are too slow
+    pub fn calculate_routes(
+        &self,
+        active_nodes: &HashSet<SfuId>,
+        available_nodes: &HashSet<SfuId>,
+    ) -> VoiceTopology {
+        let mut topology = self.calculate_topology(active_nodes);
+        self.rebalance(&mut topology, available_nodes);
+        topology
+    }
+
+    /// replace links slower than `maximum_latency`
+    ///
+    /// removing a link splits the tree in two. the halves are reconnected with either a faster
+    /// direct link or a path through an unused sfu, whichever is faster.
+    pub fn rebalance(&self, topology: &mut VoiceTopology, available_nodes: &HashSet<SfuId>) {
+        let max = self.config.maximum_latency;
+
+        // every replacement is at most `max` so each link is only replaced once
+        let mut i = 0;
+        while i < topology.links.len() {
+            let VoiceLink { src, dest } = topology.links[i];
+            let current = self.get_latency(src, dest);
+            if current <= max {
+                i += 1;
+                continue;
+            }
+
+            let left = topology.component(src, i);
+            let right = topology.component(dest, i);
+            let nodes: HashSet<SfuId> = left.union(&right).copied().collect();
+
+            // (total latency, replacement links, relay)
+            let mut best: Option<(u64, Vec<VoiceLink>, Option<SfuId>)> = None;
+
+            for &a in &left {
+                for &b in &right {
+                    let lat = self.get_latency(a, b);
+                    if lat <= max && best.as_ref().is_none_or(|(l, ..)| (lat as u64) < *l) {
+                        best = Some((lat as u64, vec![VoiceLink { src: a, dest: b }], None));
+                    }
+                }
+            }
+
+            for &relay in available_nodes.difference(&nodes) {
+                let Some((lat_a, a)) = self.closest(relay, &left) else {
+                    continue;
+                };
+                let Some((lat_b, b)) = self.closest(relay, &right) else {
+                    continue;
+                };
+                if lat_a > max || lat_b > max {
+                    continue;
+                }
+
+                let total = lat_a as u64 + lat_b as u64;
+                if best.as_ref().is_none_or(|(l, ..)| total < *l) {
+                    best = Some((
+                        total,
+                        vec![
+                            VoiceLink {
+                                src: a,
+                                dest: relay,
+                            },
+                            VoiceLink {
+                                src: relay,
+                                dest: b,
+                            },
+                        ],
+                        Some(relay),
+                    ));
+                }
+            }
+
+            match best {
+                Some((total, links, relay)) if total < current as u64 => {
+                    topology.links.splice(i..=i, links);
+                    topology.relays.extend(relay);
+                }
+                _ => i += 1,
+            }
+        }
+    }
+
+    /// find sfus that host few enough users that they should be merged into another sfu
+    ///
+    /// `users` is the number of users on each sfu. smaller sfus are merged first, into the
+    /// biggest sfu within `maximum_latency`.
+    ///
+    /// `capacity` is how many more users each sfu can take. sfus without a capacity are assumed
+    /// to have room for everyone. merges that would overfill an sfu are skipped.
+    pub fn calculate_merges(
+        &self,
+        users: &HashMap<SfuId, usize>,
+        capacity: &HashMap<SfuId, usize>,
+    ) -> Vec<VoiceMerge> {
+        let mut nodes: Vec<(SfuId, usize)> = users.iter().map(|(&id, &n)| (id, n)).collect();
+        nodes.sort_by_key(|&(id, n)| (n, id));
+
+        let mut merges = Vec::new();
+        let mut removed = HashSet::new();
+        let mut absorbing = HashSet::new();
+        let mut remaining = capacity.clone();
+
+        for &(from, count) in &nodes {
+            if count > self.config.merge_threshold as usize || absorbing.contains(&from) {
+                continue;
+            }
+
+            let into = nodes
+                .iter()
+                .filter(|(id, _)| *id != from && !removed.contains(id))
+                .filter(|(id, _)| self.get_latency(from, *id) <= self.config.maximum_latency)
+                .filter(|(id, _)| remaining.get(id).is_none_or(|&r| r >= count))
+                .max_by_key(|&&(id, n)| (n, std::cmp::Reverse(self.get_latency(from, id))));
+
+            if let Some(&(into, _)) = into {
+                removed.insert(from);
+                absorbing.insert(into);
+                if let Some(r) = remaining.get_mut(&into) {
+                    *r -= count;
+                }
+                merges.push(VoiceMerge { from, into });
+            }
+        }
+
+        merges
+    }
+
+    /// the closest node in `nodes` to `sfu_id`
+    fn closest(&self, sfu_id: SfuId, nodes: &HashSet<SfuId>) -> Option<(u32, SfuId)> {
+        nodes
+            .iter()
+            .map(|&n| (self.get_latency(sfu_id, n), n))
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u32 = 1_000_000;
+
+    fn router(latencies: &[(SfuId, SfuId, u32)]) -> VoiceRouter {
+        let mut router = VoiceRouter::new(VoiceRouterConfig::default());
+        for &(a, b, lat) in latencies {
+            router.update_latency(a, b, lat);
+        }
+        router
+    }
+
+    fn has_link(topology: &VoiceTopology, a: SfuId, b: SfuId) -> bool {
+        topology
+            .links
+            .iter()
+            .any(|l| (l.src == a && l.dest == b) || (l.src == b && l.dest == a))
+    }
+
+    #[test]
+    fn test_minimum_tree() {
+        let (a, b, c) = (SfuId::new(), SfuId::new(), SfuId::new());
+        let router = router(&[(a, b, 10 * MS), (b, c, 20 * MS), (a, c, 50 * MS)]);
+        let active = HashSet::from([a, b, c]);
+        let topology = router.calculate_routes(&active, &active);
+        assert_eq!(topology.links.len(), 2);
+        assert!(has_link(&topology, a, b));
+        assert!(has_link(&topology, b, c));
+        assert!(topology.relays.is_empty());
+    }
+
+    #[test]
+    fn test_relay_slow_link() {
+        let (a, b, relay) = (SfuId::new(), SfuId::new(), SfuId::new());
+        let router = router(&[(a, b, 150 * MS), (a, relay, 40 * MS), (relay, b, 50 * MS)]);
+        let active = HashSet::from([a, b]);
+        let available = HashSet::from([a, b, relay]);
+        let topology = router.calculate_routes(&active, &available);
+        assert_eq!(topology.relays, vec![relay]);
+        assert!(has_link(&topology, a, relay));
+        assert!(has_link(&topology, relay, b));
+        assert!(!has_link(&topology, a, b));
+    }
+
+    #[test]
+    fn test_keep_slow_link_without_better_path() {
+        let (a, b, relay) = (SfuId::new(), SfuId::new(), SfuId::new());
+        let router = router(&[(a, b, 150 * MS), (a, relay, 100 * MS), (relay, b, 100 * MS)]);
+        let active = HashSet::from([a, b]);
+        let available = HashSet::from([a, b, relay]);
+        let topology = router.calculate_routes(&active, &available);
+        assert_eq!(topology.links.len(), 1);
+        assert!(has_link(&topology, a, b));
+        assert!(topology.relays.is_empty());
+    }
+
+    #[test]
+    fn test_merge_small_sfus() {
+        let (a, b, c) = (SfuId::new(), SfuId::new(), SfuId::new());
+        let router = router(&[(a, b, 10 * MS), (a, c, 200 * MS), (b, c, 200 * MS)]);
+        let users = HashMap::from([(a, 10), (b, 2), (c, 1)]);
+        let merges = router.calculate_merges(&users, &HashMap::new());
+        assert_eq!(merges, vec![VoiceMerge { from: b, into: a }]);
+    }
+
+    #[test]
+    fn test_merge_pair() {
+        let (a, b) = (SfuId::new(), SfuId::new());
+        let router = router(&[(a, b, 10 * MS)]);
+        let users = HashMap::from([(a, 1), (b, 1)]);
+        let merges = router.calculate_merges(&users, &HashMap::new());
+        assert_eq!(merges.len(), 1);
+    }
+
+    #[test]
+    fn test_merge_respects_capacity() {
+        let (a, b, c) = (SfuId::new(), SfuId::new(), SfuId::new());
+        let router = router(&[(a, b, 10 * MS), (a, c, 10 * MS), (b, c, 10 * MS)]);
+        let users = HashMap::from([(a, 10), (b, 3), (c, 1)]);
+
+        // a is full, so c is merged into b instead
+        let capacity = HashMap::from([(a, 0)]);
+        let merges = router.calculate_merges(&users, &capacity);
+        assert_eq!(merges, vec![VoiceMerge { from: c, into: b }]);
+
+        // a only has room for one more user
+        let capacity = HashMap::from([(a, 1)]);
+        let merges = router.calculate_merges(&users, &capacity);
+        assert_eq!(merges, vec![VoiceMerge { from: c, into: a }]);
     }
 }
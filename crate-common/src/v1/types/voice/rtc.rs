@@ -20,6 +20,21 @@ pub struct SessionDescription(pub String);
 #[cfg_attr(feature = "utoipa", derive(ToSchema))]
 pub struct IceCandidate(pub String);
 
+/// a stun or turn server that a client can use to connect to a sfu
+#[record]
+pub struct IceServer {
+    /// stun or turn urls for this server
+    pub urls: Vec<String>,
+
+    /// the username to authenticate with, only used for turn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// the password to authenticate with, only used for turn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
 /// a unique identifier for a media track
 ///
 /// mids are local to each client/sfu pair. corresponds to a transceiver in webrtc.
@@ -56,6 +56,12 @@ pub struct TrackMetadata2 {
     /// whisper config
     #[serde(skip_serializing_if = "Option::is_none")]
     pub whisper: Option<TrackWhisper>,
+
+    /// whether this track's media is end to end encrypted with sframe
+    ///
+    /// the sfu still forwards encrypted tracks, but can't record them.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
 }
 
 // webrtc rids dont need to be in the signalling protocol
@@ -40,7 +40,9 @@ impl From<Media> for V1Media {
                             language: None,
                         })
                     }
-                    MediaMetadata::File => crate::v1::types::MediaTrackInfo::Other,
+                    MediaMetadata::Document { .. } | MediaMetadata::File => {
+                        crate::v1::types::MediaTrackInfo::Other
+                    }
                     MediaMetadata::Errored { .. } => crate::v1::types::MediaTrackInfo::Other,
                 },
                 size: val.size,
@@ -114,6 +116,10 @@ impl From<V1Media> for Media {
             scans: vec![],
             has_thumbnail: false,
             has_gifv: false,
+            placeholder: None,
+            audio: None,
+            blob_id: None,
+            strip_exif_failed: false,
 
             // NOTE: these should probably be populated later?
             links: vec![],
@@ -122,6 +128,7 @@ impl From<V1Media> for Media {
             hashes: Hashes::default(),
             strip_exif: false,
             remote: None,
+            signed: None,
         }
     }
 }
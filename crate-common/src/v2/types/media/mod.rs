@@ -9,12 +9,15 @@ use lamprey_macros::{Diff, record};
 
 use crate::v1::types::{
     ChannelId, EmbedId, MediaId, MediaVerId, MessageId, MessageVerId, Mime, RedexId, RedexVerId,
-    RoomId, UserId, federation::Remote, misc::hashes::Hashes, util::Time,
+    RoomId, UserId, federation::Remote, misc::Color, misc::hashes::Hashes, util::Time,
 };
 
 pub mod proxy;
 pub mod scanner;
 
+use proxy::MediaSigned;
+use scanner::ScanVerdict;
+
 /// A reference to a piece of media to be used.
 // TODO: use this in more FooCreate and FooPatch structs
 #[record]
@@ -141,6 +144,18 @@ pub struct Media {
     /// Whether this media can be fetched through the `/gifv/{media_id}` cdn route.
     pub has_gifv: bool,
 
+    /// A tiny preview to show while the thumbnail loads. Only exists for visual media.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<MediaPlaceholder>,
+
+    /// An analysis of the audio track, for drawing waveforms and normalizing volume. Only exists for audio.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio: Option<MediaAudio>,
+
+    /// the media whose files this media shares, if this media's file was deduplicated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob_id: Option<MediaId>,
+
     /// what this piece of media is linked to (admin only)
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub links: Vec<MediaLinkType>,
@@ -164,9 +179,19 @@ pub struct Media {
     #[serde(default)]
     pub strip_exif: bool,
 
+    /// Whether `strip_exif` was requested but some metadata couldn't be removed.
+    ///
+    /// This file may still contain location or device info.
+    #[serde(default)]
+    pub strip_exif_failed: bool,
+
     /// if this media exists on a remote server
     #[serde(skip_serializing_if = "Option::is_none")]
     pub remote: Option<Remote<MediaId>>,
+
+    /// Signatures for fetching this media from the cdn. Only exists for media in private channels, if the server requires signed urls.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed: Option<MediaSigned>,
     // TODO: add
     // /// If this media will expire, data about its expiry.
     // #[serde(skip_serializing_if = "Option::is_none")]
@@ -225,6 +250,54 @@ pub struct MediaScan {
 
     /// The version of the scanner that was used for this attachment.
     pub version: u16,
+
+    /// How severe the scan result is.
+    #[serde(default)]
+    pub verdict: ScanVerdict,
+}
+
+/// How much storage a user's or room's media uses
+#[record]
+#[derive(PartialEq, Eq)]
+pub struct MediaUsage {
+    /// The total size of the media in bytes
+    pub bytes: u64,
+
+    /// The number of pieces of media
+    pub count: u64,
+
+    /// The maximum number of bytes allowed, if there is a quota
+    pub quota: Option<u64>,
+}
+
+/// A tiny preview of some visual media
+#[record]
+#[derive(PartialEq, Eq)]
+pub struct MediaPlaceholder {
+    /// A base64 encoded [thumbhash](https://evanw.github.io/thumbhash/)
+    pub thumbhash: String,
+
+    /// The most common color
+    pub color: Color,
+}
+
+/// An analysis of some audio
+#[record]
+#[derive(PartialEq)]
+pub struct MediaAudio {
+    /// The peak amplitude of evenly sized chunks of audio, from 0 to 255
+    pub waveform: Vec<u8>,
+
+    /// The integrated loudness in LUFS, as defined by EBU R128
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loudness: Option<f64>,
+
+    /// The number of channels
+    pub channels: u64,
+
+    /// The channel layout, eg. `mono`, `stereo` or `5.1(side)`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_layout: Option<String>,
 }
 
 /// Filetype-specific metadata
@@ -251,16 +324,24 @@ pub enum MediaMetadata {
         /// the height of the video in pixels
         height: u64,
 
-        /// the duration of the video in seconds
+        /// the duration of the video in milliseconds
         duration: u64,
     },
 
     /// An audio file
     Audio {
-        /// the duration of the video in seconds
+        /// the duration of the audio in milliseconds
         duration: u64,
     },
 
+    /// A pdf or office document
+    ///
+    /// the first page is used as the poster
+    Document {
+        /// the number of pages in the document
+        pages: u64,
+    },
+
     /// A generic file that can be previewed in a pre/code block
     Text,
 
@@ -290,6 +371,11 @@ impl MediaMetadata {
         matches!(self, MediaMetadata::Audio { .. })
     }
 
+    /// Returns `true` if this media is a document.
+    pub fn is_document(&self) -> bool {
+        matches!(self, MediaMetadata::Document { .. })
+    }
+
     /// Returns `true` if this media is a text file.
     pub fn is_text(&self) -> bool {
         matches!(self, MediaMetadata::Text)
@@ -557,12 +643,22 @@ impl Media {
             scans: vec![],
             has_thumbnail: false,
             has_gifv: false,
+            placeholder: None,
+            audio: None,
+            blob_id: None,
+            strip_exif_failed: false,
             links: vec![],
             room_id: None,
             channel_id: None,
             hashes: Hashes::default(),
             strip_exif: false,
             remote: None,
+            signed: None,
         }
     }
+
+    /// get the id of the media whose files this media uses
+    pub fn storage_id(&self) -> MediaId {
+        self.blob_id.unwrap_or(self.id)
+    }
 }
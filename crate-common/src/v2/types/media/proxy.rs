@@ -1,6 +1,7 @@
 //! types used in the media proxy
 
 use lamprey_macros::record;
+use url::Url;
 
 #[cfg(feature = "utoipa")]
 use utoipa::IntoParams;
@@ -31,6 +32,115 @@ pub struct ThumbQuery {
     pub animate: bool,
 }
 
+/// an on-the-fly transformation of an image
+#[record]
+#[derive(Default, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(IntoParams))]
+pub struct TransformQuery {
+    /// the maximum width in pixels
+    pub w: Option<u32>,
+
+    /// the maximum height in pixels
+    pub h: Option<u32>,
+
+    /// how to fit the image into the width and height
+    #[serde(default)]
+    pub fit: TransformFit,
+
+    /// the region of the source image to use, as `x,y,width,height`
+    pub crop: Option<String>,
+
+    /// the output format
+    ///
+    /// if None, the format is negotiated with the Accept header
+    pub format: Option<TransformFormat>,
+
+    /// the output quality, from 1 to 100
+    pub quality: Option<u8>,
+}
+
+/// how to fit an image into a width and height
+#[record]
+#[derive(Default, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum TransformFit {
+    /// scale the image to fill the entire area, cropping off the edges
+    Cover,
+
+    /// scale the image to fit inside the area, preserving the aspect ratio
+    #[default]
+    Contain,
+}
+
+/// an output format for image transforms
+#[record]
+#[derive(Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum TransformFormat {
+    Avif,
+    Webp,
+    Jpeg,
+}
+
+/// a signature for a url generated by the api
+#[record]
+#[derive(PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(IntoParams))]
+pub struct SignedQuery {
+    /// when this signature expires, in seconds since the unix epoch
+    ///
+    /// only set for private media
+    pub expires: Option<u64>,
+
+    pub sig: String,
+}
+
+/// a signature for a url to private media, issued by the api
+///
+/// both fields are required when the media is private, and ignored otherwise
+#[record]
+#[derive(Default, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(IntoParams))]
+pub struct MediaSignedQuery {
+    /// when this signature expires, in seconds since the unix epoch
+    pub expires: Option<u64>,
+
+    pub sig: Option<String>,
+}
+
+/// signatures for fetching a piece of private media from the media proxy
+///
+/// append `?expires={expires}&sig={sig}` to the url for each route, using the
+/// signature for that route
+#[record]
+#[derive(PartialEq, Eq)]
+pub struct MediaSigned {
+    /// when these signatures expire, in seconds since the unix epoch
+    pub expires: u64,
+
+    /// the signature for `/media/{media_id}`
+    pub media: String,
+
+    /// the signature for `/thumb/{media_id}`
+    pub thumb: String,
+
+    /// the signature for `/gifv/{media_id}`
+    pub gifv: String,
+
+    /// the signature for `/stream/{media_id}`
+    pub stream: String,
+
+    /// the signature for `/trickplay/{media_id}`
+    pub trickplay: String,
+}
+
+/// a signed url for an image transform
+#[record]
+#[derive(PartialEq, Eq)]
+pub struct TransformSigned {
+    pub url: Url,
+}
+
 #[cfg(feature = "serde")]
 fn default_true() -> bool {
     true
@@ -52,6 +162,71 @@ pub struct TrickplayQuery {
 
     /// width for each thumbnail
     pub thumb_width: Option<u32>,
+
+    /// which sheet to fetch
+    ///
+    /// if None, fetch the index mapping timestamps to tiles
+    pub sheet: Option<u32>,
+
+    /// the format of the index
+    #[serde(default)]
+    pub format: TrickplayFormat,
+}
+
+/// the format of a trickplay index
+#[record]
+#[derive(Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TrickplayFormat {
+    /// a webvtt file with media fragment uris, ie. `?sheet=0#xywh=0,0,160,90`
+    #[default]
+    Vtt,
+
+    /// a json [`TrickplayIndex`]
+    Json,
+}
+
+/// maps timestamps to tiles in trickplay sheets
+#[record]
+#[derive(PartialEq, Eq)]
+pub struct TrickplayIndex {
+    /// milliseconds between each tile
+    pub interval: u64,
+
+    /// width of each tile
+    pub thumb_width: u32,
+
+    /// height of each tile
+    pub thumb_height: u32,
+
+    /// number of tiles on the x axis in each sheet
+    pub width: u32,
+
+    /// number of tiles on the y axis in each sheet
+    pub height: u32,
+
+    /// every tile, in order
+    pub tiles: Vec<TrickplayTile>,
+}
+
+/// a single tile in a trickplay sheet
+#[record]
+#[derive(PartialEq, Eq)]
+pub struct TrickplayTile {
+    /// start time in milliseconds
+    pub start: u64,
+
+    /// end time in milliseconds
+    pub end: u64,
+
+    /// which sheet this tile is in
+    pub sheet: u32,
+
+    /// x offset in pixels
+    pub x: u32,
+
+    /// y offset in pixels
+    pub y: u32,
 }
 
 #[record]
@@ -59,10 +234,14 @@ pub struct TrickplayQuery {
 #[cfg_attr(feature = "utoipa", derive(IntoParams))]
 pub struct StreamQuery {
     /// segment index
-    pub n: usize,
+    ///
+    /// if None, fetch the playlist for stream `s`
+    pub n: Option<usize>,
 
     /// stream identifier
-    pub s: u64,
+    ///
+    /// if None, fetch the master playlist listing every stream
+    pub s: Option<u64>,
 }
 
 /// an available stream format
@@ -2,9 +2,50 @@
 //!
 //! These types define the request and response formats for external media scanning
 //! services (e.g., NSFW detection, malware scanning).
+//!
+//! A scanner exposes up to three endpoints:
+//!
+//! - `GET /info` returns a [`ScannerInfo`] describing what the scanner can handle.
+//! - `POST /scan` takes a [`ScanRequest`] pointing at a file on a shared filesystem.
+//! - `POST /scan/stream` takes the raw file as the request body, with its
+//!   `Content-Type` header set to the media's mime type.
+//!
+//! Both scan endpoints return a [`MediaScanResponse`].
 
 use lamprey_macros::record;
 
+/// The capabilities a media scanner advertises.
+#[record]
+pub struct ScannerInfo {
+    /// The name of the scanner implementation.
+    pub name: String,
+
+    /// The version of the scanner, usually bumped when its rules or model change.
+    pub version: u16,
+
+    /// Mime type prefixes this scanner accepts (eg. `image/`). Empty means all media.
+    #[serde(default)]
+    pub accepts: Vec<String>,
+
+    /// The maximum file size in bytes this scanner accepts.
+    #[serde(default)]
+    pub max_size: Option<u64>,
+
+    /// Whether this scanner supports `POST /scan/stream`.
+    #[serde(default)]
+    pub streaming: bool,
+}
+
+impl ScannerInfo {
+    /// Whether this scanner accepts media with this mime type and size.
+    pub fn accepts(&self, content_type: &str, size: u64) -> bool {
+        let accepts_type =
+            self.accepts.is_empty() || self.accepts.iter().any(|a| content_type.starts_with(a));
+        let accepts_size = self.max_size.is_none_or(|max| size <= max);
+        accepts_type && accepts_size
+    }
+}
+
 /// A request to scan a media file.
 ///
 /// Sent to external media scanning services configured via [`ConfigMediaScanner`](crate::config::ConfigMediaScanner).
@@ -12,6 +53,45 @@ use lamprey_macros::record;
 pub struct ScanRequest {
     /// The path to the media file to scan.
     pub path: String,
+
+    /// The mime type of the media file.
+    #[serde(default)]
+    pub content_type: Option<String>,
+
+    /// The size of the media file in bytes.
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+/// How severe a scan result is.
+#[record]
+#[derive(Default, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanVerdict {
+    /// Nothing was found.
+    #[default]
+    Clean,
+
+    /// Something was found, but it may be a false positive.
+    Suspicious,
+
+    /// Something was found and the media should be blocked.
+    Malicious,
+}
+
+impl ScanVerdict {
+    /// Guess a verdict from a score, for scanners that don't return one.
+    ///
+    /// This is never `Malicious`, since a score alone (eg. from a nsfw
+    /// classifier) isn't enough to reject media. Automod and room policy decide
+    /// what to do with high scores instead.
+    pub fn from_score(score: f64) -> Self {
+        if score >= 0.5 {
+            ScanVerdict::Suspicious
+        } else {
+            ScanVerdict::Clean
+        }
+    }
 }
 
 /// The response from a media scanning service.
@@ -22,4 +102,42 @@ pub struct MediaScanResponse {
 
     /// An optional message providing additional context about the scan result.
     pub message: Option<String>,
+
+    /// The verdict of the scan. Derived from the score if missing.
+    #[serde(default)]
+    pub verdict: Option<ScanVerdict>,
+
+    /// The names of the signatures or rules that matched, if any.
+    #[serde(default)]
+    pub matches: Vec<String>,
+}
+
+impl MediaScanResponse {
+    /// The verdict of the scan, falling back to one derived from the score.
+    pub fn verdict(&self) -> ScanVerdict {
+        self.verdict
+            .unwrap_or_else(|| ScanVerdict::from_score(self.score))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verdict() {
+        let mut res = MediaScanResponse {
+            score: 0.99,
+            message: None,
+            verdict: None,
+            matches: vec![],
+        };
+        assert_eq!(res.verdict(), ScanVerdict::Suspicious);
+
+        res.score = 0.1;
+        res.verdict = Some(ScanVerdict::Malicious);
+        assert_eq!(res.verdict(), ScanVerdict::Malicious);
+
+        assert_eq!(ScanVerdict::from_score(0.2), ScanVerdict::Clean);
+    }
 }
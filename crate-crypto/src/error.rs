@@ -5,4 +5,20 @@ pub enum Error {
     /// failed to export a secret from a mls group
     #[error("{0}")]
     ExportSecret(#[from] ExportSecretError),
+
+    /// a media frame is malformed or failed to decrypt
+    #[error("invalid frame")]
+    Frame,
+
+    /// a media frame was already received
+    #[error("replayed frame")]
+    ReplayedFrame,
+
+    /// there is no voice key for this frame's epoch
+    #[error("missing voice key")]
+    MissingVoiceKey,
+
+    /// a voice epoch secret is too short
+    #[error("invalid voice key")]
+    InvalidVoiceKey,
 }
@@ -11,7 +11,11 @@ use openmls::{
     prelude::{DeserializeBytes, OpenMlsProvider},
 };
 
-use crate::{manager::EncryptionShared, prelude::*};
+use crate::{
+    manager::EncryptionShared,
+    prelude::*,
+    voice::{self, FrameCryptor},
+};
 
 pub struct EncryptionChannel {
     // channel_id: ChannelId,
@@ -230,3 +234,27 @@ impl EncryptionChannel {
         Ok(())
     }
 }
+
+impl EncryptionChannel {
+    /// export the secret that voice and video keys are derived from for the current epoch
+    pub fn voice_secret(&self) -> Result<Vec<u8>> {
+        Ok(self.group.export_secret(
+            self.shared.provider.crypto(),
+            voice::EXPORTER_LABEL,
+            &[],
+            voice::SECRET_LEN,
+        )?)
+    }
+
+    /// our sframe sender id, which is our leaf index in the group
+    pub fn voice_sender(&self) -> u32 {
+        self.group.own_leaf_index().u32()
+    }
+
+    /// create a cryptor for a call in this channel, keyed from the current epoch
+    pub fn voice_cryptor(&self) -> Result<FrameCryptor> {
+        let mut cryptor = FrameCryptor::new(self.voice_sender());
+        cryptor.set_epoch(self.epoch(), &self.voice_secret()?)?;
+        Ok(cryptor)
+    }
+}
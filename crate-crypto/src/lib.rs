@@ -29,5 +29,7 @@ pub(crate) mod prelude {
     pub type Ref<T> = ::std::sync::Arc<T>;
 }
 
+pub use error::Error;
 pub use group::EncryptionChannel;
 pub use manager::{Action, Actions, Encryption};
+pub use voice::{FrameCodec, FrameCryptor};
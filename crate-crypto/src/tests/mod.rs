@@ -1,4 +1 @@
-#[test]
-fn test_foo() {
-    panic!("TODO: add tests")
-}
+mod voice;
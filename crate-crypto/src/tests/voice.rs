@@ -0,0 +1,177 @@
+use lamprey::v1::types::e2ee::MlsEpoch;
+
+use crate::{
+    prelude::*,
+    voice::{FrameCodec, FrameCryptor, decode_header, encode_header},
+};
+
+const SECRET: [u8; 32] = [7; 32];
+const VP8_KEYFRAME: [u8; 12] = [
+    0x10, 0x02, 0x00, 0x9d, 0x01, 0x2a, 0x80, 0x02, 0xe0, 0x01, 0xaa, 0xbb,
+];
+
+fn pair() -> (FrameCryptor, FrameCryptor) {
+    let mut alice = FrameCryptor::new(0);
+    let mut bob = FrameCryptor::new(1);
+    alice.set_epoch(MlsEpoch(1), &SECRET).unwrap();
+    bob.set_epoch(MlsEpoch(1), &SECRET).unwrap();
+    (alice, bob)
+}
+
+#[test]
+fn test_header_roundtrip() {
+    for (kid, ctr) in [(0, 0), (7, 7), (8, 8), (0x1234, 0xff), (u64::MAX, u64::MAX)] {
+        let mut buf = vec![];
+        encode_header(&mut buf, kid, ctr);
+        assert_eq!(decode_header(&buf), Some((kid, ctr, buf.len())));
+    }
+
+    // extended key id and counter, two bytes each
+    let mut buf = vec![];
+    encode_header(&mut buf, 0x0123, 0x4567);
+    assert_eq!(buf, [0x99, 0x01, 0x23, 0x45, 0x67]);
+}
+
+#[test]
+fn test_roundtrip() {
+    let (mut alice, mut bob) = pair();
+    let frame = [0xfc, 1, 2, 3, 4, 5];
+    let encrypted = alice.encrypt(FrameCodec::Opus, &frame).unwrap();
+    assert_ne!(&encrypted[1..], &frame[1..]);
+    assert_eq!(bob.decrypt(FrameCodec::Opus, &encrypted).unwrap(), frame);
+
+    // counters keep frames unique
+    let again = alice.encrypt(FrameCodec::Opus, &frame).unwrap();
+    assert_ne!(again, encrypted);
+    assert_eq!(bob.decrypt(FrameCodec::Opus, &again).unwrap(), frame);
+}
+
+#[test]
+fn test_keyframe_header_is_clear() {
+    let (mut alice, mut bob) = pair();
+    let encrypted = alice.encrypt(FrameCodec::Vp8, &VP8_KEYFRAME).unwrap();
+    assert_eq!(&encrypted[..10], &VP8_KEYFRAME[..10]);
+    assert_eq!(
+        bob.decrypt(FrameCodec::Vp8, &encrypted).unwrap(),
+        VP8_KEYFRAME
+    );
+
+    // the clear bytes are still authenticated
+    let encrypted = alice.encrypt(FrameCodec::Vp8, &VP8_KEYFRAME).unwrap();
+    let mut tampered = encrypted.clone();
+    tampered[6] ^= 1;
+    assert!(matches!(
+        bob.decrypt(FrameCodec::Vp8, &tampered),
+        Err(Error::Frame)
+    ));
+    assert_eq!(
+        bob.decrypt(FrameCodec::Vp8, &encrypted).unwrap(),
+        VP8_KEYFRAME
+    );
+}
+
+#[test]
+fn test_epochs() {
+    let (mut alice, mut bob) = pair();
+    let old = alice.encrypt(FrameCodec::Other, b"hello").unwrap();
+
+    let next = [9; 32];
+    alice.set_epoch(MlsEpoch(2), &next).unwrap();
+    let new = alice.encrypt(FrameCodec::Other, b"world").unwrap();
+    assert!(matches!(
+        bob.decrypt(FrameCodec::Other, &new),
+        Err(Error::MissingVoiceKey)
+    ));
+
+    // frames from the previous epoch still decrypt after a commit
+    bob.set_epoch(MlsEpoch(2), &next).unwrap();
+    assert_eq!(bob.decrypt(FrameCodec::Other, &old).unwrap(), b"hello");
+    assert_eq!(bob.decrypt(FrameCodec::Other, &new).unwrap(), b"world");
+
+    // but not forever
+    for epoch in 3..6 {
+        bob.set_epoch(MlsEpoch(epoch), &[epoch as u8; 32]).unwrap();
+    }
+    assert!(bob.decrypt(FrameCodec::Other, &old).is_err());
+}
+
+#[test]
+fn test_wrong_secret() {
+    let mut alice = FrameCryptor::new(0);
+    let mut eve = FrameCryptor::new(1);
+    alice.set_epoch(MlsEpoch(1), &SECRET).unwrap();
+    eve.set_epoch(MlsEpoch(1), &[8; 32]).unwrap();
+
+    let encrypted = alice.encrypt(FrameCodec::Opus, &[0xfc, 1, 2, 3]).unwrap();
+    assert!(matches!(
+        eve.decrypt(FrameCodec::Opus, &encrypted),
+        Err(Error::Frame)
+    ));
+    assert!(matches!(
+        FrameCryptor::new(0).encrypt(FrameCodec::Opus, &[0xfc]),
+        Err(Error::MissingVoiceKey)
+    ));
+}
+
+#[test]
+fn test_counters_unique_per_session() {
+    // rejoining during an epoch derives the same key, so the counter must not restart
+    let mut first = FrameCryptor::new(0);
+    let mut second = FrameCryptor::new(0);
+    first.set_epoch(MlsEpoch(1), &SECRET).unwrap();
+    second.set_epoch(MlsEpoch(1), &SECRET).unwrap();
+
+    let a = first.encrypt(FrameCodec::Other, b"hello").unwrap();
+    let b = second.encrypt(FrameCodec::Other, b"hello").unwrap();
+    let (kid_a, ctr_a, _) = decode_header(&a).unwrap();
+    let (kid_b, ctr_b, _) = decode_header(&b).unwrap();
+    assert_eq!(kid_a, kid_b);
+    assert_ne!(ctr_a, ctr_b);
+}
+
+#[test]
+fn test_replay() {
+    let (mut alice, mut bob) = pair();
+    let first = alice.encrypt(FrameCodec::Other, b"one").unwrap();
+    let second = alice.encrypt(FrameCodec::Other, b"two").unwrap();
+
+    // frames can arrive out of order, but only once
+    assert_eq!(bob.decrypt(FrameCodec::Other, &second).unwrap(), b"two");
+    assert_eq!(bob.decrypt(FrameCodec::Other, &first).unwrap(), b"one");
+    assert!(matches!(
+        bob.decrypt(FrameCodec::Other, &first),
+        Err(Error::ReplayedFrame)
+    ));
+    assert!(matches!(
+        bob.decrypt(FrameCodec::Other, &second),
+        Err(Error::ReplayedFrame)
+    ));
+
+    // frames too far behind are rejected
+    let late = alice.encrypt(FrameCodec::Other, b"late").unwrap();
+    let mut latest = late.clone();
+    for _ in 0..200 {
+        latest = alice.encrypt(FrameCodec::Other, b"more").unwrap();
+    }
+    assert_eq!(bob.decrypt(FrameCodec::Other, &latest).unwrap(), b"more");
+    assert!(matches!(
+        bob.decrypt(FrameCodec::Other, &late),
+        Err(Error::ReplayedFrame)
+    ));
+}
+
+#[test]
+fn test_forged_frame_not_cached() {
+    let (mut alice, mut bob) = pair();
+    let frame = alice.encrypt(FrameCodec::Other, b"hello").unwrap();
+
+    // a forged frame with the same key id and counter doesn't poison the replay window
+    let mut forged = frame.clone();
+    let last = forged.len() - 1;
+    forged[last] ^= 1;
+    assert!(matches!(
+        bob.decrypt(FrameCodec::Other, &forged),
+        Err(Error::Frame)
+    ));
+    assert_eq!(bob.decrypt(FrameCodec::Other, &frame).unwrap(), b"hello");
+}
@@ -1 +1,424 @@
-// TODO: e2ee voice channels
+//! end to end encrypted voice and video using sframe (rfc 9605)
+//!
+//! each mls epoch exports a secret, which every member expands into a per
+//! sender base key. frames keep their codec header in the clear (but
+//! authenticated) so the sfu can still find keyframes.
+
+use std::collections::{HashMap, VecDeque};
+
+use aes_gcm::{
+    Aes128Gcm,
+    aead::{Aead, KeyInit, Payload},
+};
+use hkdf::Hkdf;
+use lamprey::v1::types::e2ee::MlsEpoch;
+use sha2::Sha256;
+
+use crate::prelude::*;
+
+/// the sframe cipher suite, AES_128_GCM_SHA256_128
+const CIPHER_SUITE: u16 = 0x0004;
+
+const KEY_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// length of the epoch secret and base keys
+pub(crate) const SECRET_LEN: usize = 32;
+
+/// the mls exporter label for sframe epoch secrets
+pub(crate) const EXPORTER_LABEL: &str = "SFrame 1.0 Base Key";
+
+/// the low bits of each key id are the epoch, the rest is the sender's leaf index
+const EPOCH_BITS: u32 = 4;
+const EPOCH_MASK: u64 = (1 << EPOCH_BITS) - 1;
+
+/// how many epochs to keep keys for, so frames sent before a commit still decrypt
+const MAX_EPOCHS: usize = 3;
+
+/// how many receiving keys to cache
+const MAX_RECV_KEYS: usize = 1024;
+
+/// how far behind the newest frame from a sender a frame can arrive and still be accepted
+const REPLAY_WINDOW: u64 = 128;
+
+/// the codec of a media frame
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameCodec {
+    Opus,
+    Vp8,
+    Vp9,
+
+    /// encrypt the entire frame
+    Other,
+}
+
+impl FrameCodec {
+    /// how many bytes at the start of a frame are left unencrypted
+    ///
+    /// this only depends on bytes that are themselves left unencrypted, so the
+    /// receiver gets the same length
+    fn clear_len(self, frame: &[u8]) -> usize {
+        let Some(&first) = frame.first() else {
+            return 0;
+        };
+
+        match self {
+            // toc byte
+            FrameCodec::Opus => 1,
+
+            // rfc 6386 section 9.1, keyframes also have the dimensions
+            FrameCodec::Vp8 if first & 0x01 == 0 => 10,
+            FrameCodec::Vp8 => 3,
+
+            // the uncompressed header up to the dimensions fits in 10 bytes
+            FrameCodec::Vp9 if vp9_is_keyframe(first) => 10,
+            FrameCodec::Vp9 => 1,
+
+            FrameCodec::Other => 0,
+        }
+    }
+}
+
+/// check the frame type in the first byte of a vp9 frame
+fn vp9_is_keyframe(first: u8) -> bool {
+    let profile = ((first >> 5) & 1) | ((first >> 3) & 2);
+    // profile 3 has an extra reserved bit
+    let shift = if profile == 3 { 1 } else { 0 };
+    let show_existing_frame = (first >> (3 - shift)) & 1;
+    let frame_type = (first >> (2 - shift)) & 1;
+    show_existing_frame == 0 && frame_type == 0
+}
+
+/// a derived sframe key
+struct SframeKey {
+    cipher: Aes128Gcm,
+    salt: [u8; NONCE_LEN],
+}
+
+impl SframeKey {
+    /// rfc 9605 section 4.4.2
+    fn derive(base_key: &[u8], kid: u64) -> Self {
+        let hk = Hkdf::<Sha256>::new(None, base_key);
+
+        let mut key = [0; KEY_LEN];
+        hk.expand(&key_label(b"SFrame 1.0 Secret key ", kid), &mut key)
+            .expect("key length is valid");
+
+        let mut salt = [0; NONCE_LEN];
+        hk.expand(&key_label(b"SFrame 1.0 Secret salt ", kid), &mut salt)
+            .expect("salt length is valid");
+
+        Self {
+            cipher: Aes128Gcm::new_from_slice(&key).expect("key length is valid"),
+            salt,
+        }
+    }
+
+    fn nonce(&self, ctr: u64) -> [u8; NONCE_LEN] {
+        let mut nonce = self.salt;
+        for (n, c) in nonce[NONCE_LEN - 8..].iter_mut().zip(ctr.to_be_bytes()) {
+            *n ^= c;
+        }
+        nonce
+    }
+}
+
+/// a key for receiving from one sender
+struct RecvKey {
+    key: SframeKey,
+    replay: ReplayWindow,
+}
+
+/// tracks which counters have been received, so frames can't be replayed
+#[derive(Default)]
+struct ReplayWindow {
+    /// the newest counter received
+    newest: Option<u64>,
+
+    /// bit n is set if `newest - n` was received
+    seen: u128,
+}
+
+impl ReplayWindow {
+    /// whether a frame with this counter hasn't been received yet
+    fn check(&self, ctr: u64) -> bool {
+        let Some(newest) = self.newest else {
+            return true;
+        };
+        match newest.checked_sub(ctr) {
+            // ahead of the newest counter
+            None => true,
+            Some(behind) if behind < REPLAY_WINDOW => self.seen & (1 << behind) == 0,
+            Some(_) => false,
+        }
+    }
+
+    /// mark a counter as received, after the frame was authenticated
+    fn update(&mut self, ctr: u64) {
+        let Some(newest) = self.newest else {
+            self.newest = Some(ctr);
+            self.seen = 1;
+            return;
+        };
+        match newest.checked_sub(ctr) {
+            Some(behind) if behind < REPLAY_WINDOW => self.seen |= 1 << behind,
+            Some(_) => {}
+            None => {
+                let ahead = ctr - newest;
+                self.seen = if ahead < REPLAY_WINDOW {
+                    (self.seen << ahead) | 1
+                } else {
+                    1
+                };
+                self.newest = Some(ctr);
+            }
+        }
+    }
+}
+
+fn key_label(prefix: &[u8], kid: u64) -> Vec<u8> {
+    let mut label = Vec::with_capacity(prefix.len() + 10);
+    label.extend_from_slice(prefix);
+    label.extend_from_slice(&kid.to_be_bytes());
+    label.extend_from_slice(&CIPHER_SUITE.to_be_bytes());
+    label
+}
+
+/// expand an epoch secret into the base key for one sender
+fn base_key(epoch_secret: &[u8], sender: u32) -> Result<[u8; SECRET_LEN]> {
+    let hk = Hkdf::<Sha256>::from_prk(epoch_secret).map_err(|_| Error::InvalidVoiceKey)?;
+    let mut key = [0; SECRET_LEN];
+    hk.expand(&sender.to_be_bytes(), &mut key)
+        .expect("key length is valid");
+    Ok(key)
+}
+
+fn key_id(sender: u32, epoch: MlsEpoch) -> u64 {
+    ((sender as u64) << EPOCH_BITS) | (epoch.0 & EPOCH_MASK)
+}
+
+/// write an sframe header (rfc 9605 section 4.3)
+pub(crate) fn encode_header(buf: &mut Vec<u8>, kid: u64, ctr: u64) {
+    let start = buf.len();
+    buf.push(0);
+
+    let mut config = 0;
+    if kid < 8 {
+        config |= (kid as u8) << 4;
+    } else {
+        let bytes = min_be_bytes(kid);
+        config |= 0x80 | ((bytes.len() as u8 - 1) << 4);
+        buf.extend_from_slice(&bytes);
+    }
+
+    if ctr < 8 {
+        config |= ctr as u8;
+    } else {
+        let bytes = min_be_bytes(ctr);
+        config |= 0x08 | (bytes.len() as u8 - 1);
+        buf.extend_from_slice(&bytes);
+    }
+
+    buf[start] = config;
+}
+
+/// big endian bytes without leading zeros
+fn min_be_bytes(n: u64) -> Vec<u8> {
+    let skip = (n.leading_zeros() / 8) as usize;
+    n.to_be_bytes()[skip..].to_vec()
+}
+
+/// parse an sframe header, returning the key id, counter, and header length
+pub(crate) fn decode_header(data: &[u8]) -> Option<(u64, u64, usize)> {
+    let config = *data.first()?;
+    let mut pos = 1;
+
+    let mut read = |len: usize| -> Option<u64> {
+        let bytes = data.get(pos..pos + len)?;
+        pos += len;
+        Some(bytes.iter().fold(0, |n, &b| (n << 8) | b as u64))
+    };
+
+    let kid = if config & 0x80 == 0 {
+        ((config >> 4) & 0x07) as u64
+    } else {
+        read(((config >> 4) & 0x07) as usize + 1)?
+    };
+
+    let ctr = if config & 0x08 == 0 {
+        (config & 0x07) as u64
+    } else {
+        read((config & 0x07) as usize + 1)?
+    };
+
+    Some((kid, ctr, pos))
+}
+
+/// encrypts and decrypts media frames for a call
+///
+/// create one from the channel's mls group with
+/// [`EncryptionChannel::voice_cryptor`](crate::EncryptionChannel::voice_cryptor)
+/// and call `set_epoch` after every commit.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct FrameCryptor {
+    /// our leaf index in the mls group
+    sender: u32,
+
+    /// secrets for the most recent epochs, newest last
+    epochs: VecDeque<(MlsEpoch, Vec<u8>)>,
+
+    /// the key id and key used for sending
+    send: Option<(u64, SframeKey)>,
+
+    /// the counter for the next frame we send
+    ///
+    /// the key only depends on the epoch and sender, so this starts at a random
+    /// value to avoid reusing nonces if we rejoin during the same epoch
+    counter: u64,
+
+    /// keys for receiving, by key id
+    ///
+    /// keys are only cached after a frame decrypts, so garbage key ids can't fill this
+    recv: HashMap<u64, RecvKey>,
+}
+
+impl FrameCryptor {
+    /// create a new cryptor without any keys
+    pub fn new(sender: u32) -> Self {
+        Self {
+            sender,
+            epochs: VecDeque::new(),
+            send: None,
+            counter: 0,
+            recv: HashMap::new(),
+        }
+    }
+
+    /// start sending with a new epoch's secret
+    ///
+    /// keys from a few previous epochs are kept for receiving
+    pub fn set_epoch(&mut self, epoch: MlsEpoch, secret: &[u8]) -> Result<()> {
+        if self.epochs.iter().any(|(e, _)| *e == epoch) {
+            return Ok(());
+        }
+
+        let kid = key_id(self.sender, epoch);
+        let send = SframeKey::derive(&base_key(secret, self.sender)?, kid);
+
+        self.epochs.push_back((epoch, secret.to_vec()));
+        while self.epochs.len() > MAX_EPOCHS {
+            self.epochs.pop_front();
+        }
+
+        // epoch bits wrap around, so drop any keys that might now be ambiguous
+        let epochs = &self.epochs;
+        self.recv.retain(|kid, _| {
+            kid & EPOCH_MASK != epoch.0 & EPOCH_MASK
+                && epochs
+                    .iter()
+                    .any(|(e, _)| e.0 & EPOCH_MASK == kid & EPOCH_MASK)
+        });
+
+        self.send = Some((kid, send));
+        self.counter = rand::random();
+        Ok(())
+    }
+
+    /// encrypt a frame
+    pub fn encrypt(&mut self, codec: FrameCodec, frame: &[u8]) -> Result<Vec<u8>> {
+        let (kid, key) = self.send.as_ref().ok_or(Error::MissingVoiceKey)?;
+        let clear = codec.clear_len(frame);
+        if frame.len() < clear {
+            return Err(Error::Frame);
+        }
+
+        let ctr = self.counter;
+        self.counter = self.counter.wrapping_add(1);
+
+        let mut out = Vec::with_capacity(frame.len() + 32);
+        out.extend_from_slice(&frame[..clear]);
+        encode_header(&mut out, *kid, ctr);
+
+        // the clear bytes are authenticated along with the header
+        let nonce = key.nonce(ctr);
+        let ciphertext = key
+            .cipher
+            .encrypt(
+                (&nonce).into(),
+                Payload {
+                    msg: &frame[clear..],
+                    aad: &out,
+                },
+            )
+            .map_err(|_| Error::Frame)?;
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    /// decrypt a frame
+    pub fn decrypt(&mut self, codec: FrameCodec, frame: &[u8]) -> Result<Vec<u8>> {
+        let clear = codec.clear_len(frame);
+        let (kid, ctr, header_len) = frame
+            .get(clear..)
+            .and_then(decode_header)
+            .ok_or(Error::Frame)?;
+        let aad_len = clear + header_len;
+
+        let mut derived = None;
+        let key = match self.recv.get(&kid) {
+            Some(recv) if !recv.replay.check(ctr) => return Err(Error::ReplayedFrame),
+            Some(recv) => &recv.key,
+            None => derived.insert(self.derive_recv(kid)?),
+        };
+
+        let nonce = key.nonce(ctr);
+        let plaintext = key
+            .cipher
+            .decrypt(
+                (&nonce).into(),
+                Payload {
+                    msg: &frame[aad_len..],
+                    aad: &frame[..aad_len],
+                },
+            )
+            .map_err(|_| Error::Frame)?;
+
+        if let Some(key) = derived {
+            if self.recv.len() >= MAX_RECV_KEYS {
+                // keys are cheap to derive again, so evicting any of them is fine
+                let evict = *self.recv.keys().next().expect("map is full");
+                self.recv.remove(&evict);
+            }
+            self.recv.insert(
+                kid,
+                RecvKey {
+                    key,
+                    replay: ReplayWindow::default(),
+                },
+            );
+        }
+        self.recv
+            .get_mut(&kid)
+            .expect("key was just inserted")
+            .replay
+            .update(ctr);
+
+        let mut out = Vec::with_capacity(clear + plaintext.len());
+        out.extend_from_slice(&frame[..clear]);
+        out.extend_from_slice(&plaintext);
+        Ok(out)
+    }
+
+    /// derive the key for receiving frames with a key id
+    fn derive_recv(&self, kid: u64) -> Result<SframeKey> {
+        let (_, secret) = self
+            .epochs
+            .iter()
+            .rev()
+            .find(|(e, _)| e.0 & EPOCH_MASK == kid & EPOCH_MASK)
+            .ok_or(Error::MissingVoiceKey)?;
+        let sender = u32::try_from(kid >> EPOCH_BITS).map_err(|_| Error::Frame)?;
+        Ok(SframeKey::derive(&base_key(secret, sender)?, kid))
+    }
+}
@@ -1 +1,41 @@
-// TODO
+use lamprey::v1::types::e2ee::MlsEpoch;
+
+use crate::{
+    prelude::*,
+    voice::{FrameCodec, FrameCryptor},
+};
+
+#[wasm_bindgen]
+impl FrameCryptor {
+    /// create a new cryptor without any keys
+    #[wasm_bindgen(constructor)]
+    pub fn js_new(sender: u32) -> FrameCryptor {
+        FrameCryptor::new(sender)
+    }
+
+    /// start sending with a new epoch's secret
+    #[wasm_bindgen(js_name = "setEpoch")]
+    pub fn js_set_epoch(&mut self, epoch: u64, secret: &[u8]) -> core::result::Result<(), JsError> {
+        Ok(self.set_epoch(MlsEpoch(epoch), secret)?)
+    }
+
+    /// encrypt a frame, eg. from an RTCRtpScriptTransform
+    #[wasm_bindgen(js_name = "encrypt")]
+    pub fn js_encrypt(
+        &mut self,
+        codec: FrameCodec,
+        frame: &[u8],
+    ) -> core::result::Result<Vec<u8>, JsError> {
+        Ok(self.encrypt(codec, frame)?)
+    }
+
+    /// decrypt a frame
+    #[wasm_bindgen(js_name = "decrypt")]
+    pub fn js_decrypt(
+        &mut self,
+        codec: FrameCodec,
+        frame: &[u8],
+    ) -> core::result::Result<Vec<u8>, JsError> {
+        Ok(self.decrypt(codec, frame)?)
+    }
+}
@@ -91,3 +91,44 @@ where
     };
     Ok((media.into(), status))
 }
+
+/// whether a piece of media is linked to something in a private channel
+///
+/// messages, message versions, and documents belong to a channel. avatars, banners,
+/// icons, and emoji are referenced by id alone so can't be signed, and embeds are
+/// copies of public web pages, so these are never private.
+///
+/// this should match `ServiceChannels::is_private` in the api
+pub async fn lookup_media_private<'e, E>(exec: E, media_id: MediaId) -> Result<bool>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let private = query_scalar!(
+        r#"
+        SELECT EXISTS(
+            SELECT 1 FROM media_link l
+            LEFT JOIN message_version v
+              ON l.link_type = 'MessageVersion' AND v.version_id = l.target_id
+            LEFT JOIN message m
+              ON l.link_type IN ('Message', 'MessageVersion')
+              AND m.id = COALESCE(v.message_id, l.target_id)
+            JOIN channel c ON c.id = CASE
+                WHEN l.link_type = 'Document' THEN l.target_id
+                ELSE m.channel_id
+            END
+            LEFT JOIN room r ON r.id = c.room_id
+            WHERE l.media_id = $1
+              AND l.link_type IN ('Message', 'MessageVersion', 'Document')
+              AND (
+                c.room_id IS NULL
+                OR c.type IN ('Dm', 'Gdm', 'ThreadPrivate')
+                OR NOT r.public
+              )
+        ) AS "private!"
+        "#,
+        *media_id
+    )
+    .fetch_one(exec)
+    .await?;
+    Ok(private)
+}
@@ -41,6 +41,15 @@ pub enum Error {
     #[error("media is still processing")]
     StillProcessing,
 
+    #[error("invalid signature")]
+    BadSignature,
+
+    #[error("signature expired")]
+    SignatureExpired,
+
+    #[error("too many variants for this media")]
+    TooManyVariants,
+
     #[error("internal error: {0}")]
     Internal(String),
 
@@ -66,6 +75,9 @@ pub enum ErrorCode {
     Tempfile,
     AsyncTempfile,
     StillProcessing,
+    BadSignature,
+    SignatureExpired,
+    TooManyVariants,
     Internal,
     OtelBuild,
     LogFilterParse,
@@ -93,6 +105,9 @@ impl Error {
             Error::Tempfile(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::AsyncTempfile(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::StillProcessing => StatusCode::CONFLICT,
+            Error::BadSignature => StatusCode::FORBIDDEN,
+            Error::SignatureExpired => StatusCode::FORBIDDEN,
+            Error::TooManyVariants => StatusCode::FORBIDDEN,
             Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::OtelBuild(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::LogFilterParse(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -112,6 +127,9 @@ impl Error {
             Error::Tempfile(_) => ErrorCode::Tempfile,
             Error::AsyncTempfile(_) => ErrorCode::AsyncTempfile,
             Error::StillProcessing => ErrorCode::StillProcessing,
+            Error::BadSignature => ErrorCode::BadSignature,
+            Error::SignatureExpired => ErrorCode::SignatureExpired,
+            Error::TooManyVariants => ErrorCode::TooManyVariants,
             Error::Internal(_) => ErrorCode::Internal,
             Error::OtelBuild(_) => ErrorCode::OtelBuild,
             Error::LogFilterParse(_) => ErrorCode::LogFilterParse,
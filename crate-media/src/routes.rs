@@ -7,6 +7,7 @@ mod gifv;
 mod media;
 mod stream;
 mod thumb;
+mod transform;
 mod trickplay;
 mod util;
 
@@ -17,5 +18,7 @@ pub fn routes() -> OpenApiRouter<AppState> {
         .merge(media::routes())
         .merge(stream::routes())
         .merge(thumb::routes())
+        .merge(transform::routes())
         .merge(trickplay::routes())
+        .layer(axum::middleware::from_fn(util::signed_cache_control))
 }
@@ -4,7 +4,7 @@ use axum::{
 };
 use common::{
     v1::types::EmojiId,
-    v2::types::media::proxy::{MediaQuery, ThumbQuery},
+    v2::types::media::proxy::{MediaQuery, MediaSignedQuery, ThumbQuery},
 };
 use http::HeaderMap;
 use utoipa_axum::router::OpenApiRouter;
@@ -33,6 +33,7 @@ pub async fn get_emoji(
         Path(media_id),
         Query(query),
         Query(media_query),
+        Query(MediaSignedQuery::default()),
         headers,
     )
     .await
@@ -55,6 +56,7 @@ pub async fn head_emoji(
         Path(media_id),
         Query(query),
         Query(media_query),
+        Query(MediaSignedQuery::default()),
         headers,
     )
     .await
@@ -6,8 +6,9 @@ use axum::{
     extract::{Path, Query, State},
 };
 use common::v1::types::MediaId;
-use common::v2::types::media::proxy::MediaQuery;
+use common::v2::types::media::proxy::{MediaQuery, MediaSignedQuery};
 use http::{HeaderMap, StatusCode};
+use lamprey_backend_core::types::media::MediaVariant;
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
@@ -18,7 +19,7 @@ use utoipa_axum::{router::OpenApiRouter, routes};
 use crate::{
     AppState,
     error::{Error, Result},
-    routes::util::{ContentInfo, build_headers},
+    routes::util::{ContentInfo, build_headers, ensure_signed},
 };
 
 async fn gifv_response(
@@ -29,6 +30,7 @@ async fn gifv_response(
     with_body: bool,
 ) -> Result<(http::StatusCode, HeaderMap, Body)> {
     let media = s.ensure_media_ready(media_id, query.wait).await?;
+    let storage_id = media.storage_id();
     if &*media.content_type != "image/gif" {
         return Err(Error::BadRequest);
     }
@@ -49,7 +51,7 @@ async fn gifv_response(
         ));
     }
 
-    let gifv_path = s.media_paths.gifv(media_id);
+    let gifv_path = s.media_paths.gifv(storage_id);
 
     if s.blobs.exists(&gifv_path).await? {
         let meta = s.blobs.stat(&gifv_path).await?;
@@ -84,8 +86,8 @@ async fn gifv_response(
 
     let temp_file = s
         .pending_gifv
-        .try_get_with(media_id, async {
-            let source_path = s.media_paths.file(media_id);
+        .try_get_with(storage_id, async {
+            let source_path = s.media_paths.file(storage_id);
             let temp_in = TempFile::new().await?;
             let temp_out = TempFile::new().await?;
             let reader = s.blobs.reader(&source_path).await?;
@@ -170,8 +172,10 @@ pub async fn get_gifv(
     State(s): State<AppState>,
     Path(media_id): Path<MediaId>,
     Query(query): Query<MediaQuery>,
+    Query(signed): Query<MediaSignedQuery>,
     headers: HeaderMap,
 ) -> Result<(http::StatusCode, HeaderMap, Body)> {
+    ensure_signed(&s, media_id, MediaVariant::Gifv, &signed).await?;
     gifv_response(s, media_id, query, headers, true).await
 }
 
@@ -183,8 +187,10 @@ pub async fn head_gifv(
     State(s): State<AppState>,
     Path(media_id): Path<MediaId>,
     Query(query): Query<MediaQuery>,
+    Query(signed): Query<MediaSignedQuery>,
     headers: HeaderMap,
 ) -> Result<(http::StatusCode, HeaderMap, Body)> {
+    ensure_signed(&s, media_id, MediaVariant::Gifv, &signed).await?;
     gifv_response(s, media_id, query, headers, false).await
 }
 
@@ -3,26 +3,25 @@ use axum::{
     extract::{Path, Query, State},
 };
 use common::v1::types::MediaId;
-use common::v2::types::media::proxy::MediaQuery;
+use common::v2::types::media::proxy::{MediaQuery, MediaSignedQuery};
 use http::{HeaderMap, StatusCode};
+use lamprey_backend_core::types::media::MediaVariant;
 use utoipa_axum::router::OpenApiRouter;
 use utoipa_axum::routes;
 
 use crate::{
     AppState,
     error::{Error, Result},
-    routes::util::{ContentInfo, build_headers},
+    routes::util::{ContentInfo, build_headers, ensure_signed},
 };
 
-/// Head media
-///
-/// get headers for a piece of media
-#[utoipa::path(head, path = "/media/{media_id}")]
-pub async fn head_media(
-    State(s): State<AppState>,
-    Path(media_id): Path<MediaId>,
-    Query(query): Query<MediaQuery>,
+/// serve a piece of media, without checking signatures
+pub(super) async fn media_response(
+    s: AppState,
+    media_id: MediaId,
+    query: MediaQuery,
     headers: HeaderMap,
+    with_body: bool,
 ) -> Result<(http::StatusCode, HeaderMap, Body)> {
     let media = s.ensure_media_ready(media_id, query.wait).await?;
     let header_info = build_headers(&headers, &ContentInfo::Media(&media))?;
@@ -37,7 +36,35 @@ pub async fn head_media(
         StatusCode::OK
     };
 
-    Ok((status, header_info.headers, Body::empty()))
+    if !with_body {
+        return Ok((status, header_info.headers, Body::empty()));
+    }
+
+    let reader = s
+        .blobs
+        .reader(&s.media_paths.file(media.storage_id()))
+        .await?;
+    let body = if let Some(r) = header_info.range {
+        Body::from_stream(reader.into_bytes_stream(r).await?)
+    } else {
+        Body::from_stream(reader.into_bytes_stream(..).await?)
+    };
+    Ok((status, header_info.headers, body))
+}
+
+/// Head media
+///
+/// get headers for a piece of media
+#[utoipa::path(head, path = "/media/{media_id}")]
+pub async fn head_media(
+    State(s): State<AppState>,
+    Path(media_id): Path<MediaId>,
+    Query(query): Query<MediaQuery>,
+    Query(signed): Query<MediaSignedQuery>,
+    headers: HeaderMap,
+) -> Result<(http::StatusCode, HeaderMap, Body)> {
+    ensure_signed(&s, media_id, MediaVariant::Media, &signed).await?;
+    media_response(s, media_id, query, headers, false).await
 }
 
 /// Head media with filename
@@ -48,8 +75,10 @@ pub async fn head_media_filename(
     State(s): State<AppState>,
     Path((media_id, filename)): Path<(MediaId, String)>,
     Query(query): Query<MediaQuery>,
+    Query(signed): Query<MediaSignedQuery>,
     headers: HeaderMap,
 ) -> Result<(http::StatusCode, HeaderMap, Body)> {
+    ensure_signed(&s, media_id, MediaVariant::Media, &signed).await?;
     let media = s.ensure_media_ready(media_id, query.wait).await?;
     if media.filename != filename {
         return Err(Error::NotFound);
@@ -78,25 +107,11 @@ pub async fn get_media(
     State(s): State<AppState>,
     Path(media_id): Path<MediaId>,
     Query(query): Query<MediaQuery>,
+    Query(signed): Query<MediaSignedQuery>,
     headers: HeaderMap,
 ) -> Result<(http::StatusCode, HeaderMap, Body)> {
-    let media = s.ensure_media_ready(media_id, query.wait).await?;
-    let path = s.media_paths.file(media_id);
-
-    let header_info = build_headers(&headers, &ContentInfo::Media(&media))?;
-
-    if header_info.unmodified {
-        return Ok((StatusCode::NOT_MODIFIED, header_info.headers, Body::empty()));
-    }
-
-    let reader = s.blobs.reader(&path).await?;
-    if let Some(r) = header_info.range {
-        let body = Body::from_stream(reader.into_bytes_stream(r).await?);
-        Ok((StatusCode::PARTIAL_CONTENT, header_info.headers, body))
-    } else {
-        let body = Body::from_stream(reader.into_bytes_stream(..).await?);
-        Ok((StatusCode::OK, header_info.headers, body))
-    }
+    ensure_signed(&s, media_id, MediaVariant::Media, &signed).await?;
+    media_response(s, media_id, query, headers, true).await
 }
 
 /// Fetch media with filename
@@ -107,10 +122,12 @@ pub async fn get_media_filename(
     State(s): State<AppState>,
     Path((media_id, filename)): Path<(MediaId, String)>,
     Query(query): Query<MediaQuery>,
+    Query(signed): Query<MediaSignedQuery>,
     headers: HeaderMap,
 ) -> Result<(http::StatusCode, HeaderMap, Body)> {
+    ensure_signed(&s, media_id, MediaVariant::Media, &signed).await?;
     let media = s.ensure_media_ready(media_id, query.wait).await?;
-    let path = s.media_paths.file(media_id);
+    let path = s.media_paths.file(media.storage_id());
 
     if media.filename != filename {
         return Err(Error::NotFound);
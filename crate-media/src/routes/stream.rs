@@ -1,39 +1,363 @@
+use std::{fmt::Write as _, path::Path as FsPath};
+
+use async_tempfile::{TempDir, TempFile};
 use axum::{
+    body::Body,
     extract::{Path, Query, State},
-    response::IntoResponse,
 };
 use common::{
     v1::types::MediaId,
-    v2::types::media::proxy::{MediaQuery, StreamQuery},
+    v2::types::media::{
+        Media, MediaMetadata,
+        proxy::{MediaQuery, MediaSignedQuery, StreamFormat, StreamKind, StreamQuery},
+    },
+};
+use futures_util::StreamExt;
+use http::{HeaderMap, StatusCode};
+use lamprey_backend_core::types::media::MediaVariant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    AppState,
+    error::{Error, Result},
+    routes::util::{ContentInfo, build_headers, ensure_signed, signed_suffix},
 };
-use http::StatusCode;
-use utoipa_axum::router::OpenApiRouter;
-use utoipa_axum::routes;
 
-use crate::{AppState, error::Result};
+/// bitrate used for audio, both for audio-only streams and video soundtracks
+const AUDIO_BITRATE: u64 = 128_000;
+
+/// list the streams that can be generated for a piece of media
+///
+/// video streams are identified by their height so ids stay stable if the
+/// configured heights change
+fn stream_formats(media: &Media, heights: &[u32]) -> Result<Vec<StreamFormat>> {
+    match media.metadata {
+        MediaMetadata::Video { width, height, .. } if width > 0 && height > 0 => {
+            let mut heights: Vec<u64> = heights
+                .iter()
+                .map(|h| *h as u64)
+                .filter(|h| *h <= height)
+                .collect();
+            heights.sort_unstable();
+            heights.dedup();
+
+            // never upscale, but always have at least one stream
+            if heights.is_empty() {
+                heights.push(height);
+            }
+
+            Ok(heights
+                .into_iter()
+                .map(|h| StreamFormat {
+                    id: h,
+                    kind: StreamKind::Video,
+                    codec: "h264".to_owned(),
+                    // x264 needs both dimensions to be even
+                    width: Some((width * h / height).max(2) & !1),
+                    height: Some(h.max(2) & !1),
+                    framerate: None,
+                    bitrate: None,
+                    channels: None,
+                })
+                .collect())
+        }
+        // older audio was stored as video without any dimensions
+        MediaMetadata::Audio { .. } | MediaMetadata::Video { .. } => Ok(vec![StreamFormat {
+            id: 0,
+            kind: StreamKind::Audio,
+            codec: "aac".to_owned(),
+            width: None,
+            height: None,
+            framerate: None,
+            bitrate: Some(AUDIO_BITRATE),
+            channels: Some(2),
+        }]),
+        _ => Err(Error::BadRequest),
+    }
+}
+
+/// the target bitrate for a stream
+fn stream_bitrate(format: &StreamFormat) -> u64 {
+    match format.kind {
+        // roughly 3 bits per pixel per second, ie. 2.7Mbps at 720p
+        StreamKind::Video => format.width.unwrap_or(0) * format.height.unwrap_or(0) * 3,
+        StreamKind::Audio => format.bitrate.unwrap_or(AUDIO_BITRATE),
+    }
+}
+
+/// generate the master playlist listing every stream
+///
+/// `suffix` is appended to each stream's url, see [`signed_suffix`]
+fn master_playlist(formats: &[StreamFormat], suffix: &str) -> String {
+    let mut out = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    for format in formats {
+        match format.kind {
+            StreamKind::Video => {
+                let bandwidth = stream_bitrate(format) + AUDIO_BITRATE;
+                let _ = writeln!(
+                    out,
+                    "#EXT-X-STREAM-INF:BANDWIDTH={bandwidth},RESOLUTION={}x{}",
+                    format.width.unwrap_or(0),
+                    format.height.unwrap_or(0),
+                );
+            }
+            StreamKind::Audio => {
+                let _ = writeln!(
+                    out,
+                    "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"mp4a.40.2\"",
+                    stream_bitrate(format),
+                );
+            }
+        }
+        let _ = writeln!(out, "?s={}{suffix}", format.id);
+    }
+    out
+}
+
+/// append `suffix` to every segment url in a stream's playlist
+fn sign_playlist(playlist: &str, suffix: &str) -> String {
+    let mut out = String::with_capacity(playlist.len());
+    for line in playlist.lines() {
+        out.push_str(line);
+        if line.starts_with('?') {
+            out.push_str(suffix);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// transcode a stream and upload its playlist and segments, if it doesn't already exist
+async fn ensure_stream(s: &AppState, media_id: MediaId, format: &StreamFormat) -> Result<()> {
+    let playlist_path = s.media_paths.stream_playlist(media_id, format.id);
+    if s.blobs.exists(&playlist_path).await? {
+        return Ok(());
+    }
+
+    s.pending_streams
+        .try_get_with((media_id, format.id), async {
+            let source_path = s.media_paths.file(media_id);
+            let temp_in = TempFile::new().await?;
+            let temp_out = TempDir::new().await?;
+            let reader = s.blobs.reader(&source_path).await?;
+            let mut writer = temp_in.open_rw().await?;
+            let mut bytes_reader = reader.into_bytes_stream(..).await?;
+            while let Some(chunk) = bytes_reader.next().await {
+                writer.write_all(&chunk?).await?;
+            }
+            writer.flush().await?;
+
+            s.ffmpeg
+                .transcode_to_hls(
+                    temp_in.file_path(),
+                    temp_out.dir_path(),
+                    format,
+                    stream_bitrate(format),
+                    &format!("?s={}&n=", format.id),
+                )
+                .await?;
+
+            let mut segments = tokio::fs::read_dir(temp_out.dir_path()).await?;
+            while let Some(entry) = segments.next_entry().await? {
+                let Some(n) = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|n| n.parse::<usize>().ok())
+                else {
+                    continue;
+                };
+                let segment_path = s.media_paths.stream_segment(media_id, format.id, n);
+                upload_file(s, &segment_path, &entry.path()).await?;
+            }
+
+            // upload the playlist last so its existence means every segment is available
+            upload_file(s, &playlist_path, &temp_out.dir_path().join("index.m3u8")).await?;
+
+            Ok::<_, Error>(())
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// upload a local file to blob storage without reading all of it into memory
+async fn upload_file(s: &AppState, path: &str, local_path: &FsPath) -> Result<()> {
+    let mut file = tokio::fs::File::open(local_path).await?;
+    let mut writer = s.blobs.writer(path).await?;
+    let mut buf = vec![0; 1024 * 64];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        writer.write(buf[..n].to_vec()).await?;
+    }
+    writer.close().await?;
+    Ok(())
+}
+
+async fn stream_response(
+    s: AppState,
+    media_id: MediaId,
+    query: StreamQuery,
+    media_query: MediaQuery,
+    signed: &MediaSignedQuery,
+    headers: HeaderMap,
+    with_body: bool,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    let media = s.ensure_media_ready(media_id, media_query.wait).await?;
+    let storage_id = media.storage_id();
+    let formats = stream_formats(&media, &s.config_media().stream_heights)?;
+    let playlist = query.n.is_none();
+
+    let pre_header_info = build_headers(
+        &headers,
+        &ContentInfo::Stream {
+            media: &media,
+            content_length: None,
+            playlist,
+        },
+    )?;
+
+    if pre_header_info.unmodified {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            pre_header_info.headers,
+            Body::empty(),
+        ));
+    }
 
-/// Fetch stream (TODO)
+    // playlists are generated for each request so their urls can carry the request's signature
+    let suffix = signed_suffix(signed);
+    let path = match (query.s, query.n) {
+        (None, None) => {
+            let data = master_playlist(&formats, &suffix);
+            return playlist_response(&media, data, &headers, with_body);
+        }
+        (None, Some(_)) => return Err(Error::BadRequest),
+        (Some(stream_id), n) => {
+            let format = formats
+                .iter()
+                .find(|f| f.id == stream_id)
+                .ok_or(Error::NotFound)?;
+            ensure_stream(&s, storage_id, format).await?;
+            match n {
+                Some(n) => {
+                    let path = s.media_paths.stream_segment(storage_id, stream_id, n);
+                    if !s.blobs.exists(&path).await? {
+                        return Err(Error::NotFound);
+                    }
+                    path
+                }
+                None => {
+                    let path = s.media_paths.stream_playlist(storage_id, stream_id);
+                    let data = s.blobs.read(&path).await?.to_vec();
+                    let data = sign_playlist(&String::from_utf8_lossy(&data), &suffix);
+                    return playlist_response(&media, data, &headers, with_body);
+                }
+            }
+        }
+    };
+
+    let meta = s.blobs.stat(&path).await?;
+    let final_headers = build_headers(
+        &headers,
+        &ContentInfo::Stream {
+            media: &media,
+            content_length: Some(meta.content_length()),
+            playlist,
+        },
+    )?;
+
+    let status = if final_headers.range.is_some() {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    };
+
+    let body = if with_body {
+        let reader = s.blobs.reader(&path).await?;
+        if let Some(r) = final_headers.range {
+            Body::from_stream(reader.into_bytes_stream(r).await?)
+        } else {
+            Body::from_stream(reader.into_bytes_stream(..).await?)
+        }
+    } else {
+        Body::empty()
+    };
+
+    Ok((status, final_headers.headers, body))
+}
+
+/// respond with a generated playlist
+fn playlist_response(
+    media: &Media,
+    data: String,
+    headers: &HeaderMap,
+    with_body: bool,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    let data = data.into_bytes();
+    let final_headers = build_headers(
+        headers,
+        &ContentInfo::Stream {
+            media,
+            content_length: Some(data.len() as u64),
+            playlist: true,
+        },
+    )?;
+
+    let (status, body) = match final_headers.range {
+        Some(range) => {
+            let data = data
+                .get((range.0.map(|b| b as usize), range.1.map(|b| b as usize)))
+                .ok_or(Error::BadRange)?;
+            (StatusCode::PARTIAL_CONTENT, data.to_vec())
+        }
+        None => (StatusCode::OK, data),
+    };
+
+    let body = if with_body {
+        Body::from(body)
+    } else {
+        Body::empty()
+    };
+
+    Ok((status, final_headers.headers, body))
+}
+
+/// Fetch stream
+///
+/// adaptive hls streaming for video and audio. without `s`, returns the master
+/// playlist. with `s` but not `n`, returns the playlist for that stream.
+/// with both, returns a single segment. streams are transcoded on first request.
 #[utoipa::path(get, path = "/stream/{media_id}")]
 async fn get_stream(
     State(s): State<AppState>,
     Path(media_id): Path<MediaId>,
-    Query(_query): Query<StreamQuery>,
+    Query(query): Query<StreamQuery>,
     Query(media_query): Query<MediaQuery>,
-) -> Result<impl IntoResponse> {
-    s.ensure_media_ready(media_id, media_query.wait).await?;
-    Ok((StatusCode::NOT_IMPLEMENTED, "doesn't exist yet :("))
+    Query(signed): Query<MediaSignedQuery>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    ensure_signed(&s, media_id, MediaVariant::Stream, &signed).await?;
+    stream_response(s, media_id, query, media_query, &signed, headers, true).await
 }
 
-/// Head stream (TODO)
+/// Head stream
+///
+/// get headers for a stream playlist or segment
 #[utoipa::path(head, path = "/stream/{media_id}")]
 async fn head_stream(
     State(s): State<AppState>,
     Path(media_id): Path<MediaId>,
-    Query(_query): Query<StreamQuery>,
+    Query(query): Query<StreamQuery>,
     Query(media_query): Query<MediaQuery>,
-) -> Result<impl IntoResponse> {
-    s.ensure_media_ready(media_id, media_query.wait).await?;
-    Ok((StatusCode::NOT_IMPLEMENTED, "doesn't exist yet :("))
+    Query(signed): Query<MediaSignedQuery>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    ensure_signed(&s, media_id, MediaVariant::Stream, &signed).await?;
+    stream_response(s, media_id, query, media_query, &signed, headers, false).await
 }
 
 pub fn routes() -> OpenApiRouter<AppState> {
@@ -41,3 +365,147 @@ pub fn routes() -> OpenApiRouter<AppState> {
         .routes(routes!(get_stream))
         .routes(routes!(head_stream))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use http::Uri;
+    use lamprey_backend_core::ffmpeg::Ffmpeg;
+    use moka::future::Cache;
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+    use crate::config::Config;
+
+    fn test_state(data_dir: &FsPath) -> AppState {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "rust_log": "info",
+            "database_url": "postgres://localhost/unused",
+            "api_url": "http://localhost",
+            "cdn_url": "http://localhost",
+            "html_url": "http://localhost",
+            "blobs": { "type": "fs", "data_dir": data_dir },
+            "oauth_provider": {},
+            "smtp": { "username": "", "password": "", "host": "", "from": "" },
+            "media": { "url_key": "secret" },
+        }))
+        .unwrap();
+        let blobs = opendal::Operator::new(
+            opendal::services::Fs::default().root(data_dir.to_str().unwrap()),
+        )
+        .unwrap()
+        .finish();
+        AppState {
+            // never connected to, every lookup is cached below
+            db: PgPoolOptions::new()
+                .connect_lazy(&config.database_url)
+                .unwrap(),
+            blobs,
+            nats: None,
+            ffmpeg: Arc::new(Ffmpeg::from_config(&config)),
+            media_paths: Arc::new(config.media.paths()),
+            config: Arc::new(config),
+            cache_emoji: Cache::new(10),
+            cache_media: Cache::new(10),
+            cache_private: Cache::new(10),
+            pending_thumbnails: Cache::new(0),
+            pending_gifv: Cache::new(10),
+            pending_streams: Cache::new(10),
+            pending_trickplay: Cache::new(10),
+            pending_transforms: Cache::new(0),
+            sushi_tx: tokio::sync::broadcast::channel(1).0,
+        }
+    }
+
+    async fn fetch(s: &AppState, media_id: MediaId, uri: &str) -> Result<String> {
+        let uri: Uri = format!("/stream/{media_id}{uri}").parse().unwrap();
+        let (_, _, body) = get_stream(
+            State(s.clone()),
+            Path(media_id),
+            Query::try_from_uri(&uri).unwrap(),
+            Query::try_from_uri(&uri).unwrap(),
+            Query::try_from_uri(&uri).unwrap(),
+            HeaderMap::new(),
+        )
+        .await?;
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        Ok(String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// the first url in a playlist
+    fn first_url(playlist: &str) -> &str {
+        playlist.lines().find(|l| l.starts_with('?')).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_private_stream_urls_are_signed() {
+        let dir = TempDir::new().await.unwrap();
+        let s = test_state(dir.dir_path());
+        let media_id = MediaId::new();
+        let media: Media = serde_json::from_value(serde_json::json!({
+            "id": media_id,
+            "version_id": media_id,
+            "status": "Uploaded",
+            "filename": "video.mp4",
+            "size": 1,
+            "content_type": "video/mp4",
+            "metadata": { "type": "Video", "width": 1280, "height": 720, "duration": 1000 },
+            "has_thumbnail": false,
+            "has_gifv": false,
+        }))
+        .unwrap();
+        s.cache_media.insert(media_id, media).await;
+        s.cache_private.insert(media_id, true).await;
+
+        let expires = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let sig = MediaVariant::Stream.sign(media_id, expires, b"secret");
+        let signed = format!(
+            "expires={expires}&sig={}",
+            percent_encoding::utf8_percent_encode(&sig, percent_encoding::NON_ALPHANUMERIC)
+        );
+
+        assert!(matches!(
+            fetch(&s, media_id, "").await,
+            Err(Error::BadSignature)
+        ));
+
+        let master = fetch(&s, media_id, &format!("?{signed}")).await.unwrap();
+        let stream_url = first_url(&master).to_owned();
+        assert!(stream_url.ends_with(&signed));
+
+        // pretend the stream was already transcoded
+        let stream_id: u64 = stream_url[3..].split('&').next().unwrap().parse().unwrap();
+        s.blobs
+            .write(
+                &s.media_paths.stream_playlist(media_id, stream_id),
+                format!("#EXTM3U\n#EXTINF:1.0,\n?s={stream_id}&n=0\n#EXT-X-ENDLIST\n"),
+            )
+            .await
+            .unwrap();
+        s.blobs
+            .write(
+                &s.media_paths.stream_segment(media_id, stream_id, 0),
+                "segment",
+            )
+            .await
+            .unwrap();
+
+        let playlist = fetch(&s, media_id, &stream_url).await.unwrap();
+        let segment_url = first_url(&playlist);
+        assert_eq!(segment_url, format!("?s={stream_id}&n=0&{signed}"));
+        assert_eq!(fetch(&s, media_id, segment_url).await.unwrap(), "segment");
+
+        assert!(matches!(
+            fetch(&s, media_id, &format!("?s={stream_id}&n=0")).await,
+            Err(Error::BadSignature)
+        ));
+    }
+}
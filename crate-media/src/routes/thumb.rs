@@ -3,9 +3,10 @@ use axum::{
     extract::{Path, Query, State},
 };
 use common::v1::types::MediaId;
-use common::v2::types::media::proxy::{MediaQuery, ThumbQuery};
+use common::v2::types::media::proxy::{MediaQuery, MediaSignedQuery, ThumbQuery};
 use futures_util::StreamExt;
 use http::{HeaderMap, StatusCode};
+use lamprey_backend_core::types::media::{MediaVariant, ThumbFormat, can_animate_thumb};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::error;
 use utoipa_axum::router::OpenApiRouter;
@@ -15,13 +16,12 @@ use crate::{
     AppState,
     error::{Error, Result},
     routes::{
-        media::{get_media, head_media},
-        util::{ContentInfo, build_headers, probably_can_thumbnail},
+        media::media_response,
+        util::{ContentInfo, build_headers, ensure_signed, probably_can_thumbnail},
     },
 };
 
-// TODO: maybe allow generating png, jpeg, or webp thumbnails?
-// NOTE: caniuse says avif has ~93% support
+// NOTE: the format is negotiated with the Accept header, defaulting to avif (static) and webp (animated)
 // NOTE: this may take up some extra space, should i impl thumbnail garbage collection? nah, probably not worth it
 #[async_recursion::async_recursion]
 async fn thumb_response(
@@ -33,18 +33,26 @@ async fn thumb_response(
     with_body: bool,
 ) -> Result<(http::StatusCode, HeaderMap, Body)> {
     let media = s.ensure_media_ready(media_id, media_query.wait).await?;
-    let animate = query.animate;
+    let storage_id = media.storage_id();
     if let Some(size) = query.size {
         if !s.config_media().thumb_sizes.contains(&size) {
             return Err(Error::BadRequest);
         }
 
+        let accept = headers
+            .get(http::header::ACCEPT)
+            .and_then(|h| h.to_str().ok());
+        let (format, animate) = ThumbFormat::negotiate(
+            accept,
+            query.animate && can_animate_thumb(&media.content_type, &media.metadata),
+        );
+
         let pre_header_info = build_headers(
             &headers,
             &ContentInfo::Thumb {
                 media: &media,
                 content_length: None,
-                animated: animate,
+                format,
             },
         )?;
 
@@ -56,11 +64,10 @@ async fn thumb_response(
             ));
         }
 
-        let ext = if animate { "webp" } else { "avif" };
         let thumb_path = if animate {
-            s.media_paths.thumb(media_id, size, ext)
+            s.media_paths.thumb(storage_id, size, format.ext())
         } else {
-            s.media_paths.thumb_static(media_id, size, ext)
+            s.media_paths.thumb_static(storage_id, size, format.ext())
         };
 
         if s.blobs.exists(&thumb_path).await? {
@@ -71,7 +78,7 @@ async fn thumb_response(
                 &ContentInfo::Thumb {
                     media: &media,
                     content_length: Some(content_length),
-                    animated: animate,
+                    format,
                 },
             )?;
 
@@ -98,12 +105,13 @@ async fn thumb_response(
         let m = media.clone();
         let thumb_data = s
             .pending_thumbnails
-            .try_get_with((media_id, size, size, animate), async move {
-                let poster_path = s.media_paths.poster(media_id);
-                let source_path = if s.blobs.exists(&poster_path).await? {
+            .try_get_with((storage_id, size, size, animate, format), async move {
+                let poster_path = s.media_paths.poster(storage_id);
+                // animated thumbnails are always generated from the file itself
+                let source_path = if !animate && s.blobs.exists(&poster_path).await? {
                     poster_path
                 } else if probably_can_thumbnail(&m) {
-                    s.media_paths.file(media_id)
+                    s.media_paths.file(storage_id)
                 } else {
                     return Err(Error::NotFound);
                 };
@@ -120,7 +128,13 @@ async fn thumb_response(
                 writer.flush().await?;
 
                 s.ffmpeg
-                    .generate_thumbnail(temp_in.file_path(), temp_out.file_path(), size, animate)
+                    .generate_thumbnail(
+                        temp_in.file_path(),
+                        temp_out.file_path(),
+                        size,
+                        format,
+                        animate,
+                    )
                     .await?;
 
                 let mut out_reader = temp_out.open_ro().await?;
@@ -143,7 +157,7 @@ async fn thumb_response(
             &ContentInfo::Thumb {
                 media: &media,
                 content_length: Some(thumb_data.len() as u64),
-                animated: animate,
+                format,
             },
         )?;
 
@@ -180,7 +194,7 @@ async fn thumb_response(
         let is_animated =
             &*media.content_type == "image/gif" || (*media.content_type).starts_with("video/");
 
-        if !animate && is_animated {
+        if !query.animate && is_animated {
             // Force static thumbnail if animate=false is requested for an animated source
             let size = s.config_media().thumb_sizes.first().copied().unwrap_or(128);
             return thumb_response(
@@ -197,7 +211,7 @@ async fn thumb_response(
             .await;
         }
 
-        let poster_path = s.media_paths.poster(media_id);
+        let poster_path = s.media_paths.poster(storage_id);
 
         if s.blobs.exists(&poster_path).await? {
             let meta = s.blobs.stat(&poster_path).await?;
@@ -207,7 +221,10 @@ async fn thumb_response(
                 &ContentInfo::Thumb {
                     media: &media,
                     content_length: Some(content_length),
-                    animated: false,
+                    format: meta
+                        .content_type()
+                        .and_then(ThumbFormat::from_mime)
+                        .unwrap_or(ThumbFormat::Avif),
                 },
             )?;
 
@@ -232,17 +249,8 @@ async fn thumb_response(
         }
 
         if (*media.content_type).starts_with("image/") {
-            if with_body {
-                return get_media(
-                    State(s.clone()),
-                    Path(media_id),
-                    Query(media_query),
-                    headers,
-                )
-                .await;
-            } else {
-                return head_media(State(s), Path(media_id), Query(media_query), headers).await;
-            }
+            // the thumbnail's signature was already checked
+            return media_response(s, media_id, media_query, headers, with_body).await;
         }
 
         Err(Error::NotFound)
@@ -258,8 +266,10 @@ pub async fn get_thumb(
     Path(media_id): Path<MediaId>,
     Query(query): Query<ThumbQuery>,
     Query(media_query): Query<MediaQuery>,
+    Query(signed): Query<MediaSignedQuery>,
     headers: HeaderMap,
 ) -> Result<(http::StatusCode, HeaderMap, Body)> {
+    ensure_signed(&s, media_id, MediaVariant::Thumb, &signed).await?;
     thumb_response(s, media_id, query, media_query, headers, true).await
 }
 
@@ -272,8 +282,10 @@ pub async fn head_thumb(
     Path(media_id): Path<MediaId>,
     Query(query): Query<ThumbQuery>,
     Query(media_query): Query<MediaQuery>,
+    Query(signed): Query<MediaSignedQuery>,
     headers: HeaderMap,
 ) -> Result<(http::StatusCode, HeaderMap, Body)> {
+    ensure_signed(&s, media_id, MediaVariant::Thumb, &signed).await?;
     thumb_response(s, media_id, query, media_query, headers, false).await
 }
 
@@ -0,0 +1,248 @@
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+};
+use common::v1::types::MediaId;
+use common::v2::types::media::proxy::{MediaQuery, SignedQuery, TransformQuery};
+use futures_util::StreamExt;
+use http::{HeaderMap, StatusCode};
+use lamprey_backend_core::types::media::{ImageTransform, ThumbFormat};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::error;
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+
+use crate::{
+    AppState,
+    error::{Error, Result},
+    routes::util::{ContentInfo, build_headers, ensure_unexpired, probably_can_thumbnail},
+};
+
+// NOTE: the variant limit is checked before generating, so concurrent requests
+// for different variants can go slightly over it
+async fn transform_response(
+    s: AppState,
+    media_id: MediaId,
+    query: TransformQuery,
+    signed: SignedQuery,
+    media_query: MediaQuery,
+    headers: HeaderMap,
+    with_body: bool,
+) -> Result<(http::StatusCode, HeaderMap, Body)> {
+    let Some(key) = &s.config_media().transform_key else {
+        return Err(Error::NotFound);
+    };
+    let key = key
+        .load()
+        .map_err(|e| Error::Internal(format!("failed to load secret: {e}")))?;
+
+    let transform = ImageTransform::from_query(&query).map_err(|_| Error::BadRequest)?;
+    if !transform.verify(media_id, signed.expires, key.as_bytes(), &signed.sig) {
+        return Err(Error::BadSignature);
+    }
+    ensure_unexpired(&s, media_id, signed.expires).await?;
+
+    let media = s.ensure_media_ready(media_id, media_query.wait).await?;
+    let storage_id = media.storage_id();
+    let format = match transform.format {
+        Some(format) => format.into(),
+        None => {
+            let accept = headers
+                .get(http::header::ACCEPT)
+                .and_then(|h| h.to_str().ok());
+            ThumbFormat::negotiate(accept, false).0
+        }
+    };
+    let transform_key = transform.key();
+
+    let pre_header_info = build_headers(
+        &headers,
+        &ContentInfo::Transform {
+            media: &media,
+            content_length: None,
+            format,
+            key: &transform_key,
+        },
+    )?;
+
+    if pre_header_info.unmodified {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            pre_header_info.headers,
+            Body::empty(),
+        ));
+    }
+
+    let transform_path = s.media_paths.transform(storage_id, &transform, format);
+
+    if s.blobs.exists(&transform_path).await? {
+        let meta = s.blobs.stat(&transform_path).await?;
+        let content_length = meta.content_length();
+        let final_headers = build_headers(
+            &headers,
+            &ContentInfo::Transform {
+                media: &media,
+                content_length: Some(content_length),
+                format,
+                key: &transform_key,
+            },
+        )?;
+
+        let status = if final_headers.range.is_some() {
+            StatusCode::PARTIAL_CONTENT
+        } else {
+            StatusCode::OK
+        };
+
+        let body = if with_body {
+            let reader = s.blobs.reader(&transform_path).await?;
+            if let Some(r) = final_headers.range {
+                Body::from_stream(reader.into_bytes_stream(r).await?)
+            } else {
+                Body::from_stream(reader.into_bytes_stream(..).await?)
+            }
+        } else {
+            Body::empty()
+        };
+
+        return Ok((status, final_headers.headers, body));
+    }
+
+    let variants = match s.blobs.list(&s.media_paths.transform_dir(storage_id)).await {
+        Ok(entries) => entries.iter().filter(|e| e.metadata().is_file()).count(),
+        Err(err) if err.kind() == opendal::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err.into()),
+    };
+    if variants >= s.config_media().max_transforms {
+        return Err(Error::TooManyVariants);
+    }
+
+    let m = media.clone();
+    let data = s
+        .pending_transforms
+        .try_get_with((storage_id, transform.clone(), format), async move {
+            let poster_path = s.media_paths.poster(storage_id);
+            let source_path = if s.blobs.exists(&poster_path).await? {
+                poster_path
+            } else if probably_can_thumbnail(&m) {
+                s.media_paths.file(storage_id)
+            } else {
+                return Err(Error::NotFound);
+            };
+
+            let temp_in = async_tempfile::TempFile::new().await?;
+            let temp_out = async_tempfile::TempFile::new().await?;
+
+            let reader = s.blobs.reader(&source_path).await?;
+            let mut writer = temp_in.open_rw().await?;
+            let mut bytes_reader = reader.into_bytes_stream(..).await?;
+            while let Some(chunk) = bytes_reader.next().await {
+                writer.write_all(&chunk?).await?;
+            }
+            writer.flush().await?;
+
+            s.ffmpeg
+                .transform_image(
+                    temp_in.file_path(),
+                    temp_out.file_path(),
+                    &transform,
+                    format,
+                )
+                .await?;
+
+            let mut out_reader = temp_out.open_ro().await?;
+            let mut data = Vec::new();
+            out_reader.read_to_end(&mut data).await?;
+
+            let s_clone = s.blobs.clone();
+            let data_clone = data.clone();
+            tokio::spawn(async move {
+                if let Err(err) = s_clone.write(&transform_path, data_clone).await {
+                    error!("error while uploading transform: {err}")
+                }
+            });
+            Ok(data)
+        })
+        .await?;
+
+    let final_headers = build_headers(
+        &headers,
+        &ContentInfo::Transform {
+            media: &media,
+            content_length: Some(data.len() as u64),
+            format,
+            key: &transform_key,
+        },
+    )?;
+
+    let status = if final_headers.range.is_some() {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    };
+
+    let body = if with_body {
+        if let Some(range) = final_headers.range {
+            let start = match range.0 {
+                std::ops::Bound::Included(s) => s,
+                std::ops::Bound::Excluded(s) => s + 1,
+                std::ops::Bound::Unbounded => 0,
+            };
+            let end = match range.1 {
+                std::ops::Bound::Included(e) => e.saturating_add(1),
+                std::ops::Bound::Excluded(e) => e,
+                std::ops::Bound::Unbounded => data.len() as u64,
+            }
+            .min(data.len() as u64);
+            if start >= end {
+                return Err(Error::BadRange);
+            }
+
+            let part = data[start as usize..end as usize].to_vec();
+            Body::from(part)
+        } else {
+            Body::from(data)
+        }
+    } else {
+        Body::empty()
+    };
+
+    Ok((status, final_headers.headers, body))
+}
+
+/// Fetch transform
+///
+/// resize, crop, or convert an image. the url must be signed by the api, and
+/// signatures for private media expire.
+#[utoipa::path(get, path = "/transform/{media_id}")]
+pub async fn get_transform(
+    State(s): State<AppState>,
+    Path(media_id): Path<MediaId>,
+    Query(query): Query<TransformQuery>,
+    Query(signed): Query<SignedQuery>,
+    Query(media_query): Query<MediaQuery>,
+    headers: HeaderMap,
+) -> Result<(http::StatusCode, HeaderMap, Body)> {
+    transform_response(s, media_id, query, signed, media_query, headers, true).await
+}
+
+/// Head transform
+///
+/// get headers for a transformed image
+#[utoipa::path(head, path = "/transform/{media_id}")]
+pub async fn head_transform(
+    State(s): State<AppState>,
+    Path(media_id): Path<MediaId>,
+    Query(query): Query<TransformQuery>,
+    Query(signed): Query<SignedQuery>,
+    Query(media_query): Query<MediaQuery>,
+    headers: HeaderMap,
+) -> Result<(http::StatusCode, HeaderMap, Body)> {
+    transform_response(s, media_id, query, signed, media_query, headers, false).await
+}
+
+pub fn routes() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(head_transform))
+        .routes(routes!(get_transform))
+}
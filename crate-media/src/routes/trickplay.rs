@@ -1,39 +1,375 @@
+use std::fmt::Write as _;
+
+use async_tempfile::{TempDir, TempFile};
 use axum::{
+    body::Body,
     extract::{Path, Query, State},
-    response::IntoResponse,
 };
 use common::{
-    v1::types::MediaId, v2::types::media::proxy::MediaQuery,
-    v2::types::media::proxy::TrickplayQuery,
+    v1::types::MediaId,
+    v2::types::media::{
+        MediaMetadata,
+        proxy::{
+            MediaQuery, MediaSignedQuery, TrickplayFormat, TrickplayIndex, TrickplayQuery,
+            TrickplayTile,
+        },
+    },
 };
-use http::StatusCode;
+use futures_util::StreamExt;
+use http::{HeaderMap, StatusCode};
+use lamprey_backend_core::types::media::MediaVariant;
+use tokio::io::AsyncWriteExt;
 use utoipa_axum::router::OpenApiRouter;
 use utoipa_axum::routes;
 
-use crate::{AppState, error::Result};
+use crate::{
+    AppState,
+    error::{Error, Result},
+    routes::util::{ContentInfo, build_headers, ensure_signed, signed_suffix},
+};
+
+const DEFAULT_THUMB_WIDTH: u32 = 160;
+const MIN_THUMB_SIZE: u32 = 16;
+const MAX_THUMB_SIZE: u32 = 480;
+
+const DEFAULT_GRID_SIZE: u32 = 10;
+const MAX_GRID_SIZE: u32 = 16;
+
+/// the longest time between tiles, in milliseconds
+///
+/// shorter videos use a smaller interval so they still fill a sheet
+const MAX_INTERVAL: u64 = 10_000;
+const MIN_INTERVAL: u64 = 1_000;
+
+/// calculate the tile and grid size for a query
+fn trickplay_dims(
+    query: &TrickplayQuery,
+    width: u64,
+    height: u64,
+) -> Result<((u32, u32), (u32, u32))> {
+    if width == 0 || height == 0 {
+        return Err(Error::BadRequest);
+    }
+
+    // scale `a` by num/den, keeping it within bounds
+    let scale = |a: u32, num: u64, den: u64| {
+        ((a as u64 * num / den) as u32).clamp(MIN_THUMB_SIZE, MAX_THUMB_SIZE)
+    };
+
+    let (thumb_width, thumb_height) = match (query.thumb_width, query.thumb_height) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => (w, scale(w, height, width)),
+        (None, Some(h)) => (scale(h, width, height), h),
+        (None, None) => (
+            DEFAULT_THUMB_WIDTH,
+            scale(DEFAULT_THUMB_WIDTH, height, width),
+        ),
+    };
+
+    let cols = query.width.unwrap_or(DEFAULT_GRID_SIZE);
+    let rows = query.height.unwrap_or(DEFAULT_GRID_SIZE);
+
+    let thumb_range = MIN_THUMB_SIZE..=MAX_THUMB_SIZE;
+    let grid_range = 1..=MAX_GRID_SIZE;
+    if !thumb_range.contains(&thumb_width)
+        || !thumb_range.contains(&thumb_height)
+        || !grid_range.contains(&cols)
+        || !grid_range.contains(&rows)
+    {
+        return Err(Error::BadRequest);
+    }
+
+    // keep dimensions even for yuv420
+    Ok(((thumb_width & !1, thumb_height & !1), (cols, rows)))
+}
+
+/// generate and upload trickplay sheets, if they don't already exist
+async fn ensure_trickplay(
+    s: &AppState,
+    media_id: MediaId,
+    duration: u64,
+    tile: (u32, u32),
+    grid: (u32, u32),
+) -> Result<TrickplayIndex> {
+    let index = s
+        .pending_trickplay
+        .try_get_with((media_id, tile, grid), async {
+            let index_path = s.media_paths.trickplay_index(media_id, tile, grid);
+            if s.blobs.exists(&index_path).await? {
+                let data = s.blobs.read(&index_path).await?.to_vec();
+                return serde_json::from_slice::<TrickplayIndex>(&data)
+                    .map_err(|e| Error::Internal(e.to_string()));
+            }
+
+            let per_sheet = (grid.0 * grid.1) as u64;
+            let interval = (duration / per_sheet).clamp(MIN_INTERVAL, MAX_INTERVAL);
+
+            let source_path = s.media_paths.file(media_id);
+            let temp_in = TempFile::new().await?;
+            let temp_out = TempDir::new().await?;
+            let reader = s.blobs.reader(&source_path).await?;
+            let mut writer = temp_in.open_rw().await?;
+            let mut bytes_reader = reader.into_bytes_stream(..).await?;
+            while let Some(chunk) = bytes_reader.next().await {
+                writer.write_all(&chunk?).await?;
+            }
+            writer.flush().await?;
+
+            s.ffmpeg
+                .generate_trickplay(
+                    temp_in.file_path(),
+                    temp_out.dir_path(),
+                    interval,
+                    tile,
+                    grid,
+                )
+                .await?;
+
+            let mut sheets = 0;
+            loop {
+                let path = temp_out.dir_path().join(format!("{sheets}.jpg"));
+                let data = match tokio::fs::read(&path).await {
+                    Ok(data) => data,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+                    Err(e) => return Err(e.into()),
+                };
+                s.blobs
+                    .write(
+                        &s.media_paths.trickplay_sheet(media_id, tile, grid, sheets),
+                        data,
+                    )
+                    .await?;
+                sheets += 1;
+            }
+
+            if sheets == 0 {
+                return Err(Error::Internal("ffmpeg generated no sheets".to_owned()));
+            }
+
+            let count = duration
+                .div_ceil(interval)
+                .clamp(1, sheets as u64 * per_sheet);
+            let tiles = (0..count)
+                .map(|i| {
+                    let pos = (i % per_sheet) as u32;
+                    let start = i * interval;
+                    let end = if i + 1 == count {
+                        duration.max(start + 1)
+                    } else {
+                        start + interval
+                    };
+                    TrickplayTile {
+                        start,
+                        end,
+                        sheet: (i / per_sheet) as u32,
+                        x: (pos % grid.0) * tile.0,
+                        y: (pos / grid.0) * tile.1,
+                    }
+                })
+                .collect();
+
+            let index = TrickplayIndex {
+                interval,
+                thumb_width: tile.0,
+                thumb_height: tile.1,
+                width: grid.0,
+                height: grid.1,
+                tiles,
+            };
+
+            // upload the index last so its existence means every sheet is available
+            let data = serde_json::to_vec(&index).map_err(|e| Error::Internal(e.to_string()))?;
+            s.blobs.write(&index_path, data).await?;
+
+            Ok::<_, Error>(index)
+        })
+        .await?;
+
+    Ok(index)
+}
+
+/// format milliseconds as a webvtt timestamp
+fn vtt_timestamp(ms: u64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// generate a webvtt file pointing to each tile
+///
+/// `suffix` is appended to each tile's url, see [`signed_suffix`]
+fn trickplay_vtt(index: &TrickplayIndex, suffix: &str) -> String {
+    let mut out = String::from("WEBVTT\n");
+    for tile in &index.tiles {
+        let _ = write!(
+            out,
+            "\n{} --> {}\n?sheet={}&width={}&height={}&thumb_width={}&thumb_height={}{suffix}#xywh={},{},{},{}\n",
+            vtt_timestamp(tile.start),
+            vtt_timestamp(tile.end),
+            tile.sheet,
+            index.width,
+            index.height,
+            index.thumb_width,
+            index.thumb_height,
+            tile.x,
+            tile.y,
+            index.thumb_width,
+            index.thumb_height,
+        );
+    }
+    out
+}
+
+async fn trickplay_response(
+    s: AppState,
+    media_id: MediaId,
+    query: TrickplayQuery,
+    media_query: MediaQuery,
+    signed: &MediaSignedQuery,
+    headers: HeaderMap,
+    with_body: bool,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    let media = s.ensure_media_ready(media_id, media_query.wait).await?;
+    let MediaMetadata::Video {
+        width,
+        height,
+        duration,
+    } = media.metadata
+    else {
+        return Err(Error::BadRequest);
+    };
+
+    let (tile, grid) = trickplay_dims(&query, width, height)?;
+    let index_format = query.sheet.is_none().then(|| query.format.clone());
+
+    let pre_header_info = build_headers(
+        &headers,
+        &ContentInfo::Trickplay {
+            media: &media,
+            content_length: None,
+            index: index_format.clone(),
+        },
+    )?;
+
+    if pre_header_info.unmodified {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            pre_header_info.headers,
+            Body::empty(),
+        ));
+    }
+
+    let index = ensure_trickplay(&s, media.storage_id(), duration, tile, grid).await?;
+
+    let Some(sheet) = query.sheet else {
+        let data = match query.format {
+            TrickplayFormat::Vtt => trickplay_vtt(&index, &signed_suffix(signed)).into_bytes(),
+            TrickplayFormat::Json => {
+                serde_json::to_vec(&index).map_err(|e| Error::Internal(e.to_string()))?
+            }
+        };
+
+        let final_headers = build_headers(
+            &headers,
+            &ContentInfo::Trickplay {
+                media: &media,
+                content_length: Some(data.len() as u64),
+                index: index_format,
+            },
+        )?;
+
+        let (status, body) = match final_headers.range {
+            Some(range) => {
+                let data = data
+                    .get((range.0.map(|b| b as usize), range.1.map(|b| b as usize)))
+                    .ok_or(Error::BadRange)?;
+                (StatusCode::PARTIAL_CONTENT, data.to_vec())
+            }
+            None => (StatusCode::OK, data),
+        };
+
+        let body = if with_body {
+            Body::from(body)
+        } else {
+            Body::empty()
+        };
+
+        return Ok((status, final_headers.headers, body));
+    };
+
+    let sheet_path = s
+        .media_paths
+        .trickplay_sheet(media.storage_id(), tile, grid, sheet);
+    if !s.blobs.exists(&sheet_path).await? {
+        return Err(Error::NotFound);
+    }
+
+    let meta = s.blobs.stat(&sheet_path).await?;
+    let final_headers = build_headers(
+        &headers,
+        &ContentInfo::Trickplay {
+            media: &media,
+            content_length: Some(meta.content_length()),
+            index: None,
+        },
+    )?;
+
+    let status = if final_headers.range.is_some() {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    };
+
+    let body = if with_body {
+        let reader = s.blobs.reader(&sheet_path).await?;
+        if let Some(r) = final_headers.range {
+            Body::from_stream(reader.into_bytes_stream(r).await?)
+        } else {
+            Body::from_stream(reader.into_bytes_stream(..).await?)
+        }
+    } else {
+        Body::empty()
+    };
+
+    Ok((status, final_headers.headers, body))
+}
 
-/// Fetch trickplay (TODO)
+/// Fetch trickplay
+///
+/// scrubbing previews for videos. without `sheet`, returns an index mapping
+/// timestamps to tiles. with `sheet`, returns a jpeg of tiled thumbnails.
+/// sheets are generated on first request.
 #[utoipa::path(get, path = "/trickplay/{media_id}")]
 async fn get_trickplay(
     State(s): State<AppState>,
     Path(media_id): Path<MediaId>,
-    Query(_query): Query<TrickplayQuery>,
+    Query(query): Query<TrickplayQuery>,
     Query(media_query): Query<MediaQuery>,
-) -> Result<impl IntoResponse> {
-    s.ensure_media_ready(media_id, media_query.wait).await?;
-    Ok((StatusCode::NOT_IMPLEMENTED, "doesn't exist yet :("))
+    Query(signed): Query<MediaSignedQuery>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    ensure_signed(&s, media_id, MediaVariant::Trickplay, &signed).await?;
+    trickplay_response(s, media_id, query, media_query, &signed, headers, true).await
 }
 
-/// Head trickplay (TODO)
+/// Head trickplay
+///
+/// get headers for a trickplay index or sheet
 #[utoipa::path(head, path = "/trickplay/{media_id}")]
 async fn head_trickplay(
     State(s): State<AppState>,
     Path(media_id): Path<MediaId>,
-    Query(_query): Query<TrickplayQuery>,
+    Query(query): Query<TrickplayQuery>,
     Query(media_query): Query<MediaQuery>,
-) -> Result<impl IntoResponse> {
-    s.ensure_media_ready(media_id, media_query.wait).await?;
-    Ok((StatusCode::NOT_IMPLEMENTED, "doesn't exist yet :("))
+    Query(signed): Query<MediaSignedQuery>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, Body)> {
+    ensure_signed(&s, media_id, MediaVariant::Trickplay, &signed).await?;
+    trickplay_response(s, media_id, query, media_query, &signed, headers, false).await
 }
 
 pub fn routes() -> OpenApiRouter<AppState> {
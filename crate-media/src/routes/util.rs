@@ -1,12 +1,106 @@
-use common::v2::types::media::{Media, MediaMetadata};
+use axum::{
+    extract::{Query, Request},
+    middleware::Next,
+    response::Response,
+};
+use common::v1::types::MediaId;
+use common::v2::types::media::{
+    Media, MediaMetadata,
+    proxy::{MediaSignedQuery, TrickplayFormat},
+};
 use headers::HeaderMapExt;
 use http::HeaderMap;
+use lamprey_backend_core::types::media::{MediaVariant, ThumbFormat};
 use std::{
     ops::Bound,
-    time::{Duration, SystemTime},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    AppState,
+    error::{Error, Result},
 };
 
-use crate::error::{Error, Result};
+/// check that a request for private media has a valid, unexpired signature
+///
+/// media that isn't private (or all media, if `url_key` isn't set) doesn't need a signature
+pub async fn ensure_signed(
+    s: &AppState,
+    media_id: MediaId,
+    variant: MediaVariant,
+    query: &MediaSignedQuery,
+) -> Result<()> {
+    let Some(key) = &s.config_media().url_key else {
+        return Ok(());
+    };
+
+    // only private media gets signed, so skip the lookup if there's a signature
+    let (Some(expires), Some(sig)) = (query.expires, &query.sig) else {
+        return ensure_unexpired(s, media_id, None).await;
+    };
+
+    let key = key
+        .load()
+        .map_err(|e| Error::Internal(format!("failed to load secret: {e}")))?;
+    if !variant.verify(media_id, expires, key.as_bytes(), sig) {
+        return Err(Error::BadSignature);
+    }
+
+    ensure_unexpired(s, media_id, Some(expires)).await
+}
+
+/// the query string to append to urls in a playlist or index so they carry the
+/// request's signature
+///
+/// every url in a stream or trickplay response is for the same media and
+/// variant, so the request's signature is valid for all of them
+pub fn signed_suffix(query: &MediaSignedQuery) -> String {
+    match (query.expires, &query.sig) {
+        (Some(expires), Some(sig)) => format!(
+            "&expires={expires}&sig={}",
+            percent_encoding::utf8_percent_encode(sig, percent_encoding::NON_ALPHANUMERIC)
+        ),
+        _ => String::new(),
+    }
+}
+
+/// check that an already verified signature hasn't expired
+///
+/// signatures for private media always expire, so a signature without an expiry
+/// is only valid for media that isn't private
+pub async fn ensure_unexpired(s: &AppState, media_id: MediaId, expires: Option<u64>) -> Result<()> {
+    match expires {
+        Some(expires) if expires < unix_now() => Err(Error::SignatureExpired),
+        Some(_) => Ok(()),
+        None if s.is_media_private(media_id).await? => Err(Error::BadSignature),
+        None => Ok(()),
+    }
+}
+
+/// stop shared caches from serving signed urls after they expire
+pub async fn signed_cache_control(
+    Query(query): Query<MediaSignedQuery>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let mut res = next.run(req).await;
+    if let Some(expires) = query.expires {
+        let max_age = expires.saturating_sub(unix_now());
+        res.headers_mut().typed_insert(
+            headers::CacheControl::new()
+                .with_private()
+                .with_max_age(Duration::from_secs(max_age)),
+        );
+    }
+    res
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
 /// create a content-disposition header
 pub fn content_disposition_attachment(filename: &str, inline: bool) -> String {
@@ -50,38 +144,67 @@ pub enum ContentInfo<'a> {
     Thumb {
         media: &'a Media,
         content_length: Option<u64>,
-        animated: bool,
+        format: ThumbFormat,
     },
     Gifv {
         media: &'a Media,
         content_length: Option<u64>,
     },
+    Stream {
+        media: &'a Media,
+        content_length: Option<u64>,
+        /// whether this is a playlist or a segment
+        playlist: bool,
+    },
+    Trickplay {
+        media: &'a Media,
+        content_length: Option<u64>,
+        /// the format of the index, or None for a sheet
+        index: Option<TrickplayFormat>,
+    },
+    Transform {
+        media: &'a Media,
+        content_length: Option<u64>,
+        format: ThumbFormat,
+        /// the canonical name of the transform
+        key: &'a str,
+    },
 }
 
 impl<'a> ContentInfo<'a> {
     fn content_type(&self) -> headers::ContentType {
         match self {
             ContentInfo::Media(media) => media.content_type.to_string().parse().unwrap(),
-            ContentInfo::Thumb { animated, .. } => {
-                if *animated {
-                    "image/webp".parse().unwrap()
+            ContentInfo::Thumb { format, .. } => format.mime().parse().unwrap(),
+            ContentInfo::Transform { format, .. } => format.mime().parse().unwrap(),
+            ContentInfo::Gifv { .. } => "video/webm".parse().unwrap(),
+            ContentInfo::Stream { playlist, .. } => {
+                if *playlist {
+                    "application/vnd.apple.mpegurl".parse().unwrap()
                 } else {
-                    "image/avif".parse().unwrap()
+                    "video/mp2t".parse().unwrap()
                 }
             }
-            ContentInfo::Gifv { .. } => "video/webm".parse().unwrap(),
+            ContentInfo::Trickplay { index, .. } => match index {
+                Some(TrickplayFormat::Vtt) => "text/vtt".parse().unwrap(),
+                Some(TrickplayFormat::Json) => "application/json".parse().unwrap(),
+                None => "image/jpeg".parse().unwrap(),
+            },
         }
     }
 
     fn filename(&self) -> String {
         match self {
             ContentInfo::Media(media) => media.filename.clone(),
-            ContentInfo::Thumb { animated, .. } => {
-                if *animated {
-                    "thumbnail.webp".to_string()
-                } else {
-                    "thumbnail.avif".to_string()
+            ContentInfo::Thumb { format, .. } => format!("thumbnail.{}", format.ext()),
+            ContentInfo::Transform { media, format, .. } => {
+                let mut f = media.filename.clone();
+                if let Some(p) = f.rsplit_once('.') {
+                    f = p.0.to_owned();
                 }
+                f.push('.');
+                f.push_str(format.ext());
+                f
             }
             ContentInfo::Gifv { media, .. } => {
                 let mut f = media.filename.clone();
@@ -91,6 +214,21 @@ impl<'a> ContentInfo<'a> {
                 f.push_str(".webm");
                 f
             }
+            ContentInfo::Stream {
+                media, playlist, ..
+            } => {
+                let mut f = media.filename.clone();
+                if let Some(p) = f.rsplit_once('.') {
+                    f = p.0.to_owned();
+                }
+                f.push_str(if *playlist { ".m3u8" } else { ".ts" });
+                f
+            }
+            ContentInfo::Trickplay { index, .. } => match index {
+                Some(TrickplayFormat::Vtt) => "trickplay.vtt".to_string(),
+                Some(TrickplayFormat::Json) => "trickplay.json".to_string(),
+                None => "trickplay.jpg".to_string(),
+            },
         }
     }
 
@@ -99,6 +237,9 @@ impl<'a> ContentInfo<'a> {
             ContentInfo::Media(media) => Some(media.size),
             ContentInfo::Thumb { content_length, .. } => *content_length,
             ContentInfo::Gifv { content_length, .. } => *content_length,
+            ContentInfo::Stream { content_length, .. } => *content_length,
+            ContentInfo::Trickplay { content_length, .. } => *content_length,
+            ContentInfo::Transform { content_length, .. } => *content_length,
         }
     }
 
@@ -107,6 +248,9 @@ impl<'a> ContentInfo<'a> {
             ContentInfo::Media(media) => media,
             ContentInfo::Thumb { media, .. } => media,
             ContentInfo::Gifv { media, .. } => media,
+            ContentInfo::Stream { media, .. } => media,
+            ContentInfo::Trickplay { media, .. } => media,
+            ContentInfo::Transform { media, .. } => media,
         }
     }
 }
@@ -126,7 +270,16 @@ pub fn build_headers<'a>(
             .with_max_age(Duration::from_secs(604800)),
     );
 
-    let etag: headers::ETag = format!("W/\"{}\"", media.id).parse().unwrap();
+    // thumbnails are negotiated, so each format needs its own etag
+    let etag: headers::ETag = match content_info {
+        ContentInfo::Thumb { format, .. } => format!("W/\"{}.{}\"", media.id, format.ext()),
+        ContentInfo::Transform { format, key, .. } => {
+            format!("W/\"{}.{}.{}\"", media.id, key, format.ext())
+        }
+        _ => format!("W/\"{}\"", media.id),
+    }
+    .parse()
+    .unwrap();
     headers.typed_insert(etag.clone());
 
     let id_timestamp: SystemTime = media
@@ -145,6 +298,9 @@ pub fn build_headers<'a>(
             .parse()
             .unwrap(),
     );
+    if let ContentInfo::Thumb { .. } | ContentInfo::Transform { .. } = content_info {
+        headers.insert(http::header::VARY, http::HeaderValue::from_static("accept"));
+    }
 
     // step 2. check range request headers
     // if If-Range is present, return a range if content is not modified. otherwise, return the full content.
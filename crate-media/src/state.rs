@@ -2,12 +2,12 @@ use std::{sync::Arc, time::Duration};
 
 use common::{
     v1::types::{EmojiId, MediaId, MessageSync},
-    v2::types::media::{Media, MediaStatus},
+    v2::types::media::{Media, MediaStatus, proxy::TrickplayIndex},
 };
 use lamprey_backend_core::{
     config::{ConfigBlobs, ConfigMedia},
     ffmpeg::Ffmpeg,
-    types::media::MediaPaths,
+    types::media::{ImageTransform, MediaPaths, ThumbFormat},
 };
 use moka::future::Cache;
 use opendal::{Operator, layers::LoggingLayer};
@@ -15,6 +15,9 @@ use sqlx::{PgPool, postgres::PgPoolOptions};
 
 use crate::{Error, Result, config::Config, data};
 
+/// media id, tile size, and grid size
+type TrickplayKey = (MediaId, (u32, u32), (u32, u32));
+
 #[derive(Clone)]
 pub struct AppState {
     pub(crate) db: PgPool,
@@ -27,8 +30,12 @@ pub struct AppState {
     // NOTE: be careful about allowing emoji/media editing! i'd need to invalidate these caches
     pub(crate) cache_emoji: Cache<EmojiId, MediaId>,
     pub(crate) cache_media: Cache<MediaId, Media>,
-    pub(crate) pending_thumbnails: Cache<(MediaId, u32, u32, bool), Vec<u8>>,
+    pub(crate) cache_private: Cache<MediaId, bool>,
+    pub(crate) pending_thumbnails: Cache<(MediaId, u32, u32, bool, ThumbFormat), Vec<u8>>,
     pub(crate) pending_gifv: Cache<MediaId, Arc<async_tempfile::TempFile>>,
+    pub(crate) pending_streams: Cache<(MediaId, u64), ()>,
+    pub(crate) pending_trickplay: Cache<TrickplayKey, TrickplayIndex>,
+    pub(crate) pending_transforms: Cache<(MediaId, ImageTransform, ThumbFormat), Vec<u8>>,
 
     pub(crate) sushi_tx: tokio::sync::broadcast::Sender<MessageSync>,
 }
@@ -83,9 +90,17 @@ impl AppState {
             None
         };
 
+        let media_paths = config.media.paths();
         let cache_media = Cache::new(config.media.cache_media);
         let cache_emoji = Cache::new(config.media.cache_emoji);
 
+        // rooms can be made public or private and media can be attached to a
+        // private message at any time, so don't cache this for too long
+        let cache_private = Cache::builder()
+            .max_capacity(config.media.cache_media)
+            .time_to_live(Duration::from_secs(60))
+            .build();
+
         Ok(Self {
             db,
             blobs,
@@ -94,10 +109,14 @@ impl AppState {
             config: Arc::new(config),
             cache_emoji,
             cache_media,
+            cache_private,
             pending_thumbnails: Cache::new(0),
             pending_gifv: Cache::new(100),
+            pending_streams: Cache::new(100),
+            pending_trickplay: Cache::new(100),
+            pending_transforms: Cache::new(0),
             sushi_tx,
-            media_paths: Arc::new(MediaPaths::new("media/")),
+            media_paths: Arc::new(media_paths),
         })
     }
 
@@ -110,6 +129,16 @@ impl AppState {
         Ok(m)
     }
 
+    /// whether a piece of media is linked to something in a private channel
+    pub async fn is_media_private(&self, media_id: MediaId) -> Result<bool> {
+        if let Some(p) = self.cache_private.get(&media_id).await {
+            return Ok(p);
+        }
+        let p = data::lookup_media_private(&self.db, media_id).await?;
+        self.cache_private.insert(media_id, p).await;
+        Ok(p)
+    }
+
     pub async fn ensure_media_ready(&self, media_id: MediaId, wait: bool) -> Result<Media> {
         if let Some(m) = self.cache_media.get(&media_id).await {
             return Ok(m);
@@ -381,6 +381,8 @@ route!(post   "/api/v1/voice/{channel_id}/call"                             => v
 route!(delete "/api/v1/voice/{channel_id}/call"                             => voice_call_delete(channel_id: ChannelId, _q: CallDeleteParams));
 route!(patch  "/api/v1/voice/{channel_id}/call"                             => voice_call_patch(channel_id: ChannelId) -> Call, CallPatch);
 route!(get    "/api/v1/voice/{channel_id}/call"                             => voice_call_get(channel_id: ChannelId) -> Call);
+route!(post   "/api/v1/voice/{channel_id}/call/recording"                   => voice_call_recording_start(channel_id: ChannelId) -> Call);
+route!(delete "/api/v1/voice/{channel_id}/call/recording"                   => voice_call_recording_stop(channel_id: ChannelId));
 route!(post   "/api/v1/voice/{channel_id}/ring"                             => voice_ring_start(channel_id: ChannelId), RingStart);
 route!(post   "/api/v1/voice/{channel_id}/ring/stop"                        => voice_ring_stop(channel_id: ChannelId), RingStop);
 route!(get    "/api/v1/voice/{channel_id}/ring/eligibility"                 => voice_ring_eligibility(channel_id: ChannelId) -> RingEligibility);
@@ -1,18 +1,22 @@
 use std::{net::SocketAddr, sync::Arc, time::Instant};
 
+#[cfg(feature = "e2ee")]
+use common::v1::types::e2ee::MlsEpoch;
 use common::{
     v1::types::voice::{
-        VoiceState,
+        MediaKind, Mid, TrackCreate, TrackKey, TrackMetadata2, VoiceState,
         datachannel::ProtocolType,
         messages::{SignallingCommand, SignallingEvent},
     },
     v2::types::ChannelId,
 };
 use futures_util::{StreamExt, stream::BoxStream};
-use str0m::Rtc;
+#[cfg(feature = "e2ee")]
+use lamprey_crypto::{FrameCodec, FrameCryptor};
+use str0m::{Rtc, media::MediaTime};
 use tokio::{
     net::UdpSocket,
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, oneshot},
     time,
 };
 use tracing::{error, info};
@@ -37,6 +41,9 @@ pub struct VoiceBuilder<'a> {
     channel_id: ChannelId,
     self_mute: bool,
     self_deaf: bool,
+
+    #[cfg(feature = "e2ee")]
+    cryptor: Option<FrameCryptor>,
 }
 
 impl Voice {
@@ -63,6 +70,7 @@ impl Voice {
                     // FIXME: Map RtcEvent to VoiceEvent
                     None
                 }
+                Ok(RtcEvent::Media { .. }) => None,
                 Err(_) => None,
             }
         })
@@ -90,6 +98,51 @@ impl Voice {
     //     todo!()
     // }
 
+    /// switch to the keys for a new mls epoch
+    ///
+    /// call this after every commit to the channel's group while the call is encrypted
+    #[cfg(feature = "e2ee")]
+    pub async fn set_epoch(&self, epoch: MlsEpoch, secret: Vec<u8>) -> Result<(), VoiceError> {
+        self.state
+            .tx
+            .send(RtcCommand::SetEpoch { epoch, secret })
+            .await
+            .map_err(|_| VoiceError::Internal)
+    }
+
+    /// start sending a new track, returning its mid
+    ///
+    /// tracks are marked as encrypted if this call is encrypted
+    pub async fn publish(&self, kind: MediaKind, key: TrackKey) -> Result<Mid, VoiceError> {
+        let (reply, rx) = oneshot::channel();
+        self.state
+            .tx
+            .send(RtcCommand::Publish { kind, key, reply })
+            .await
+            .map_err(|_| VoiceError::Internal)?;
+        rx.await.map_err(|_| VoiceError::Internal)?
+    }
+
+    /// send a frame of media on a published track
+    ///
+    /// the frame is encrypted first if this call is encrypted
+    pub async fn send_media(
+        &self,
+        mid: Mid,
+        rtp_time: MediaTime,
+        data: Vec<u8>,
+    ) -> Result<(), VoiceError> {
+        self.state
+            .tx
+            .send(RtcCommand::Media {
+                mid,
+                rtp_time,
+                data,
+            })
+            .await
+            .map_err(|_| VoiceError::Internal)
+    }
+
     /// create a new datachannel
     pub async fn create_channel(&self, _protocol: ProtocolType) -> Result<(), VoiceError> {
         todo!()
@@ -122,6 +175,8 @@ impl<'a> VoiceBuilder<'a> {
             channel_id,
             self_mute: false,
             self_deaf: false,
+            #[cfg(feature = "e2ee")]
+            cryptor: None,
         }
     }
 
@@ -143,6 +198,15 @@ impl<'a> VoiceBuilder<'a> {
         self
     }
 
+    /// encrypt this call end to end
+    ///
+    /// the cryptor should be created from the channel's mls group
+    #[cfg(feature = "e2ee")]
+    pub fn encryption(mut self, cryptor: FrameCryptor) -> Self {
+        self.cryptor = Some(cryptor);
+        self
+    }
+
     pub async fn connect(self) -> Result<Voice, VoiceError> {
         // TODO: return better error
         let _channel_id = self.channel_id;
@@ -177,6 +241,8 @@ impl<'a> VoiceBuilder<'a> {
             tx: evt_tx.clone(),
             sock,
             pending: None,
+            #[cfg(feature = "e2ee")]
+            cryptor: self.cryptor,
         };
         tokio::spawn(worker.spawn());
 
@@ -188,10 +254,28 @@ impl<'a> VoiceBuilder<'a> {
 // TODO: move below into new file?
 
 /// sent to the worker
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum RtcCommand {
     /// handle a signalling event from the server
     Signalling(SignallingEvent),
+
+    /// add an outbound track and renegotiate
+    Publish {
+        kind: MediaKind,
+        key: TrackKey,
+        reply: oneshot::Sender<Result<Mid, VoiceError>>,
+    },
+
+    /// send a frame of media on an outbound track
+    Media {
+        mid: Mid,
+        rtp_time: MediaTime,
+        data: Vec<u8>,
+    },
+
+    /// switch to the keys for a new mls epoch
+    #[cfg(feature = "e2ee")]
+    SetEpoch { epoch: MlsEpoch, secret: Vec<u8> },
     // create/remove track
 }
 
@@ -200,6 +284,9 @@ pub enum RtcCommand {
 pub enum RtcEvent {
     /// send this signalling command to the server
     Signalling(SignallingCommand),
+
+    /// a frame of media was received
+    Media { mid: Mid, data: Arc<[u8]> },
 }
 
 pub struct VoiceActor {
@@ -208,6 +295,9 @@ pub struct VoiceActor {
     tx: broadcast::Sender<RtcEvent>,
     sock: UdpSocket,
     pending: Option<str0m::change::SdpPendingOffer>,
+
+    #[cfg(feature = "e2ee")]
+    cryptor: Option<FrameCryptor>,
 }
 
 impl VoiceActor {
@@ -285,6 +375,20 @@ impl VoiceActor {
 
     pub async fn handle_command(&mut self, cmd: RtcCommand) -> Result<(), VoiceError> {
         match cmd {
+            RtcCommand::Publish { kind, key, reply } => {
+                let _ = reply.send(self.publish(kind, key));
+            }
+            RtcCommand::Media {
+                mid,
+                rtp_time,
+                data,
+            } => self.send_media(mid, rtp_time, data)?,
+            #[cfg(feature = "e2ee")]
+            RtcCommand::SetEpoch { epoch, secret } => {
+                if let Some(cryptor) = &mut self.cryptor {
+                    cryptor.set_epoch(epoch, &secret)?;
+                }
+            }
             RtcCommand::Signalling(s) => match s {
                 SignallingEvent::Connected { .. } => {
                     info!("Connected to SFU");
@@ -339,9 +443,140 @@ impl VoiceActor {
     pub async fn handle_str0m_event(&mut self, event: str0m::Event) -> Result<(), VoiceError> {
         match event {
             str0m::Event::Connected => info!("player connected!"),
+            str0m::Event::MediaData(media) => {
+                if let Some(data) = self.decrypt_media(&media) {
+                    let _ = self.tx.send(RtcEvent::Media {
+                        mid: media.mid.into(),
+                        data,
+                    });
+                }
+            }
             _ => {}
         }
 
         Ok(())
     }
+
+    /// add an outbound track and send an offer for it
+    fn publish(&mut self, kind: MediaKind, key: TrackKey) -> Result<Mid, VoiceError> {
+        if self.pending.is_some() {
+            // TODO: queue tracks while an offer is pending
+            return Err(VoiceError::Internal);
+        }
+
+        let mut changes = self.rtc.sdp_api();
+        let mid = changes.add_media(
+            kind.into(),
+            str0m::media::Direction::SendOnly,
+            None,
+            None,
+            None,
+        );
+        let (offer, pending) = changes.apply().ok_or(VoiceError::Internal)?;
+        self.pending = Some(pending);
+
+        let track = TrackCreate {
+            inner: TrackMetadata2 {
+                kind,
+                key,
+                whisper: None,
+                encrypted: self.is_encrypted(),
+            },
+            mid: mid.into(),
+        };
+        self.tx
+            .send(RtcEvent::Signalling(SignallingCommand::Offer {
+                sdp: common::v1::types::voice::SessionDescription(offer.to_sdp_string()),
+                tracks: vec![track],
+            }))
+            .map_err(|_| VoiceError::Internal)?;
+        Ok(mid.into())
+    }
+
+    /// encrypt and send a frame of media
+    fn send_media(&mut self, mid: Mid, rtp_time: MediaTime, data: Vec<u8>) -> Result<(), VoiceError> {
+        let writer = self
+            .rtc
+            .writer(mid.into())
+            .ok_or(VoiceError::NoMatchingTrack)?;
+        let params = *writer
+            .payload_params()
+            .next()
+            .ok_or(VoiceError::NoMatchingTrack)?;
+        let data = encrypt_media(
+            #[cfg(feature = "e2ee")]
+            self.cryptor.as_mut(),
+            &params,
+            data,
+        )?;
+        self.rtc
+            .writer(mid.into())
+            .ok_or(VoiceError::NoMatchingTrack)?
+            .write(params.pt(), Instant::now(), rtp_time, data)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "e2ee")]
+    fn is_encrypted(&self) -> bool {
+        self.cryptor.is_some()
+    }
+
+    #[cfg(not(feature = "e2ee"))]
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+
+    /// decrypt a received frame, returning None if it should be dropped
+    #[cfg(feature = "e2ee")]
+    fn decrypt_media(&mut self, media: &str0m::media::MediaData) -> Option<Arc<[u8]>> {
+        let Some(cryptor) = &mut self.cryptor else {
+            return Some(Arc::clone(&media.data));
+        };
+
+        match cryptor.decrypt(frame_codec(&media.params), &media.data) {
+            Ok(data) => Some(data.into()),
+            Err(e) => {
+                // frames sent before we got the new epoch's key can't be decrypted
+                tracing::trace!(mid = ?media.mid, "failed to decrypt frame: {e}");
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "e2ee"))]
+    fn decrypt_media(&mut self, media: &str0m::media::MediaData) -> Option<Arc<[u8]>> {
+        Some(Arc::clone(&media.data))
+    }
+}
+
+/// encrypt an outbound frame, if the call is encrypted
+#[cfg(feature = "e2ee")]
+fn encrypt_media(
+    cryptor: Option<&mut FrameCryptor>,
+    params: &str0m::format::PayloadParams,
+    data: Vec<u8>,
+) -> Result<Vec<u8>, VoiceError> {
+    match cryptor {
+        Some(cryptor) => Ok(cryptor.encrypt(frame_codec(params), &data)?),
+        None => Ok(data),
+    }
+}
+
+#[cfg(not(feature = "e2ee"))]
+fn encrypt_media(
+    _params: &str0m::format::PayloadParams,
+    data: Vec<u8>,
+) -> Result<Vec<u8>, VoiceError> {
+    Ok(data)
+}
+
+/// the sframe codec for a payload type, which decides how much of each frame is left in the clear
+#[cfg(feature = "e2ee")]
+fn frame_codec(params: &str0m::format::PayloadParams) -> FrameCodec {
+    match params.spec().codec {
+        str0m::format::Codec::Opus => FrameCodec::Opus,
+        str0m::format::Codec::Vp8 => FrameCodec::Vp8,
+        str0m::format::Codec::Vp9 => FrameCodec::Vp9,
+        _ => FrameCodec::Other,
+    }
 }
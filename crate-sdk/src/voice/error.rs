@@ -19,6 +19,11 @@ pub enum VoiceError {
     #[error("rtc error: {0}")]
     Rtc(#[from] str0m::error::RtcError),
 
+    /// failed to encrypt or decrypt media
+    #[cfg(feature = "e2ee")]
+    #[error("crypto error: {0}")]
+    Crypto(#[from] lamprey_crypto::Error),
+
     #[error("internal error")]
     Internal,
 
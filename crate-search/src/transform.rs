@@ -3,7 +3,10 @@ use common::{
         AuditLogEntry, Channel, Message, MessageAttachmentType, MessageType, Room, RoomMember,
         User, search::Doctype, util::Time,
     },
-    v2::types::{ChannelId, RoomId, media::Media},
+    v2::types::{
+        ChannelId, RoomId,
+        media::{Media, MediaMetadata},
+    },
 };
 use lamprey_markdown::{Parser, query::QueryableExt};
 use std::collections::BTreeMap;
@@ -77,11 +80,11 @@ define_transformer! {
     pub struct SearchChannel<'a> {
         pub channel: &'a Channel,
         pub first_message: Option<&'a Message>,
-        pub hotness: Option<f64>,
     }
 
     pub struct SearchMedia<'a> {
         pub media: &'a Media,
+        pub text: Option<&'a str>,
     }
 
     pub struct SearchRoomMember<'a> {
@@ -424,16 +427,26 @@ impl SearchDocument for SearchChannel<'_> {
             .collect();
         meta_fast.insert("recipients".to_string(), OwnedValue::Array(recipients));
 
-        if let Some(m) = self.first_message {
-            for r in &m.reactions.0 {
-                let key = r.key.to_key_str().replace(r"\", r"\\").replace(r".", r"\.");
-                meta_fast.insert(format!("reactions.{key}"), OwnedValue::U64(r.count));
-            }
+        if let Some(message_count) = channel.message_count {
+            meta_fast.insert("message_count".to_string(), message_count.into());
+        }
 
-            // TODO: use calculate_hotness here
-            if let Some(hotness) = self.hotness {
-                meta_fast.insert("hotness".to_string(), OwnedValue::F64(hotness));
-            }
+        if let Some(m) = self.first_message {
+            // reaction counts are keyed by the raw key string, use
+            // `util::escape_json_path_segment` when building a path to one
+            let reactions: Vec<(String, OwnedValue)> = m
+                .reactions
+                .0
+                .iter()
+                .map(|r| (r.key.to_key_str(), OwnedValue::U64(r.count)))
+                .collect();
+            let reaction_count: u64 = m.reactions.0.iter().map(|r| r.count).sum();
+            meta_fast.insert("reactions".to_string(), OwnedValue::Object(reactions));
+            meta_fast.insert("reaction_count".to_string(), reaction_count.into());
+            meta_fast.insert(
+                "first_message_at".to_string(),
+                m.created_at.unix_timestamp().into(),
+            );
         }
 
         doc.add_object(s.metadata_fast, meta_fast);
@@ -486,6 +499,15 @@ impl SearchDocument for SearchMedia<'_> {
             meta_text.insert("media_alt".to_string(), alt.clone().into());
         }
 
+        // text extracted from documents
+        if let Some(text) = self.text {
+            doc.add_text(s.content, text);
+        }
+
+        if let MediaMetadata::Document { pages } = media.metadata {
+            meta_fast.insert("media_pages".to_string(), pages.into());
+        }
+
         meta_fast.insert("quarantined".to_string(), media.quarantine.is_some().into());
 
         doc.add_object(s.metadata_fast, meta_fast);
@@ -1,7 +1,12 @@
 //! various utility types
 
 use common::v1::types::search::Order;
-use tantivy::query::{BooleanQuery, Occur, Query};
+use tantivy::{
+    DocAddress, DocId, Score, SegmentReader,
+    collector::{Collector, TopDocs},
+    columnar::{ColumnType, MonotonicallyMappableToU64},
+    query::{BooleanQuery, Occur, Query},
+};
 
 // TODO: copy Reindex and Doctype types to common
 
@@ -45,3 +50,107 @@ impl BqBuilder {
         Box::new(BooleanQuery::from(self.queries))
     }
 }
+
+/// escape a single json object key so it can be used as part of a fast field path
+pub fn escape_json_path_segment(segment: &str) -> String {
+    segment.replace('\\', r"\\").replace('.', r"\.")
+}
+
+/// order documents by a number stored inside a json fast field
+///
+/// `TopDocs::order_by_fast_field` only works for top level fields, so this reads
+/// the json column directly. documents without a value are treated as 0.
+pub fn order_by_json_number(
+    top_docs: TopDocs,
+    path: String,
+    order: Order,
+) -> impl Collector<Fruit = Vec<(f64, DocAddress)>> {
+    let sign = match order {
+        Order::Ascending => -1.0,
+        Order::Descending => 1.0,
+    };
+
+    top_docs.tweak_score(move |segment_reader: &SegmentReader| {
+        let column = segment_reader
+            .fast_fields()
+            .u64_lenient_for_type(
+                Some(&[ColumnType::I64, ColumnType::U64, ColumnType::F64]),
+                &path,
+            )
+            .ok()
+            .flatten();
+
+        move |doc: DocId, _score: Score| {
+            let value = column
+                .as_ref()
+                .and_then(|(col, ty)| {
+                    col.first(doc).map(|v| match ty {
+                        ColumnType::I64 => i64::from_u64(v) as f64,
+                        ColumnType::F64 => f64::from_u64(v),
+                        _ => v as f64,
+                    })
+                })
+                .unwrap_or(0.0);
+            value * sign
+        }
+    })
+}
+
+/// calculate the hotness of a thread for `ChannelSearchOrderField::Score`
+///
+/// every reaction on the first message counts as one point, and the score decays
+/// as the first message gets older
+// TODO: use per-reaction weights once the forum sorting config is on Channel
+pub fn hotness(points: u64, age_hours: f64) -> f64 {
+    let gravity: f64 = 1.8;
+    let dampening: f64 = 0.8;
+    let freshness: f64 = 2.0;
+
+    if points == 0 {
+        return 0.0;
+    }
+
+    let points_dampened = (points as f64).powf(dampening);
+    points_dampened / (age_hours.max(0.0) + freshness).powf(gravity)
+}
+
+/// order threads by their hotness at `now` (a unix timestamp in seconds)
+///
+/// hotness decays over time, so it's calculated while searching from the indexed
+/// reaction count and first message timestamp instead of being indexed itself
+pub fn order_by_hotness(
+    top_docs: TopDocs,
+    order: Order,
+    now: i64,
+) -> impl Collector<Fruit = Vec<(f64, DocAddress)>> {
+    let sign = match order {
+        Order::Ascending => -1.0,
+        Order::Descending => 1.0,
+    };
+
+    top_docs.tweak_score(move |segment_reader: &SegmentReader| {
+        let fast_fields = segment_reader.fast_fields();
+        let points = fast_fields
+            .u64_lenient_for_type(Some(&[ColumnType::U64]), "metadata_fast.reaction_count")
+            .ok()
+            .flatten();
+        let created_at = fast_fields
+            .u64_lenient_for_type(Some(&[ColumnType::I64]), "metadata_fast.first_message_at")
+            .ok()
+            .flatten();
+
+        move |doc: DocId, _score: Score| {
+            let points = points
+                .as_ref()
+                .and_then(|(col, _)| col.first(doc))
+                .unwrap_or(0);
+            let created_at = created_at
+                .as_ref()
+                .and_then(|(col, _)| col.first(doc))
+                .map(i64::from_u64)
+                .unwrap_or(now);
+            let age_hours = (now - created_at) as f64 / 3600.0;
+            hotness(points, age_hours) * sign
+        }
+    })
+}
@@ -0,0 +1,49 @@
+//! the http client shared with plugins
+
+use std::sync::Arc;
+
+use reqwest::{Client, header::ACCEPT};
+use url::Url;
+
+use crate::{error::UnfurlError, host::HostLimiter, net::AddrFilter};
+
+/// The unfurler's http client, for plugins that need to make their own requests
+///
+/// Requests go through the same address filter and per host limits as the
+/// unfurler's own requests.
+pub struct UnfurlClient {
+    pub(crate) client: Client,
+    pub(crate) filter: Arc<AddrFilter>,
+    pub(crate) hosts: HostLimiter,
+}
+
+impl UnfurlClient {
+    /// Fetch the body of this url, failing if it's larger than `max_bytes`
+    pub async fn get(
+        &self,
+        url: &Url,
+        accept: &str,
+        max_bytes: usize,
+    ) -> Result<Vec<u8>, UnfurlError> {
+        self.filter.check_url(url)?;
+
+        let _permit = self.hosts.acquire(url).await?;
+        let mut res = self
+            .client
+            .get(url.clone())
+            .header(ACCEPT, accept)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let mut body = Vec::new();
+        while let Some(chunk) = res.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() > max_bytes {
+                return Err(UnfurlError::Parse(format!("{url} is too large")));
+            }
+        }
+
+        Ok(body)
+    }
+}
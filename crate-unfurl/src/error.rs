@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use tokio::task::JoinError;
 
 #[derive(thiserror::Error, Debug)]
@@ -17,6 +19,12 @@ pub enum UnfurlError {
     #[error("Forbidden from unfurling this url")]
     Forbidden,
 
+    #[error("Timed out waiting for other requests to this host")]
+    HostBusy,
+
+    #[error(transparent)]
+    Shared(#[from] Arc<UnfurlError>),
+
     #[error(transparent)]
     JoinError(#[from] JoinError),
 
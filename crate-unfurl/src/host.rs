@@ -0,0 +1,114 @@
+//! per host politeness
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use moka::future::Cache;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+use url::Url;
+
+use crate::error::UnfurlError;
+
+/// how long to wait for a host's other requests before giving up
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Permission to send a request to a host
+///
+/// Hold this while reading the response, and drop it before sending another
+/// request to the same host.
+pub struct HostPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// limits how many requests are sent to each host and how often
+pub(crate) struct HostLimiter {
+    concurrency: usize,
+    interval: Duration,
+    hosts: Cache<String, Arc<HostSlot>>,
+}
+
+struct HostSlot {
+    semaphore: Arc<Semaphore>,
+
+    /// the earliest time the next request can start
+    next: Mutex<Instant>,
+}
+
+impl HostLimiter {
+    pub(crate) fn new(concurrency: usize, interval: Duration) -> Self {
+        Self {
+            concurrency: concurrency.max(1),
+            interval,
+            hosts: Cache::builder()
+                .max_capacity(10_000)
+                .time_to_idle(Duration::from_secs(60 * 10))
+                .build(),
+        }
+    }
+
+    /// wait until a request can be sent to this url's host
+    ///
+    /// the returned permit should be held until the response is done
+    pub(crate) async fn acquire(&self, url: &Url) -> Result<HostPermit, UnfurlError> {
+        tokio::time::timeout(ACQUIRE_TIMEOUT, self.wait(url))
+            .await
+            .map_err(|_| UnfurlError::HostBusy)
+    }
+
+    async fn wait(&self, url: &Url) -> HostPermit {
+        let host = url.host_str().unwrap_or_default().to_owned();
+        let slot = self
+            .hosts
+            .get_with(host, async {
+                Arc::new(HostSlot {
+                    semaphore: Arc::new(Semaphore::new(self.concurrency)),
+                    next: Mutex::new(Instant::now()),
+                })
+            })
+            .await;
+
+        let permit = slot
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("host semaphore is never closed");
+
+        let start = {
+            let mut next = slot.next.lock().unwrap();
+            let start = (*next).max(Instant::now());
+            *next = start + self.interval;
+            start
+        };
+        tokio::time::sleep_until(start).await;
+
+        HostPermit { _permit: permit }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_timeout() {
+        let hosts = HostLimiter::new(1, Duration::ZERO);
+        let url = Url::parse("https://example.com/a").unwrap();
+        let other = Url::parse("https://example.org/").unwrap();
+
+        let permit = hosts.acquire(&url).await.unwrap();
+        assert!(hosts.acquire(&other).await.is_ok());
+        assert!(matches!(
+            hosts.acquire(&url).await,
+            Err(UnfurlError::HostBusy)
+        ));
+
+        drop(permit);
+        assert!(hosts.acquire(&url).await.is_ok());
+    }
+}
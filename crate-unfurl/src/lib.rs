@@ -1,10 +1,18 @@
+pub mod client;
 pub mod error;
+mod host;
 pub mod logging;
+pub mod net;
 pub mod plugin;
+mod robots;
 pub mod unfurler;
 pub mod util;
 
+pub use client::UnfurlClient;
+pub use host::HostPermit;
+pub use net::AddrFilter;
 pub use plugin::UnfurlPlugin;
 pub use plugin::direct_media::DirectMediaPlugin;
 pub use plugin::html::HtmlStreamPlugin;
+pub use plugin::oembed::{OEmbedPlugin, OEmbedProvider};
 pub use unfurler::Unfurler;
@@ -0,0 +1,286 @@
+//! network level protection against fetching internal resources
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use ipnet::IpNet;
+use reqwest::{
+    ClientBuilder,
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect,
+};
+use url::{Host, Url};
+
+use crate::error::UnfurlError;
+
+/// the maximum number of redirects to follow
+const MAX_REDIRECTS: usize = 10;
+
+/// Decides which ip addresses the unfurler is allowed to connect to
+///
+/// Loopback, private, link-local, and other non-global addresses are always
+/// denied. Extra ranges can be denied with [`AddrFilter::deny`].
+#[derive(Debug, Clone, Default)]
+pub struct AddrFilter {
+    deny: Vec<IpNet>,
+}
+
+impl AddrFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Deny connecting to these networks in addition to the built in list
+    pub fn deny(mut self, nets: impl IntoIterator<Item = IpNet>) -> Self {
+        self.deny.extend(nets);
+        self
+    }
+
+    /// Whether connecting to this address is allowed
+    ///
+    /// Ipv6 addresses that embed an ipv4 address are checked as both.
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        let embedded = match addr {
+            IpAddr::V6(v6) => embedded_v4(v6).map(IpAddr::V4),
+            IpAddr::V4(_) => None,
+        };
+
+        std::iter::once(addr).chain(embedded).all(|addr| {
+            let global = match addr {
+                IpAddr::V4(v4) => is_global_v4(v4),
+                IpAddr::V6(v6) => is_global_v6(v6),
+            };
+            global && !self.deny.iter().any(|n| n.contains(&addr))
+        })
+    }
+
+    /// Resolve a domain, keeping only the addresses this filter allows
+    ///
+    /// Fails if none of the addresses are allowed. Clients can use this as
+    /// their dns resolver so that domains pointing to internal addresses
+    /// can't be fetched.
+    pub async fn resolve(&self, host: &str) -> Result<Vec<SocketAddr>, UnfurlError> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+            .await
+            .map_err(|err| UnfurlError::Other(err.into()))?
+            .filter(|a| self.allows(a.ip()))
+            .collect();
+
+        if addrs.is_empty() {
+            return Err(UnfurlError::Forbidden);
+        }
+
+        Ok(addrs)
+    }
+
+    /// Use this filter for every connection made by a client
+    ///
+    /// Domains are filtered when they're resolved and every redirect is
+    /// checked, but ip literals still need to go through [`AddrFilter::check_url`].
+    pub(crate) fn apply(self: Arc<Self>, builder: ClientBuilder) -> ClientBuilder {
+        builder
+            .dns_resolver(Arc::new(FilteredResolver {
+                filter: self.clone(),
+            }))
+            .redirect(self.redirect_policy())
+    }
+
+    /// Check a url before fetching it
+    ///
+    /// Domain names are checked when they're resolved, but ip literals never
+    /// go through the resolver and need to be checked here.
+    pub fn check_url(&self, url: &Url) -> Result<(), UnfurlError> {
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(UnfurlError::UnsupportedProtocol);
+        }
+
+        let ip = match url.host() {
+            Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+            Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+            Some(Host::Domain(_)) => return Ok(()),
+            None => return Err(UnfurlError::Forbidden),
+        };
+
+        if self.allows(ip) {
+            Ok(())
+        } else {
+            Err(UnfurlError::Forbidden)
+        }
+    }
+
+    /// A redirect policy that checks every url in the redirect chain
+    fn redirect_policy(self: Arc<Self>) -> redirect::Policy {
+        redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+
+            match self.check_url(attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(err) => attempt.error(err),
+            }
+        })
+    }
+}
+
+/// A dns resolver that drops addresses the [`AddrFilter`] denies
+///
+/// Since every connection (including redirects) resolves through this, it
+/// isn't possible to sneak past it with a domain that points to an internal
+/// address.
+struct FilteredResolver {
+    filter: Arc<AddrFilter>,
+}
+
+impl Resolve for FilteredResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let filter = self.filter.clone();
+        Box::pin(async move {
+            let addrs = filter.resolve(name.as_str()).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The ipv4 address inside an ipv4 mapped, ipv4 compatible, or 6to4 address
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return Some(v4);
+    }
+
+    match ip.segments() {
+        // `::` and `::1` are handled as ipv6
+        [0, 0, 0, 0, 0, 0, 0, 0 | 1] => None,
+        [0, 0, 0, 0, 0, 0, hi, lo] | [0x2002, hi, lo, ..] => {
+            let [a, b] = hi.to_be_bytes();
+            let [c, d] = lo.to_be_bytes();
+            Some(Ipv4Addr::new(a, b, c, d))
+        }
+        _ => None,
+    }
+}
+
+// these are the stable parts of the unstable `Ipv4Addr::is_global`
+fn is_global_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "this network"
+        || a == 0
+        // shared address space (cgnat)
+        || (a == 100 && (b & 0b1100_0000) == 64)
+        // ietf protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // benchmarking
+        || (a == 198 && (b & 0xfe) == 18)
+        // reserved
+        || a >= 240)
+}
+
+// these are the stable parts of the unstable `Ipv6Addr::is_global`
+fn is_global_v6(ip: Ipv6Addr) -> bool {
+    let seg = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // nat64 addresses can point anywhere
+        || (seg[0] == 0x64 && seg[1] == 0xff9b)
+        // discard only
+        || (seg[0] == 0x100 && seg[1..4] == [0, 0, 0])
+        // documentation
+        || (seg[0] == 0x2001 && seg[1] == 0xdb8)
+        // site local (deprecated)
+        || (seg[0] & 0xffc0) == 0xfec0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(filter: &AddrFilter, addr: &str) -> bool {
+        filter.allows(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn test_allows() {
+        let filter = AddrFilter::new();
+        for addr in ["1.1.1.1", "93.184.215.14", "2606:4700::1111"] {
+            assert!(allows(&filter, addr), "{addr} should be allowed");
+        }
+        for addr in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "64:ff9b::7f00:1",
+        ] {
+            assert!(!allows(&filter, addr), "{addr} should be denied");
+        }
+    }
+
+    #[test]
+    fn test_embedded_v4() {
+        let filter = AddrFilter::new();
+
+        // ipv4 mapped
+        assert!(!allows(&filter, "::ffff:127.0.0.1"));
+        assert!(!allows(&filter, "::ffff:10.0.0.1"));
+        assert!(allows(&filter, "::ffff:1.1.1.1"));
+
+        // ipv4 compatible
+        assert!(!allows(&filter, "::127.0.0.1"));
+        assert!(!allows(&filter, "::169.254.169.254"));
+        assert!(allows(&filter, "::1.1.1.1"));
+
+        // 6to4
+        assert!(!allows(&filter, "2002:7f00:1::"));
+        assert!(!allows(&filter, "2002:c0a8:101::1"));
+        assert!(allows(&filter, "2002:101:101::1"));
+    }
+
+    #[test]
+    fn test_deny() {
+        let filter = AddrFilter::new().deny(["1.1.1.0/24".parse().unwrap()]);
+        assert!(!allows(&filter, "1.1.1.1"));
+        assert!(!allows(&filter, "::ffff:1.1.1.1"));
+        assert!(!allows(&filter, "2002:101:101::1"));
+        assert!(allows(&filter, "1.0.0.1"));
+    }
+
+    #[test]
+    fn test_check_url() {
+        let filter = AddrFilter::new();
+        let check = |url: &str| filter.check_url(&Url::parse(url).unwrap());
+        assert!(check("https://example.com/").is_ok());
+        assert!(check("http://1.1.1.1/").is_ok());
+        assert!(matches!(
+            check("http://127.0.0.1/"),
+            Err(UnfurlError::Forbidden)
+        ));
+        assert!(matches!(
+            check("http://[::ffff:7f00:1]/"),
+            Err(UnfurlError::Forbidden)
+        ));
+        assert!(matches!(
+            check("ftp://example.com/"),
+            Err(UnfurlError::UnsupportedProtocol)
+        ));
+    }
+}
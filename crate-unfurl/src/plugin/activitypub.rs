@@ -0,0 +1,233 @@
+//! fediverse posts, from `<link rel="alternate" type="application/activity+json">`
+
+use std::cell::RefCell;
+
+use html5ever::{
+    local_name,
+    tendril::StrTendril,
+    tokenizer::{
+        BufferQueue, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+    },
+};
+use lamprey_common::v1::types::{EmbedType, Mime, util::Time};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::Value;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use url::Url;
+
+use crate::{
+    client::UnfurlClient,
+    error::UnfurlError,
+    util::{EmbedGenerationTemplate, EmbedMediaPending},
+};
+
+/// the maximum size of an activitypub object
+const MAX_OBJECT_BYTES: usize = 256 * 1024;
+
+const ACCEPT: &str = r#"application/activity+json, application/ld+json; profile="https://www.w3.org/ns/activitystreams""#;
+
+/// a post, article, or other object
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Object {
+    id: Option<Url>,
+    content: Option<String>,
+
+    /// the content warning, if `sensitive` is set
+    summary: Option<String>,
+
+    #[serde(default)]
+    sensitive: bool,
+
+    published: Option<String>,
+    attributed_to: Option<Value>,
+
+    #[serde(default)]
+    attachment: Value,
+
+    #[serde(skip)]
+    actor: Option<Actor>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Actor {
+    id: Option<Url>,
+    name: Option<String>,
+    preferred_username: Option<String>,
+    url: Option<Value>,
+    icon: Option<Value>,
+}
+
+/// fetch an object and its author
+///
+/// objects must be on the same host as the page linking to them, and authors
+/// must be on the same host as the object. otherwise, any page could claim to
+/// be written by anyone.
+pub(crate) async fn fetch(
+    client: &UnfurlClient,
+    page: &Url,
+    url: &Url,
+) -> Result<Object, UnfurlError> {
+    if url.host() != page.host() {
+        return Err(UnfurlError::Forbidden);
+    }
+
+    let mut object: Object = fetch_json(client, url).await?;
+    if object.id.as_ref().is_some_and(|id| id.host() != url.host()) {
+        return Err(UnfurlError::Forbidden);
+    }
+
+    object.actor = match object.attributed_to.as_ref().and_then(first) {
+        Some(Value::String(actor_url)) => match Url::parse(actor_url) {
+            Ok(actor_url) if actor_url.host() == url.host() => {
+                fetch_json(client, &actor_url).await.ok()
+            }
+            _ => None,
+        },
+        Some(v @ Value::Object(_)) => serde_json::from_value(v.clone()).ok(),
+        _ => None,
+    };
+
+    Ok(object)
+}
+
+async fn fetch_json<T: DeserializeOwned>(
+    client: &UnfurlClient,
+    url: &Url,
+) -> Result<T, UnfurlError> {
+    let body = client.get(url, ACCEPT, MAX_OBJECT_BYTES).await?;
+    serde_json::from_slice(&body).map_err(|e| UnfurlError::Parse(e.to_string()))
+}
+
+impl Object {
+    /// merge this object into an embed
+    pub(crate) fn apply(self, tmpl: &mut EmbedGenerationTemplate) {
+        // don't show hidden content or media in the preview
+        if self.sensitive {
+            tmpl.description = self
+                .summary
+                .filter(|s| !s.is_empty())
+                .map(|s| format!("CW: {s}"));
+            tmpl.media = None;
+            tmpl.thumbnail = None;
+        } else if let Some(content) = self.content.as_deref().map(html_to_text)
+            && !content.is_empty()
+        {
+            tmpl.description = Some(content);
+        }
+
+        if let Some(published) = self
+            .published
+            .and_then(|p| OffsetDateTime::parse(&p, &Rfc3339).ok())
+        {
+            tmpl.published_at = Some(Time::from(published));
+        }
+
+        if let Some(actor) = self.actor {
+            let handle = actor.preferred_username.as_ref().and_then(|u| {
+                let host = actor.id.as_ref()?.host_str()?;
+                Some(format!("@{u}@{host}"))
+            });
+            tmpl.author_name = match (actor.name.filter(|n| !n.is_empty()), handle) {
+                (Some(name), Some(handle)) => Some(format!("{name} ({handle})")),
+                (name, handle) => name.or(handle),
+            };
+            tmpl.author_url = actor
+                .url
+                .as_ref()
+                .and_then(first)
+                .and_then(url_of)
+                .or(actor.id);
+            if let Some(icon) = actor.icon.as_ref().and_then(first).and_then(url_of) {
+                tmpl.author_avatar = Some(EmbedMediaPending::new(icon).into());
+            }
+        }
+
+        if self.sensitive || tmpl.media.is_some() {
+            return;
+        }
+
+        let attachment = match &self.attachment {
+            Value::Array(a) => a.first(),
+            v @ Value::Object(_) => Some(v),
+            _ => None,
+        };
+        let Some(attachment) = attachment else {
+            return;
+        };
+
+        let mime: Option<Mime> = attachment
+            .get("mediaType")
+            .and_then(|m| m.as_str())
+            .and_then(|m| m.parse().ok());
+        let Some(url) = attachment.get("url").and_then(first).and_then(url_of) else {
+            return;
+        };
+        let is_media = mime.as_ref().is_some_and(|m| {
+            m.starts_with("image/") || m.starts_with("video/") || m.starts_with("audio/")
+        });
+        if !is_media {
+            return;
+        }
+
+        let mut pending = EmbedMediaPending::new(url);
+        if let Some(mime) = mime {
+            pending = pending.mime_guess(mime);
+        }
+        if let Some(alt) = attachment.get("name").and_then(|n| n.as_str()) {
+            pending = pending.alt(alt);
+        }
+        tmpl.ty = EmbedType::Link;
+        tmpl.media = Some(pending.into());
+        tmpl.thumbnail = None;
+    }
+}
+
+fn first(v: &Value) -> Option<&Value> {
+    match v {
+        Value::Array(a) => a.first(),
+        v => Some(v),
+    }
+}
+
+fn url_of(v: &Value) -> Option<Url> {
+    match v {
+        Value::String(s) => Url::parse(s).ok(),
+        Value::Object(o) => o.get("href").or_else(|| o.get("url")).and_then(url_of),
+        _ => None,
+    }
+}
+
+/// convert the html content of a post to plain text
+fn html_to_text(html: &str) -> String {
+    let tokenizer = Tokenizer::new(TextSink::default(), TokenizerOpts::default());
+    let queue = BufferQueue::default();
+    queue.push_back(StrTendril::from_slice(html));
+    let _ = tokenizer.feed(&queue);
+    tokenizer.end();
+    tokenizer.sink.text.take().trim().to_owned()
+}
+
+#[derive(Default)]
+struct TextSink {
+    text: RefCell<String>,
+}
+
+impl TokenSink for TextSink {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        let mut text = self.text.borrow_mut();
+        match token {
+            Token::CharacterTokens(s) => text.push_str(&s),
+            Token::TagToken(tag) => match (tag.kind, tag.name) {
+                (TagKind::StartTag, local_name!("br")) => text.push('\n'),
+                (TagKind::EndTag, local_name!("p")) => text.push_str("\n\n"),
+                _ => {}
+            },
+            _ => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
@@ -4,7 +4,9 @@ use reqwest::Response;
 use url::Url;
 
 use crate::{
+    client::UnfurlClient,
     error::UnfurlError,
+    host::HostPermit,
     plugin::UnfurlPlugin,
     unfurler::EmbedGeneration,
     util::{EmbedGenerationTemplate, EmbedMedia, EmbedMediaPending},
@@ -31,6 +33,8 @@ impl UnfurlPlugin for DirectMediaPlugin {
         &self,
         url: &Url,
         res: Response,
+        _permit: HostPermit,
+        _client: &UnfurlClient,
     ) -> Result<Vec<EmbedGeneration>, UnfurlError> {
         // Extract basic mime info
         let ct_str = res
@@ -60,6 +64,10 @@ impl UnfurlPlugin for DirectMediaPlugin {
                 author_avatar: None,
                 site_name: None,
                 site_avatar: None,
+                player: None,
+                published_at: None,
+                price: None,
+                event: None,
             },
         }])
     }
@@ -6,15 +6,23 @@ use html5ever::{
     tendril::StrTendril,
     tokenizer::{
         BufferQueue, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+        states::RawKind,
     },
 };
-use lamprey_common::v1::types::{EmbedType, misc::Color};
+use lamprey_common::v1::types::{EmbedPlayer, EmbedType, misc::Color, util::Time};
 use reqwest::Response;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tracing::debug;
 use url::Url;
 
 use crate::{
+    client::UnfurlClient,
     error::UnfurlError,
-    plugin::UnfurlPlugin,
+    host::HostPermit,
+    plugin::{
+        UnfurlPlugin, activitypub,
+        linked_data::{ItemKind, LinkedData},
+    },
     unfurler::EmbedGeneration,
     util::{EmbedGenerationTemplate, EmbedMediaPending},
 };
@@ -40,8 +48,21 @@ impl UnfurlPlugin for HtmlStreamPlugin {
     async fn process_response(
         &self,
         url: &Url,
-        mut res: Response,
+        res: Response,
+        permit: HostPermit,
+        client: &UnfurlClient,
     ) -> Result<Vec<EmbedGeneration>, UnfurlError> {
+        let data = self.extract(res).await?;
+        drop(permit);
+        Ok(vec![EmbedGeneration {
+            embed: generate(url, data, client).await,
+        }])
+    }
+}
+
+impl HtmlStreamPlugin {
+    /// stream an html page into the parser, stopping after `max_bytes`
+    pub(crate) async fn extract(&self, mut res: Response) -> Result<ExtractedData, UnfurlError> {
         let (tx, mut rx) = tokio::sync::mpsc::channel::<bytes::Bytes>(32);
 
         // the html parser is !Send due to Rc, so spawn a blocking task
@@ -51,7 +72,7 @@ impl UnfurlPlugin for HtmlStreamPlugin {
                 data: shared_data.clone(),
             };
             let tokenizer = Tokenizer::new(sink, TokenizerOpts::default());
-            let mut queue = BufferQueue::default();
+            let queue = BufferQueue::default();
 
             // In your spawn_blocking task:
             let mut tail = Vec::new();
@@ -59,7 +80,7 @@ impl UnfurlPlugin for HtmlStreamPlugin {
             while let Some(chunk) = rx.blocking_recv() {
                 let s = decode_chunk(&mut tail, &chunk);
                 queue.push_back(StrTendril::from_slice(&s));
-                let _ = tokenizer.feed(&mut queue);
+                let _ = tokenizer.feed(&queue);
             }
 
             tokenizer.end();
@@ -83,103 +104,204 @@ impl UnfurlPlugin for HtmlStreamPlugin {
 
         drop(tx);
 
-        let data = parse_task.await?;
-
-        let image_mode = determine_image_mode(&data);
-
-        let mut tmpl = EmbedGenerationTemplate {
-            ty: EmbedType::Link,
-            url: Some(url.clone()),
-            canonical_url: data.canonical_url.and_then(|u| url.join(&u).ok()),
-            title: data.og_title.or(data.twitter_title).or(data.title),
-            description: data.og_description.or(data.description),
-            site_name: data.og_site_name,
-            color: data
-                .theme_color
-                .as_ref()
-                .and_then(|c| Color::from_str(c).ok()),
-            media: None,
-            thumbnail: None,
-            author_name: None,
-            author_url: None,
-            author_avatar: None,
-            site_avatar: None,
-            // TODO: handle favicon as site_avatar
-            // i need some way to avoid constantly refetching the same favicon though...
-            // site_avatar: data.favicon_url.and_then(|u| url.join(&u).ok()).map(|u| {
-            //     EmbedMediaPending::new(u)
-            //         .mime_guess("image/x-icon".parse().unwrap())
-            //         .into()
-            // }),
-        };
+        Ok(parse_task.await?)
+    }
+}
 
-        // Handle nested/recursive media
-        let og_type = data.og_type.as_deref().unwrap_or("website");
-        let is_media = matches!(
-            og_type,
-            "video"
-                | "video.movie"
-                | "video.episode"
-                | "video.tv_show"
-                | "video.other"
-                | "music.song"
-                | "music.album"
-                | "music.playlist"
-                | "music.radio_station"
-        );
+/// build an embed from the data extracted from an html page, including any
+/// activitypub object it links to
+pub(crate) async fn generate(
+    url: &Url,
+    mut data: ExtractedData,
+    client: &UnfurlClient,
+) -> EmbedGenerationTemplate {
+    let activity_url = data.activity_url.take().and_then(|u| url.join(&u).ok());
+    let mut tmpl = template(url, data);
+
+    if let Some(activity_url) = activity_url {
+        match activitypub::fetch(client, url, &activity_url).await {
+            Ok(object) => object.apply(&mut tmpl),
+            Err(err) => debug!("failed to fetch activitypub object for {url}: {err}"),
+        }
+    }
 
-        // TODO: parse mime types from url? likely unnecessary if the media importer system autodetects mime anyways
-        if is_media && !data.videos.is_empty() {
-            let video_type = data.video_types.first().and_then(|t| t.as_ref());
-            if video_type.map(|s| s.as_str()) == Some("text/html") {
-                // html videos (iframes) aren't allowed
-                tmpl.ty = EmbedType::Link;
-            } else {
-                tmpl.ty = EmbedType::Media;
-                if let Ok(v_url) = url.join(&data.videos[0]) {
-                    tmpl.media = Some(
-                        EmbedMediaPending::new(v_url)
-                            .mime_guess("video/mp4".parse().unwrap())
+    tmpl
+}
+
+/// build an embed from the data extracted from an html page
+pub(crate) fn template(url: &Url, data: ExtractedData) -> EmbedGenerationTemplate {
+    let image_mode = determine_image_mode(&data);
+    let linked = LinkedData::parse(&data.ld_json);
+
+    let mut tmpl = EmbedGenerationTemplate {
+        ty: EmbedType::Link,
+        url: Some(url.clone()),
+        canonical_url: data.canonical_url.and_then(|u| url.join(&u).ok()),
+        title: data.og_title.or(data.twitter_title).or(data.title),
+        description: data
+            .og_description
+            .or(data.description)
+            .or(data.twitter_description),
+        site_name: data.og_site_name,
+        color: data
+            .theme_color
+            .as_ref()
+            .and_then(|c| Color::from_str(c).ok()),
+        media: None,
+        thumbnail: None,
+        // some sites put a profile url in article:author
+        author_name: data.author_name.filter(|a| Url::parse(a).is_err()),
+        author_url: data.author_url.and_then(|u| url.join(&u).ok()),
+        author_avatar: None,
+        site_avatar: None,
+        player: None,
+        published_at: data
+            .published_time
+            .and_then(|t| OffsetDateTime::parse(&t, &Rfc3339).ok())
+            .map(Time::from),
+        price: None,
+        event: None,
+        // TODO: handle favicon as site_avatar
+        // i need some way to avoid constantly refetching the same favicon though...
+        // site_avatar: data.favicon_url.and_then(|u| url.join(&u).ok()).map(|u| {
+        //     EmbedMediaPending::new(u)
+        //         .mime_guess("image/x-icon".parse().unwrap())
+        //         .into()
+        // }),
+    };
+
+    // Handle nested/recursive media
+    let og_type = data.og_type.as_deref().unwrap_or("website");
+    let is_media = matches!(
+        og_type,
+        "video"
+            | "video.movie"
+            | "video.episode"
+            | "video.tv_show"
+            | "video.other"
+            | "music.song"
+            | "music.album"
+            | "music.playlist"
+            | "music.radio_station"
+    );
+
+    // TODO: parse mime types from url? likely unnecessary if the media importer system autodetects mime anyways
+    if is_media && !data.videos.is_empty() {
+        let video_type = data.video_types.first().and_then(|t| t.as_ref());
+        if video_type.map(|s| s.as_str()) == Some("text/html") {
+            // html videos (iframes) aren't allowed
+            tmpl.ty = EmbedType::Link;
+        } else {
+            tmpl.ty = EmbedType::Media;
+            if let Ok(v_url) = url.join(&data.videos[0]) {
+                tmpl.media = Some(
+                    EmbedMediaPending::new(v_url)
+                        .mime_guess("video/mp4".parse().unwrap())
+                        .into(),
+                );
+
+                if let Some(img) = data.images.first()
+                    && let Ok(i_url) = url.join(img)
+                {
+                    tmpl.thumbnail = Some(
+                        EmbedMediaPending::new(i_url)
+                            .mime_guess("image/jpeg".parse().unwrap())
                             .into(),
                     );
-
-                    if let Some(img) = data.images.first() {
-                        if let Ok(i_url) = url.join(img) {
-                            tmpl.thumbnail = Some(
-                                EmbedMediaPending::new(i_url)
-                                    .mime_guess("image/jpeg".parse().unwrap())
-                                    .into(),
-                            );
-                        }
-                    }
                 }
             }
-        } else if let Some(img) = data.images.first() {
-            if let Ok(i_url) = url.join(img) {
-                // NOTE: there might be cases where i want to include full media *and* a thumbnail?
-                match image_mode {
-                    ImageMode::Hide => {}
-                    ImageMode::Full => {
-                        tmpl.media = Some(
-                            EmbedMediaPending::new(i_url)
-                                .mime_guess("image/jpeg".parse().unwrap())
-                                .into(),
-                        );
-                    }
-                    ImageMode::Thumb => {
-                        tmpl.thumbnail = Some(
-                            EmbedMediaPending::new(i_url)
-                                .mime_guess("image/jpeg".parse().unwrap())
-                                .into(),
-                        );
-                    }
-                }
+        }
+    } else if let Some(img) = data.images.first()
+        && let Ok(i_url) = url.join(img)
+    {
+        // NOTE: there might be cases where i want to include full media *and* a thumbnail?
+        match image_mode {
+            ImageMode::Hide => {}
+            ImageMode::Full => {
+                tmpl.media = Some(
+                    EmbedMediaPending::new(i_url)
+                        .mime_guess("image/jpeg".parse().unwrap())
+                        .into(),
+                );
+            }
+            ImageMode::Thumb => {
+                tmpl.thumbnail = Some(
+                    EmbedMediaPending::new(i_url)
+                        .mime_guess("image/jpeg".parse().unwrap())
+                        .into(),
+                );
             }
         }
+    }
 
-        // TODO: handle rel=me and RSS feeds if needed later...
+    if let Some(linked) = linked {
+        apply_linked_data(url, linked, image_mode, &mut tmpl);
+    }
 
-        Ok(vec![EmbedGeneration { embed: tmpl }])
+    // TODO: handle rel=me and RSS feeds if needed later...
+
+    tmpl
+}
+
+/// merge schema.org data into an embed
+///
+/// meta tags are usually written for embeds, so they're preferred for the
+/// title, description, and image. json-ld is preferred for everything else.
+fn apply_linked_data(
+    url: &Url,
+    linked: LinkedData,
+    image_mode: ImageMode,
+    tmpl: &mut EmbedGenerationTemplate,
+) {
+    tmpl.title = tmpl.title.take().or(linked.title);
+    tmpl.description = tmpl.description.take().or(linked.description);
+    tmpl.site_name = tmpl.site_name.take().or(linked.site_name);
+
+    if let Some(name) = linked.author_name {
+        tmpl.author_name = Some(name);
+        tmpl.author_url = linked.author_url.and_then(|u| url.join(&u).ok());
+        if let Some(avatar) = linked.author_avatar.and_then(|u| url.join(&u).ok()) {
+            tmpl.author_avatar = Some(EmbedMediaPending::new(avatar).into());
+        }
+    }
+
+    tmpl.published_at = linked.published_at.or(tmpl.published_at);
+    tmpl.price = linked.price;
+    tmpl.event = linked.event;
+
+    if linked.kind == Some(ItemKind::Video) {
+        tmpl.player = linked
+            .player
+            .and_then(|u| url.join(&u).ok())
+            .filter(|u| u.scheme() == "https")
+            .map(|url| EmbedPlayer {
+                url,
+                width: None,
+                height: None,
+            });
+
+        if tmpl.media.is_none()
+            && let Some(video) = linked.video.and_then(|u| url.join(&u).ok())
+        {
+            tmpl.ty = EmbedType::Media;
+            tmpl.media = Some(
+                EmbedMediaPending::new(video)
+                    .mime_guess("video/mp4".parse().unwrap())
+                    .into(),
+            );
+        }
+    }
+
+    if tmpl.media.is_none()
+        && tmpl.thumbnail.is_none()
+        && image_mode != ImageMode::Hide
+        && let Some(image) = linked.image.and_then(|u| url.join(&u).ok())
+    {
+        tmpl.thumbnail = Some(
+            EmbedMediaPending::new(image)
+                .mime_guess("image/jpeg".parse().unwrap())
+                .into(),
+        );
     }
 }
 
@@ -257,7 +379,7 @@ fn parse_robots_image_preview(content: &str) -> Option<RobotsImagePreview> {
 
 /// Merges `tail` with `chunk`, returning the valid UTF-8 string and
 /// storing any incomplete trailing bytes back into `tail`.
-fn decode_chunk<'a>(tail: &mut Vec<u8>, chunk: &[u8]) -> String {
+fn decode_chunk(tail: &mut Vec<u8>, chunk: &[u8]) -> String {
     let bytes = if tail.is_empty() {
         chunk.to_vec()
     } else {
@@ -277,7 +399,7 @@ fn decode_chunk<'a>(tail: &mut Vec<u8>, chunk: &[u8]) -> String {
 }
 
 #[derive(Default, Debug)]
-struct ExtractedData {
+pub(crate) struct ExtractedData {
     in_title: bool,
     current_title: String,
 
@@ -310,6 +432,20 @@ struct ExtractedData {
     feeds: Vec<String>,
     rel_me: Vec<String>,
 
+    /// the url of a json oembed endpoint for this page
+    pub(crate) oembed_url: Option<String>,
+
+    /// the url of an activitypub object for this page
+    pub(crate) activity_url: Option<String>,
+
+    published_time: Option<String>,
+
+    in_ld_json: bool,
+    current_ld_json: String,
+
+    /// the contents of each `<script type="application/ld+json">`
+    ld_json: Vec<String>,
+
     twitter_card: Option<String>,
     robots_max_image_preview: Option<RobotsImagePreview>,
 }
@@ -327,8 +463,10 @@ struct MetaSink {
     data: Rc<RefCell<ExtractedData>>,
 }
 
+/// the maximum total size of json-ld to keep
+const MAX_LD_JSON_BYTES: usize = 256 * 1024;
+
 // TODO: parse microdata (<anything itemprop="foo">)
-// TODO: parse linked json (<script type="application/ld+json">)
 
 impl TokenSink for MetaSink {
     type Handle = ();
@@ -343,6 +481,18 @@ impl TokenSink for MetaSink {
                             data.in_title = true;
                             data.current_title.clear();
                         }
+                        local_name!("script") => {
+                            let is_ld_json = tag.attrs.iter().any(|a| {
+                                a.name.local == local_name!("type")
+                                    && a.value.trim().eq_ignore_ascii_case("application/ld+json")
+                            });
+                            let mut data = self.data.borrow_mut();
+                            data.in_ld_json = is_ld_json;
+                            data.current_ld_json.clear();
+
+                            // don't parse the script's contents as html
+                            return TokenSinkResult::RawData(RawKind::ScriptData);
+                        }
                         local_name!("meta") => {
                             let mut name = None;
                             let mut property = None;
@@ -384,10 +534,9 @@ impl TokenSink for MetaSink {
                                     }
 
                                     "author" => data.author_name = Some(content),
-                                    "article:author" => {
-                                        if data.author_name.is_none() {
-                                            data.author_name = Some(content);
-                                        }
+                                    "article:published_time" => data.published_time = Some(content),
+                                    "article:author" if data.author_name.is_none() => {
+                                        data.author_name = Some(content);
                                     }
                                     "profile:image" | "twitter:creator:image" => {
                                         data.author_avatar = Some(content)
@@ -427,11 +576,9 @@ impl TokenSink for MetaSink {
 
                                 for r in rels {
                                     match r.to_lowercase().as_str() {
-                                        "icon" => {
-                                            // TODO: make some icon rels higher priority than others?
-                                            if data.favicon_url.is_none() {
-                                                data.favicon_url = Some(href.clone());
-                                            }
+                                        // TODO: make some icon rels higher priority than others?
+                                        "icon" if data.favicon_url.is_none() => {
+                                            data.favicon_url = Some(href.clone());
                                         }
                                         "apple-touch-icon" => {
                                             data.apple_touch_icon_url = Some(href.clone());
@@ -447,6 +594,16 @@ impl TokenSink for MetaSink {
                                                     || lt == "application/json"
                                                 {
                                                     data.feeds.push(href.clone());
+                                                } else if lt == "application/json+oembed"
+                                                    && data.oembed_url.is_none()
+                                                {
+                                                    data.oembed_url = Some(href.clone());
+                                                } else if (lt == "application/activity+json"
+                                                    || (lt.starts_with("application/ld+json")
+                                                        && lt.contains("activitystreams")))
+                                                    && data.activity_url.is_none()
+                                                {
+                                                    data.activity_url = Some(href.clone());
                                                 }
                                             }
                                         }
@@ -458,7 +615,17 @@ impl TokenSink for MetaSink {
                         _ => {}
                     }
                 } else if tag.kind == TagKind::EndTag {
-                    if tag.name == local_name!("title") {
+                    if tag.name == local_name!("script") {
+                        let mut data = self.data.borrow_mut();
+                        if data.in_ld_json {
+                            data.in_ld_json = false;
+                            let json = std::mem::take(&mut data.current_ld_json);
+                            let total: usize = data.ld_json.iter().map(|j| j.len()).sum();
+                            if total + json.len() <= MAX_LD_JSON_BYTES {
+                                data.ld_json.push(json);
+                            }
+                        }
+                    } else if tag.name == local_name!("title") {
                         let mut data = self.data.borrow_mut();
                         data.in_title = false;
                         if data.title.is_none() {
@@ -473,6 +640,8 @@ impl TokenSink for MetaSink {
                 let mut data = self.data.borrow_mut();
                 if data.in_title {
                     data.current_title.push_str(&s);
+                } else if data.in_ld_json && data.current_ld_json.len() < MAX_LD_JSON_BYTES {
+                    data.current_ld_json.push_str(&s);
                 }
             }
             _ => {}
@@ -0,0 +1,259 @@
+//! schema.org metadata from `<script type="application/ld+json">` blocks
+
+use lamprey_common::v1::types::{EmbedEvent, EmbedPrice, util::Time};
+use serde_json::Value;
+use time::{
+    Date, OffsetDateTime, PrimitiveDateTime,
+    format_description::well_known::{Iso8601, Rfc3339},
+};
+
+/// the kinds of schema.org items that are worth embedding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ItemKind {
+    Article,
+    Product,
+    Recipe,
+    Video,
+    Event,
+}
+
+/// the useful parts of the main schema.org item on a page
+#[derive(Debug, Default)]
+pub(crate) struct LinkedData {
+    pub kind: Option<ItemKind>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub author_name: Option<String>,
+    pub author_url: Option<String>,
+    pub author_avatar: Option<String>,
+    pub site_name: Option<String>,
+    pub published_at: Option<Time>,
+    pub price: Option<EmbedPrice>,
+    pub event: Option<EmbedEvent>,
+
+    /// a direct link to a video file
+    pub video: Option<String>,
+
+    /// an iframe player for a video
+    pub player: Option<String>,
+}
+
+impl LinkedData {
+    /// find the first embeddable item in some json-ld blocks
+    pub(crate) fn parse(blocks: &[String]) -> Option<Self> {
+        let values: Vec<Value> = blocks
+            .iter()
+            .filter_map(|b| serde_json::from_str(b).ok())
+            .collect();
+
+        let mut nodes = vec![];
+        for v in &values {
+            flatten(v, &mut nodes);
+        }
+
+        nodes
+            .into_iter()
+            .find_map(|n| item_kind(n).map(|k| Self::from_item(n, k)))
+    }
+
+    fn from_item(item: &Value, kind: ItemKind) -> Self {
+        let (author_name, author_url, author_avatar) = item
+            .get("author")
+            .or_else(|| item.get("creator"))
+            .or_else(|| item.get("organizer"))
+            .map(person)
+            .unwrap_or_default();
+
+        let mut data = LinkedData {
+            kind: Some(kind),
+            title: item
+                .get("headline")
+                .or_else(|| item.get("name"))
+                .and_then(text),
+            description: item.get("description").and_then(text),
+            image: item
+                .get("image")
+                .or_else(|| item.get("thumbnailUrl"))
+                .and_then(url_of),
+            author_name,
+            author_url,
+            author_avatar,
+            site_name: item
+                .get("publisher")
+                .and_then(|p| p.get("name"))
+                .and_then(text),
+            published_at: item
+                .get("datePublished")
+                .or_else(|| item.get("uploadDate"))
+                .and_then(date),
+            ..Default::default()
+        };
+
+        match kind {
+            ItemKind::Article | ItemKind::Recipe => {}
+            ItemKind::Product => {
+                data.price = item.get("offers").and_then(price);
+                if data.author_name.is_none() {
+                    data.author_name = item
+                        .get("brand")
+                        .and_then(|b| b.get("name").or(Some(b)))
+                        .and_then(text);
+                }
+            }
+            ItemKind::Video => {
+                data.video = item.get("contentUrl").and_then(text);
+                data.player = item.get("embedUrl").and_then(text);
+            }
+            ItemKind::Event => {
+                data.price = item.get("offers").and_then(price);
+                data.event = Some(EmbedEvent {
+                    start_at: item.get("startDate").and_then(date),
+                    end_at: item.get("endDate").and_then(date),
+                    location: item.get("location").and_then(location),
+                });
+            }
+        }
+
+        data
+    }
+}
+
+/// collect every node in a json-ld document, including `@graph`s
+fn flatten<'a>(v: &'a Value, out: &mut Vec<&'a Value>) {
+    match v {
+        Value::Array(items) => items.iter().for_each(|i| flatten(i, out)),
+        Value::Object(obj) => {
+            out.push(v);
+            if let Some(graph) = obj.get("@graph") {
+                flatten(graph, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn item_kind(node: &Value) -> Option<ItemKind> {
+    let types: Vec<&str> = match node.get("@type")? {
+        Value::String(s) => vec![s],
+        Value::Array(a) => a.iter().filter_map(|t| t.as_str()).collect(),
+        _ => return None,
+    };
+
+    types.into_iter().find_map(|t| {
+        // types can be full iris or prefixed
+        let t = t.rsplit(['/', ':']).next().unwrap_or(t);
+        match t {
+            "BlogPosting" | "LiveBlogPosting" | "SocialMediaPosting" | "Report" => {
+                Some(ItemKind::Article)
+            }
+            "Product" | "ProductGroup" | "ProductModel" => Some(ItemKind::Product),
+            "Recipe" => Some(ItemKind::Recipe),
+            "VideoObject" => Some(ItemKind::Video),
+            t if t.ends_with("Article") => Some(ItemKind::Article),
+            t if t.ends_with("Event") => Some(ItemKind::Event),
+            _ => None,
+        }
+    })
+}
+
+/// get a non-empty string, or the first string in an array
+fn text(v: &Value) -> Option<String> {
+    match v {
+        Value::String(s) => Some(s.trim().to_owned()).filter(|s| !s.is_empty()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Array(a) => a.iter().find_map(text),
+        _ => None,
+    }
+}
+
+/// get a url from a string or an object like ImageObject
+fn url_of(v: &Value) -> Option<String> {
+    match v {
+        Value::Object(o) => o
+            .get("url")
+            .or_else(|| o.get("contentUrl"))
+            .or_else(|| o.get("@id"))
+            .and_then(text),
+        Value::Array(a) => a.iter().find_map(url_of),
+        v => text(v),
+    }
+}
+
+/// get the name, url, and image of a Person or Organization
+fn person(v: &Value) -> (Option<String>, Option<String>, Option<String>) {
+    match v {
+        Value::Object(o) => (
+            o.get("name").and_then(text),
+            o.get("url").and_then(text),
+            o.get("image").and_then(url_of),
+        ),
+        Value::Array(a) => a.first().map(person).unwrap_or_default(),
+        v => (text(v), None, None),
+    }
+}
+
+/// parse a date, which may or may not have a time or offset
+fn date(v: &Value) -> Option<Time> {
+    let s = text(v)?;
+    OffsetDateTime::parse(&s, &Rfc3339)
+        .or_else(|_| OffsetDateTime::parse(&s, &Iso8601::DEFAULT))
+        .or_else(|_| PrimitiveDateTime::parse(&s, &Iso8601::DEFAULT).map(|d| d.assume_utc()))
+        .or_else(|_| Date::parse(&s, &Iso8601::DEFAULT).map(|d| d.midnight().assume_utc()))
+        .ok()
+        .map(Time::from)
+}
+
+/// get the price from an Offer or AggregateOffer
+fn price(v: &Value) -> Option<EmbedPrice> {
+    match v {
+        Value::Array(a) => a.iter().find_map(price),
+        Value::Object(o) => {
+            let amount = o
+                .get("price")
+                .or_else(|| o.get("lowPrice"))
+                .and_then(text)
+                .or_else(|| {
+                    o.get("priceSpecification")
+                        .and_then(price)
+                        .map(|p| p.amount)
+                })?;
+            Some(EmbedPrice {
+                amount,
+                currency: o.get("priceCurrency").and_then(text),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// get a readable location from a Place, PostalAddress, or VirtualLocation
+fn location(v: &Value) -> Option<String> {
+    match v {
+        Value::Array(a) => a.iter().find_map(location),
+        Value::Object(o) => {
+            let name = o.get("name").and_then(text);
+            let address = o.get("address").and_then(location).or_else(|| {
+                let parts: Vec<String> = [
+                    "streetAddress",
+                    "addressLocality",
+                    "addressRegion",
+                    "addressCountry",
+                ]
+                .iter()
+                .filter_map(|k| o.get(*k))
+                .filter_map(|p| p.get("name").or(Some(p)).and_then(text))
+                .collect();
+                (!parts.is_empty()).then(|| parts.join(", "))
+            });
+
+            match (name, address) {
+                (Some(n), Some(a)) if !a.starts_with(&n) => Some(format!("{n}, {a}")),
+                (_, Some(a)) => Some(a),
+                (Some(n), None) => Some(n),
+                (None, None) => o.get("url").and_then(text),
+            }
+        }
+        v => text(v),
+    }
+}
@@ -2,10 +2,15 @@ use async_trait::async_trait;
 use reqwest::Response;
 use url::Url;
 
-use crate::{error::UnfurlError, unfurler::EmbedGeneration};
+use crate::{
+    client::UnfurlClient, error::UnfurlError, host::HostPermit, unfurler::EmbedGeneration,
+};
 
+mod activitypub;
 pub mod direct_media;
 pub mod html;
+mod linked_data;
+pub mod oembed;
 
 #[async_trait]
 pub trait UnfurlPlugin: Send + Sync {
@@ -16,7 +21,13 @@ pub trait UnfurlPlugin: Send + Sync {
     ///
     /// Use this for custom protocols (`magnet://`) or specific API targets (`youtube.com`).
     /// Return `Ok(Some(EmbedGeneration))` to short-circuit the HTTP request entirely.
-    async fn process_url(&self, _url: &Url) -> Result<Option<Vec<EmbedGeneration>>, UnfurlError> {
+    ///
+    /// `client` is the unfurler's http client, for plugins that need to make their own requests.
+    async fn process_url(
+        &self,
+        _url: &Url,
+        _client: &UnfurlClient,
+    ) -> Result<Option<Vec<EmbedGeneration>>, UnfurlError> {
         Ok(None)
     }
 
@@ -25,10 +36,14 @@ pub trait UnfurlPlugin: Send + Sync {
 
     /// Generate an embed from this http response.
     ///
-    /// This takes ownership of the `reqwest::Response` stream.
+    /// This takes ownership of the `reqwest::Response` stream. `permit` counts
+    /// against the response's host limit, so drop it once the body is read and
+    /// before making more requests with `client`.
     async fn process_response(
         &self,
         url: &Url,
         res: Response,
+        permit: HostPermit,
+        client: &UnfurlClient,
     ) -> Result<Vec<EmbedGeneration>, UnfurlError>;
 }
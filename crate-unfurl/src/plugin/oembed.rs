@@ -0,0 +1,362 @@
+use std::cell::RefCell;
+
+use async_trait::async_trait;
+use html5ever::{
+    local_name,
+    tendril::StrTendril,
+    tokenizer::{
+        BufferQueue, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+    },
+};
+use lamprey_common::v1::types::{EmbedPlayer, EmbedType};
+use reqwest::Response;
+use serde::Deserialize;
+use tracing::debug;
+use url::Url;
+
+use crate::{
+    client::UnfurlClient,
+    error::UnfurlError,
+    host::HostPermit,
+    plugin::{
+        UnfurlPlugin,
+        html::{HtmlStreamPlugin, generate},
+    },
+    unfurler::EmbedGeneration,
+    util::{EmbedGenerationTemplate, EmbedMediaPending, glob_match},
+};
+
+/// the maximum size of an oembed response
+const MAX_OEMBED_BYTES: usize = 64 * 1024;
+
+/// Generate embeds from [oembed](https://oembed.com) responses
+///
+/// Urls matching a registered provider are sent straight to that provider.
+/// Otherwise, html pages are parsed like [`HtmlStreamPlugin`], and any
+/// `<link rel="alternate" type="application/json+oembed">` is fetched and merged
+/// on top of the page's metadata.
+pub struct OEmbedPlugin {
+    html: HtmlStreamPlugin,
+    providers: Vec<OEmbedProvider>,
+    discovery: bool,
+}
+
+/// A site that serves oembed responses for some urls
+#[derive(Debug, Clone)]
+pub struct OEmbedProvider {
+    /// The name of the provider, used as the site name if the response doesn't have one
+    pub name: String,
+
+    /// Url patterns this provider handles, where `*` matches anything (eg. `https://*.example.com/watch/*`)
+    pub schemes: Vec<String>,
+
+    /// The oembed endpoint. `url` and `format=json` are added to the query string.
+    pub endpoint: Url,
+}
+
+impl OEmbedProvider {
+    /// Whether this provider handles this url
+    pub fn matches(&self, url: &Url) -> bool {
+        self.schemes.iter().any(|s| glob_match(s, url.as_str()))
+    }
+
+    fn request_url(&self, url: &Url) -> Url {
+        let mut endpoint = self.endpoint.clone();
+        endpoint
+            .query_pairs_mut()
+            .append_pair("url", url.as_str())
+            .append_pair("format", "json");
+        endpoint
+    }
+}
+
+impl OEmbedPlugin {
+    /// Create a new oembed plugin, reading up to `max_bytes` of each html page
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            html: HtmlStreamPlugin { max_bytes },
+            providers: Vec::new(),
+            discovery: true,
+        }
+    }
+
+    /// Register a provider
+    pub fn provider(mut self, provider: OEmbedProvider) -> Self {
+        self.providers.push(provider);
+        self
+    }
+
+    /// Whether to look for oembed links in html pages (default true)
+    ///
+    /// If disabled, this plugin only handles urls with a registered provider.
+    pub fn discovery(mut self, discovery: bool) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    async fn fetch(
+        &self,
+        client: &UnfurlClient,
+        endpoint: &Url,
+    ) -> Result<OEmbedResponse, UnfurlError> {
+        let body = client
+            .get(endpoint, "application/json", MAX_OEMBED_BYTES)
+            .await?;
+        serde_json::from_slice(&body).map_err(|e| UnfurlError::Parse(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl UnfurlPlugin for OEmbedPlugin {
+    fn name(&self) -> &'static str {
+        "OEmbedPlugin"
+    }
+
+    async fn process_url(
+        &self,
+        url: &Url,
+        client: &UnfurlClient,
+    ) -> Result<Option<Vec<EmbedGeneration>>, UnfurlError> {
+        let Some(provider) = self.providers.iter().find(|p| p.matches(url)) else {
+            return Ok(None);
+        };
+
+        // fall back to fetching the page if the provider doesn't work
+        let oembed = match self.fetch(client, &provider.request_url(url)).await {
+            Ok(oembed) => oembed,
+            Err(err) => {
+                debug!("oembed provider {} failed for {url}: {err}", provider.name);
+                return Ok(None);
+            }
+        };
+
+        let mut tmpl = EmbedGenerationTemplate {
+            ty: EmbedType::Link,
+            url: Some(url.clone()),
+            canonical_url: None,
+            title: None,
+            description: None,
+            color: None,
+            media: None,
+            thumbnail: None,
+            author_name: None,
+            author_url: None,
+            author_avatar: None,
+            site_name: Some(provider.name.clone()),
+            site_avatar: None,
+            player: None,
+            published_at: None,
+            price: None,
+            event: None,
+        };
+        oembed.apply(&provider.endpoint, &mut tmpl);
+
+        Ok(Some(vec![EmbedGeneration { embed: tmpl }]))
+    }
+
+    fn accepts_response(&self, res: &Response) -> bool {
+        self.discovery && self.html.accepts_response(res)
+    }
+
+    async fn process_response(
+        &self,
+        url: &Url,
+        res: Response,
+        permit: HostPermit,
+        client: &UnfurlClient,
+    ) -> Result<Vec<EmbedGeneration>, UnfurlError> {
+        let mut data = self.html.extract(res).await?;
+        drop(permit);
+        let oembed_url = data.oembed_url.take().and_then(|u| url.join(&u).ok());
+        let mut tmpl = generate(url, data, client).await;
+
+        // the page's own metadata is still useful if oembed fails
+        if let Some(oembed_url) = oembed_url {
+            match self.fetch(client, &oembed_url).await {
+                Ok(oembed) => oembed.apply(&oembed_url, &mut tmpl),
+                Err(err) => debug!("failed to fetch oembed for {url}: {err}"),
+            }
+        }
+
+        Ok(vec![EmbedGeneration { embed: tmpl }])
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OEmbedResponse {
+    #[serde(rename = "type")]
+    ty: String,
+    title: Option<String>,
+    author_name: Option<String>,
+    author_url: Option<String>,
+    provider_name: Option<String>,
+    thumbnail_url: Option<String>,
+
+    /// the image url, for photos
+    url: Option<String>,
+
+    /// the html to embed, for videos and rich embeds
+    html: Option<String>,
+
+    width: Option<Dimension>,
+    height: Option<Dimension>,
+}
+
+/// some providers send dimensions as strings
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Dimension {
+    Int(u32),
+    Float(f64),
+    Str(String),
+}
+
+impl Dimension {
+    fn get(&self) -> Option<u32> {
+        match self {
+            Dimension::Int(n) => Some(*n),
+            Dimension::Float(n) => Some(*n as u32),
+            Dimension::Str(s) => s.trim().parse().ok(),
+        }
+    }
+}
+
+impl OEmbedResponse {
+    /// merge this response into an embed, overwriting what the page said
+    fn apply(self, base: &Url, tmpl: &mut EmbedGenerationTemplate) {
+        if self.title.is_some() {
+            tmpl.title = self.title;
+        }
+        if self.author_name.is_some() {
+            tmpl.author_name = self.author_name;
+        }
+        if let Some(u) = self.author_url.and_then(|u| base.join(&u).ok()) {
+            tmpl.author_url = Some(u);
+        }
+        if self.provider_name.is_some() {
+            tmpl.site_name = self.provider_name;
+        }
+        if let Some(u) = self.thumbnail_url.and_then(|u| base.join(&u).ok()) {
+            tmpl.thumbnail = Some(
+                EmbedMediaPending::new(u)
+                    .mime_guess("image/jpeg".parse().unwrap())
+                    .into(),
+            );
+        }
+
+        let width = self.width.and_then(|d| d.get());
+        let height = self.height.and_then(|d| d.get());
+        match self.ty.as_str() {
+            "photo" => {
+                if let Some(u) = self.url.and_then(|u| base.join(&u).ok()) {
+                    tmpl.ty = EmbedType::Media;
+                    tmpl.media = Some(
+                        EmbedMediaPending::new(u)
+                            .mime_guess("image/jpeg".parse().unwrap())
+                            .into(),
+                    );
+                    tmpl.thumbnail = None;
+                }
+            }
+            "video" | "rich" => {
+                if let Some(src) = self.html.as_deref().and_then(iframe_src) {
+                    tmpl.player = Some(EmbedPlayer {
+                        url: src,
+                        width,
+                        height,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// find the src of the first iframe in some html
+///
+/// only https iframes are allowed. the rest of the html is ignored, since
+/// running arbitrary third party html isn't safe.
+fn iframe_src(html: &str) -> Option<Url> {
+    let tokenizer = Tokenizer::new(IframeSink::default(), TokenizerOpts::default());
+    let queue = BufferQueue::default();
+    queue.push_back(StrTendril::from_slice(html));
+    let _ = tokenizer.feed(&queue);
+    tokenizer.end();
+
+    let src = tokenizer.sink.src.take()?;
+    let src = match src.strip_prefix("//") {
+        Some(rest) => Url::parse(&format!("https://{rest}")),
+        None => Url::parse(&src),
+    }
+    .ok()?;
+    (src.scheme() == "https").then_some(src)
+}
+
+#[derive(Default)]
+struct IframeSink {
+    src: RefCell<Option<String>>,
+}
+
+impl TokenSink for IframeSink {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        if let Token::TagToken(tag) = token
+            && tag.kind == TagKind::StartTag
+            && tag.name == local_name!("iframe")
+        {
+            let mut src = self.src.borrow_mut();
+            if src.is_none() {
+                *src = tag
+                    .attrs
+                    .iter()
+                    .find(|a| a.name.local == local_name!("src"))
+                    .map(|a| a.value.to_string());
+            }
+        }
+        TokenSinkResult::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iframe_src() {
+        assert_eq!(
+            iframe_src(r#"<iframe width="560" src="https://example.com/embed/1"></iframe>"#),
+            Some(Url::parse("https://example.com/embed/1").unwrap())
+        );
+        assert_eq!(
+            iframe_src(r#"<div><iframe src="//example.com/embed/1"></iframe></div>"#),
+            Some(Url::parse("https://example.com/embed/1").unwrap())
+        );
+
+        // only the first iframe is used
+        assert_eq!(
+            iframe_src(
+                r#"<iframe src="https://example.com/1"></iframe><iframe src="https://example.com/2"></iframe>"#
+            ),
+            Some(Url::parse("https://example.com/1").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_iframe_src_rejects() {
+        assert_eq!(
+            iframe_src(r#"<iframe src="http://example.com/embed/1"></iframe>"#),
+            None
+        );
+        assert_eq!(
+            iframe_src(r#"<iframe src="javascript:alert(1)"></iframe>"#),
+            None
+        );
+        assert_eq!(iframe_src(r#"<iframe width="560"></iframe>"#), None);
+        assert_eq!(
+            iframe_src(r#"<script src="https://example.com/a.js"></script>"#),
+            None
+        );
+        assert_eq!(iframe_src("no html here"), None);
+    }
+}
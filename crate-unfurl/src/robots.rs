@@ -0,0 +1,325 @@
+//! robots.txt support
+
+use std::{sync::Arc, time::Duration};
+
+use moka::future::Cache;
+use reqwest::StatusCode;
+use tracing::debug;
+use url::Url;
+
+use crate::{client::UnfurlClient, error::UnfurlError, util::TtlExpiry};
+
+/// the maximum size of a robots.txt file, as recommended by rfc 9309
+const MAX_ROBOTS_BYTES: usize = 500 * 1024;
+
+/// how long to remember a robots.txt file
+const ROBOTS_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// how long to remember that a robots.txt file couldn't be fetched
+const ROBOTS_ERROR_TTL: Duration = Duration::from_secs(60 * 5);
+
+/// the rules from a robots.txt that apply to us
+#[derive(Debug, Default)]
+pub(crate) struct Robots {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+impl Robots {
+    /// parse a robots.txt, keeping the rules for `agent`
+    ///
+    /// if there's no group for `agent`, the rules for `*` are used instead
+    pub(crate) fn parse(body: &str, agent: &str) -> Self {
+        let agent = agent.to_ascii_lowercase();
+        let mut specific = vec![];
+        let mut wildcard = vec![];
+
+        // which groups the current rules apply to
+        let mut is_specific = false;
+        let mut is_wildcard = false;
+        let mut in_agents = false;
+
+        for line in body.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let key = key.trim().to_ascii_lowercase();
+            let value = value.trim();
+
+            match key.as_str() {
+                "user-agent" => {
+                    // consecutive user-agent lines share a group
+                    if !in_agents {
+                        is_specific = false;
+                        is_wildcard = false;
+                        in_agents = true;
+                    }
+                    let ua = value.to_ascii_lowercase();
+                    if ua == "*" {
+                        is_wildcard = true;
+                    } else if !ua.is_empty() && agent.contains(&ua) {
+                        is_specific = true;
+                    }
+                }
+                "allow" | "disallow" => {
+                    in_agents = false;
+
+                    // an empty disallow means allow everything
+                    if value.is_empty() {
+                        continue;
+                    }
+
+                    let rule = || Rule {
+                        allow: key == "allow",
+                        pattern: value.to_owned(),
+                    };
+                    if is_specific {
+                        specific.push(rule());
+                    }
+                    if is_wildcard {
+                        wildcard.push(rule());
+                    }
+                }
+                _ => in_agents = false,
+            }
+        }
+
+        let rules = if specific.is_empty() {
+            wildcard
+        } else {
+            specific
+        };
+        Robots { rules }
+    }
+
+    /// rules that disallow fetching anything
+    fn disallow_all() -> Self {
+        Robots {
+            rules: vec![Rule {
+                allow: false,
+                pattern: "/".to_owned(),
+            }],
+        }
+    }
+
+    /// whether we're allowed to fetch this url
+    ///
+    /// the longest matching rule wins, and allow wins ties
+    pub(crate) fn allows(&self, url: &Url) -> bool {
+        let path = match url.query() {
+            Some(q) => format!("{}?{q}", url.path()),
+            None => url.path().to_owned(),
+        };
+
+        let mut best: Option<&Rule> = None;
+        for rule in &self.rules {
+            if !pattern_match(&rule.pattern, &path) {
+                continue;
+            }
+            best = match best {
+                Some(b)
+                    if b.pattern.len() > rule.pattern.len()
+                        || (b.pattern.len() == rule.pattern.len() && b.allow) =>
+                {
+                    Some(b)
+                }
+                _ => Some(rule),
+            };
+        }
+
+        best.is_none_or(|r| r.allow)
+    }
+}
+
+/// match a path against a robots.txt pattern, where `*` matches anything and
+/// a trailing `$` anchors the end
+fn pattern_match(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(p) => (p, true),
+        None => (pattern, false),
+    };
+
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = path.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return !anchored || rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    if anchored {
+        rest.ends_with(last)
+    } else {
+        rest.contains(last)
+    }
+}
+
+/// fetches and caches robots.txt files per origin
+pub(crate) struct RobotsCache {
+    agent: String,
+    cache: Cache<String, (Arc<Robots>, Duration)>,
+}
+
+impl RobotsCache {
+    pub(crate) fn new(agent: String) -> Self {
+        Self {
+            agent,
+            cache: Cache::builder()
+                .max_capacity(10_000)
+                .expire_after(TtlExpiry)
+                .build(),
+        }
+    }
+
+    /// check if robots.txt allows fetching this url
+    pub(crate) async fn check(&self, client: &UnfurlClient, url: &Url) -> Result<(), UnfurlError> {
+        let origin = url.origin().ascii_serialization();
+        let (robots, _) = self
+            .cache
+            .get_with(origin.clone(), async {
+                match self.fetch(client, url).await {
+                    Ok(robots) => (Arc::new(robots), ROBOTS_TTL),
+                    Err(err) => {
+                        // rfc 9309 treats an unreachable robots.txt as disallowing everything
+                        debug!("failed to fetch robots.txt for {origin}: {err}");
+                        (Arc::new(Robots::disallow_all()), ROBOTS_ERROR_TTL)
+                    }
+                }
+            })
+            .await;
+
+        if robots.allows(url) {
+            Ok(())
+        } else {
+            Err(UnfurlError::Forbidden)
+        }
+    }
+
+    async fn fetch(&self, client: &UnfurlClient, url: &Url) -> Result<Robots, UnfurlError> {
+        let robots_url = url
+            .join("/robots.txt")
+            .map_err(|_| UnfurlError::Forbidden)?;
+
+        let _permit = client.hosts.acquire(&robots_url).await?;
+        let mut res = client.client.get(robots_url).send().await?;
+
+        // a missing robots.txt means everything is allowed
+        if res.status().is_client_error() && res.status() != StatusCode::TOO_MANY_REQUESTS {
+            return Ok(Robots::default());
+        }
+        if !res.status().is_success() {
+            return Err(UnfurlError::Other(
+                format!("robots.txt returned {}", res.status()).into(),
+            ));
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = res.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_ROBOTS_BYTES {
+                body.truncate(MAX_ROBOTS_BYTES);
+                break;
+            }
+        }
+
+        Ok(Robots::parse(&String::from_utf8_lossy(&body), &self.agent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(robots: &Robots, path: &str) -> bool {
+        robots.allows(
+            &Url::parse("https://example.com")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_groups() {
+        let body = "
+            User-agent: *
+            Disallow: /private
+
+            User-agent: OtherBot
+            User-agent: Lamprey # us
+            Disallow: /
+            Allow: /public
+        ";
+
+        let robots = Robots::parse(body, "Lamprey");
+        assert!(!allows(&robots, "/"));
+        assert!(!allows(&robots, "/private"));
+        assert!(allows(&robots, "/public/page"));
+
+        // other agents fall back to the wildcard group
+        let robots = Robots::parse(body, "SomeBot");
+        assert!(allows(&robots, "/"));
+        assert!(!allows(&robots, "/private/page"));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let body = "
+            user-agent: *
+            disallow: /a
+            allow: /a/b
+            disallow: /a/b/c
+            allow: /x
+            disallow: /x
+            disallow:
+        ";
+        let robots = Robots::parse(body, "Lamprey");
+        assert!(!allows(&robots, "/a"));
+        assert!(allows(&robots, "/a/b"));
+        assert!(!allows(&robots, "/a/b/c"));
+        assert!(allows(&robots, "/x"));
+        assert!(allows(&robots, "/other"));
+    }
+
+    #[test]
+    fn test_patterns() {
+        let body = "
+            User-agent: *
+            Disallow: /*.pdf$
+            Disallow: /search?*q=
+            Disallow: /tmp*/cache
+        ";
+        let robots = Robots::parse(body, "Lamprey");
+        assert!(!allows(&robots, "/files/doc.pdf"));
+        assert!(allows(&robots, "/files/doc.pdf.html"));
+        assert!(!allows(&robots, "/search?lang=en&q=test"));
+        assert!(allows(&robots, "/search"));
+        assert!(!allows(&robots, "/tmp/x/cache"));
+        assert!(allows(&robots, "/tmp/x"));
+    }
+
+    #[test]
+    fn test_empty() {
+        let robots = Robots::parse("", "Lamprey");
+        assert!(allows(&robots, "/anything"));
+
+        let robots = Robots::disallow_all();
+        assert!(!allows(&robots, "/"));
+        assert!(!allows(&robots, "/anything?q=1"));
+    }
+}
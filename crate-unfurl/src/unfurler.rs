@@ -1,17 +1,25 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
+use ipnet::IpNet;
 use lamprey_common::{
     v1::types::{Embed, EmbedId, MediaId},
     v2::types::media::Media,
 };
+use moka::future::Cache;
 use reqwest::{Client, ClientBuilder};
 use url::Url;
 
 use crate::{
+    client::UnfurlClient,
     error::UnfurlError,
+    host::HostLimiter,
     logging::{LogEntry, LogSink, NoopLogSink, SelectPluginEntry, SelectPluginReason},
+    net::AddrFilter,
     plugin::UnfurlPlugin,
-    util::{EmbedGenerationTemplate, EmbedMedia, EmbedMediaPending},
+    robots::RobotsCache,
+    util::{
+        EmbedGenerationTemplate, EmbedMedia, EmbedMediaPending, TtlExpiry, cache_ttl, normalize_url,
+    },
 };
 
 /// Helper function to extract finished media from EmbedMedia
@@ -30,14 +38,29 @@ pub struct EmbedGeneration {
     pub(crate) embed: EmbedGenerationTemplate,
 }
 
+/// the default user agent token to look for in robots.txt
+const DEFAULT_ROBOTS_AGENT: &str = "Lamprey";
+
+/// how long to cache unfurls for if the response doesn't say
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
 pub struct Unfurler {
-    client: Client,
+    client: UnfurlClient,
     plugins: Vec<Arc<dyn UnfurlPlugin>>,
+    robots: Option<RobotsCache>,
+    cache: Cache<Url, (Arc<Vec<EmbedGeneration>>, Duration)>,
+    cache_ttl: (Duration, Duration),
 }
 
 pub struct UnfurlerBuilder {
     client_builder: ClientBuilder,
     plugins: Vec<Arc<dyn UnfurlPlugin>>,
+    filter: AddrFilter,
+    host_concurrency: usize,
+    host_interval: Duration,
+    robots_agent: Option<String>,
+    cache_capacity: u64,
+    cache_ttl: (Duration, Duration),
 }
 
 impl Unfurler {
@@ -46,43 +69,87 @@ impl Unfurler {
             // Safe defaults for external fetching
             client_builder: Client::builder()
                 .timeout(std::time::Duration::from_secs(10))
-                .user_agent("Mozilla/5.0 (compatible; LampreyBot/1.0; +https://example.com)"),
+                .user_agent(concat!(
+                    "Lamprey/v",
+                    env!("CARGO_PKG_VERSION"),
+                    " (+",
+                    env!("CARGO_PKG_REPOSITORY"),
+                    ")"
+                )),
             plugins: Vec::new(),
+            filter: AddrFilter::new(),
+            host_concurrency: 2,
+            host_interval: Duration::from_millis(250),
+            robots_agent: Some(DEFAULT_ROBOTS_AGENT.to_owned()),
+            cache_capacity: 10_000,
+            cache_ttl: (Duration::from_secs(60), Duration::from_secs(60 * 60 * 24)),
         }
     }
 
     /// Generate some url embeds for this url. Only runs the first sucessful plugin, but the plugin may return multiple embeds.
+    ///
+    /// Results are cached by normalized url, and concurrent unfurls of the same url share one fetch.
     pub async fn unfurl(&self, url: &Url) -> Result<Vec<EmbedGeneration>, UnfurlError> {
-        self.unfurl_with_logger(url, &mut NoopLogSink).await
+        let (generations, _) = self
+            .cache
+            .try_get_with(normalize_url(url), async {
+                let (generations, ttl) = self.fetch(url, &mut NoopLogSink).await?;
+                let (min, max) = self.cache_ttl;
+                let ttl = ttl.unwrap_or(DEFAULT_CACHE_TTL).clamp(min, max);
+                Ok::<_, UnfurlError>((Arc::new(generations), ttl))
+            })
+            .await?;
+        Ok((*generations).clone())
     }
 
     /// Generate some url embeds for this url with logging support.
     ///
     /// Only runs the first sucessful plugin, but the plugin may return multiple embeds.
     /// Log entries are emitted to the provided `log_sink` during unfurling.
+    ///
+    /// This always fetches the url, skipping the cache.
     pub async fn unfurl_with_logger(
         &self,
         url: &Url,
         log_sink: &mut dyn LogSink,
     ) -> Result<Vec<EmbedGeneration>, UnfurlError> {
+        let (generations, _) = self.fetch(url, log_sink).await?;
+        Ok(generations)
+    }
+
+    /// Forget all cached unfurls
+    pub fn purge_cache(&self) {
+        self.cache.invalidate_all();
+    }
+
+    /// unfurl a url, returning how long the result can be cached for
+    async fn fetch(
+        &self,
+        url: &Url,
+        log_sink: &mut dyn LogSink,
+    ) -> Result<(Vec<EmbedGeneration>, Option<Duration>), UnfurlError> {
         // 1. Try URL-based plugins (e.g. magnet://, ipfs://)
         for plugin in &self.plugins {
-            if let Some(generation) = plugin.process_url(url).await? {
+            if let Some(generation) = plugin.process_url(url, &self.client).await? {
                 log_sink.handle(LogEntry::SelectPlugin(SelectPluginEntry::new(
                     plugin.name(),
                     SelectPluginReason::Url,
                 )));
-                return Ok(generation);
+                return Ok((generation, None));
             }
         }
 
-        // 2. We need an HTTP response. Reject non-HTTP protocols at this point.
-        if url.scheme() != "http" && url.scheme() != "https" {
-            return Err(UnfurlError::UnsupportedProtocol);
+        // 2. We need an HTTP response. Reject non-HTTP protocols and internal addresses at this point.
+        self.client.filter.check_url(url)?;
+
+        if let Some(robots) = &self.robots {
+            robots.check(&self.client, url).await?;
         }
 
-        let res = self.client.get(url.clone()).send().await?;
+        let permit = self.client.hosts.acquire(url).await?;
+        let res = self.client.client.get(url.clone()).send().await?;
         let final_url = res.url().clone();
+        let ttl = cache_ttl(res.headers());
 
         // 3. Find a plugin that handles this specific response
         for plugin in &self.plugins {
@@ -91,7 +158,10 @@ impl Unfurler {
                     plugin.name(),
                     SelectPluginReason::Response,
                 )));
-                return plugin.process_response(&final_url, res).await;
+                let generations = plugin
+                    .process_response(&final_url, res, permit, &self.client)
+                    .await?;
+                return Ok((generations, ttl));
             }
         }
 
@@ -113,10 +183,48 @@ impl UnfurlerBuilder {
         self
     }
 
+    /// Refuse to connect to these networks, in addition to loopback, private, and link-local addresses
+    pub fn deny(mut self, nets: impl IntoIterator<Item = IpNet>) -> Self {
+        self.filter = self.filter.deny(nets);
+        self
+    }
+
+    /// Limit requests to each host to `concurrency` at once, starting at most once every `interval`
+    pub fn host_limit(mut self, concurrency: usize, interval: Duration) -> Self {
+        self.host_concurrency = concurrency;
+        self.host_interval = interval;
+        self
+    }
+
+    /// The user agent token to look for in robots.txt, or None to ignore robots.txt
+    pub fn robots(mut self, agent: Option<String>) -> Self {
+        self.robots_agent = agent;
+        self
+    }
+
+    /// Cache up to `capacity` unfurls, clamping the ttl from Cache-Control between `min_ttl` and `max_ttl`
+    pub fn cache(mut self, capacity: u64, min_ttl: Duration, max_ttl: Duration) -> Self {
+        self.cache_capacity = capacity;
+        self.cache_ttl = (min_ttl, max_ttl.max(min_ttl));
+        self
+    }
+
     pub fn build(self) -> Result<Unfurler, reqwest::Error> {
+        let filter = Arc::new(self.filter);
+        let client = filter.clone().apply(self.client_builder).build()?;
         Ok(Unfurler {
-            client: self.client_builder.build()?,
+            client: UnfurlClient {
+                client,
+                filter,
+                hosts: HostLimiter::new(self.host_concurrency, self.host_interval),
+            },
             plugins: self.plugins,
+            robots: self.robots_agent.map(RobotsCache::new),
+            cache: Cache::builder()
+                .max_capacity(self.cache_capacity)
+                .expire_after(TtlExpiry)
+                .build(),
+            cache_ttl: self.cache_ttl,
         })
     }
 }
@@ -141,6 +249,10 @@ impl EmbedGeneration {
             author_avatar: self.embed.author_avatar.and_then(media_to_finished),
             site_name: self.embed.site_name,
             site_avatar: self.embed.site_avatar.and_then(media_to_finished),
+            player: self.embed.player,
+            published_at: self.embed.published_at,
+            price: self.embed.price,
+            event: self.embed.event,
         }
     }
 
@@ -170,11 +282,11 @@ impl EmbedGeneration {
     pub fn update_media(&mut self, pending_id: MediaId, new_state: EmbedMedia) -> bool {
         let mut updated = false;
         for field in self.iter_media_mut() {
-            if let Some(EmbedMedia::Pending(p)) = field {
-                if p.placeholder_media_id == pending_id {
-                    *field = Some(new_state.clone());
-                    updated = true;
-                }
+            if let Some(EmbedMedia::Pending(p)) = field
+                && p.placeholder_media_id == pending_id
+            {
+                *field = Some(new_state.clone());
+                updated = true;
             }
         }
         updated
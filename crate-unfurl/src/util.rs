@@ -1,7 +1,12 @@
 use lamprey_common::{
-    v1::types::{EmbedType, MediaId, Mime, misc::Color},
+    v1::types::{
+        EmbedEvent, EmbedPlayer, EmbedPrice, EmbedType, MediaId, Mime, misc::Color, util::Time,
+    },
     v2::types::media::Media,
 };
+use moka::Expiry;
+use reqwest::header::{AGE, CACHE_CONTROL, HeaderMap};
+use std::time::{Duration, Instant};
 use url::Url;
 
 #[derive(Debug, Clone)]
@@ -19,6 +24,10 @@ pub struct EmbedGenerationTemplate {
     pub author_avatar: Option<EmbedMedia>,
     pub site_name: Option<String>,
     pub site_avatar: Option<EmbedMedia>,
+    pub player: Option<EmbedPlayer>,
+    pub published_at: Option<Time>,
+    pub price: Option<EmbedPrice>,
+    pub event: Option<EmbedEvent>,
 }
 
 #[derive(Debug, Clone)]
@@ -75,3 +84,163 @@ impl From<EmbedMediaPending> for EmbedMedia {
         EmbedMedia::Pending(value)
     }
 }
+
+/// query parameters that only exist for tracking and don't change the page
+const TRACKING_PARAMS: &[&str] = &["fbclid", "gclid", "dclid", "msclkid", "mc_eid", "igshid"];
+
+/// Normalize a url for use as a cache key
+///
+/// Removes the fragment and tracking query parameters. The `url` crate already
+/// lowercases the host and removes default ports.
+pub fn normalize_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    url.set_fragment(None);
+
+    if url.query().is_some() {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(k, _)| !k.starts_with("utm_") && !TRACKING_PARAMS.contains(&k.as_ref()))
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect();
+        if pairs.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(pairs);
+        }
+    }
+
+    url
+}
+
+/// How long a response can be cached for according to its Cache-Control header
+///
+/// Returns `Some(Duration::ZERO)` if it shouldn't be cached, and `None` if
+/// the response doesn't say.
+pub fn cache_ttl(headers: &HeaderMap) -> Option<Duration> {
+    let cache_control = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase());
+
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for directive in cache_control {
+        match directive.split_once('=') {
+            None if directive == "no-store" || directive == "no-cache" => {
+                return Some(Duration::ZERO);
+            }
+            Some(("max-age", v)) => max_age = v.trim_matches('"').parse::<u64>().ok(),
+            Some(("s-maxage", v)) => s_maxage = v.trim_matches('"').parse::<u64>().ok(),
+            _ => {}
+        }
+    }
+
+    let age = headers
+        .get(AGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+
+    s_maxage
+        .or(max_age)
+        .map(|secs| Duration::from_secs(secs.saturating_sub(age)))
+}
+
+/// Expire cache entries after the duration stored next to the value
+pub(crate) struct TtlExpiry;
+
+impl<K, V> Expiry<K, (V, Duration)> for TtlExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &K,
+        value: &(V, Duration),
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(value.1)
+    }
+}
+
+/// match a url against a pattern, where `*` matches anything
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = s.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // no wildcards, so it has to be an exact match
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(url: &str) -> String {
+        normalize_url(&Url::parse(url).unwrap()).to_string()
+    }
+
+    #[test]
+    fn test_normalize_url() {
+        assert_eq!(
+            normalize("HTTPS://Example.com:443/a#section"),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            normalize("https://example.com/?utm_source=x&fbclid=y"),
+            "https://example.com/"
+        );
+        assert_eq!(
+            normalize("https://example.com/watch?v=abc&utm_medium=social&t=10"),
+            "https://example.com/watch?v=abc&t=10"
+        );
+        assert_eq!(
+            normalize("http://example.com:8080/?q=a+b"),
+            "http://example.com:8080/?q=a+b"
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("https://example.com/a", "https://example.com/a"));
+        assert!(!glob_match(
+            "https://example.com/a",
+            "https://example.com/ab"
+        ));
+        assert!(glob_match(
+            "https://*.example.com/*",
+            "https://www.example.com/video/1"
+        ));
+        assert!(glob_match(
+            "https://example.com/*/video/*",
+            "https://example.com/u/video/1"
+        ));
+        assert!(!glob_match(
+            "https://example.com/*/video/*",
+            "https://example.com/u/photo/1"
+        ));
+        assert!(!glob_match(
+            "https://*.example.com/*",
+            "http://www.example.com/"
+        ));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("https://example.com/*", "https://example.com/"));
+
+        // the suffix can't overlap a middle part
+        assert!(!glob_match("https://*abc*bc", "https://abc"));
+        assert!(glob_match("https://*abc*bc", "https://abcbc"));
+    }
+}
@@ -134,4 +134,16 @@ impl BackendHandle {
         BroadcastStream::new(self.command_broadcast.subscribe())
             .filter_map(|r| async move { r.ok() })
     }
+
+    /// create a handle that isn't connected to a backend
+    #[cfg(test)]
+    pub fn mock() -> (Self, mpsc::UnboundedReceiver<SfuEvent>) {
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let (command_broadcast, _) = broadcast::channel(100);
+        let handle = Self {
+            event_tx,
+            command_broadcast,
+        };
+        (handle, event_rx)
+    }
 }
@@ -5,6 +5,7 @@ use str0m::Rtc;
 #[derive(Debug, Default)]
 pub struct Datachannels {
     speaking: Option<SChannelId>,
+    position: Option<SChannelId>,
 }
 
 impl Datachannels {
@@ -20,6 +21,11 @@ impl Datachannels {
         self.speaking
     }
 
+    /// get the datachannel for spatial audio positions
+    pub fn position(&self) -> Option<SChannelId> {
+        self.position
+    }
+
     pub fn handle(&mut self, event: &SEvent, rtc: &mut Rtc) {
         match event {
             SEvent::ChannelOpen(channel_id, _label) => {
@@ -29,6 +35,7 @@ impl Datachannels {
                     .expect("guaranteed to exist when ChannelOpen is emitted");
                 match config.protocol.as_str() {
                     "speaking" => self.speaking = Some(*channel_id),
+                    "position" => self.position = Some(*channel_id),
                     _ => {}
                 }
             }
@@ -36,6 +43,9 @@ impl Datachannels {
                 if self.speaking == Some(*channel_id) {
                     self.speaking = None;
                 }
+                if self.position == Some(*channel_id) {
+                    self.position = None;
+                }
             }
             _ => return,
         }
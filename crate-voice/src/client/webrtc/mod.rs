@@ -5,6 +5,7 @@ use str0m::Rtc;
 use tracing::{debug, trace};
 
 use crate::client::webrtc::mapping::Mapping;
+use crate::client::webrtc::track::Frame;
 use crate::prelude::*;
 use crate::{
     client::webrtc::{datachannels::Datachannels, signalling::Signalling},
@@ -47,7 +48,7 @@ impl Webrtc {
         self.datachannels.handle(event, &mut self.rtc);
     }
 
-    pub fn write_media(&mut self, track: TrackSlot, media: &str0m::media::MediaData) {
+    pub fn write_media(&mut self, track: TrackSlot, media: &Frame) {
         let Some(mid) = self.mapping.lookup_mid(track) else {
             trace!(track = ?track, "Failed to lookup mid");
             return;
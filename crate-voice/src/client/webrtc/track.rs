@@ -1,6 +1,14 @@
+use std::time::Instant;
+
+use common::v1::types::SfuId;
 use common::v1::types::voice::{MediaKind, TrackKey, TrackMetadata2};
+use common::v2::types::UserId;
+use str0m::format::PayloadParams;
+use str0m::media::MediaTime;
 
 use crate::prelude::*;
+use crate::server::spatial::{Proximity, TrackPosition};
+use crate::util::simulcast::layer_rank;
 
 /// the current state of a webrtc track
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -30,12 +38,44 @@ impl TrackState {
     }
 }
 
+/// where an inbound track is received from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Publisher {
+    /// a peer connected to this sfu
+    Local(PeerSlot),
+
+    /// another sfu in the mesh
+    Remote {
+        sfu_id: SfuId,
+
+        /// the track's id on the remote sfu
+        track_id: u64,
+    },
+}
+
+impl Publisher {
+    /// the sfu this track is received from, if its not local
+    pub fn sfu_id(&self) -> Option<SfuId> {
+        match self {
+            Publisher::Local(_) => None,
+            Publisher::Remote { sfu_id, .. } => Some(*sfu_id),
+        }
+    }
+}
+
 /// info about a track that the sfu is receiving
 // formerly called Track
 pub struct Inbound {
-    pub publisher: PeerSlot,
+    pub publisher: Publisher,
+    pub user_id: UserId,
     pub metadata: TrackMetadata2,
     pub state: TrackState,
+
+    /// simulcast layers that have been received, highest quality first
+    pub layers: Vec<SRid>,
+
+    /// where this track is in a virtual space
+    pub position: TrackPosition,
 }
 
 /// info about a track that the sfu is forwarding to a peer
@@ -44,6 +84,25 @@ pub struct Outbound {
     pub subscriber: PeerSlot,
     pub source: TrackSlot,
     pub state: TrackState,
+
+    /// how close the source is to the subscriber
+    pub proximity: Proximity,
+
+    /// the simulcast layer being forwarded
+    pub layer: Option<SRid>,
+}
+
+impl Outbound {
+    /// a new subscription that still needs to be negotiated
+    pub fn new(subscriber: PeerSlot, source: TrackSlot) -> Self {
+        Self {
+            subscriber,
+            source,
+            state: TrackState::Pending,
+            proximity: Proximity::Near,
+            layer: None,
+        }
+    }
 }
 
 impl Inbound {
@@ -65,4 +124,47 @@ impl Inbound {
     pub fn kind(&self) -> MediaKind {
         self.metadata.kind
     }
+
+    /// remember that a simulcast layer is being received
+    pub fn add_layer(&mut self, rid: SRid) {
+        if self.layers.contains(&rid) {
+            return;
+        }
+
+        self.layers.push(rid);
+        self.layers.sort_by_key(|r| (layer_rank(r), r.to_string()));
+    }
+
+    /// which simulcast layer to forward at some proximity
+    pub fn layer_for(&self, proximity: Proximity) -> Option<SRid> {
+        match proximity {
+            Proximity::Near => self.layers.first().copied(),
+            Proximity::Mid => self.layers.last().copied(),
+            Proximity::Far => None,
+        }
+    }
+}
+
+/// media data that can be written to a track, either from a local peer or a remote sfu
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub params: PayloadParams,
+    pub network_time: Instant,
+    pub time: MediaTime,
+    pub data: Arc<[u8]>,
+
+    /// the simulcast layer this frame is from
+    pub rid: Option<SRid>,
+}
+
+impl From<&str0m::media::MediaData> for Frame {
+    fn from(media: &str0m::media::MediaData) -> Self {
+        Self {
+            params: media.params,
+            network_time: media.network_time,
+            time: media.time,
+            data: Arc::clone(&media.data),
+            rid: media.rid,
+        }
+    }
 }
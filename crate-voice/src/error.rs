@@ -14,11 +14,26 @@ pub enum Error {
     #[error("quinn connection: {0}")]
     QuinnConnection(#[from] quinn::ConnectionError),
 
+    #[error("quinn connect: {0}")]
+    QuinnConnect(#[from] quinn::ConnectError),
+
+    #[error("quinn write: {0}")]
+    QuinnWrite(#[from] quinn::WriteError),
+
+    #[error("quinn read: {0}")]
+    QuinnRead(#[from] quinn::ReadExactError),
+
+    #[error("postcard error: {0}")]
+    Postcard(#[from] postcard::Error),
+
+    #[error("mesh error: {0}")]
+    Mesh(String),
+
     #[error("{0}")]
     Rustls(#[from] rustls::Error),
 
     #[error("websocket error: {0}")]
-    Tungstenite(#[from] tokio_tungstenite::tungstenite::Error),
+    Tungstenite(Box<tokio_tungstenite::tungstenite::Error>),
 
     #[error("invalid auth token: {0}")]
     InvalidAuthToken(String),
@@ -29,6 +44,9 @@ pub enum Error {
     #[error("backend error: {0}")]
     Backend(String),
 
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+
     #[error("sdp error: {0}")]
     Sdp(#[from] SdpError),
 
@@ -39,4 +57,10 @@ pub enum Error {
     Io(#[from] std::io::Error),
 }
 
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+        Error::Tungstenite(Box::new(err))
+    }
+}
+
 pub type Result<T> = ::core::result::Result<T, Error>;
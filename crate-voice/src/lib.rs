@@ -2,6 +2,7 @@ pub mod backend;
 pub mod client;
 pub mod error;
 pub mod mesh;
+pub mod recording;
 pub mod server;
 pub mod util;
 
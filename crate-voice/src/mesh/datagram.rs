@@ -1,15 +1,82 @@
 //! wire format for datagrams
 
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use str0m::format::PayloadParams;
+use str0m::media::{Frequency, MediaTime};
+
+use crate::client::webrtc::track::Frame;
+use crate::mesh::stream::SubscribeId;
 use crate::prelude::*;
-use common::v1::types::voice::datachannel::SpeakingDatagram;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Datagram {
-    Speaking(SpeakingDatagram),
+    /// speaking flags for a subscribed track
+    Speaking {
+        subscribe_id: SubscribeId,
+        flags: u8,
+    },
+
+    /// a media frame for a subscribed track
+    Media(MediaFrame),
+}
+
+/// a single depacketized media frame
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaFrame {
+    pub subscribe_id: SubscribeId,
+    pub params: PayloadParams,
+
+    /// rtp timestamp
+    pub time: u64,
+
+    /// clock rate of `time`
+    pub clock_rate: u32,
+
+    pub data: Vec<u8>,
+
+    /// the simulcast layer this frame is from
+    pub rid: Option<String>,
 }
 
 impl Datagram {
     pub fn parse(data: &[u8]) -> Result<Self> {
-        todo!()
+        Ok(postcard::from_bytes(data)?)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(postcard::to_stdvec(self)?)
+    }
+
+    pub fn subscribe_id(&self) -> SubscribeId {
+        match self {
+            Datagram::Speaking { subscribe_id, .. } => *subscribe_id,
+            Datagram::Media(frame) => frame.subscribe_id,
+        }
+    }
+}
+
+impl MediaFrame {
+    pub fn new(subscribe_id: SubscribeId, media: &Frame) -> Self {
+        Self {
+            subscribe_id,
+            params: media.params,
+            time: media.time.numer(),
+            clock_rate: media.time.frequency().get(),
+            data: media.data.to_vec(),
+            rid: media.rid.map(|r| r.to_string()),
+        }
+    }
+
+    /// convert this into a frame that can be written to local peers
+    pub fn into_frame(self) -> Option<Frame> {
+        Some(Frame {
+            params: self.params,
+            network_time: Instant::now(),
+            time: MediaTime::new(self.time, Frequency::new(self.clock_rate)?),
+            data: self.data.into(),
+            rid: self.rid.as_deref().map(SRid::from),
+        })
     }
 }
@@ -1,122 +1,180 @@
 use std::net::SocketAddr;
+use std::time::Duration;
 
 use common::v1::types::SfuId;
+use common::v2::types::ChannelId;
 use dashmap::DashMap;
 use lamprey_backend_core::config::Config;
-use quinn::{ConnectionError, default_runtime};
-use tokio::{sync::mpsc, task::JoinSet};
-use tracing::{debug, error, info};
-
-use crate::mesh::remote::RemoteHandle;
-use crate::mesh::stream::MeshStream;
+use quinn::{VarInt, default_runtime};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use sha2::{Digest, Sha256};
+use tokio::{sync::broadcast, task::JoinSet};
+use tokio_stream::wrappers::BroadcastStream;
+use tracing::{debug, error, info, warn};
+
+use crate::mesh::remote::Remote;
+use crate::mesh::stream::{AcceptedStream, Header, Hello, MeshStream};
 use crate::prelude::*;
 
-// TODO: use postcard to serialize/deserialize datagrams and stream data
-// postcard::from_bytes(&[1, 2, 3])
-// postcard::to_stdvec(&Foo {});
-mod datagram;
-mod remote;
-mod stream;
+pub mod datagram;
+pub mod remote;
+pub mod stream;
+
+pub use remote::{MediaSink, RemoteHandle};
+
+/// server name used for tls
+const SERVER_NAME: &str = "lamprey-sfu";
+
+/// alpn protocol for the mesh
+const ALPN: &[u8] = b"lamprey-rtc";
+
+/// how long a prepared cascade token is valid for
+const TOKEN_TTL: Duration = Duration::from_secs(60);
+
+/// how long an incoming connection has to authenticate
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Clone)]
 pub struct MeshHandle {
     shared: Arc<MeshShared>,
-    event_tx: mpsc::UnboundedSender<MeshEvent>,
 }
 
 /// manages QUIC connections to other SFUs
 pub struct Mesh {
     shared: Arc<MeshShared>,
-    endpoint: quinn::Endpoint,
     client_tasks: JoinSet<Result<()>>,
-    // event_tx: mpsc::UnboundedSender<MeshEvent>,
-    // event_rx: mpsc::UnboundedReceiver<MeshEvent>
 }
 
 /// internal shared state
 struct MeshShared {
+    endpoint: quinn::Endpoint,
+
+    /// the sha-256 hash of our certificate, which remotes pin
+    fingerprint: String,
+
+    /// the address other sfus should connect to
+    addr: SocketAddr,
+
     /// active QUIC connections indexed by remote SFU id
     remotes: DashMap<SfuId, RemoteHandle>,
 
     /// tokens authorized by the master for incoming connections
     pending_tokens: DashMap<String, SfuId>,
+
+    events: broadcast::Sender<MeshEvent>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum MeshEvent {
-    // TODO
-    // /// a reliable dispatch was received from a remote SFU
-    // Dispatch {
-    //     sfu_id: SfuId,
-    //     nonce: Option<String>,
-    //     dispatch: MeshDispatch,
-    // },
-
-    // /// an unreliable datagram was received from a remote SFU
-    // Datagram(MeshDatagram),
     /// a mesh connection was established
     Connected { sfu_id: SfuId },
 
     /// a mesh connection was closed
     Closed { sfu_id: SfuId },
+
+    /// a remote sfu changed the tracks available in a call
+    Announce {
+        sfu_id: SfuId,
+        announce: stream::Announce,
+    },
+
+    /// a remote sfu subscribed to one of our tracks
+    Subscribe {
+        sfu_id: SfuId,
+        subscribe: stream::Subscribe,
+    },
+
+    /// a remote sfu unsubscribed from one of our tracks
+    Unsubscribe {
+        sfu_id: SfuId,
+        channel_id: ChannelId,
+        id: stream::SubscribeId,
+    },
+
+    /// a remote sfu wants a keyframe for a subscription
+    Keyframe {
+        sfu_id: SfuId,
+        channel_id: ChannelId,
+        id: stream::SubscribeId,
+    },
+}
+
+fn transport_config() -> quinn::TransportConfig {
+    let mut config = quinn::TransportConfig::default();
+    // every subscription is its own stream
+    config.max_concurrent_bidi_streams(VarInt::from_u32(4096));
+    config.max_concurrent_uni_streams(VarInt::from_u32(1024));
+    config.keep_alive_interval(Some(Duration::from_secs(5)));
+    config
 }
 
 impl Mesh {
     /// create a new mesh listener and return a handle
     pub async fn spawn(config: &Config) -> Result<MeshHandle> {
-        let subject_alt_names = vec!["lamprey-sfu".to_string()];
+        let voice_config = config
+            .voice
+            .as_ref()
+            .ok_or_else(|| Error::Mesh("voice config missing".into()))?;
+        let addr: SocketAddr = format!(
+            "{}:{}",
+            voice_config.host_ipv4.as_deref().unwrap_or("0.0.0.0"),
+            voice_config.quic_port
+        )
+        .parse()
+        .map_err(|e| Error::Mesh(format!("invalid mesh address: {e}")))?;
+        // TODO: listen on v4 and v6
+        Self::bind(addr).await
+    }
+
+    /// create a new mesh listener on an address
+    pub async fn bind(addr: SocketAddr) -> Result<MeshHandle> {
+        let subject_alt_names = vec![SERVER_NAME.to_string()];
         let cert = rcgen::generate_simple_self_signed(subject_alt_names).unwrap();
         let key = rustls::pki_types::PrivateKeyDer::Pkcs8(cert.signing_key.serialize_der().into());
         let cert_der = cert.cert.der().clone();
+        let fingerprint = fingerprint(&cert_der);
 
         let mut server_crypto = rustls::ServerConfig::builder()
             .with_no_client_auth()
             .with_single_cert(vec![cert_der], key)?;
-        server_crypto.alpn_protocols = vec![b"lamprey-rtc".to_vec()];
-
-        let voice_config = config.voice.as_ref().expect("TODO: better error handling");
+        server_crypto.alpn_protocols = vec![ALPN.to_vec()];
 
         let mut quic_config = quinn::ServerConfig::with_crypto(Arc::new(
             quinn::crypto::rustls::QuicServerConfig::try_from(server_crypto)
                 .expect("TODO: better error handling"),
         ));
-        let transport_config = Arc::get_mut(&mut quic_config.transport).unwrap();
-        transport_config.max_concurrent_uni_streams(0_u8.into());
-        transport_config.max_concurrent_bidi_streams(1_u8.into());
+        quic_config.transport_config(Arc::new(transport_config()));
 
-        let addr_v4: SocketAddr = format!(
-            "{}:{}",
-            voice_config.host_ipv4.as_deref().unwrap_or("0.0.0.0"),
-            voice_config.quic_port
-        )
-        .parse()
-        .expect("TODO: better error handling");
-        let socket_v4 = std::net::UdpSocket::bind(addr_v4)?;
-        socket_v4.set_nonblocking(true)?;
-        // TODO: listen on v4 and v6
+        let socket = std::net::UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
 
         let endpoint = quinn::Endpoint::new(
             quinn::EndpointConfig::default(),
             Some(quic_config),
-            socket_v4,
+            socket,
             default_runtime().unwrap(),
         )?;
 
-        info!("Mesh listening on {}", endpoint.local_addr()?);
+        let addr = endpoint.local_addr()?;
+        info!("Mesh listening on {}", addr);
 
+        let (events, _) = broadcast::channel(1024);
         let shared = Arc::new(MeshShared {
+            endpoint,
+            fingerprint,
+            addr,
             remotes: DashMap::new(),
             pending_tokens: DashMap::new(),
+            events,
         });
 
-        let (event_tx, event_rx) = mpsc::unbounded_channel();
         let me = Mesh {
             shared: shared.clone(),
-            endpoint,
             client_tasks: JoinSet::new(),
         };
 
-        let handle = MeshHandle { shared, event_tx };
+        let handle = MeshHandle { shared };
 
         tokio::spawn(me.run());
 
@@ -126,8 +184,8 @@ impl Mesh {
     async fn run(mut self) {
         loop {
             tokio::select! {
-                Some(incoming) = self.endpoint.accept() => {
-                    self.handle_incoming(incoming).await;
+                Some(incoming) = self.shared.endpoint.accept() => {
+                    self.handle_incoming(incoming);
                 }
                 Some(next) = self.client_tasks.join_next() => {
                     match next {
@@ -141,74 +199,299 @@ impl Mesh {
     }
 
     #[tracing::instrument(skip(self, incoming))]
-    async fn handle_incoming(&mut self, incoming: quinn::Incoming) {
+    fn handle_incoming(&mut self, incoming: quinn::Incoming) {
         let shared = Arc::clone(&self.shared);
 
         self.client_tasks.spawn(async move {
             let conn = incoming.await?;
             debug!("new mesh connection from {}", conn.remote_address());
 
-            loop {
-                let (send, recv) = match conn.accept_bi().await {
-                    Ok(conn) => conn,
-                    Err(err) => match err {
-                        ConnectionError::ApplicationClosed(c) => {
-                            debug!("closed with code {}", c.error_code);
-                            break;
-                        }
-                        ConnectionError::LocallyClosed => break,
-                        err => return Err(err.into()),
-                    },
+            // the first stream must authenticate the connection
+            let auth = async {
+                let (send, recv) = conn.accept_bi().await?;
+                let AcceptedStream::Hello(mut stream) =
+                    MeshStream::new(send, recv).accept().await?
+                else {
+                    return Err(Error::Mesh("expected hello".into()));
                 };
+                let hello: Hello = stream
+                    .read()
+                    .await?
+                    .ok_or_else(|| Error::Mesh("missing hello".into()))?;
+                let (_, sfu_id) = shared
+                    .pending_tokens
+                    .remove(&hello.token)
+                    .ok_or_else(|| Error::InvalidAuthToken("unknown mesh token".into()))?;
+                stream.finish();
+                Ok(sfu_id)
+            };
+
+            let sfu_id = match tokio::time::timeout(HELLO_TIMEOUT, auth).await {
+                Ok(Ok(sfu_id)) => sfu_id,
+                Ok(Err(err)) => {
+                    conn.close(VarInt::from_u32(1), b"unauthorized");
+                    return Err(err);
+                }
+                Err(_) => {
+                    conn.close(VarInt::from_u32(1), b"timeout");
+                    return Err(Error::Mesh("hello timed out".into()));
+                }
+            };
 
-                let stream = MeshStream::new(send, recv)
-                    .accept()
-                    .await
-                    .expect("TODO: better error handling");
-                // match stream { }
-                // conn.close(error_code, reason);
-                // recv.read(buf).await.unwrap();
-                // send.write(buf).await.unwrap();
-
-                // let handle: ShardHandle = todo!();
-                // handle.handle_remote_inbound(stream);
+            shared.register(sfu_id, conn);
+            Ok(())
+        });
+    }
+}
 
-                // fn handle_remote_inbound(&self, stream: Stream<Subscription>) {
-                //     // poll stream, forward frames to call
-                //     // maybe spawn a tokio task? (who has the JoinSet)
-                // }
+impl MeshShared {
+    /// start handling a newly authenticated connection
+    fn register(self: &Arc<Self>, sfu_id: SfuId, conn: quinn::Connection) {
+        info!(%sfu_id, addr = %conn.remote_address(), "mesh connected");
+        let stable_id = conn.stable_id();
+        let remote = Remote::new(sfu_id, conn, self.events.clone());
+        if let Some(old) = self.remotes.insert(sfu_id, remote.handle()) {
+            old.connection()
+                .close(VarInt::from_u32(0), b"replaced by new connection");
+        }
+        let _ = self.events.send(MeshEvent::Connected { sfu_id });
 
-                todo!()
+        let shared = Arc::clone(self);
+        tokio::spawn(async move {
+            if let Err(err) = remote.run().await {
+                warn!(%sfu_id, "mesh connection error {err}");
             }
 
-            Ok(())
+            // don't remove a connection that replaced this one
+            let removed = shared
+                .remotes
+                .remove_if(&sfu_id, |_, r| r.connection().stable_id() == stable_id)
+                .is_some();
+            if removed {
+                info!(%sfu_id, "mesh disconnected");
+                let _ = shared.events.send(MeshEvent::Closed { sfu_id });
+            }
         });
     }
 }
 
 impl MeshHandle {
     /// initiate an outbound connection to a remote SFU
+    ///
+    /// the remote's certificate must match `fingerprint`, which the backend got from it
     pub async fn connect(
         &self,
         addr: SocketAddr,
         token: String,
         remote_sfu_id: SfuId,
+        fingerprint: &str,
     ) -> Result<()> {
-        todo!()
+        if self.shared.remotes.contains_key(&remote_sfu_id) {
+            debug!(%remote_sfu_id, "already connected");
+            return Ok(());
+        }
+
+        let conn = self
+            .shared
+            .endpoint
+            .connect_with(client_config(fingerprint), addr, SERVER_NAME)?
+            .await?;
+
+        let mut stream = MeshStream::<Hello>::open(&conn, Header::Hello).await?;
+        stream.write(&Hello { token }).await?;
+        stream.finish();
+
+        // the remote finishes the stream once we're authenticated
+        stream.closed().await?;
+
+        self.shared.register(remote_sfu_id, conn);
+        Ok(())
     }
 
     /// get a handle to a peer
     pub fn lookup(&self, sfu_id: &SfuId) -> Option<RemoteHandle> {
-        todo!()
+        self.shared.remotes.get(sfu_id).map(|r| r.clone())
+    }
+
+    /// get handles to all connected peers
+    pub fn remotes(&self) -> Vec<RemoteHandle> {
+        self.shared.remotes.iter().map(|r| r.clone()).collect()
     }
 
     /// register a token for an expected incoming connection
     pub fn add_pending_token(&self, token: String, expected_sfu_id: SfuId) {
-        todo!()
+        self.shared
+            .pending_tokens
+            .insert(token.clone(), expected_sfu_id);
+
+        let shared = Arc::clone(&self.shared);
+        tokio::spawn(async move {
+            tokio::time::sleep(TOKEN_TTL).await;
+            shared.pending_tokens.remove(&token);
+        });
+    }
+
+    /// the address that other sfus should connect to
+    pub fn addr(&self) -> SocketAddr {
+        self.shared.addr
+    }
+
+    /// the fingerprint other sfus should pin when connecting
+    pub fn fingerprint(&self) -> &str {
+        &self.shared.fingerprint
+    }
+
+    pub fn subscribe(&self) -> impl Stream<Item = MeshEvent> + 'static {
+        BroadcastStream::new(self.shared.events.subscribe()).filter_map(|r| async move { r.ok() })
+    }
+}
+
+/// create a client config that only accepts a certificate with this fingerprint
+fn client_config(fingerprint: &str) -> quinn::ClientConfig {
+    // sfus use self signed certificates, so the backend distributes their fingerprints
+    let mut client_crypto = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedServerVerification::new(fingerprint)))
+        .with_no_client_auth();
+    client_crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut client_config = quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(client_crypto)
+            .expect("ring supports tls 1.3"),
+    ));
+    client_config.transport_config(Arc::new(transport_config()));
+    client_config
+}
+
+/// the hex encoded sha-256 hash of a certificate
+fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// accepts a single pinned server certificate
+#[derive(Debug)]
+struct PinnedServerVerification {
+    fingerprint: String,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+}
+
+impl PinnedServerVerification {
+    fn new(fingerprint: &str) -> Self {
+        Self {
+            fingerprint: fingerprint.to_ascii_lowercase(),
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedServerVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// create a mesh listening on loopback
+    async fn bind() -> MeshHandle {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        Mesh::bind("127.0.0.1:0".parse().unwrap()).await.unwrap()
+    }
+
+    /// wait until a mesh has a connection to a remote
+    async fn connected(mesh: &MeshHandle, sfu_id: SfuId) -> bool {
+        for _ in 0..100 {
+            if mesh.lookup(&sfu_id).is_some() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_pinned_connection() {
+        let a = bind().await;
+        let b = bind().await;
+        let (a_id, b_id) = (SfuId::new(), SfuId::new());
+        assert_ne!(a.fingerprint(), b.fingerprint());
+
+        // a different certificate is rejected before sending the token
+        b.add_pending_token("token".into(), a_id);
+        let res = a
+            .connect(b.addr(), "token".into(), b_id, a.fingerprint())
+            .await;
+        assert!(res.is_err());
+        assert!(a.lookup(&b_id).is_none());
+
+        a.connect(b.addr(), "token".into(), b_id, b.fingerprint())
+            .await
+            .unwrap();
+        assert!(connected(&a, b_id).await);
+        assert!(connected(&b, a_id).await);
     }
 
-    pub fn subscribe(&self) -> impl Stream<Item = MeshEvent> {
-        // TODO
-        futures_util::stream::empty().boxed()
+    #[tokio::test]
+    async fn test_unknown_token() {
+        let a = bind().await;
+        let b = bind().await;
+        let b_id = SfuId::new();
+
+        let res = a
+            .connect(b.addr(), "wrong".into(), b_id, b.fingerprint())
+            .await;
+        assert!(res.is_err());
+        assert!(a.lookup(&b_id).is_none());
     }
 }
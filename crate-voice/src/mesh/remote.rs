@@ -1,43 +1,401 @@
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
-use crate::{mesh::datagram::Datagram, prelude::*};
+use common::v1::types::SfuId;
+use common::v2::types::ChannelId;
+use dashmap::DashMap;
+use quinn::{ConnectionError, VarInt};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinSet;
+use tracing::{debug, info, trace, warn};
+
+use crate::mesh::MeshEvent;
+use crate::mesh::datagram::Datagram;
+use crate::mesh::stream::{
+    AcceptedStream, Announce, Goodbye, GoodbyeCode, Header, MAX_MESSAGE_SIZE, MeshStream, Probe,
+    ProbeResponse, Subscribe, SubscribeCommand, SubscribeConfig, SubscribeId,
+};
+use crate::prelude::*;
+
+/// how long to wait for the remote to close the connection after a goodbye
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// where media for a subscription is sent
+pub type MediaSink = Arc<dyn Fn(SfuId, Datagram) + Send + Sync>;
 
 /// manages a connection to another sfu
 pub struct Remote {
-    // TODO
+    handle: RemoteHandle,
+    events: broadcast::Sender<MeshEvent>,
+    announce_rx: mpsc::UnboundedReceiver<Announce>,
+    stream_tasks: JoinSet<Result<()>>,
 }
 
+#[derive(Clone)]
 pub struct RemoteHandle {
-    // TODO
+    inner: Arc<RemoteInner>,
+}
+
+struct RemoteInner {
+    sfu_id: SfuId,
+    conn: quinn::Connection,
+
+    /// announcements to be written to the announce stream, in order
+    announce: mpsc::UnboundedSender<Announce>,
+
+    /// subscriptions to the remote's tracks
+    subscriptions: DashMap<SubscribeId, Subscription>,
+    next_subscribe_id: AtomicU64,
+}
+
+/// a subscription to one of the remote's tracks
+struct Subscription {
+    sink: MediaSink,
+
+    /// commands for the subscription stream. the stream is closed when this is dropped.
+    commands: mpsc::UnboundedSender<SubscribeCommand>,
 }
 
 impl Remote {
-    // #[tracing::instrument(skip(self, incoming))]
-    // pub async fn handle_incoming(&mut self, incoming: quinn::Incoming) {
-    //     todo!()
-    // }
+    pub fn new(
+        sfu_id: SfuId,
+        conn: quinn::Connection,
+        events: broadcast::Sender<MeshEvent>,
+    ) -> Self {
+        let (announce, announce_rx) = mpsc::unbounded_channel();
+        let handle = RemoteHandle {
+            inner: Arc::new(RemoteInner {
+                sfu_id,
+                conn,
+                announce,
+                subscriptions: DashMap::new(),
+                next_subscribe_id: AtomicU64::new(0),
+            }),
+        };
+        Self {
+            handle,
+            events,
+            announce_rx,
+            stream_tasks: JoinSet::new(),
+        }
+    }
+
+    pub fn handle(&self) -> RemoteHandle {
+        self.handle.clone()
+    }
+
+    /// handle incoming streams and datagrams until the connection closes
+    #[tracing::instrument(skip(self), fields(sfu_id = %self.handle.sfu_id()))]
+    pub async fn run(mut self) -> Result<()> {
+        let conn = self.handle.inner.conn.clone();
+
+        // a single long lived stream keeps announcements ordered
+        let mut announce_rx = self.announce_rx;
+        let announce_conn = conn.clone();
+        self.stream_tasks.spawn(async move {
+            let mut stream = MeshStream::<Announce>::open(&announce_conn, Header::Announce).await?;
+            while let Some(announce) = announce_rx.recv().await {
+                stream.write(&announce).await?;
+            }
+            stream.finish();
+            Ok(())
+        });
+
+        let res = loop {
+            tokio::select! {
+                bi = conn.accept_bi() => {
+                    let (send, recv) = match bi {
+                        Ok(s) => s,
+                        Err(err) => break err,
+                    };
+                    let handle = self.handle.clone();
+                    let events = self.events.clone();
+                    self.stream_tasks.spawn(async move {
+                        let stream = MeshStream::new(send, recv).accept().await?;
+                        handle.handle_stream(stream, events).await
+                    });
+                }
+                uni = conn.accept_uni() => {
+                    let mut recv = match uni {
+                        Ok(s) => s,
+                        Err(err) => break err,
+                    };
+                    let handle = self.handle.clone();
+                    self.stream_tasks.spawn(async move {
+                        let mut header = [0u8; 1];
+                        recv.read_exact(&mut header).await?;
+                        if Header::from_byte(header[0]) != Some(Header::Frame) {
+                            return Err(Error::Mesh(format!("Unsupported uni stream header: {}", header[0])));
+                        }
+                        let data = recv
+                            .read_to_end(MAX_MESSAGE_SIZE)
+                            .await
+                            .map_err(|e| Error::Mesh(e.to_string()))?;
+                        handle.dispatch(Datagram::parse(&data)?);
+                        Ok(())
+                    });
+                }
+                datagram = conn.read_datagram() => {
+                    let data = match datagram {
+                        Ok(d) => d,
+                        Err(err) => break err,
+                    };
+                    match Datagram::parse(&data) {
+                        Ok(d) => self.handle.dispatch(d),
+                        Err(err) => trace!("invalid datagram: {err}"),
+                    }
+                }
+                Some(next) = self.stream_tasks.join_next() => {
+                    match next {
+                        Err(err) => warn!("stream task join error {err}"),
+                        Ok(Err(err)) => debug!("stream error {err}"),
+                        Ok(Ok(())) => {}
+                    }
+                }
+            }
+        };
+
+        self.stream_tasks.shutdown().await;
+        match res {
+            ConnectionError::ApplicationClosed(c) => {
+                debug!("closed with code {}", c.error_code);
+                Ok(())
+            }
+            ConnectionError::LocallyClosed => Ok(()),
+            err => Err(err.into()),
+        }
+    }
 }
 
 impl RemoteHandle {
+    pub fn sfu_id(&self) -> SfuId {
+        self.inner.sfu_id
+    }
+
+    pub(super) fn connection(&self) -> &quinn::Connection {
+        &self.inner.conn
+    }
+
     /// get round trip time
     pub fn rtt(&self) -> Option<Duration> {
-        todo!()
+        if self.inner.conn.close_reason().is_some() {
+            None
+        } else {
+            Some(self.inner.conn.rtt())
+        }
+    }
+
+    /// send track changes for a call
+    pub fn announce(&self, announce: Announce) {
+        let _ = self.inner.announce.send(announce);
     }
 
     /// subscribe to a track
-    pub async fn subscribe(&self /* ... */) -> Result<()> {
-        todo!()
+    ///
+    /// media for this subscription is sent to `sink`
+    pub fn subscribe(&self, channel_id: ChannelId, track_id: u64, sink: MediaSink) -> SubscribeId {
+        let id = SubscribeId(self.inner.next_subscribe_id.fetch_add(1, Ordering::Relaxed));
+        let (commands, mut commands_rx) = mpsc::unbounded_channel();
+        self.inner
+            .subscriptions
+            .insert(id, Subscription { sink, commands });
+
+        let handle = self.clone();
+        let subscribe = Subscribe {
+            id,
+            channel_id,
+            track_id,
+            config: SubscribeConfig::default(),
+        };
+        tokio::spawn(async move {
+            let res: Result<()> = async {
+                let mut stream =
+                    MeshStream::<Subscribe>::open(&handle.inner.conn, Header::Subscribe).await?;
+                stream.create(&subscribe).await?;
+                while let Some(cmd) = commands_rx.recv().await {
+                    stream.command(cmd).await?;
+                }
+                stream.command(SubscribeCommand::Close).await?;
+                stream.finish();
+                Ok(())
+            }
+            .await;
+
+            if let Err(err) = res {
+                debug!(?id, "subscription stream error {err}");
+            }
+
+            handle.inner.subscriptions.remove(&id);
+        });
+
+        id
+    }
+
+    /// whether a subscription is still active
+    pub fn has_subscription(&self, id: SubscribeId) -> bool {
+        self.inner.subscriptions.contains_key(&id)
+    }
+
+    /// stop receiving media for a subscription
+    pub fn unsubscribe(&self, id: SubscribeId) {
+        // dropping the command sender closes the stream
+        self.inner.subscriptions.remove(&id);
+    }
+
+    /// ask the publisher to generate a keyframe
+    pub fn request_keyframe(&self, id: SubscribeId) {
+        if let Some(sub) = self.inner.subscriptions.get(&id) {
+            let _ = sub.commands.send(SubscribeCommand::Keyframe);
+        }
+    }
+
+    /// send a datagram, falling back to a stream if its too big
+    pub fn send_datagram(&self, datagram: &Datagram) -> Result<()> {
+        let data = datagram.encode()?;
+        let fits = self
+            .inner
+            .conn
+            .max_datagram_size()
+            .is_some_and(|max| data.len() <= max);
+
+        if fits {
+            self.inner
+                .conn
+                .send_datagram(data.into())
+                .map_err(|e| Error::Mesh(e.to_string()))?;
+        } else {
+            let conn = self.inner.conn.clone();
+            tokio::spawn(async move {
+                let res: Result<()> = async {
+                    let mut send = conn.open_uni().await?;
+                    send.write_all(&[Header::Frame as u8]).await?;
+                    send.write_all(&data).await?;
+                    let _ = send.finish();
+                    Ok(())
+                }
+                .await;
+                if let Err(err) = res {
+                    debug!("failed to send frame stream {err}");
+                }
+            });
+        }
+
+        Ok(())
     }
 
-    pub async fn send_datagram(&self, datagram: Datagram) -> Result<()> {
-        todo!()
+    /// measure the round trip time to the remote
+    pub async fn probe(&self) -> Result<Duration> {
+        let nonce = rand::random();
+        let start = Instant::now();
+        let mut stream = MeshStream::<Probe>::open(&self.inner.conn, Header::Probe).await?;
+        stream.write(&Probe { nonce }).await?;
+        stream.finish();
+        let res: ProbeResponse = stream
+            .read()
+            .await?
+            .ok_or_else(|| Error::Mesh("probe stream closed".into()))?;
+        if res.nonce != nonce {
+            return Err(Error::Mesh("probe nonce mismatch".into()));
+        }
+        Ok(start.elapsed())
     }
 
-    pub async fn probe(&self) -> Result<()> {
-        todo!()
+    /// gracefully close this connection
+    pub async fn goodbye(&self, code: GoodbyeCode) -> Result<()> {
+        let mut stream = MeshStream::<Goodbye>::open(&self.inner.conn, Header::Goodbye).await?;
+        stream.write(&Goodbye { code }).await?;
+        stream.finish();
+
+        // give the remote a chance to read the goodbye and close the connection itself
+        let _ = tokio::time::timeout(GOODBYE_TIMEOUT, self.inner.conn.closed()).await;
+        self.inner.conn.close(VarInt::from_u32(0), b"goodbye");
+        Ok(())
     }
 
-    pub async fn goodbye(&self) -> Result<()> {
-        todo!()
+    /// forward a datagram to its subscription's sink
+    fn dispatch(&self, datagram: Datagram) {
+        let Some(sub) = self.inner.subscriptions.get(&datagram.subscribe_id()) else {
+            trace!("datagram for unknown subscription");
+            return;
+        };
+
+        (sub.sink)(self.inner.sfu_id, datagram);
+    }
+
+    async fn handle_stream(
+        &self,
+        stream: AcceptedStream,
+        events: broadcast::Sender<MeshEvent>,
+    ) -> Result<()> {
+        let sfu_id = self.inner.sfu_id;
+        match stream {
+            AcceptedStream::Hello(_) => Err(Error::Mesh("already authenticated".into())),
+            AcceptedStream::Announce(mut stream) => {
+                while let Some(announce) = stream.read::<Announce>().await? {
+                    let _ = events.send(MeshEvent::Announce { sfu_id, announce });
+                }
+                Ok(())
+            }
+            AcceptedStream::Subscribe(mut stream) => {
+                if stream.next_command().await? != Some(SubscribeCommand::Create) {
+                    return Err(Error::Mesh("subscription must start with create".into()));
+                }
+                let subscribe: Subscribe = stream
+                    .read()
+                    .await?
+                    .ok_or_else(|| Error::Mesh("missing subscribe".into()))?;
+                let channel_id = subscribe.channel_id;
+                let id = subscribe.id;
+                let _ = events.send(MeshEvent::Subscribe { sfu_id, subscribe });
+
+                let res = async {
+                    loop {
+                        match stream.next_command().await? {
+                            Some(SubscribeCommand::Create) => {
+                                return Err(Error::Mesh("duplicate create".into()));
+                            }
+                            Some(SubscribeCommand::Update) => {
+                                // TODO: use subscription config
+                                let config: Option<SubscribeConfig> = stream.read().await?;
+                                debug!(?id, ?config, "subscription updated");
+                            }
+                            Some(SubscribeCommand::Ack) => {}
+                            Some(SubscribeCommand::Keyframe) => {
+                                let _ = events.send(MeshEvent::Keyframe {
+                                    sfu_id,
+                                    channel_id,
+                                    id,
+                                });
+                            }
+                            Some(SubscribeCommand::Close) | None => return Ok(()),
+                        }
+                    }
+                }
+                .await;
+
+                stream.finish();
+                let _ = events.send(MeshEvent::Unsubscribe {
+                    sfu_id,
+                    channel_id,
+                    id,
+                });
+                res
+            }
+            AcceptedStream::Probe(mut stream) => {
+                let probe: Probe = stream
+                    .read()
+                    .await?
+                    .ok_or_else(|| Error::Mesh("missing probe".into()))?;
+                stream.write(&ProbeResponse { nonce: probe.nonce }).await?;
+                stream.finish();
+                Ok(())
+            }
+            AcceptedStream::Goodbye(mut stream) => {
+                let goodbye: Option<Goodbye> = stream.read().await?;
+                info!(?goodbye, "remote said goodbye");
+                self.inner.conn.close(VarInt::from_u32(0), b"goodbye");
+                Ok(())
+            }
+        }
     }
 }
@@ -1,15 +1,18 @@
 //! wire format for streams
 
 use crate::prelude::*;
-use common::{v1::types::voice::Mid, v2::types::UserId};
-use serde::{Deserialize, Serialize};
+use common::v2::types::{ChannelId, UserId};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::marker::PhantomData;
 
 // TODO: move these types to lamprey-common?
 // somewhat inspired by https://www.ietf.org/archive/id/draft-lcurley-moq-lite-04.html
 
+/// max size of a single length prefixed message on a stream
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
 /// header byte, the first thing that is sent when a quic stream is opened
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum Header {
     /// authenticate
@@ -30,17 +33,76 @@ pub enum Header {
 
     /// disconnect
     Goodbye = 0x06,
+
+    /// a single media frame that is too big for a datagram
+    ///
+    /// only sent on unidirectional streams
+    Frame = 0x07,
+}
+
+impl Header {
+    pub fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0x01 => Some(Header::Hello),
+            0x02 => Some(Header::Announce),
+            0x03 => Some(Header::Subscribe),
+            0x04 => Some(Header::Fetch),
+            0x05 => Some(Header::Probe),
+            0x06 => Some(Header::Goodbye),
+            0x07 => Some(Header::Frame),
+            _ => None,
+        }
+    }
 }
 
 pub struct Blank;
 
+/// the first stream opened by the connecting sfu
+///
+/// the accepting sfu finishes its side of the stream once the token is verified
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Hello {
     pub token: String,
 }
 
+/// a batch of track changes for a call
+///
+/// sent on a single long lived stream so that changes are applied in order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Announce {
+    #[serde(with = "id_str")]
+    pub channel_id: ChannelId,
+    pub added: Vec<AnnouncedTrack>,
+    pub removed: Vec<u64>,
+
+    /// tracks that moved in the call's virtual space
+    pub positions: Vec<AnnouncedPosition>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnouncedTrack {
+    /// the track id on the announcing sfu
+    pub id: u64,
+
+    /// the user publishing this track
+    #[serde(with = "id_str")]
+    pub user_id: UserId,
+
+    /// json encoded `TrackMetadata2`
+    // NOTE: postcard doesn't support flattened or untagged types
+    pub metadata: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SubscribeId(u64);
+pub struct AnnouncedPosition {
+    /// the track id on the announcing sfu
+    pub id: u64,
+    pub position: Option<[f32; 3]>,
+    pub direction: Option<[f32; 3]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SubscribeId(pub u64);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Subscribe {
@@ -48,8 +110,9 @@ pub struct Subscribe {
     pub id: SubscribeId,
 
     // which track to subscribe to
-    pub publisher_id: UserId,
-    pub track_id: Mid,
+    #[serde(with = "id_str")]
+    pub channel_id: ChannelId,
+    pub track_id: u64,
     // pub layer_id: Option<Rid>,
     pub config: SubscribeConfig,
 }
@@ -68,8 +131,18 @@ pub struct SubscribeConfig {
     pub max_latency: u32,
 }
 
+impl Default for SubscribeConfig {
+    fn default() -> Self {
+        Self {
+            priority: 0,
+            ordered: SubscribeOrder::Desc,
+            max_latency: 500,
+        }
+    }
+}
+
 /// command sent to a subscription stream
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum SubscribeCommand {
     /// create a new subscription
@@ -92,6 +165,19 @@ pub enum SubscribeCommand {
     Keyframe = 0x04,
 }
 
+impl SubscribeCommand {
+    pub fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0x00 => Some(SubscribeCommand::Create),
+            0x01 => Some(SubscribeCommand::Update),
+            0x02 => Some(SubscribeCommand::Ack),
+            0x03 => Some(SubscribeCommand::Close),
+            0x04 => Some(SubscribeCommand::Keyframe),
+            _ => None,
+        }
+    }
+}
+
 /// whether to transmit groups in ascending or descending order
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[repr(u8)]
@@ -102,19 +188,20 @@ pub enum SubscribeOrder {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Probe {
+    /// echoed back in the response
+    pub nonce: u64,
     // bitrate
-    // rtt
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProbeResponse {
+    pub nonce: u64,
     // bitrate
-    // rtt
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Goodbye {
-    code: GoodbyeCode,
+    pub code: GoodbyeCode,
     // /// where to reconnect
     // ///
     // /// if this is None, don't reconnect. may be the same url.
@@ -122,7 +209,7 @@ pub struct Goodbye {
 }
 
 // impl fn can_reconnect()
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum GoodbyeCode {
     /// move to a different voice channel
@@ -136,21 +223,33 @@ pub enum GoodbyeCode {
 
     /// user lost permission
     Deauthorized,
+
+    /// no calls are routed through this connection anymore
+    Unused,
 }
 
-/// a stream of ordered frames
+/// (de)serialize ids as plain strings
 ///
-/// sent by the peer in response to a Subscription stream
-pub struct Group {
-    /// corresponding subscribe id for this group
-    subscribe_id: SubscribeId,
+/// the common id deserializer uses `deserialize_any`, which postcard doesn't support
+mod id_str {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::{fmt::Display, str::FromStr};
 
-    /// sequence number of this group. monotonic, may have gaps.
-    seq: u64,
-}
+    pub fn serialize<T: Display, S: Serializer>(id: &T, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(id)
+    }
 
-/// a chunk of bytes in a group
-pub struct Frame<'a>(&'a [u8]);
+    pub fn deserialize<'de, T, D>(d: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(d)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
 
 /// a wrapper to parse a stream
 pub struct MeshStream<T> {
@@ -160,18 +259,93 @@ pub struct MeshStream<T> {
 }
 
 pub enum AcceptedStream {
+    Announce(MeshStream<Announce>),
     Subscribe(MeshStream<Subscribe>),
     Probe(MeshStream<Probe>),
     Hello(MeshStream<Hello>),
     Goodbye(MeshStream<Goodbye>),
 }
 
+/// write a length prefixed postcard message
+pub async fn write_message<M: Serialize>(send: &mut quinn::SendStream, msg: &M) -> Result<()> {
+    let data = postcard::to_stdvec(msg)?;
+    send.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    send.write_all(&data).await?;
+    Ok(())
+}
+
+/// read a length prefixed postcard message
+///
+/// returns None if the stream was finished
+pub async fn read_message<M: DeserializeOwned>(recv: &mut quinn::RecvStream) -> Result<Option<M>> {
+    let mut len = [0u8; 4];
+    match recv.read_exact(&mut len).await {
+        Ok(()) => {}
+        Err(quinn::ReadExactError::FinishedEarly(0)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(Error::Mesh(format!("message too large: {len}")));
+    }
+
+    let mut buf = vec![0u8; len];
+    recv.read_exact(&mut buf).await?;
+    Ok(Some(postcard::from_bytes(&buf)?))
+}
+
+/// read a single byte, returning None if the stream was finished
+async fn read_byte(recv: &mut quinn::RecvStream) -> Result<Option<u8>> {
+    let mut buf = [0u8; 1];
+    match recv.read_exact(&mut buf).await {
+        Ok(()) => Ok(Some(buf[0])),
+        Err(quinn::ReadExactError::FinishedEarly(0)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
 impl<T> MeshStream<T> {
-    // pub fn new(recv: ()) -> Self {
-    //     todo!()
-    // }
+    fn cast(send: quinn::SendStream, recv: quinn::RecvStream) -> Self {
+        Self {
+            send,
+            recv,
+            _t: PhantomData,
+        }
+    }
+
+    /// open a new stream of this type
+    pub async fn open(conn: &quinn::Connection, header: Header) -> Result<Self> {
+        let (mut send, recv) = conn.open_bi().await?;
+        send.write_all(&[header as u8]).await?;
+        Ok(Self {
+            send,
+            recv,
+            _t: PhantomData,
+        })
+    }
+
+    pub async fn write<M: Serialize>(&mut self, msg: &M) -> Result<()> {
+        write_message(&mut self.send, msg).await
+    }
+
+    pub async fn read<M: DeserializeOwned>(&mut self) -> Result<Option<M>> {
+        read_message(&mut self.recv).await
+    }
 
-    // fn parse(&mut self, data: &[u8]) {}
+    /// gracefully close the sending half of this stream
+    pub fn finish(&mut self) {
+        let _ = self.send.finish();
+    }
+
+    /// wait until the peer finishes its sending half
+    pub async fn closed(&mut self) -> Result<()> {
+        self.recv
+            .read_to_end(0)
+            .await
+            .map_err(|e| Error::Mesh(e.to_string()))?;
+        Ok(())
+    }
 }
 
 impl MeshStream<Blank> {
@@ -184,51 +358,48 @@ impl MeshStream<Blank> {
     }
 
     pub async fn accept(mut self) -> Result<AcceptedStream> {
-        let mut header_buf = [0u8; 1];
-        self.recv
-            .read_exact(&mut header_buf)
-            .await
-            .expect("TODO: better error handling");
-        // TODO: impl Header::from_byte() -> Option<Self>
-        // maybe use serde/postcard directly?
-        match header_buf[0] {
-            0x01 => Ok(AcceptedStream::Hello(MeshStream {
-                send: self.send,
-                recv: self.recv,
-                _t: PhantomData,
-            })),
-            0x03 => Ok(AcceptedStream::Subscribe(MeshStream {
-                send: self.send,
-                recv: self.recv,
-                _t: PhantomData,
-            })),
-            0x05 => Ok(AcceptedStream::Probe(MeshStream {
-                send: self.send,
-                recv: self.recv,
-                _t: PhantomData,
-            })),
-            0x06 => Ok(AcceptedStream::Goodbye(MeshStream {
-                send: self.send,
-                recv: self.recv,
-                _t: PhantomData,
-            })),
-            other => Err(Error::Backend(format!(
-                "Unsupported stream header: {other}"
-            ))),
+        let header = read_byte(&mut self.recv)
+            .await?
+            .ok_or_else(|| Error::Mesh("stream finished before header".into()))?;
+        let header = Header::from_byte(header)
+            .ok_or_else(|| Error::Mesh(format!("Unsupported stream header: {header}")))?;
+        let MeshStream { send, recv, .. } = self;
+        match header {
+            Header::Hello => Ok(AcceptedStream::Hello(MeshStream::cast(send, recv))),
+            Header::Announce => Ok(AcceptedStream::Announce(MeshStream::cast(send, recv))),
+            Header::Subscribe => Ok(AcceptedStream::Subscribe(MeshStream::cast(send, recv))),
+            Header::Probe => Ok(AcceptedStream::Probe(MeshStream::cast(send, recv))),
+            Header::Goodbye => Ok(AcceptedStream::Goodbye(MeshStream::cast(send, recv))),
+            other => Err(Error::Mesh(format!("Unsupported stream header: {other:?}"))),
         }
     }
 }
 
 impl MeshStream<Subscribe> {
-    // these need `send`
-    // pub fn configure(&self, subscription: ()) {
-    //     todo!()
-    // }
-    //
-    // pub fn ack(&self) {}
-    // pub fn keyframe(&self) {}
-    // pub fn close(self) {}
-
-    // how do i type this?
-    // pub fn recv(self) {}
+    /// send a command without a payload
+    pub async fn command(&mut self, cmd: SubscribeCommand) -> Result<()> {
+        self.send.write_all(&[cmd as u8]).await?;
+        Ok(())
+    }
+
+    /// create a subscription. must be the first thing written to the stream.
+    pub async fn create(&mut self, subscribe: &Subscribe) -> Result<()> {
+        self.command(SubscribeCommand::Create).await?;
+        self.write(subscribe).await
+    }
+
+    pub async fn configure(&mut self, config: &SubscribeConfig) -> Result<()> {
+        self.command(SubscribeCommand::Update).await?;
+        self.write(config).await
+    }
+
+    /// read the next command, returning None once the stream is finished
+    pub async fn next_command(&mut self) -> Result<Option<SubscribeCommand>> {
+        let Some(b) = read_byte(&mut self.recv).await? else {
+            return Ok(None);
+        };
+        SubscribeCommand::from_byte(b)
+            .map(Some)
+            .ok_or_else(|| Error::Mesh(format!("invalid subscribe command {b}")))
+    }
 }
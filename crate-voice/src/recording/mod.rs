@@ -0,0 +1,249 @@
+//! call recording
+//!
+//! each sfu records the tracks published by its own peers into one file per
+//! track. the files are uploaded to the api server once recording stops, which
+//! mixes the audio and posts everything to the channel. the files themselves
+//! are written by a separate thread, see [`writer`].
+
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    io::{self, BufWriter},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use common::{
+    v1::types::voice::MediaKind,
+    v2::types::{ChannelId, UserId},
+};
+use slotmap::Key;
+use str0m::{format::Codec, media::MediaTime};
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+use crate::client::webrtc::track::{Frame, Inbound};
+use crate::prelude::*;
+use crate::recording::{
+    ogg::OggOpusWriter,
+    webm::{VideoCodec, WebmWriter},
+    writer::{CHUNK_SIZE, FileWriter, Sink},
+};
+
+pub mod ogg;
+pub mod upload;
+pub mod webm;
+pub mod writer;
+
+/// how often to ask the publisher for a keyframe while a video track is waiting for one
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// records every local track in a call
+pub struct Recorder {
+    channel_id: ChannelId,
+    started_at: Instant,
+    tracks: HashMap<TrackSlot, TrackRecorder>,
+    files: FileWriter,
+
+    /// tracks that can't be recorded, eg. because of an unsupported codec or encryption
+    skipped: HashSet<TrackSlot>,
+}
+
+/// a file containing a single recorded track
+#[derive(Debug)]
+pub struct RecordedFile {
+    pub user_id: UserId,
+    pub kind: MediaKind,
+
+    /// ms after recording started that this track begins
+    pub offset: u64,
+
+    pub path: PathBuf,
+    pub filename: String,
+}
+
+struct TrackRecorder {
+    file: RecordedFile,
+    writer: Writer,
+    offset: Option<u64>,
+    last_keyframe_request: Option<Instant>,
+}
+
+enum Writer {
+    Ogg(OggOpusWriter<BufWriter<Sink>>),
+    Webm(WebmWriter<BufWriter<Sink>>),
+}
+
+impl Recorder {
+    pub fn new(channel_id: ChannelId) -> io::Result<Self> {
+        Ok(Self {
+            channel_id,
+            started_at: Instant::now(),
+            tracks: HashMap::new(),
+            files: FileWriter::spawn(channel_id)?,
+            skipped: HashSet::new(),
+        })
+    }
+
+    /// write a frame from a local inbound track
+    ///
+    /// returns true if a keyframe should be requested for this track
+    pub fn write(&mut self, source: TrackSlot, track: &Inbound, frame: &Frame) -> bool {
+        if self.skipped.contains(&source) {
+            return false;
+        }
+
+        // the sfu doesn't have the keys for e2ee tracks
+        if track.metadata().encrypted {
+            debug!(channel_id = ?self.channel_id, ?source, "Not recording encrypted track");
+            self.skipped.insert(source);
+            return false;
+        }
+
+        let index = self.tracks.len();
+        let recorder = match self.tracks.entry(source) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                match TrackRecorder::new(self.channel_id, &self.files, source, index, track, frame)
+                {
+                    Ok(Some(r)) => e.insert(r),
+                    Ok(None) => {
+                        debug!(channel_id = ?self.channel_id, ?source, "Not recording track with unsupported codec");
+                        self.skipped.insert(source);
+                        return false;
+                    }
+                    Err(err) => {
+                        warn!(channel_id = ?self.channel_id, ?source, "Failed to create recording: {}", err);
+                        self.files.remove(source);
+                        self.skipped.insert(source);
+                        return false;
+                    }
+                }
+            }
+        };
+
+        match recorder.write(frame, self.started_at) {
+            Ok(wants_keyframe) => wants_keyframe,
+            Err(err) => {
+                warn!(channel_id = ?self.channel_id, ?source, "Failed to write recording: {}", err);
+                self.tracks.remove(&source);
+                self.files.remove(source);
+                self.skipped.insert(source);
+                false
+            }
+        }
+    }
+
+    /// stop recording
+    ///
+    /// resolves to every file that has something in it once they're all written
+    pub fn finish(self) -> oneshot::Receiver<Vec<RecordedFile>> {
+        let mut files = Vec::new();
+        for (source, recorder) in self.tracks {
+            match recorder.finish() {
+                Ok(Some(file)) => files.push((source, file)),
+                Ok(None) => {}
+                Err(err) => {
+                    warn!(channel_id = ?self.channel_id, ?source, "Failed to finish recording: {}", err);
+                }
+            }
+        }
+        self.files.finish(files)
+    }
+}
+
+impl TrackRecorder {
+    /// returns None if this track's codec can't be recorded
+    fn new(
+        channel_id: ChannelId,
+        files: &FileWriter,
+        source: TrackSlot,
+        index: usize,
+        track: &Inbound,
+        frame: &Frame,
+    ) -> io::Result<Option<Self>> {
+        let ext = match frame.params.spec().codec {
+            Codec::Opus => "ogg",
+            Codec::Vp8 | Codec::Vp9 => "webm",
+            _ => return Ok(None),
+        };
+
+        // random so a new recording in the same call can't clobber one that's still uploading
+        let path = std::env::temp_dir().join(format!(
+            "lamprey-recording-{}-{}-{:016x}.{}",
+            channel_id,
+            source.data().as_ffi(),
+            rand::random::<u64>(),
+            ext
+        ));
+        let out = BufWriter::with_capacity(CHUNK_SIZE, files.create(source, path.clone()));
+        let writer = match frame.params.spec().codec {
+            Codec::Opus => Writer::Ogg(OggOpusWriter::new(out, rand::random())?),
+            Codec::Vp8 => Writer::Webm(WebmWriter::new(out, VideoCodec::Vp8)),
+            _ => Writer::Webm(WebmWriter::new(out, VideoCodec::Vp9)),
+        };
+
+        Ok(Some(Self {
+            file: RecordedFile {
+                user_id: track.user_id,
+                kind: track.kind(),
+                offset: 0,
+                path,
+                filename: format!("{}-{}.{}", track.user_id, index, ext),
+            },
+            writer,
+            offset: None,
+            last_keyframe_request: None,
+        }))
+    }
+
+    fn write(&mut self, frame: &Frame, started_at: Instant) -> io::Result<bool> {
+        let written = match &mut self.writer {
+            Writer::Ogg(w) => {
+                w.write(rescale(frame.time, 48_000), &frame.data)?;
+                true
+            }
+            Writer::Webm(w) => w.write(rescale(frame.time, 1_000), &frame.data)?,
+        };
+
+        if written {
+            self.offset.get_or_insert_with(|| {
+                frame
+                    .network_time
+                    .saturating_duration_since(started_at)
+                    .as_millis() as u64
+            });
+            return Ok(false);
+        }
+
+        let now = Instant::now();
+        if self
+            .last_keyframe_request
+            .is_some_and(|t| now.duration_since(t) < KEYFRAME_REQUEST_INTERVAL)
+        {
+            return Ok(false);
+        }
+        self.last_keyframe_request = Some(now);
+        Ok(true)
+    }
+
+    fn finish(self) -> io::Result<Option<RecordedFile>> {
+        let Some(offset) = self.offset else {
+            return Ok(None);
+        };
+
+        match self.writer {
+            Writer::Ogg(w) => w.finish()?,
+            Writer::Webm(w) => w.finish()?,
+        };
+
+        Ok(Some(RecordedFile {
+            offset,
+            ..self.file
+        }))
+    }
+}
+
+/// convert a media time to a timestamp with a different clock rate
+fn rescale(time: MediaTime, rate: u64) -> u64 {
+    (time.numer() as u128 * rate as u128 / time.frequency().get() as u128) as u64
+}
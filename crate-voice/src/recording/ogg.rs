@@ -0,0 +1,220 @@
+//! ogg opus muxer (rfc 7845)
+
+use std::io::{self, Write};
+
+/// opus always uses a 48khz clock for granule positions and rtp timestamps
+const OPUS_CLOCK_RATE: u64 = 48_000;
+
+/// 20ms of silence, used to fill gaps from dtx and packet loss
+const OPUS_SILENCE: [u8; 3] = [0xf8, 0xff, 0xfe];
+
+/// the number of samples in `OPUS_SILENCE`
+const OPUS_SILENCE_SAMPLES: u64 = 960;
+
+/// flush a page after this many bytes of packet data
+const MAX_PAGE_SIZE: usize = 4096;
+
+/// the maximum number of lacing values in a single page
+const MAX_SEGMENTS: usize = 255;
+
+const FLAG_BOS: u8 = 0x02;
+const FLAG_EOS: u8 = 0x04;
+
+/// writes opus packets into an ogg container
+pub struct OggOpusWriter<W> {
+    inner: W,
+    serial: u32,
+    sequence: u32,
+
+    /// rtp timestamp of the first packet
+    first_time: Option<u64>,
+
+    /// granule position after the last packet that was written
+    granule: u64,
+
+    segments: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl<W: Write> OggOpusWriter<W> {
+    /// create a new writer, writing the opus headers immediately
+    pub fn new(inner: W, serial: u32) -> io::Result<Self> {
+        let mut me = Self {
+            inner,
+            serial,
+            sequence: 0,
+            first_time: None,
+            granule: 0,
+            segments: Vec::new(),
+            data: Vec::new(),
+        };
+
+        let mut head = Vec::with_capacity(19);
+        head.extend_from_slice(b"OpusHead");
+        head.push(1); // version
+        head.push(2); // channel count
+        head.extend_from_slice(&0u16.to_le_bytes()); // pre-skip
+        head.extend_from_slice(&(OPUS_CLOCK_RATE as u32).to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+        head.push(0); // channel mapping family
+        me.push_packet(&head);
+        me.write_page(FLAG_BOS, 0)?;
+
+        let vendor = b"lamprey";
+        let mut tags = Vec::new();
+        tags.extend_from_slice(b"OpusTags");
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor);
+        tags.extend_from_slice(&0u32.to_le_bytes()); // user comment count
+        me.push_packet(&tags);
+        me.write_page(0, 0)?;
+
+        Ok(me)
+    }
+
+    /// whether no packets have been written yet
+    pub fn is_empty(&self) -> bool {
+        self.first_time.is_none()
+    }
+
+    /// write a packet with a 48khz rtp timestamp
+    pub fn write(&mut self, rtp_time: u64, packet: &[u8]) -> io::Result<()> {
+        let Some(samples) = opus_packet_samples(packet) else {
+            return Ok(());
+        };
+
+        let first = *self.first_time.get_or_insert(rtp_time);
+        let position = rtp_time.saturating_sub(first);
+
+        // nothing is sent while dtx is active, so fill the gap with silence
+        while position >= self.granule + OPUS_SILENCE_SAMPLES {
+            self.write_packet(&OPUS_SILENCE, OPUS_SILENCE_SAMPLES)?;
+        }
+
+        self.write_packet(packet, samples)
+    }
+
+    /// flush everything and end the stream
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_page(FLAG_EOS, self.granule)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_packet(&mut self, packet: &[u8], samples: u64) -> io::Result<()> {
+        let needed = packet.len() / 255 + 1;
+        if self.segments.len() + needed > MAX_SEGMENTS {
+            self.write_page(0, self.granule)?;
+        }
+
+        self.push_packet(packet);
+        self.granule += samples;
+
+        if self.data.len() >= MAX_PAGE_SIZE {
+            self.write_page(0, self.granule)?;
+        }
+
+        Ok(())
+    }
+
+    fn push_packet(&mut self, packet: &[u8]) {
+        let mut remaining = packet.len();
+        while remaining >= 255 {
+            self.segments.push(255);
+            remaining -= 255;
+        }
+        self.segments.push(remaining as u8);
+        self.data.extend_from_slice(packet);
+    }
+
+    fn write_page(&mut self, flags: u8, granule: u64) -> io::Result<()> {
+        let mut page = Vec::with_capacity(27 + self.segments.len() + self.data.len());
+        page.extend_from_slice(b"OggS");
+        page.push(0); // version
+        page.push(flags);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&0u32.to_le_bytes()); // crc, filled in below
+        page.push(self.segments.len() as u8);
+        page.extend_from_slice(&self.segments);
+        page.extend_from_slice(&self.data);
+
+        let crc = crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        self.inner.write_all(&page)?;
+        self.sequence += 1;
+        self.segments.clear();
+        self.data.clear();
+        Ok(())
+    }
+}
+
+/// get the number of 48khz samples in an opus packet from its toc byte
+fn opus_packet_samples(packet: &[u8]) -> Option<u64> {
+    let toc = *packet.first()?;
+    let config = toc >> 3;
+    let frame_size = match config {
+        // silk
+        0..=11 => [480, 960, 1920, 2880][config as usize % 4],
+        // hybrid
+        12..=15 => [480, 960][config as usize % 2],
+        // celt
+        _ => [120, 240, 480, 960][config as usize % 4],
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*packet.get(1)? & 0x3f) as u64,
+    };
+    Some(frame_size * frames)
+}
+
+/// the crc used by ogg (polynomial 0x04c11db7, no reflection, no final xor)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(&[0x01]), 0x04c1_1db7);
+    }
+
+    #[test]
+    fn test_packet_samples() {
+        // celt 20ms, one frame
+        assert_eq!(opus_packet_samples(&[0xf8, 0xff, 0xfe]), Some(960));
+        // silk 10ms, two frames
+        assert_eq!(opus_packet_samples(&[0x01]), Some(960));
+        // code 3 with 3 frames of 2.5ms
+        assert_eq!(opus_packet_samples(&[0x83, 0x03]), Some(360));
+        assert_eq!(opus_packet_samples(&[]), None);
+    }
+
+    #[test]
+    fn test_fills_gaps() {
+        let mut w = OggOpusWriter::new(Vec::new(), 1).unwrap();
+        w.write(1000, &[0xf8, 0x00]).unwrap();
+        w.write(1000 + 960 * 4, &[0xf8, 0x00]).unwrap();
+        assert_eq!(w.granule, 960 * 5);
+        let out = w.finish().unwrap();
+        assert_eq!(&out[..4], b"OggS");
+    }
+}
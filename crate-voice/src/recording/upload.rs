@@ -0,0 +1,87 @@
+use common::v1::types::voice::internal::RecordingUploadParams;
+use common::v2::types::ChannelId;
+use lamprey_backend_core::config::Config;
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::prelude::*;
+use crate::recording::RecordedFile;
+
+/// uploads recorded tracks to the api server
+#[derive(Clone)]
+pub struct Uploader {
+    http: reqwest::Client,
+    api_url: Url,
+    token: Arc<str>,
+}
+
+impl Uploader {
+    pub fn new(config: &Config) -> Result<Self> {
+        let voice_config = config
+            .voice
+            .as_ref()
+            .ok_or_else(|| Error::Channel("voice config missing".into()))?;
+        let token = voice_config
+            .token
+            .load()
+            .map_err(|e| Error::Channel(format!("failed to load voice token: {e}")))?;
+        Ok(Self {
+            http: reqwest::Client::new(),
+            api_url: config.api_url.clone(),
+            token,
+        })
+    }
+
+    /// create an uploader that doesn't point to a real api server
+    #[cfg(test)]
+    pub fn mock() -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            api_url: Url::parse("http://localhost/").unwrap(),
+            token: "token".into(),
+        }
+    }
+
+    /// upload every file, removing them afterwards
+    pub async fn upload_all(&self, channel_id: ChannelId, files: Vec<RecordedFile>) {
+        for file in files {
+            if let Err(e) = self.upload(channel_id, &file).await {
+                warn!(?channel_id, filename = %file.filename, "Failed to upload recording: {}", e);
+            }
+
+            if let Err(e) = tokio::fs::remove_file(&file.path).await {
+                warn!(path = ?file.path, "Failed to remove recording: {}", e);
+            }
+        }
+    }
+
+    async fn upload(&self, channel_id: ChannelId, file: &RecordedFile) -> Result<()> {
+        let f = tokio::fs::File::open(&file.path).await?;
+        let len = f.metadata().await?.len();
+        debug!(?channel_id, filename = %file.filename, len, "Uploading recording");
+
+        let params = RecordingUploadParams {
+            user_id: file.user_id,
+            kind: file.kind,
+            filename: file.filename.clone(),
+            offset: file.offset,
+        };
+
+        self.http
+            .put(format!(
+                "{}api/v1/internal/recording/{}",
+                self.api_url, channel_id
+            ))
+            .query(&params)
+            .header(AUTHORIZATION, format!("Server {}", self.token))
+            .header(CONTENT_LENGTH, len)
+            .body(reqwest::Body::wrap_stream(ReaderStream::new(f)))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
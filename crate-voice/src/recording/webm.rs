@@ -0,0 +1,321 @@
+//! minimal webm muxer for a single vp8 or vp9 track
+
+use std::io::{self, Write};
+
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+
+/// segments are written without a size so the file can be streamed
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+
+/// start a new cluster on the next keyframe after this many ms
+const CLUSTER_DURATION: u64 = 1_000;
+
+/// block timestamps are relative to the cluster and must fit in an i16
+const MAX_CLUSTER_DURATION: u64 = i16::MAX as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    Vp8,
+    Vp9,
+}
+
+/// writes video frames into a webm container
+pub struct WebmWriter<W> {
+    inner: W,
+    codec: VideoCodec,
+
+    /// timestamp of the first frame in ms, set after the first keyframe
+    first_time: Option<u64>,
+
+    /// timestamp of the current cluster in ms, relative to the first frame
+    cluster_time: u64,
+    cluster: Vec<u8>,
+}
+
+impl<W: Write> WebmWriter<W> {
+    pub fn new(inner: W, codec: VideoCodec) -> Self {
+        Self {
+            inner,
+            codec,
+            first_time: None,
+            cluster_time: 0,
+            cluster: Vec::new(),
+        }
+    }
+
+    /// whether no frames have been written yet
+    pub fn is_empty(&self) -> bool {
+        self.first_time.is_none()
+    }
+
+    /// write a frame with a timestamp in ms
+    ///
+    /// returns false if the frame was dropped because the writer is waiting for a keyframe
+    pub fn write(&mut self, time: u64, frame: &[u8]) -> io::Result<bool> {
+        let keyframe = parse_keyframe(self.codec, frame);
+
+        let first = match self.first_time {
+            Some(first) => first,
+            None => {
+                let Some((width, height)) = keyframe else {
+                    return Ok(false);
+                };
+                self.write_header(width, height)?;
+                self.first_time = Some(time);
+                time
+            }
+        };
+
+        let time = time.saturating_sub(first).max(self.cluster_time);
+        let elapsed = time - self.cluster_time;
+        let split =
+            (keyframe.is_some() && elapsed >= CLUSTER_DURATION) || elapsed > MAX_CLUSTER_DURATION;
+        if self.cluster.is_empty() || split {
+            self.flush_cluster()?;
+            self.cluster_time = time;
+            write_uint(&mut self.cluster, TIMESTAMP, time);
+        }
+
+        let mut block = Vec::with_capacity(frame.len() + 4);
+        write_vint(&mut block, 1); // track number
+        block.extend_from_slice(&((time - self.cluster_time) as i16).to_be_bytes());
+        block.push(if keyframe.is_some() { 0x80 } else { 0 });
+        block.extend_from_slice(frame);
+        write_element(&mut self.cluster, SIMPLE_BLOCK, &block);
+
+        Ok(true)
+    }
+
+    /// flush everything and end the stream
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_cluster()?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_header(&mut self, width: u32, height: u32) -> io::Result<()> {
+        let mut buf = Vec::new();
+
+        let mut header = Vec::new();
+        write_uint(&mut header, EBML_VERSION, 1);
+        write_uint(&mut header, EBML_READ_VERSION, 1);
+        write_uint(&mut header, EBML_MAX_ID_LENGTH, 4);
+        write_uint(&mut header, EBML_MAX_SIZE_LENGTH, 8);
+        write_element(&mut header, DOC_TYPE, b"webm");
+        write_uint(&mut header, DOC_TYPE_VERSION, 4);
+        write_uint(&mut header, DOC_TYPE_READ_VERSION, 2);
+        write_element(&mut buf, EBML, &header);
+
+        write_id(&mut buf, SEGMENT);
+        buf.extend_from_slice(&UNKNOWN_SIZE);
+
+        let mut info = Vec::new();
+        write_uint(&mut info, TIMESTAMP_SCALE, 1_000_000);
+        write_element(&mut info, MUXING_APP, b"lamprey");
+        write_element(&mut info, WRITING_APP, b"lamprey");
+        write_element(&mut buf, INFO, &info);
+
+        let mut video = Vec::new();
+        write_uint(&mut video, PIXEL_WIDTH, width as u64);
+        write_uint(&mut video, PIXEL_HEIGHT, height as u64);
+
+        let mut entry = Vec::new();
+        write_uint(&mut entry, TRACK_NUMBER, 1);
+        write_uint(&mut entry, TRACK_UID, 1);
+        write_uint(&mut entry, TRACK_TYPE, 1); // video
+        let codec_id: &[u8] = match self.codec {
+            VideoCodec::Vp8 => b"V_VP8",
+            VideoCodec::Vp9 => b"V_VP9",
+        };
+        write_element(&mut entry, CODEC_ID, codec_id);
+        write_element(&mut entry, VIDEO, &video);
+
+        let mut tracks = Vec::new();
+        write_element(&mut tracks, TRACK_ENTRY, &entry);
+        write_element(&mut buf, TRACKS, &tracks);
+
+        self.inner.write_all(&buf)
+    }
+
+    fn flush_cluster(&mut self) -> io::Result<()> {
+        if self.cluster.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = Vec::with_capacity(self.cluster.len() + 12);
+        write_element(&mut buf, CLUSTER, &self.cluster);
+        self.cluster.clear();
+        self.inner.write_all(&buf)
+    }
+}
+
+/// returns the frame's dimensions if it is a keyframe
+fn parse_keyframe(codec: VideoCodec, frame: &[u8]) -> Option<(u32, u32)> {
+    match codec {
+        VideoCodec::Vp8 => {
+            // rfc 6386 section 9.1
+            if frame.len() < 10 || frame[0] & 0x01 != 0 || frame[3..6] != [0x9d, 0x01, 0x2a] {
+                return None;
+            }
+            let width = u16::from_le_bytes([frame[6], frame[7]]) & 0x3fff;
+            let height = u16::from_le_bytes([frame[8], frame[9]]) & 0x3fff;
+            Some((width as u32, height as u32))
+        }
+        VideoCodec::Vp9 => parse_vp9_keyframe(frame),
+    }
+}
+
+/// parse the uncompressed header of a vp9 frame (vp9 bitstream spec section 6.2)
+fn parse_vp9_keyframe(frame: &[u8]) -> Option<(u32, u32)> {
+    let mut r = BitReader::new(frame);
+    if r.read(2)? != 0b10 {
+        return None;
+    }
+    let profile = r.read(1)? | (r.read(1)? << 1);
+    if profile == 3 {
+        r.read(1)?;
+    }
+    // show_existing_frame
+    if r.read(1)? == 1 {
+        return None;
+    }
+    // frame_type, 0 is a keyframe
+    if r.read(1)? != 0 {
+        return None;
+    }
+    // show_frame, error_resilient_mode
+    r.read(2)?;
+    if r.read(24)? != 0x49_83_42 {
+        return None;
+    }
+
+    // color_config
+    if profile >= 2 {
+        r.read(1)?;
+    }
+    let color_space = r.read(3)?;
+    if color_space != 7 {
+        r.read(1)?;
+        if profile == 1 || profile == 3 {
+            r.read(3)?;
+        }
+    } else if profile == 1 || profile == 3 {
+        r.read(1)?;
+    }
+
+    let width = r.read(16)? + 1;
+    let height = r.read(16)? + 1;
+    Some((width, height))
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// read up to 32 bits, msb first
+    fn read(&mut self, bits: usize) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..bits {
+            let byte = self.data.get(self.pos / 8)?;
+            let bit = (byte >> (7 - self.pos % 8)) & 1;
+            value = (value << 1) | bit as u32;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+}
+
+fn write_id(buf: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = (id.leading_zeros() / 8) as usize;
+    buf.extend_from_slice(&bytes[skip..]);
+}
+
+fn write_vint(buf: &mut Vec<u8>, n: u64) {
+    // all ones is reserved for unknown sizes
+    let mut len = 1;
+    while len < 8 && n >= (1 << (7 * len)) - 1 {
+        len += 1;
+    }
+    let value = n | (1 << (7 * len));
+    buf.extend_from_slice(&value.to_be_bytes()[8 - len..]);
+}
+
+fn write_element(buf: &mut Vec<u8>, id: u32, data: &[u8]) {
+    write_id(buf, id);
+    write_vint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+fn write_uint(buf: &mut Vec<u8>, id: u32, n: u64) {
+    let bytes = n.to_be_bytes();
+    let skip = ((n.leading_zeros() / 8) as usize).min(7);
+    write_element(buf, id, &bytes[skip..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vint() {
+        let mut buf = Vec::new();
+        write_vint(&mut buf, 1);
+        write_vint(&mut buf, 127);
+        write_vint(&mut buf, 300);
+        assert_eq!(buf, [0x81, 0x40, 0x7f, 0x41, 0x2c]);
+    }
+
+    #[test]
+    fn test_vp8_keyframe() {
+        let key = [0x10, 0x02, 0x00, 0x9d, 0x01, 0x2a, 0x80, 0x02, 0xe0, 0x01];
+        assert_eq!(parse_keyframe(VideoCodec::Vp8, &key), Some((640, 480)));
+
+        let mut inter = key;
+        inter[0] |= 0x01;
+        assert_eq!(parse_keyframe(VideoCodec::Vp8, &inter), None);
+    }
+
+    #[test]
+    fn test_waits_for_keyframe() {
+        let mut w = WebmWriter::new(Vec::new(), VideoCodec::Vp8);
+        assert!(!w.write(0, &[0x01, 0x00, 0x00]).unwrap());
+        assert!(w.is_empty());
+
+        let key = [0x10, 0x02, 0x00, 0x9d, 0x01, 0x2a, 0x80, 0x02, 0xe0, 0x01];
+        assert!(w.write(33, &key).unwrap());
+        assert!(w.write(66, &[0x01, 0x00, 0x00]).unwrap());
+        let out = w.finish().unwrap();
+        assert_eq!(&out[..4], &[0x1a, 0x45, 0xdf, 0xa3]);
+    }
+}
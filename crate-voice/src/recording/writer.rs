@@ -0,0 +1,219 @@
+//! recording file io
+//!
+//! the shard's task shouldn't block on the disk, so each recorder hands its
+//! encoded data to a dedicated thread that owns the files.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Write},
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+};
+
+use common::v2::types::ChannelId;
+use tokio::sync::oneshot;
+use tracing::warn;
+
+use crate::prelude::*;
+use crate::recording::RecordedFile;
+
+/// how much data to buffer before sending it to the writer thread
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+enum Op {
+    Create {
+        slot: TrackSlot,
+        path: PathBuf,
+    },
+    Write {
+        slot: TrackSlot,
+        data: Vec<u8>,
+    },
+    Remove {
+        slot: TrackSlot,
+    },
+    Finish {
+        files: Vec<(TrackSlot, RecordedFile)>,
+        done: oneshot::Sender<Vec<RecordedFile>>,
+    },
+}
+
+/// a handle to a recording's writer thread
+pub struct FileWriter {
+    tx: mpsc::Sender<Op>,
+}
+
+/// the output for a single track
+pub struct Sink {
+    slot: TrackSlot,
+    tx: mpsc::Sender<Op>,
+}
+
+impl FileWriter {
+    pub fn spawn(channel_id: ChannelId) -> io::Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name(format!("recording-{channel_id}"))
+            .spawn(move || run(channel_id, rx))?;
+        Ok(Self { tx })
+    }
+
+    /// create the file for a track
+    pub fn create(&self, slot: TrackSlot, path: PathBuf) -> Sink {
+        let _ = self.tx.send(Op::Create { slot, path });
+        Sink {
+            slot,
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// stop writing a track and delete its file
+    pub fn remove(&self, slot: TrackSlot) {
+        let _ = self.tx.send(Op::Remove { slot });
+    }
+
+    /// close every file once all pending writes are done
+    ///
+    /// files that failed to write and tracks that aren't in `files` are deleted
+    pub fn finish(
+        self,
+        files: Vec<(TrackSlot, RecordedFile)>,
+    ) -> oneshot::Receiver<Vec<RecordedFile>> {
+        let (done, rx) = oneshot::channel();
+        let _ = self.tx.send(Op::Finish { files, done });
+        rx
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx
+            .send(Op::Write {
+                slot: self.slot,
+                data: buf.to_vec(),
+            })
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "recording writer stopped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct OpenFile {
+    path: PathBuf,
+
+    /// None if writing to this file failed
+    file: Option<File>,
+}
+
+fn run(channel_id: ChannelId, rx: mpsc::Receiver<Op>) {
+    let mut open: HashMap<TrackSlot, OpenFile> = HashMap::new();
+
+    while let Ok(op) = rx.recv() {
+        match op {
+            Op::Create { slot, path } => {
+                let file = match File::create_new(&path) {
+                    Ok(f) => Some(f),
+                    Err(err) => {
+                        warn!(?channel_id, ?path, "Failed to create recording: {}", err);
+                        None
+                    }
+                };
+                open.insert(slot, OpenFile { path, file });
+            }
+            Op::Write { slot, data } => {
+                let Some(f) = open.get_mut(&slot) else {
+                    continue;
+                };
+                let Some(file) = &mut f.file else {
+                    continue;
+                };
+                if let Err(err) = file.write_all(&data) {
+                    warn!(?channel_id, path = ?f.path, "Failed to write recording: {}", err);
+                    f.file = None;
+                    let _ = std::fs::remove_file(&f.path);
+                }
+            }
+            Op::Remove { slot } => {
+                if let Some(f) = open.remove(&slot) {
+                    drop(f.file);
+                    let _ = std::fs::remove_file(&f.path);
+                }
+            }
+            Op::Finish { files, done } => {
+                let mut finished = Vec::new();
+                for (slot, file) in files {
+                    if let Some(OpenFile { file: Some(_), .. }) = open.remove(&slot) {
+                        finished.push(file);
+                    }
+                }
+                remove_all(open);
+                let _ = done.send(finished);
+                return;
+            }
+        }
+    }
+
+    // the recorder was dropped without finishing
+    remove_all(open);
+}
+
+fn remove_all(open: HashMap<TrackSlot, OpenFile>) {
+    for (_, f) in open {
+        drop(f.file);
+        let _ = std::fs::remove_file(&f.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::v1::types::voice::MediaKind;
+    use common::v2::types::UserId;
+    use slotmap::SlotMap;
+
+    fn recorded(path: PathBuf) -> RecordedFile {
+        RecordedFile {
+            user_id: UserId::new(),
+            kind: MediaKind::Audio,
+            offset: 0,
+            path,
+            filename: "test.ogg".into(),
+        }
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut slots: SlotMap<TrackSlot, ()> = SlotMap::with_key();
+        let (kept, dropped) = (slots.insert(()), slots.insert(()));
+        let dir = std::env::temp_dir();
+        let kept_path = dir.join(format!(
+            "lamprey-writer-test-{:016x}",
+            rand::random::<u64>()
+        ));
+        let dropped_path = dir.join(format!(
+            "lamprey-writer-test-{:016x}",
+            rand::random::<u64>()
+        ));
+
+        let writer = FileWriter::spawn(ChannelId::new()).unwrap();
+        let mut a = writer.create(kept, kept_path.clone());
+        let mut b = writer.create(dropped, dropped_path.clone());
+        a.write_all(b"hello ").unwrap();
+        b.write_all(b"unused").unwrap();
+        a.write_all(b"world").unwrap();
+
+        let files = writer
+            .finish(vec![(kept, recorded(kept_path.clone()))])
+            .blocking_recv()
+            .unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(std::fs::read(&kept_path).unwrap(), b"hello world");
+        assert!(!dropped_path.exists());
+        std::fs::remove_file(&kept_path).unwrap();
+    }
+}
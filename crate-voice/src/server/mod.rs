@@ -1,4 +1,5 @@
 pub mod sfu;
 pub mod shard;
 pub mod shard_call;
+pub mod spatial;
 pub mod stun;
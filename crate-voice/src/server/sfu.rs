@@ -1,30 +1,66 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 use crate::{
     backend::{BackendConnection, BackendHandle},
-    mesh::{Mesh, MeshHandle},
+    mesh::{Mesh, MeshEvent, MeshHandle, stream::GoodbyeCode},
     prelude::*,
-    server::shard::{Shard, ShardHandle},
+    recording::upload::Uploader,
+    server::{
+        shard::{Shard, ShardEvent, ShardHandle},
+        stun::StunServer,
+    },
 };
 use common::{
-    v1::types::voice::messages::SfuCommand,
+    v1::types::{
+        SfuId,
+        voice::{
+            SfuStats, VoiceStateUpdate,
+            internal::SfuVoiceState,
+            messages::{SfuCommand, SfuEvent, SignallingCommand, SignallingEvent},
+        },
+    },
     v2::types::{ChannelId, UserId},
 };
 use futures::StreamExt;
 use lamprey_backend_core::config::{Config, ConfigVoice};
-use tokio::task::JoinSet;
+use tokio::{
+    sync::mpsc,
+    task::{self, JoinSet},
+};
 use tracing::{debug, error, info, warn};
 
+/// how often to report latency to other sfus
+const LATENCY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// how often to report stats to the backend
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
 /// main entry point for the server
 pub struct Sfu {
+    sfu_id: Option<SfuId>,
     backend: BackendHandle,
     mesh: MeshHandle,
+    uploader: Uploader,
     shards: Vec<ShardHandle>,
     shard_tasks: JoinSet<Result<()>>,
+
+    /// which shard each task is running
+    shard_task_ids: HashMap<task::Id, usize>,
+    shard_events_tx: mpsc::UnboundedSender<(usize, ShardEvent)>,
+    shard_events: mpsc::UnboundedReceiver<(usize, ShardEvent)>,
+
     calls: HashMap<ChannelId, Call>,
-    user_to_channel: HashMap<UserId, ChannelId>,
-    config_full: Box<Config>,
+
+    /// every peer connected to this sfu, kept so they can be recreated if their shard crashes
+    peers: HashMap<UserId, Peer>,
+
+    /// recordings that a shard was told to stop but hasn't yet, and which shard they're on
+    stopping: HashMap<ChannelId, usize>,
     config: Box<ConfigVoice>,
+
+    /// urls for the builtin stun/turn server, if it's enabled
+    ice_urls: Vec<String>,
 }
 
 /// a single voice call known by this sfu
@@ -32,6 +68,21 @@ pub struct Sfu {
 /// contains routing data and logic for local and remote cascading
 pub struct Call {
     shard: ShardHandle,
+
+    /// other sfus that this call is routed to
+    routes: Vec<SfuId>,
+
+    /// whether this call is being recorded
+    recording: bool,
+}
+
+/// a peer connected to this sfu
+struct Peer {
+    channel_id: ChannelId,
+    state: SfuVoiceState,
+
+    /// the most recent voice state update from the user
+    update: Option<VoiceStateUpdate>,
 }
 
 pub struct SfuHandle {
@@ -45,18 +96,20 @@ impl Sfu {
         // PERF: init in parallel
         let backend = BackendConnection::connect(config.clone()).await?;
         let mesh = Mesh::spawn(&config).await?;
+        let uploader = Uploader::new(&config)?;
 
-        let me = Sfu {
-            backend,
-            mesh,
-            shards: Vec::new(),
-            shard_tasks: JoinSet::new(),
-            calls: HashMap::new(),
-            user_to_channel: HashMap::new(),
-            config_full: Box::new(config),
-            config: Box::new(voice_config),
+        let ice_urls = match voice_config.stun_port {
+            Some(port) => {
+                let stun = StunServer::new(&voice_config, port).await?;
+                let urls = stun.urls();
+                tokio::spawn(stun.run());
+                urls
+            }
+            None => Vec::new(),
         };
 
+        let me = Sfu::new(backend, mesh, uploader, voice_config, ice_urls);
+
         let handle = SfuHandle {
             // TODO
         };
@@ -66,31 +119,88 @@ impl Sfu {
         Ok(handle)
     }
 
+    fn new(
+        backend: BackendHandle,
+        mesh: MeshHandle,
+        uploader: Uploader,
+        config: ConfigVoice,
+        ice_urls: Vec<String>,
+    ) -> Self {
+        let (shard_events_tx, shard_events) = mpsc::unbounded_channel();
+        Sfu {
+            sfu_id: None,
+            backend,
+            mesh,
+            uploader,
+            shards: Vec::new(),
+            shard_tasks: JoinSet::new(),
+            shard_task_ids: HashMap::new(),
+            shard_events_tx,
+            shard_events,
+            calls: HashMap::new(),
+            peers: HashMap::new(),
+            stopping: HashMap::new(),
+            config: Box::new(config),
+            ice_urls,
+        }
+    }
+
     async fn run(mut self) {
         let num_shards = self.config.workers.unwrap_or_else(|| num_cpus::get() as u8);
 
         info!("Spawning {} shards", num_shards);
         for _ in 0..num_shards {
-            if let Err(e) = self.spawn_shard().await {
+            if let Err(e) = self.spawn_shard(self.shards.len()).await {
                 error!("Failed to spawn shard: {}", e);
             }
         }
 
         let backend = self.backend.clone();
         let mut commands = Box::pin(backend.subscribe());
+        let mut mesh_events = Box::pin(self.mesh.subscribe());
+        let mut latency = tokio::time::interval(LATENCY_INTERVAL);
+        let mut stats = tokio::time::interval(STATS_INTERVAL);
 
         loop {
             tokio::select! {
                 Some(cmd) = commands.next() => {
                     self.handle_command(cmd).await;
                 }
-                Some(res) = self.shard_tasks.join_next() => {
-                    match res {
-                        Ok(Err(e)) => error!("Shard task failed: {}", e),
-                        Ok(Ok(())) => warn!("Shard task exited unexpectedly"),
-                        Err(e) => error!("Shard task panicked: {}", e),
+                Some(event) = mesh_events.next() => {
+                    self.handle_mesh_event(event);
+                }
+                _ = latency.tick() => {
+                    for remote in self.mesh.remotes() {
+                        if let Some(rtt) = remote.rtt() {
+                            self.send_latency(remote.sfu_id(), rtt);
+                        }
+                    }
+                }
+                _ = stats.tick() => {
+                    self.send_stats();
+                }
+                Some((shard_id, event)) = self.shard_events.recv() => {
+                    self.handle_shard_event(shard_id, event);
+                }
+                Some(res) = self.shard_tasks.join_next_with_id() => {
+                    let task_id = match res {
+                        Ok((id, Err(e))) => {
+                            error!("Shard task failed: {}", e);
+                            id
+                        }
+                        Ok((id, Ok(()))) => {
+                            warn!("Shard task exited unexpectedly");
+                            id
+                        }
+                        Err(e) => {
+                            error!("Shard task panicked: {}", e);
+                            e.id()
+                        }
+                    };
+
+                    if let Some(shard_id) = self.shard_task_ids.remove(&task_id) {
+                        self.restart_shard(shard_id).await;
                     }
-                    // TODO: try to respawn shard
                 }
             }
         }
@@ -101,39 +211,31 @@ impl Sfu {
         match cmd {
             SfuCommand::Init { sfu_id } => {
                 debug!(?sfu_id, "sfu init");
+                self.sfu_id = Some(sfu_id);
+
+                if !self.ice_urls.is_empty() {
+                    let _ = self.backend.send(SfuEvent::IceServers {
+                        urls: self.ice_urls.clone(),
+                    });
+                }
             }
             SfuCommand::CreatePeer { channel_id, state } => {
                 let user_id = state.user_id;
                 debug!(?channel_id, ?user_id, "Creating peer");
 
-                let shard = match self.calls.get(&channel_id) {
-                    Some(call) => {
-                        debug!(?channel_id, "Using existing call shard");
-                        call.shard.clone()
-                    }
-                    None => {
-                        debug!(?channel_id, "Creating new call for channel");
-                        // TODO: select shard based on load
-                        // TODO: shut down `ShardCall`s when idle
-                        // TODO: update user_to_channel based on voice state, disconnect
-                        let shard = match self.shards.first() {
-                            Some(s) => s.clone(),
-                            None => {
-                                error!("No shards available to handle CreatePeer");
-                                return;
-                            }
-                        };
-                        self.calls.insert(
-                            channel_id,
-                            Call {
-                                shard: shard.clone(),
-                            },
-                        );
-                        shard
-                    }
+                let Some(shard) = self.call_shard(channel_id) else {
+                    error!("No shards available to handle CreatePeer");
+                    return;
                 };
 
-                self.user_to_channel.insert(user_id, channel_id);
+                self.peers.insert(
+                    user_id,
+                    Peer {
+                        channel_id,
+                        state: state.clone(),
+                        update: None,
+                    },
+                );
 
                 shard.create_peer(channel_id, state);
             }
@@ -143,6 +245,23 @@ impl Sfu {
                 inner,
             } => {
                 debug!(?user_id, ?channel_id, ?inner, "Received signalling command");
+                match &inner {
+                    SignallingCommand::VoiceState { state } => {
+                        if let Some(peer) = self.peers.get_mut(&user_id) {
+                            peer.update = Some(state.clone());
+                        }
+                    }
+                    SignallingCommand::Disconnect
+                        if self
+                            .peers
+                            .get(&user_id)
+                            .is_some_and(|p| p.channel_id == channel_id) =>
+                    {
+                        self.peers.remove(&user_id);
+                    }
+                    _ => {}
+                }
+
                 if let Some(call) = self.calls.get(&channel_id) {
                     call.shard.handle_signalling(channel_id, user_id, inner);
                 } else {
@@ -155,13 +274,91 @@ impl Sfu {
             //     kind,
             //     user_id,
             // } => {
-            //     if let Some(channel_id) = self.user_to_channel.get(&user_id).copied() {
+            //     if let Some(channel_id) = self.peers.get(&user_id).map(|p| p.channel_id) {
             //         if let Some(call) = self.calls.get(&channel_id) {
             //             call.shard
             //                 .generate_keyframe(channel_id, user_id, mid, rid, kind.into());
             //         }
             //     }
             // }
+            SfuCommand::CreateCascade {
+                sfu_id,
+                token,
+                addr,
+                fingerprint,
+            } => {
+                debug!(?sfu_id, %addr, "Creating cascade");
+                let mesh = self.mesh.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = mesh.connect(addr, token, sfu_id, &fingerprint).await {
+                        error!(?sfu_id, %addr, "Failed to connect to sfu: {}", e);
+                    }
+                });
+            }
+            SfuCommand::RouteUpdate {
+                channel_id,
+                destinations,
+            } => {
+                debug!(?channel_id, ?destinations, "Updating routes");
+                let Some(shard) = self.call_shard(channel_id) else {
+                    error!("No shards available to handle RouteUpdate");
+                    return;
+                };
+
+                for &target in &destinations {
+                    self.prepare_cascade(target);
+                }
+
+                shard.update_routes(channel_id, destinations.clone());
+                let old_routes = match self.calls.get_mut(&channel_id) {
+                    Some(call) => std::mem::replace(&mut call.routes, destinations),
+                    None => Vec::new(),
+                };
+
+                self.close_unused_remotes(&old_routes);
+            }
+            SfuCommand::RecalculateLatency { target_sfu } => {
+                let Some(remote) = self.mesh.lookup(&target_sfu) else {
+                    warn!(?target_sfu, "Can't calculate latency to unconnected sfu");
+                    return;
+                };
+                let backend = self.backend.clone();
+                tokio::spawn(async move {
+                    match remote.probe().await {
+                        Ok(rtt) => {
+                            let _ = backend.send(SfuEvent::Latency {
+                                target_sfu,
+                                rtt: rtt.as_nanos().try_into().unwrap_or(u32::MAX),
+                            });
+                        }
+                        Err(e) => warn!(?target_sfu, "Failed to probe sfu: {}", e),
+                    }
+                });
+            }
+            SfuCommand::RecordingStart { channel_id } => {
+                debug!(?channel_id, "Starting recording");
+                let Some(shard) = self.call_shard(channel_id) else {
+                    error!("No shards available to handle RecordingStart");
+                    return;
+                };
+                shard.start_recording(channel_id);
+                if let Some(call) = self.calls.get_mut(&channel_id) {
+                    call.recording = true;
+                }
+            }
+            SfuCommand::RecordingStop { channel_id } => {
+                debug!(?channel_id, "Stopping recording");
+                if let Some(call) = self.calls.get_mut(&channel_id) {
+                    call.recording = false;
+                    call.shard.stop_recording(channel_id);
+                    self.stopping.insert(channel_id, call.shard.id());
+                } else {
+                    // nothing was recorded here
+                    let _ = self
+                        .backend
+                        .send(SfuEvent::RecordingFinished { channel_id });
+                }
+            }
             // TODO: handle more commands
             _ => {
                 warn!("Unhandled SfuCommand");
@@ -169,15 +366,313 @@ impl Sfu {
         }
     }
 
-    async fn spawn_shard(&mut self) -> Result<()> {
-        let (shard, handle) = Shard::new(self.backend.clone(), (*self.config).clone()).await?;
+    fn handle_mesh_event(&mut self, event: MeshEvent) {
+        match &event {
+            MeshEvent::Connected { sfu_id } => {
+                for (channel_id, call) in &self.calls {
+                    if call.routes.contains(sfu_id) {
+                        let _ = self.backend.send(SfuEvent::CascadeCreated {
+                            sfu_id: *sfu_id,
+                            channel_id: *channel_id,
+                        });
+                    }
+                }
+                for shard in &self.shards {
+                    shard.handle_mesh_event(event.clone());
+                }
+            }
+            MeshEvent::Closed { .. } => {
+                for shard in &self.shards {
+                    shard.handle_mesh_event(event.clone());
+                }
+            }
+            MeshEvent::Announce { announce, .. } => {
+                if let Some(shard) = self.call_shard(announce.channel_id) {
+                    shard.handle_mesh_event(event);
+                }
+            }
+            MeshEvent::Subscribe { subscribe, .. } => {
+                if let Some(call) = self.calls.get(&subscribe.channel_id) {
+                    call.shard.handle_mesh_event(event);
+                }
+            }
+            MeshEvent::Unsubscribe { channel_id, .. } | MeshEvent::Keyframe { channel_id, .. } => {
+                if let Some(call) = self.calls.get(channel_id) {
+                    call.shard.handle_mesh_event(event);
+                }
+            }
+        }
+    }
+
+    fn handle_shard_event(&mut self, shard_id: usize, event: ShardEvent) {
+        match event {
+            ShardEvent::PeerRemoved {
+                channel_id,
+                user_id,
+            } => {
+                // peers that the backend disconnected are already gone
+                if self
+                    .peers
+                    .get(&user_id)
+                    .is_none_or(|p| p.channel_id != channel_id)
+                {
+                    return;
+                }
+
+                debug!(?channel_id, ?user_id, "Peer disconnected");
+                self.peers.remove(&user_id);
+                let _ = self.backend.send(SfuEvent::PeerDisconnect {
+                    user_id,
+                    channel_id,
+                });
+            }
+            ShardEvent::CallIdle { channel_id } => {
+                // the call may have been used or moved to another shard since
+                let Some(call) = self.calls.get(&channel_id) else {
+                    return;
+                };
+                if call.shard.id() != shard_id || !self.is_idle(channel_id, call) {
+                    return;
+                }
+
+                debug!(?channel_id, "Removing idle call");
+                call.shard.remove_call(channel_id);
+                self.calls.remove(&channel_id);
+            }
+            ShardEvent::RecordingStopped { channel_id } => {
+                if self.stopping.get(&channel_id) == Some(&shard_id) {
+                    self.stopping.remove(&channel_id);
+                }
+            }
+            ShardEvent::RecordingFinished { channel_id } => {
+                if let Err(e) = self
+                    .backend
+                    .send(SfuEvent::RecordingFinished { channel_id })
+                {
+                    warn!("Failed to send RecordingFinished: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// whether a call has nothing that depends on it
+    fn is_idle(&self, channel_id: ChannelId, call: &Call) -> bool {
+        !call.recording
+            && call.routes.is_empty()
+            && !self.stopping.contains_key(&channel_id)
+            && !self.peers.values().any(|p| p.channel_id == channel_id)
+    }
+
+    /// get the shard for a call, creating the call if it doesn't exist
+    fn call_shard(&mut self, channel_id: ChannelId) -> Option<ShardHandle> {
+        if let Some(call) = self.calls.get(&channel_id) {
+            debug!(?channel_id, "Using existing call shard");
+            return Some(call.shard.clone());
+        }
+
+        debug!(?channel_id, "Creating new call for channel");
+        let shard = self.select_shard()?;
+        self.calls.insert(
+            channel_id,
+            Call {
+                shard: shard.clone(),
+                routes: Vec::new(),
+                recording: false,
+            },
+        );
+        Some(shard)
+    }
+
+    /// pick the least loaded shard for a new call
+    fn select_shard(&self) -> Option<ShardHandle> {
+        let score = |shard: &ShardHandle| {
+            // calls placed since the shard last reported its load aren't counted yet
+            let placed = self
+                .calls
+                .values()
+                .filter(|c| c.shard.id() == shard.id())
+                .count();
+            let mut load = shard.load();
+            load.calls = load.calls.max(placed);
+            load.score()
+        };
+
+        self.shards
+            .iter()
+            .min_by(|a, b| score(a).total_cmp(&score(b)))
+            .cloned()
+    }
+
+    /// replace a shard that exited, moving its calls and peers to new shards
+    async fn restart_shard(&mut self, shard_id: usize) {
+        warn!(shard_id, "Restarting shard");
+
+        // handle anything the shard did before it exited
+        while let Ok((id, event)) = self.shard_events.try_recv() {
+            self.handle_shard_event(id, event);
+        }
+
+        // recordings the shard never stopped are gone, so don't leave the backend waiting
+        let lost_recordings: Vec<ChannelId> = self
+            .stopping
+            .extract_if(|_, id| *id == shard_id)
+            .map(|(channel_id, _)| channel_id)
+            .collect();
+        for channel_id in lost_recordings {
+            warn!(?channel_id, "Lost recording when shard exited");
+            let _ = self
+                .backend
+                .send(SfuEvent::RecordingFinished { channel_id });
+        }
+
+        if let Err(e) = self.spawn_shard(shard_id).await {
+            error!(shard_id, "Failed to respawn shard: {}", e);
+            self.shards.retain(|s| s.id() != shard_id);
+        }
+
+        // every call on the old shard lost its state
+        let lost: Vec<(ChannelId, Call)> = self
+            .calls
+            .extract_if(|_, c| c.shard.id() == shard_id)
+            .collect();
+
+        for (channel_id, old) in lost {
+            let Some(shard) = self.call_shard(channel_id) else {
+                error!(?channel_id, "No shards available to move call to");
+                continue;
+            };
+
+            if !old.routes.is_empty() {
+                shard.update_routes(channel_id, old.routes.clone());
+            }
+            if old.recording {
+                shard.start_recording(channel_id);
+            }
+            if let Some(call) = self.calls.get_mut(&channel_id) {
+                call.routes = old.routes;
+                call.recording = old.recording;
+            }
+
+            for (&user_id, peer) in &self.peers {
+                if peer.channel_id != channel_id {
+                    continue;
+                }
+
+                shard.create_peer(channel_id, peer.state.clone());
+                if let Some(update) = &peer.update {
+                    shard.handle_signalling(
+                        channel_id,
+                        user_id,
+                        SignallingCommand::VoiceState {
+                            state: update.clone(),
+                        },
+                    );
+                }
+
+                // the client needs to negotiate a new connection with the new peer
+                if let Some(sfu_id) = self.sfu_id {
+                    let _ = self.backend.send(SfuEvent::VoiceDispatch {
+                        user_id,
+                        channel_id,
+                        payload: Box::new(SignallingEvent::Migrate { new_sfu_id: sfu_id }),
+                    });
+                }
+            }
+        }
+    }
+
+    /// let another sfu connect to this one
+    fn prepare_cascade(&self, target: SfuId) {
+        if self.mesh.lookup(&target).is_some() {
+            return;
+        }
+
+        // both sides of a route get the same update, so only one of them should accept the connection
+        let Some(sfu_id) = self.sfu_id else {
+            warn!("Can't prepare cascade before init");
+            return;
+        };
+        if sfu_id > target {
+            return;
+        }
+
+        let token = format!("{:032x}", rand::random::<u128>());
+        self.mesh.add_pending_token(token.clone(), target);
+        if let Err(e) = self.backend.send(SfuEvent::CascadePrepared {
+            sfu_id: target,
+            token,
+            addr: self.mesh.addr(),
+            fingerprint: self.mesh.fingerprint().to_owned(),
+        }) {
+            warn!("Failed to send CascadePrepared: {:?}", e);
+        }
+    }
+
+    /// disconnect from sfus that were previously routed but no call is routed to anymore
+    fn close_unused_remotes(&self, previous: &[SfuId]) {
+        let routed: HashSet<SfuId> = self
+            .calls
+            .values()
+            .flat_map(|c| c.routes.iter().copied())
+            .collect();
+
+        for sfu_id in previous {
+            if routed.contains(sfu_id) {
+                continue;
+            }
 
-        self.shard_tasks.spawn(async move {
+            let Some(remote) = self.mesh.lookup(sfu_id) else {
+                continue;
+            };
+
+            tokio::spawn(async move {
+                if let Err(e) = remote.goodbye(GoodbyeCode::Unused).await {
+                    debug!("Failed to say goodbye: {}", e);
+                }
+            });
+        }
+    }
+
+    fn send_stats(&self) {
+        let loads: Vec<_> = self.shards.iter().map(|s| s.load()).collect();
+        let _ = self.backend.send(SfuEvent::Stats {
+            stats: SfuStats {
+                peer_count: loads.iter().map(|l| l.peers as u64).sum(),
+                bandwidth_usage: loads.iter().map(|l| l.bitrate).sum(),
+                bandwidth_max: self.config.bandwidth_max.unwrap_or(0),
+            },
+        });
+    }
+
+    fn send_latency(&self, target_sfu: SfuId, rtt: Duration) {
+        let _ = self.backend.send(SfuEvent::Latency {
+            target_sfu,
+            rtt: rtt.as_nanos().try_into().unwrap_or(u32::MAX),
+        });
+    }
+
+    /// spawn a shard, replacing the existing shard with this id
+    async fn spawn_shard(&mut self, shard_id: usize) -> Result<()> {
+        let (shard, handle) = Shard::new(
+            shard_id,
+            self.backend.clone(),
+            self.mesh.clone(),
+            self.uploader.clone(),
+            (*self.config).clone(),
+            self.shard_events_tx.clone(),
+        )
+        .await?;
+
+        let task = self.shard_tasks.spawn(async move {
             shard.run().await;
             Ok(())
         });
+        self.shard_task_ids.insert(task.id(), shard_id);
 
-        self.shards.push(handle);
+        match self.shards.iter_mut().find(|s| s.id() == shard_id) {
+            Some(existing) => *existing = handle,
+            None => self.shards.push(handle),
+        }
         Ok(())
     }
 }
@@ -195,3 +690,147 @@ impl SfuHandle {
 
     // fn metrics(&self) -> ...
 }
+
+#[cfg(test)]
+mod tests {
+    use common::v1::types::{
+        ConnectionId, SessionId,
+        misc::Time,
+        voice::internal::{SfuVoiceFlags, SfuVoiceState},
+    };
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    use super::*;
+
+    async fn sfu(shards: usize) -> (Sfu, UnboundedReceiver<SfuEvent>) {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let config: ConfigVoice = serde_json::from_value(serde_json::json!({
+            "token": "token",
+            "host_ipv4": "127.0.0.1",
+            "host_ipv6": "::1",
+        }))
+        .unwrap();
+        let (backend, events) = BackendHandle::mock();
+        let mesh = Mesh::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut sfu = Sfu::new(backend, mesh, Uploader::mock(), config, Vec::new());
+        for id in 0..shards {
+            sfu.spawn_shard(id).await.unwrap();
+        }
+        (sfu, events)
+    }
+
+    fn add_peer(sfu: &mut Sfu, channel_id: ChannelId) -> UserId {
+        let user_id = UserId::new();
+        let state = SfuVoiceState {
+            user_id,
+            session_id: SessionId::new(),
+            connection_id: ConnectionId::new(),
+            joined_at: Time::now_utc(),
+            flags: SfuVoiceFlags::empty(),
+        };
+        sfu.peers.insert(
+            user_id,
+            Peer {
+                channel_id,
+                state,
+                update: None,
+            },
+        );
+        user_id
+    }
+
+    fn shard_of(sfu: &Sfu, channel_id: ChannelId) -> Option<usize> {
+        sfu.calls.get(&channel_id).map(|c| c.shard.id())
+    }
+
+    #[tokio::test]
+    async fn test_placement() {
+        let (mut sfu, _events) = sfu(2).await;
+        let (a, b) = (ChannelId::new(), ChannelId::new());
+
+        // new calls are spread between shards before they report any load
+        let shard_a = sfu.call_shard(a).unwrap().id();
+        let shard_b = sfu.call_shard(b).unwrap().id();
+        assert_ne!(shard_a, shard_b);
+
+        // existing calls stay where they are
+        assert_eq!(sfu.call_shard(a).unwrap().id(), shard_a);
+    }
+
+    #[tokio::test]
+    async fn test_idle_calls() {
+        let (mut sfu, _events) = sfu(2).await;
+        let channel_id = ChannelId::new();
+        let shard_id = sfu.call_shard(channel_id).unwrap().id();
+        let user_id = add_peer(&mut sfu, channel_id);
+
+        // calls with peers are kept
+        sfu.handle_shard_event(shard_id, ShardEvent::CallIdle { channel_id });
+        assert_eq!(shard_of(&sfu, channel_id), Some(shard_id));
+
+        // reports from shards the call isn't on are stale
+        sfu.peers.remove(&user_id);
+        sfu.handle_shard_event(1 - shard_id, ShardEvent::CallIdle { channel_id });
+        assert_eq!(shard_of(&sfu, channel_id), Some(shard_id));
+
+        // recordings that are still stopping are kept
+        sfu.stopping.insert(channel_id, shard_id);
+        sfu.handle_shard_event(shard_id, ShardEvent::CallIdle { channel_id });
+        assert_eq!(shard_of(&sfu, channel_id), Some(shard_id));
+
+        sfu.handle_shard_event(shard_id, ShardEvent::RecordingStopped { channel_id });
+        sfu.handle_shard_event(shard_id, ShardEvent::CallIdle { channel_id });
+        assert_eq!(shard_of(&sfu, channel_id), None);
+    }
+
+    #[tokio::test]
+    async fn test_restart() {
+        let (mut sfu, mut events) = sfu(1).await;
+        sfu.sfu_id = Some(SfuId::new());
+        let (recording, stopped) = (ChannelId::new(), ChannelId::new());
+        sfu.call_shard(recording).unwrap();
+        sfu.call_shard(stopped).unwrap();
+        let user_id = add_peer(&mut sfu, recording);
+
+        sfu.handle_command(SfuCommand::RecordingStart {
+            channel_id: recording,
+        })
+        .await;
+        sfu.handle_command(SfuCommand::RecordingStop {
+            channel_id: stopped,
+        })
+        .await;
+
+        // crash the shard before it handles anything
+        sfu.shard_tasks.abort_all();
+        sfu.restart_shard(0).await;
+
+        // calls are recreated with the same state
+        assert!(sfu.calls[&recording].recording);
+        assert!(sfu.calls.contains_key(&stopped));
+        assert!(sfu.stopping.is_empty());
+
+        // the backend isn't left waiting for a recording that was lost
+        let mut finished = false;
+        let mut migrated = false;
+        while let Ok(event) = events.try_recv() {
+            match event {
+                SfuEvent::RecordingFinished { channel_id } => {
+                    assert_eq!(channel_id, stopped);
+                    finished = true;
+                }
+                SfuEvent::VoiceDispatch {
+                    user_id: target,
+                    payload,
+                    ..
+                } if matches!(*payload, SignallingEvent::Migrate { .. }) => {
+                    assert_eq!(target, user_id);
+                    migrated = true;
+                }
+                _ => {}
+            }
+        }
+        assert!(finished);
+        assert!(migrated);
+    }
+}
@@ -1,26 +1,115 @@
 use common::v1::types::voice::internal::SfuVoiceState;
 use lamprey_backend_core::config::ConfigVoice;
-use std::{collections::HashMap, net::SocketAddr, time::Instant};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
-use common::v1::types::voice::messages::{SfuEvent, SignallingCommand};
+use common::v1::types::SfuId;
+use common::v1::types::voice::messages::{SfuEvent, SignallingCommand, SignallingEvent};
 use common::v2::types::{ChannelId, UserId};
 use slotmap::SlotMap;
 use str0m::{Candidate, RtcConfig};
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, watch},
+};
 use tokio_stream::StreamExt;
 use tokio_util::time::{DelayQueue, delay_queue::Key};
 use tracing::{debug, warn};
 
+use crate::mesh::{MediaSink, MeshEvent, MeshHandle, datagram::Datagram};
 use crate::prelude::*;
+use crate::recording::upload::Uploader;
 use crate::util::stun::extract_local_ufrag;
 use crate::{backend::BackendHandle, server::shard_call::ShardCall};
 
+/// how often to sync track announcements and subscriptions with other sfus
+const MESH_SYNC_INTERVAL: Duration = Duration::from_millis(100);
+
+/// how often to update proximity based subscriptions
+const SPATIAL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// how often to report load and look for idle calls
+const LOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// how long a call can be unused before it's reported as idle
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// resource usage of a shard
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShardLoad {
+    pub calls: usize,
+    pub peers: usize,
+    pub tracks: usize,
+
+    /// bits per second sent and received
+    pub bitrate: u64,
+
+    /// the fraction of time spent working instead of waiting, from 0 to 1
+    pub cpu: f32,
+}
+
+impl ShardLoad {
+    /// a rough measure of how busy this shard is, lower is better
+    pub fn score(&self) -> f32 {
+        // cpu dominates once a shard is actually busy, everything else breaks
+        // ties between mostly idle shards
+        self.cpu * 100.0
+            + self.calls as f32
+            + self.peers as f32 * 0.5
+            + self.tracks as f32 * 0.25
+            + self.bitrate as f32 / 1_000_000.0
+    }
+}
+
+/// sent from a shard to the sfu
+#[derive(Debug)]
+pub enum ShardEvent {
+    /// a peer disconnected or timed out
+    PeerRemoved {
+        channel_id: ChannelId,
+        user_id: UserId,
+    },
+
+    /// a call was idle for too long and can be removed
+    ///
+    /// the sfu decides whether to remove it, since it may have sent the shard
+    /// something for this call that hasn't been handled yet
+    CallIdle { channel_id: ChannelId },
+
+    /// a recording was stopped and is being uploaded
+    RecordingStopped { channel_id: ChannelId },
+
+    /// a stopped recording finished uploading
+    RecordingFinished { channel_id: ChannelId },
+}
+
+/// what woke up a shard
+enum Wake {
+    Udp(SocketAddr, SocketAddr, Bytes),
+    Command(ShardCommand),
+    Media(CallSlot, SfuId, Datagram),
+    MeshSync,
+    Spatial,
+    Load,
+    Timeout(CallSlot, PeerSlot),
+}
+
 // one shard per thread
 pub struct Shard {
+    id: usize,
     backend: BackendHandle,
+    mesh: MeshHandle,
+    uploader: Uploader,
     control_rx: mpsc::Receiver<ShardCommand>,
 
+    /// media received from other sfus
+    media_tx: mpsc::Sender<(CallSlot, SfuId, Datagram)>,
+    media_rx: mpsc::Receiver<(CallSlot, SfuId, Datagram)>,
+
     sock_v4: UdpSocket,
     sock_v6: UdpSocket,
 
@@ -38,11 +127,26 @@ pub struct Shard {
 
     timeout_queue: DelayQueue<(CallSlot, PeerSlot)>,
     timeout_keys: HashMap<(CallSlot, PeerSlot), Key>,
+
+    /// when each unused call stopped being used
+    idle_since: HashMap<CallSlot, Instant>,
+
+    events_tx: mpsc::UnboundedSender<(usize, ShardEvent)>,
+    load_tx: watch::Sender<ShardLoad>,
+
+    /// bytes sent and received since the last load report
+    bytes: u64,
+
+    /// time spent working since the last load report
+    busy: Duration,
+    last_load: Instant,
 }
 
 #[derive(Clone)]
 pub struct ShardHandle {
+    id: usize,
     control_tx: mpsc::Sender<ShardCommand>,
+    load: watch::Receiver<ShardLoad>,
 }
 
 pub enum ShardCommand {
@@ -55,6 +159,24 @@ pub enum ShardCommand {
         user_id: UserId,
         inner: SignallingCommand,
     },
+
+    /// set which other sfus a call is routed to
+    Routes {
+        channel_id: ChannelId,
+        destinations: Vec<SfuId>,
+    },
+
+    /// an event from the mesh
+    Mesh(MeshEvent),
+
+    /// start recording a call
+    RecordingStart { channel_id: ChannelId },
+
+    /// stop recording a call and upload everything that was recorded
+    RecordingStop { channel_id: ChannelId },
+
+    /// remove an idle call
+    RemoveCall { channel_id: ChannelId },
     // GenerateKeyframe {
     //     channel_id: ChannelId,
     //     user_id: UserId,
@@ -69,8 +191,17 @@ pub enum ShardCommand {
 // }
 
 impl Shard {
-    pub async fn new(backend: BackendHandle, config: ConfigVoice) -> Result<(Self, ShardHandle)> {
+    pub async fn new(
+        id: usize,
+        backend: BackendHandle,
+        mesh: MeshHandle,
+        uploader: Uploader,
+        config: ConfigVoice,
+        events_tx: mpsc::UnboundedSender<(usize, ShardEvent)>,
+    ) -> Result<(Self, ShardHandle)> {
         let (control_tx, control_rx) = mpsc::channel(100);
+        let (media_tx, media_rx) = mpsc::channel(1024);
+        let (load_tx, load) = watch::channel(ShardLoad::default());
 
         let host_v4 = config
             .host_ipv4
@@ -85,8 +216,13 @@ impl Shard {
         let sock_v6 = UdpSocket::bind(format!("[{host_v6}]:0")).await?;
 
         let me = Self {
+            id,
             backend,
+            mesh,
+            uploader,
             control_rx,
+            media_tx,
+            media_rx,
             sock_v4,
             sock_v6,
             calls: SlotMap::with_key(),
@@ -95,9 +231,19 @@ impl Shard {
             channels: HashMap::new(),
             timeout_queue: DelayQueue::new(),
             timeout_keys: HashMap::new(),
+            idle_since: HashMap::new(),
+            events_tx,
+            load_tx,
+            bytes: 0,
+            busy: Duration::ZERO,
+            last_load: Instant::now(),
         };
 
-        let handle = ShardHandle { control_tx };
+        let handle = ShardHandle {
+            id,
+            control_tx,
+            load,
+        };
 
         Ok((me, handle))
     }
@@ -105,6 +251,13 @@ impl Shard {
     pub async fn run(mut self) {
         let mut buf_v4 = [0u8; 2000];
         let mut buf_v6 = [0u8; 2000];
+        let mut mesh_sync = tokio::time::interval(MESH_SYNC_INTERVAL);
+        mesh_sync.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut spatial = tokio::time::interval(SPATIAL_INTERVAL);
+        spatial.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut load = tokio::time::interval(LOAD_INTERVAL);
+        load.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut woke = Instant::now();
 
         loop {
             self.cleanup_dead_peers();
@@ -115,26 +268,66 @@ impl Shard {
             // run sdp renegotiation and dispatch resulting offers/answers back to clients
             self.process_all_negotiations();
 
-            tokio::select! {
+            self.busy += woke.elapsed();
+
+            let wake = tokio::select! {
                 // TODO: warn if local_addr is None
                 Ok((len, source)) = self.sock_v4.recv_from(&mut buf_v4) => {
-                    if let Ok(dst) = self.sock_v4.local_addr() {
-                        self.handle_udp(dst, source, Bytes::copy_from_slice(&buf_v4[..len]));
-                    }
+                    let Ok(dst) = self.sock_v4.local_addr() else {
+                        continue;
+                    };
+                    Wake::Udp(dst, source, Bytes::copy_from_slice(&buf_v4[..len]))
                 }
 
                 Ok((len, source)) = self.sock_v6.recv_from(&mut buf_v6) => {
-                    if let Ok(dst) = self.sock_v6.local_addr() {
-                        self.handle_udp(dst, source, Bytes::copy_from_slice(&buf_v6[..len]));
-                    }
+                    let Ok(dst) = self.sock_v6.local_addr() else {
+                        continue;
+                    };
+                    Wake::Udp(dst, source, Bytes::copy_from_slice(&buf_v6[..len]))
                 }
 
-                Some(cmd) = self.control_rx.recv() => {
-                    self.handle_command(cmd);
+                Some(cmd) = self.control_rx.recv() => Wake::Command(cmd),
+
+                Some((call_slot, sfu_id, datagram)) = self.media_rx.recv() => {
+                    Wake::Media(call_slot, sfu_id, datagram)
                 }
 
+                _ = mesh_sync.tick() => Wake::MeshSync,
+
+                _ = spatial.tick() => Wake::Spatial,
+
+                _ = load.tick() => Wake::Load,
+
                 Some(expired) = self.timeout_queue.next() => {
                     let (call_slot, peer_slot) = expired.into_inner();
+                    Wake::Timeout(call_slot, peer_slot)
+                }
+            };
+
+            woke = Instant::now();
+            match wake {
+                Wake::Udp(dst, source, data) => self.handle_udp(dst, source, data),
+                Wake::Command(cmd) => self.handle_command(cmd),
+                Wake::Media(call_slot, sfu_id, datagram) => {
+                    if let Some(call) = self.calls.get_mut(call_slot) {
+                        call.handle_remote_datagram(sfu_id, datagram);
+                    }
+                }
+                Wake::MeshSync => {
+                    for (_, call) in self.calls.iter_mut() {
+                        call.sync_mesh();
+                    }
+                }
+                Wake::Spatial => {
+                    for (_, call) in self.calls.iter_mut() {
+                        call.sync_spatial();
+                    }
+                }
+                Wake::Load => {
+                    self.report_idle_calls();
+                    self.report_load();
+                }
+                Wake::Timeout(call_slot, peer_slot) => {
                     self.timeout_keys.remove(&(call_slot, peer_slot));
                     if let Some(call) = self.calls.get_mut(call_slot) {
                         call.unpause(peer_slot);
@@ -145,12 +338,81 @@ impl Shard {
         }
     }
 
+    /// publish this shard's load for the sfu to use when placing calls
+    fn report_load(&mut self) {
+        let elapsed = self.last_load.elapsed().as_secs_f32();
+        if elapsed <= 0.0 {
+            return;
+        }
+
+        let load = ShardLoad {
+            calls: self.calls.len(),
+            peers: self.calls.values().map(|c| c.peer_count()).sum(),
+            tracks: self.calls.values().map(|c| c.track_count()).sum(),
+            bitrate: (self.bytes as f32 * 8.0 / elapsed) as u64,
+            cpu: (self.busy.as_secs_f32() / elapsed).min(1.0),
+        };
+
+        self.load_tx.send_replace(load);
+        self.bytes = 0;
+        self.busy = Duration::ZERO;
+        self.last_load = Instant::now();
+    }
+
+    /// tell the sfu about calls that haven't been used for a while
+    fn report_idle_calls(&mut self) {
+        let now = Instant::now();
+        for (call_slot, call) in &self.calls {
+            if !call.is_idle() {
+                self.idle_since.remove(&call_slot);
+                continue;
+            }
+
+            let since = self.idle_since.entry(call_slot).or_insert(now);
+            if now.duration_since(*since) >= IDLE_TIMEOUT {
+                // report again later if the sfu decides to keep it
+                *since = now;
+                let channel_id = call.channel_id();
+                let _ = self
+                    .events_tx
+                    .send((self.id, ShardEvent::CallIdle { channel_id }));
+            }
+        }
+    }
+
+    /// remove a call and everything that refers to it
+    fn remove_call(&mut self, channel_id: ChannelId) {
+        let Some(call_slot) = self.channels.remove(&channel_id) else {
+            return;
+        };
+        debug!(?channel_id, "Shard: Removing call");
+
+        self.calls.remove(call_slot);
+        self.idle_since.remove(&call_slot);
+        self.addrs.retain(|_, (c_slot, _)| *c_slot != call_slot);
+        self.ufrags.retain(|_, (c_slot, _)| *c_slot != call_slot);
+        self.timeout_keys.retain(|(c_slot, _), key| {
+            if *c_slot == call_slot {
+                self.timeout_queue.remove(key);
+            }
+            *c_slot != call_slot
+        });
+    }
+
     fn cleanup_dead_peers(&mut self) {
         // PERF: theres probably a better way to do this than iterating over every call every loop
         for (call_slot, call) in self.calls.iter_mut() {
             let dead_peers = call.get_dead_peers();
             for peer_slot in dead_peers {
+                let user_id = call.peer_user_id(peer_slot);
                 call.remove_peer(peer_slot);
+                let _ = self.events_tx.send((
+                    self.id,
+                    ShardEvent::PeerRemoved {
+                        channel_id: call.channel_id(),
+                        user_id,
+                    },
+                ));
 
                 self.addrs
                     .retain(|_, (c_slot, p_slot)| *c_slot != call_slot || *p_slot != peer_slot);
@@ -189,6 +451,7 @@ impl Shard {
             }
 
             for t in transmits {
+                self.bytes += t.contents.len() as u64;
                 let res = if t.destination.is_ipv4() {
                     self.sock_v4.send_to(&t.contents, t.destination).await
                 } else {
@@ -215,6 +478,7 @@ impl Shard {
 
     /// handle a udp packet from `dst` to `src` with data `data`
     fn handle_udp(&mut self, dst: SocketAddr, src: SocketAddr, data: Bytes) {
+        self.bytes += data.len() as u64;
         let now = Instant::now();
         let input = SInput::Receive(
             now,
@@ -268,10 +532,7 @@ impl Shard {
         match cmd {
             ShardCommand::CreatePeer(channel_id, state) => {
                 debug!(?channel_id, ?state.user_id, "Shard: Creating peer");
-                let call_slot = *self
-                    .channels
-                    .entry(channel_id)
-                    .or_insert_with(|| self.calls.insert(ShardCall::new(channel_id)));
+                let call_slot = self.get_or_create_call(channel_id);
                 let Some(call) = self.calls.get_mut(call_slot) else {
                     warn!(
                         ?channel_id,
@@ -282,22 +543,30 @@ impl Shard {
 
                 let mut rtc = RtcConfig::new().set_ice_lite(true).build(Instant::now());
 
-                if let Ok(addr) = self.sock_v4.local_addr() {
-                    if let Ok(c) = Candidate::host(addr, "udp") {
-                        rtc.add_local_candidate(c);
-                    }
+                if let Ok(addr) = self.sock_v4.local_addr()
+                    && let Ok(c) = Candidate::host(addr, "udp")
+                {
+                    rtc.add_local_candidate(c);
                 }
 
-                if let Ok(addr) = self.sock_v6.local_addr() {
-                    if let Ok(c) = Candidate::host(addr, "udp") {
-                        rtc.add_local_candidate(c);
-                    }
+                if let Ok(addr) = self.sock_v6.local_addr()
+                    && let Ok(c) = Candidate::host(addr, "udp")
+                {
+                    rtc.add_local_candidate(c);
                 }
 
                 let local_ufrag = rtc.direct_api().local_ice_credentials().ufrag.to_string();
+                let user_id = state.user_id;
                 let peer_slot = call.create_peer(state, rtc);
                 self.ufrags.insert(local_ufrag, (call_slot, peer_slot));
                 debug!(?channel_id, "Shard: Peer created");
+
+                if let Err(e) = self.backend.send(SfuEvent::PeerCreated {
+                    user_id,
+                    channel_id,
+                }) {
+                    warn!("Failed to send PeerCreated: {:?}", e);
+                }
             }
             ShardCommand::Signalling {
                 channel_id,
@@ -327,26 +596,154 @@ impl Shard {
                         }
                     }
                 }
-            } // ShardCommand::GenerateKeyframe {
-              //     channel_id,
-              //     user_id,
-              //     mid,
-              //     rid,
-              //     kind,
-              // } => {
-              //     debug!(?channel_id, ?user_id, ?mid, "Shard: Generating keyframe");
-              //     let Some(&call_slot) = self.channels.get(&channel_id) else {
-              //         return;
-              //     };
-              //     if let Some(call) = self.calls.get_mut(call_slot) {
-              //         call.generate_keyframe(user_id, mid, rid, kind);
-              //     }
-              // }
+            }
+            ShardCommand::Routes {
+                channel_id,
+                destinations,
+            } => {
+                debug!(?channel_id, ?destinations, "Shard: Updating routes");
+                let call_slot = self.get_or_create_call(channel_id);
+                self.calls[call_slot].set_routes(destinations);
+            }
+            ShardCommand::Mesh(event) => self.handle_mesh_event(event),
+            ShardCommand::RecordingStart { channel_id } => {
+                let call_slot = self.get_or_create_call(channel_id);
+                self.calls[call_slot].start_recording();
+            }
+            ShardCommand::RecordingStop { channel_id } => {
+                let files = self
+                    .call_mut(channel_id)
+                    .and_then(|call| call.stop_recording());
+                let _ = self
+                    .events_tx
+                    .send((self.id, ShardEvent::RecordingStopped { channel_id }));
+
+                // uploading can take a while, so don't block the shard
+                let uploader = self.uploader.clone();
+                let events_tx = self.events_tx.clone();
+                let id = self.id;
+                tokio::spawn(async move {
+                    let files = match files {
+                        Some(files) => files.await.unwrap_or_default(),
+                        None => Vec::new(),
+                    };
+                    uploader.upload_all(channel_id, files).await;
+                    let _ = events_tx.send((id, ShardEvent::RecordingFinished { channel_id }));
+                });
+            }
+            ShardCommand::RemoveCall { channel_id } => self.remove_call(channel_id),
+            // ShardCommand::GenerateKeyframe {
+            //     channel_id,
+            //     user_id,
+            //     mid,
+            //     rid,
+            //     kind,
+            // } => {
+            //     debug!(?channel_id, ?user_id, ?mid, "Shard: Generating keyframe");
+            //     let Some(&call_slot) = self.channels.get(&channel_id) else {
+            //         return;
+            //     };
+            //     if let Some(call) = self.calls.get_mut(call_slot) {
+            //         call.generate_keyframe(user_id, mid, rid, kind);
+            //     }
+            // }
+        }
+    }
+
+    /// get the call for a channel, creating it if it doesn't exist
+    fn get_or_create_call(&mut self, channel_id: ChannelId) -> CallSlot {
+        if let Some(&slot) = self.channels.get(&channel_id) {
+            return slot;
+        }
+
+        let slot = self.calls.insert_with_key(|call_slot| {
+            let media_tx = self.media_tx.clone();
+            let sink: MediaSink = Arc::new(move |sfu_id, datagram| {
+                // drop media instead of blocking if the shard is falling behind
+                let _ = media_tx.try_send((call_slot, sfu_id, datagram));
+            });
+            ShardCall::new(channel_id, self.mesh.clone(), sink)
+        });
+        self.channels.insert(channel_id, slot);
+        slot
+    }
+
+    fn handle_mesh_event(&mut self, event: MeshEvent) {
+        match event {
+            MeshEvent::Connected { sfu_id } | MeshEvent::Closed { sfu_id } => {
+                // anything from an old connection is stale, a new connection will announce everything again
+                for (_, call) in self.calls.iter_mut() {
+                    let channel_id = call.channel_id();
+                    let events = call.remove_remote(sfu_id);
+                    dispatch(&self.backend, channel_id, events);
+                }
+            }
+            MeshEvent::Announce { sfu_id, announce } => {
+                let channel_id = announce.channel_id;
+                let call_slot = self.get_or_create_call(channel_id);
+                let events = self.calls[call_slot].handle_remote_announce(sfu_id, announce);
+                dispatch(&self.backend, channel_id, events);
+            }
+            MeshEvent::Subscribe { sfu_id, subscribe } => {
+                if let Some(call) = self.call_mut(subscribe.channel_id) {
+                    call.handle_remote_subscribe(sfu_id, subscribe);
+                }
+            }
+            MeshEvent::Unsubscribe {
+                sfu_id,
+                channel_id,
+                id,
+            } => {
+                if let Some(call) = self.call_mut(channel_id) {
+                    call.handle_remote_unsubscribe(sfu_id, id);
+                }
+            }
+            MeshEvent::Keyframe {
+                sfu_id,
+                channel_id,
+                id,
+            } => {
+                if let Some(call) = self.call_mut(channel_id) {
+                    call.handle_remote_keyframe(sfu_id, id);
+                }
+            }
+        }
+    }
+
+    fn call_mut(&mut self, channel_id: ChannelId) -> Option<&mut ShardCall> {
+        let slot = *self.channels.get(&channel_id)?;
+        self.calls.get_mut(slot)
+    }
+}
+
+/// send signalling events to users
+fn dispatch(
+    backend: &BackendHandle,
+    channel_id: ChannelId,
+    events: Vec<(UserId, SignallingEvent)>,
+) {
+    for (user_id, event) in events {
+        if let Err(e) = backend.send(SfuEvent::VoiceDispatch {
+            user_id,
+            channel_id,
+            payload: Box::new(event),
+        }) {
+            warn!("Failed to dispatch signalling event: {:?}", e);
         }
     }
 }
 
 impl ShardHandle {
+    /// the index of this shard in the sfu
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// the most recently reported load of this shard
+    pub fn load(&self) -> ShardLoad {
+        *self.load.borrow()
+    }
+
     // NOTE: maybe i should make this async?
     pub fn create_peer(&self, channel_id: ChannelId, s: SfuVoiceState) {
         let _ = self
@@ -384,6 +781,32 @@ impl ShardHandle {
         });
     }
 
-    // pub fn handle_remote_inbound(&self, ...) { todo!() }
-    // pub fn handle_remote_outbound(&self, ...) { todo!() }
+    pub fn update_routes(&self, channel_id: ChannelId, destinations: Vec<SfuId>) {
+        let _ = self.control_tx.try_send(ShardCommand::Routes {
+            channel_id,
+            destinations,
+        });
+    }
+
+    pub fn handle_mesh_event(&self, event: MeshEvent) {
+        let _ = self.control_tx.try_send(ShardCommand::Mesh(event));
+    }
+
+    pub fn start_recording(&self, channel_id: ChannelId) {
+        let _ = self
+            .control_tx
+            .try_send(ShardCommand::RecordingStart { channel_id });
+    }
+
+    pub fn stop_recording(&self, channel_id: ChannelId) {
+        let _ = self
+            .control_tx
+            .try_send(ShardCommand::RecordingStop { channel_id });
+    }
+
+    pub fn remove_call(&self, channel_id: ChannelId) {
+        let _ = self
+            .control_tx
+            .try_send(ShardCommand::RemoveCall { channel_id });
+    }
 }
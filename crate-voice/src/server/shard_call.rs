@@ -6,22 +6,35 @@ use std::{
 use crate::{
     client::webrtc::{
         PeerChange, Webrtc,
-        track::{Inbound, Outbound, TrackState},
+        track::{Frame, Inbound, Outbound, Publisher, TrackState},
+    },
+    mesh::{
+        MediaSink, MeshHandle,
+        datagram::{self, MediaFrame},
+        stream::{Announce, AnnouncedPosition, AnnouncedTrack, Subscribe, SubscribeId},
     },
     prelude::*,
+    recording::{RecordedFile, Recorder},
+    server::spatial::{self, Proximity, TrackPosition},
 };
 
 use common::{
-    v1::types::voice::{
-        MediaKind, TrackAnnouncement, TrackId, TrackKey, TrackMapping,
-        datachannel::{Datagram, SpeakingDatagram},
-        internal::SfuVoiceState,
-        messages::{SignallingCommand, SignallingEvent},
+    v1::types::{
+        SfuId,
+        voice::{
+            MediaKind, TrackAnnouncement, TrackId, TrackKey, TrackMapping, TrackMetadata2,
+            datachannel::{
+                Datagram, PositionDatagram, PositionDatagramUpdate, SpeakingDatagram, SpeakingFlags,
+            },
+            internal::SfuVoiceState,
+            messages::{SignallingCommand, SignallingEvent},
+        },
     },
     v2::types::{ChannelId, UserId},
 };
 use slotmap::{Key, SlotMap};
 use str0m::Rtc;
+use tokio::sync::oneshot;
 use tracing::{debug, info, trace, warn};
 
 /// a shard's voice call data
@@ -40,6 +53,30 @@ pub struct ShardCall {
     inbound: SlotMap<TrackSlot, Inbound>,   // formerly `tracks`
     outbound: SlotMap<TrackSlot, Outbound>, // formerly `sinks`
     paused: HashSet<PeerSlot>,
+
+    mesh: MeshHandle,
+    sink: MediaSink,
+
+    /// other sfus that this call is routed to
+    routes: Vec<SfuId>,
+
+    /// tracks that have been announced to each remote sfu
+    announced: HashMap<SfuId, HashSet<TrackSlot>>,
+
+    /// our subscriptions to remote tracks
+    subscribed: HashMap<(SfuId, SubscribeId), TrackSlot>,
+
+    /// remote subscriptions to our tracks
+    remote_outbound: HashMap<(SfuId, SubscribeId), TrackSlot>,
+
+    /// records local tracks while this call is being recorded
+    recorder: Option<Recorder>,
+
+    /// where each local peer is listening from in the call's virtual space
+    listeners: HashMap<PeerSlot, [f32; 3]>,
+
+    /// tracks that moved since positions were last announced to other sfus
+    moved: HashSet<TrackSlot>,
     // TODO: add routing information
     // - inbound tracks by user id
     // - outbound tracks by inbound track key
@@ -50,7 +87,7 @@ pub struct ShardCall {
 }
 
 impl ShardCall {
-    pub fn new(channel_id: ChannelId) -> Self {
+    pub fn new(channel_id: ChannelId, mesh: MeshHandle, sink: MediaSink) -> Self {
         Self {
             channel_id,
             peers: SlotMap::with_key(),
@@ -58,6 +95,15 @@ impl ShardCall {
             inbound: SlotMap::with_key(),
             outbound: SlotMap::with_key(),
             paused: HashSet::new(),
+            mesh,
+            sink,
+            routes: Vec::new(),
+            announced: HashMap::new(),
+            subscribed: HashMap::new(),
+            remote_outbound: HashMap::new(),
+            recorder: None,
+            listeners: HashMap::new(),
+            moved: HashSet::new(),
         }
     }
 
@@ -66,6 +112,21 @@ impl ShardCall {
         self.channel_id
     }
 
+    /// the number of peers connected to this call
+    pub fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// the number of local and remote tracks published in this call
+    pub fn track_count(&self) -> usize {
+        self.inbound.len()
+    }
+
+    /// whether nothing is using this call anymore
+    pub fn is_idle(&self) -> bool {
+        self.peers.is_empty() && self.routes.is_empty() && self.recorder.is_none()
+    }
+
     /// create a new peer connected to this call
     pub fn create_peer(&mut self, s: SfuVoiceState, rtc: Rtc) -> PeerSlot {
         let user_id = s.user_id;
@@ -89,20 +150,24 @@ impl ShardCall {
             info!(user_id = %peer.user_id(), channel_id = %self.channel_id, "remove peer");
             self.users.remove(&peer.user_id());
             self.paused.remove(&peer_slot);
+            self.listeners.remove(&peer_slot);
 
             // find tracks published by this peer
             let tracks_to_remove: HashSet<TrackSlot> = self
                 .inbound
                 .iter()
-                .filter(|(_, t)| t.publisher == peer_slot)
+                .filter(|(_, t)| t.publisher == Publisher::Local(peer_slot))
                 .map(|(id, _)| id)
                 .collect();
 
-            self.inbound.retain(|_, t| t.publisher != peer_slot);
+            self.inbound
+                .retain(|_, t| t.publisher != Publisher::Local(peer_slot));
 
             // remove this peer's subscriptions to other peers' tracks and other peers' subscriptions to this peer's tracks
             self.outbound
                 .retain(|_, o| o.subscriber != peer_slot && !tracks_to_remove.contains(&o.source));
+            self.remote_outbound
+                .retain(|_, source| !tracks_to_remove.contains(source));
         } else {
             // TODO: warn
         }
@@ -137,7 +202,9 @@ impl ShardCall {
 
                                 // check if we already have this inbound track
                                 let existing_track_id = self.inbound.iter().find_map(|(id, t)| {
-                                    if t.publisher == peer && t.state.mid() == Some(mid) {
+                                    if t.publisher == Publisher::Local(peer)
+                                        && t.state.mid() == Some(mid)
+                                    {
                                         Some(id)
                                     } else {
                                         None
@@ -153,10 +220,24 @@ impl ShardCall {
                                         id: TrackId(track_id.data().as_ffi()),
                                     });
                                 } else {
+                                    // new tracks start wherever the rest of their stream is
+                                    let position = self
+                                        .inbound
+                                        .values()
+                                        .find(|t| {
+                                            t.publisher == Publisher::Local(peer)
+                                                && t.metadata.key == track.inner.key
+                                        })
+                                        .map(|t| t.position)
+                                        .unwrap_or_default();
+
                                     let track_id = self.inbound.insert(Inbound {
-                                        publisher: peer,
+                                        publisher: Publisher::Local(peer),
+                                        user_id: publisher_user_id,
                                         metadata: track.inner.clone(),
                                         state: TrackState::Open(mid),
+                                        layers: Vec::new(),
+                                        position,
                                     });
 
                                     p.mapping_mut().insert(mid, track_id);
@@ -175,13 +256,12 @@ impl ShardCall {
                             let mut dead_tracks = Vec::new();
                             let mut removed_tracks = Vec::new();
                             for (track_id, t) in self.inbound.iter() {
-                                if t.publisher == peer {
-                                    if let Some(mid) = t.state.mid() {
-                                        if !incoming_mids.contains(&mid) {
-                                            dead_tracks.push((track_id, mid));
-                                            removed_tracks.push(TrackId(track_id.data().as_ffi()));
-                                        }
-                                    }
+                                if t.publisher == Publisher::Local(peer)
+                                    && let Some(mid) = t.state.mid()
+                                    && !incoming_mids.contains(&mid)
+                                {
+                                    dead_tracks.push((track_id, mid));
+                                    removed_tracks.push(TrackId(track_id.data().as_ffi()));
                                 }
                             }
 
@@ -201,6 +281,7 @@ impl ShardCall {
                                 for out_id in dead_outbound {
                                     self.outbound.remove(out_id);
                                 }
+                                self.remote_outbound.retain(|_, source| *source != track_id);
                             }
 
                             // subscribe other peers to implicit tracks
@@ -208,11 +289,7 @@ impl ShardCall {
                                 let target_peers: Vec<_> =
                                     self.peers.keys().filter(|&k| k != peer).collect();
                                 for target_peer in target_peers {
-                                    self.outbound.insert(Outbound {
-                                        subscriber: target_peer,
-                                        source: track_id,
-                                        state: TrackState::Pending,
-                                    });
+                                    self.outbound.insert(Outbound::new(target_peer, track_id));
                                 }
                             }
 
@@ -237,7 +314,11 @@ impl ShardCall {
                     p.handle_answer(sdp);
 
                     // update inbound tracks
-                    for (_, t) in self.inbound.iter_mut().filter(|(_, t)| t.publisher == peer) {
+                    for (_, t) in self
+                        .inbound
+                        .iter_mut()
+                        .filter(|(_, t)| t.publisher == Publisher::Local(peer))
+                    {
                         if let TrackState::Negotiating(mid) = t.state {
                             t.state = TrackState::Open(mid);
                         }
@@ -305,11 +386,7 @@ impl ShardCall {
                             }
 
                             if !exists {
-                                self.outbound.insert(Outbound {
-                                    subscriber: peer,
-                                    source: source_slot,
-                                    state: TrackState::Pending,
-                                });
+                                self.outbound.insert(Outbound::new(peer, source_slot));
                             }
                         }
                     }
@@ -445,8 +522,7 @@ impl ShardCall {
                     .inbound
                     .iter()
                     .fold(HashMap::new(), |mut acc, (track_id, t)| {
-                        let user_id = self.peers[t.publisher].user_id();
-                        acc.entry(user_id).or_default().push(TrackAnnouncement {
+                        acc.entry(t.user_id).or_default().push(TrackAnnouncement {
                             inner: t.metadata().clone(),
                             id: TrackId(track_id.data().as_ffi()),
                         });
@@ -467,11 +543,7 @@ impl ShardCall {
                 // Subscribe the new peer to all implicit tracks
                 for (track_id, t) in self.inbound.iter() {
                     if t.is_implicit() {
-                        self.outbound.insert(Outbound {
-                            subscriber: peer_slot,
-                            source: track_id,
-                            state: TrackState::Pending,
-                        });
+                        self.outbound.insert(Outbound::new(peer_slot, track_id));
                     }
                 }
 
@@ -482,7 +554,7 @@ impl ShardCall {
                 let mut my_tracks = Vec::new();
                 for (track_id, t) in self.inbound.iter() {
                     // PERF: don't iterate over every inbound track, maybe have a map of peer -> inbound tracks the peer is sending
-                    if t.publisher == peer_slot {
+                    if t.publisher == Publisher::Local(peer_slot) {
                         my_tracks.push(TrackAnnouncement {
                             inner: t.metadata().clone(),
                             id: TrackId(track_id.data().as_ffi()),
@@ -540,28 +612,7 @@ impl ShardCall {
                     return;
                 }
 
-                // get subscribers
-                let mut subscriber_peers = Vec::new();
-                for (outbound_id, outbound) in self.outbound.iter() {
-                    if outbound.source == track_id {
-                        subscriber_peers.push((outbound.subscriber, outbound_id));
-                    }
-                }
-
-                for (sub_peer_id, outbound_id) in subscriber_peers {
-                    if let Some(target) = self.peers.get_mut(sub_peer_id) {
-                        let target_perms = target.permissions();
-
-                        // if target is deafened and track is audio, skip writing
-                        if kind == MediaKind::Audio && target_perms.deaf {
-                            continue;
-                        }
-
-                        trace!(user_id = ?target.user_id(), "write media");
-
-                        target.write_media(outbound_id, &media);
-                    }
-                }
+                self.forward_media(track_id, &Frame::from(&media));
             }
             SEvent::KeyframeRequest(keyframe_request) => {
                 debug!(channel_id = ?self.channel_id, mid = ?keyframe_request.mid, "Keyframe request");
@@ -575,18 +626,11 @@ impl ShardCall {
                 };
                 let inbound_track_id = outbound.source;
 
-                let Some(inbound) = self.inbound.get(inbound_track_id) else {
-                    return;
-                };
-                let publisher_id = inbound.publisher;
-
-                if let Some(publisher) = self.peers.get_mut(publisher_id) {
-                    let _ = publisher.request_keyframe(
-                        inbound_track_id,
-                        keyframe_request.rid.map(Into::into),
-                        keyframe_request.kind,
-                    );
-                }
+                self.request_keyframe(
+                    inbound_track_id,
+                    keyframe_request.rid,
+                    keyframe_request.kind,
+                );
             }
 
             // these two events are handled inside peer
@@ -611,7 +655,7 @@ impl ShardCall {
                         return;
                     };
 
-                    if track.publisher != peer_slot {
+                    if track.publisher != Publisher::Local(peer_slot) {
                         return;
                     }
 
@@ -626,29 +670,25 @@ impl ShardCall {
                         return;
                     }
 
-                    // broadcast to other peers
-                    // PERF: O(n) iteration over every outbound track. maybe i should add back the old Router struct?
-                    for (_, outbound) in &self.outbound {
-                        if outbound.source != track_id {
-                            continue;
-                        }
+                    self.forward_speaking(track_id, speaking.flags);
+                }
 
-                        let target_peer = &mut self.peers[outbound.subscriber];
-                        let target_perms = target_peer.permissions();
+                // position data
+                if self.peers[peer_slot].datachannels().position() == Some(data.id) {
+                    let Ok(position) = PositionDatagram::decode(&mut &data.data[..]) else {
+                        return;
+                    };
 
-                        // if target is deafened and track is audio, skip writing speaking indicator
-                        if track.kind() == MediaKind::Audio && target_perms.deaf {
-                            continue;
-                        }
+                    let track_id = slotmap::KeyData::from_ffi(position.track_id.0).into();
+                    let Some(track) = self.inbound.get(track_id) else {
+                        return;
+                    };
 
-                        if let Some(chan) = target_peer.datachannels().speaking() {
-                            if let Some(mut c) = target_peer.rtc_mut().channel(chan) {
-                                let mut buf = Vec::new();
-                                speaking.encode(&mut buf);
-                                let _ = c.write(true, &buf);
-                            }
-                        }
+                    if track.publisher != Publisher::Local(peer_slot) {
+                        return;
                     }
+
+                    self.move_stream(track_id, &position.update);
                 }
             }
 
@@ -656,6 +696,784 @@ impl ShardCall {
         }
     }
 
+    /// write media from an inbound track to all of its local and remote subscribers
+    fn forward_media(&mut self, source: TrackSlot, frame: &Frame) {
+        let Some(track) = self.inbound.get_mut(source) else {
+            trace!("no inbound");
+            return;
+        };
+        if let Some(rid) = frame.rid {
+            track.add_layer(rid);
+        }
+        let kind = track.kind();
+
+        // remote tracks are recorded by the sfu they're published to
+        if let Some(recorder) = &mut self.recorder
+            && matches!(track.publisher, Publisher::Local(_))
+            && recorder.write(source, track, frame)
+        {
+            self.request_keyframe(source, None, SKeyframeRequestKind::Pli);
+        }
+
+        // simulcast layers that subscribers switched to
+        let mut switched = Vec::new();
+        let track = &self.inbound[source];
+
+        for (outbound_id, outbound) in self.outbound.iter_mut() {
+            if outbound.source != source || outbound.proximity == Proximity::Far {
+                continue;
+            }
+
+            // only forward one simulcast layer, picked by how close the source is
+            if frame.rid.is_some() {
+                let layer = track.layer_for(outbound.proximity);
+                if outbound.layer != layer {
+                    outbound.layer = layer;
+                    switched.extend(layer);
+                }
+                if frame.rid != outbound.layer {
+                    continue;
+                }
+            }
+
+            let Some(target) = self.peers.get_mut(outbound.subscriber) else {
+                continue;
+            };
+
+            // if target is deafened and track is audio, skip writing
+            if kind == MediaKind::Audio && target.permissions().deaf {
+                continue;
+            }
+
+            trace!(user_id = ?target.user_id(), "write media");
+
+            target.write_media(outbound_id, frame);
+        }
+
+        for (&(sfu_id, subscribe_id), &s) in &self.remote_outbound {
+            if s != source {
+                continue;
+            }
+
+            let Some(remote) = self.mesh.lookup(&sfu_id) else {
+                continue;
+            };
+
+            let d = datagram::Datagram::Media(MediaFrame::new(subscribe_id, frame));
+            if let Err(e) = remote.send_datagram(&d) {
+                trace!(?sfu_id, "failed to forward media: {e}");
+            }
+        }
+
+        // the new layer can't be decoded until its next keyframe
+        switched.sort_unstable_by_key(|rid| rid.to_string());
+        switched.dedup();
+        for rid in switched {
+            self.request_keyframe(source, Some(rid), SKeyframeRequestKind::Pli);
+        }
+    }
+
+    /// send speaking flags for an inbound track to all of its local and remote subscribers
+    fn forward_speaking(&mut self, source: TrackSlot, flags: SpeakingFlags) {
+        let Some(kind) = self.inbound.get(source).map(|t| t.kind()) else {
+            return;
+        };
+
+        let speaking = SpeakingDatagram {
+            track_id: TrackId(source.data().as_ffi()),
+            flags,
+        };
+        let mut buf = Vec::new();
+        speaking.encode(&mut buf);
+
+        // broadcast to other peers
+        // PERF: O(n) iteration over every outbound track. maybe i should add back the old Router struct?
+        for (_, outbound) in &self.outbound {
+            if outbound.source != source {
+                continue;
+            }
+
+            let Some(target_peer) = self.peers.get_mut(outbound.subscriber) else {
+                continue;
+            };
+
+            // if target is deafened and track is audio, skip writing speaking indicator
+            if kind == MediaKind::Audio && target_peer.permissions().deaf {
+                continue;
+            }
+
+            if let Some(chan) = target_peer.datachannels().speaking()
+                && let Some(mut c) = target_peer.rtc_mut().channel(chan)
+            {
+                let _ = c.write(true, &buf);
+            }
+        }
+
+        for (&(sfu_id, subscribe_id), &s) in &self.remote_outbound {
+            if s != source {
+                continue;
+            }
+
+            if let Some(remote) = self.mesh.lookup(&sfu_id) {
+                let _ = remote.send_datagram(&datagram::Datagram::Speaking {
+                    subscribe_id,
+                    flags: flags.bits(),
+                });
+            }
+        }
+    }
+
+    /// move every track in the same stream as a local track
+    fn move_stream(&mut self, source: TrackSlot, update: &PositionDatagramUpdate) {
+        let Some(track) = self.inbound.get(source) else {
+            return;
+        };
+
+        let mut position = track.position;
+        if !position.apply(update) {
+            return;
+        }
+
+        let stream: Vec<TrackSlot> = self
+            .inbound
+            .iter()
+            .filter(|(_, t)| t.publisher == track.publisher && t.metadata.key == track.metadata.key)
+            .map(|(slot, _)| slot)
+            .collect();
+        for slot in stream {
+            let mut p = self.inbound[slot].position;
+            p.apply(update);
+            self.set_position(slot, p);
+        }
+    }
+
+    /// set where a track is and tell nearby peers about it
+    fn set_position(&mut self, slot: TrackSlot, position: TrackPosition) {
+        let Some(track) = self.inbound.get_mut(slot) else {
+            return;
+        };
+
+        let old = std::mem::replace(&mut track.position, position);
+        if old == position {
+            return;
+        }
+        self.moved.insert(slot);
+
+        // the user stream is where a peer listens from
+        if let Publisher::Local(peer) = track.publisher
+            && track.metadata.key == TrackKey::User
+            && let Some(p) = position.position
+            && self.listeners.insert(peer, p).is_none()
+        {
+            self.send_nearby_positions(peer);
+        }
+
+        let changed = TrackPosition {
+            position: position
+                .position
+                .filter(|_| old.position != position.position),
+            direction: position
+                .direction
+                .filter(|_| old.direction != position.direction),
+        };
+        self.forward_position(slot, &changed.datagrams(TrackId(slot.data().as_ffi())));
+    }
+
+    /// send position datagrams for a track to every local peer close enough to hear it
+    fn forward_position(&mut self, source: TrackSlot, datagrams: &[PositionDatagram]) {
+        let Some(track) = self.inbound.get(source) else {
+            return;
+        };
+        let Some(position) = track.position.position else {
+            return;
+        };
+        if datagrams.is_empty() {
+            return;
+        }
+
+        let mut buf = Vec::new();
+        for d in datagrams {
+            d.encode(&mut buf);
+        }
+
+        // PERF: O(n) iteration over every listener. maybe use a spatial index for really big spaces?
+        let targets: Vec<PeerSlot> = self
+            .listeners
+            .iter()
+            .filter(|(peer_slot, listener)| {
+                track.publisher != Publisher::Local(**peer_slot)
+                    && spatial::is_nearby(position, **listener)
+            })
+            .map(|(peer_slot, _)| *peer_slot)
+            .collect();
+        for peer_slot in targets {
+            self.send_position_data(peer_slot, &buf);
+        }
+    }
+
+    /// send a peer the positions of every track near them
+    fn send_nearby_positions(&mut self, peer_slot: PeerSlot) {
+        let Some(&listener) = self.listeners.get(&peer_slot) else {
+            return;
+        };
+
+        let mut buf = Vec::new();
+        for (slot, t) in &self.inbound {
+            if t.publisher == Publisher::Local(peer_slot) {
+                continue;
+            }
+            if !t
+                .position
+                .position
+                .is_some_and(|p| spatial::is_nearby(p, listener))
+            {
+                continue;
+            }
+            for d in t.position.datagrams(TrackId(slot.data().as_ffi())) {
+                d.encode(&mut buf);
+            }
+        }
+
+        self.send_position_data(peer_slot, &buf);
+    }
+
+    fn send_position_data(&mut self, peer_slot: PeerSlot, buf: &[u8]) {
+        if buf.is_empty() {
+            return;
+        }
+
+        let Some(peer) = self.peers.get_mut(peer_slot) else {
+            return;
+        };
+        if let Some(chan) = peer.datachannels().position()
+            && let Some(mut c) = peer.rtc_mut().channel(chan)
+        {
+            let _ = c.write(true, buf);
+        }
+    }
+
+    /// update how close every subscribed track is, and subscribe to or unsubscribe from implicit tracks as they move in and out of range
+    // PERF: iterates over every outbound track and every implicit track for each listener, so this should only be run periodically
+    pub fn sync_spatial(&mut self) {
+        if self.listeners.is_empty() {
+            return;
+        }
+
+        // tracks that peers can now hear, and need positions for
+        let mut entered: Vec<(PeerSlot, TrackSlot)> = Vec::new();
+        let mut dead_outbound = Vec::new();
+
+        for (out_id, out) in self.outbound.iter_mut() {
+            if matches!(out.state, TrackState::Closing(_)) {
+                continue;
+            }
+            let Some(track) = self.inbound.get(out.source) else {
+                continue;
+            };
+
+            let proximity = match (track.position.position, self.listeners.get(&out.subscriber)) {
+                (Some(a), Some(&b)) => out.proximity.update(spatial::distance(a, b)),
+                _ => Proximity::Near,
+            };
+            if proximity == out.proximity {
+                continue;
+            }
+
+            if out.proximity == Proximity::Far {
+                entered.push((out.subscriber, out.source));
+            }
+            out.proximity = proximity;
+
+            // implicit tracks are only subscribed while they're in range
+            if proximity == Proximity::Far && track.is_implicit() {
+                match out.state.mid() {
+                    Some(mid) => out.state = TrackState::Closing(mid),
+                    None => dead_outbound.push(out_id),
+                }
+            }
+        }
+
+        for out_id in dead_outbound {
+            self.outbound.remove(out_id);
+        }
+
+        // resubscribe to implicit tracks that came back into range
+        let subscribed: HashSet<(PeerSlot, TrackSlot)> = self
+            .outbound
+            .values()
+            .map(|o| (o.subscriber, o.source))
+            .collect();
+        for (slot, t) in &self.inbound {
+            if !t.is_implicit() {
+                continue;
+            }
+            let Some(position) = t.position.position else {
+                continue;
+            };
+
+            for (&peer_slot, &listener) in &self.listeners {
+                if t.publisher == Publisher::Local(peer_slot)
+                    || subscribed.contains(&(peer_slot, slot))
+                {
+                    continue;
+                }
+
+                let proximity = Proximity::between(Some(position), Some(listener));
+                if proximity == Proximity::Far {
+                    continue;
+                }
+
+                let mut out = Outbound::new(peer_slot, slot);
+                out.proximity = proximity;
+                self.outbound.insert(out);
+                entered.push((peer_slot, slot));
+            }
+        }
+
+        for (peer_slot, slot) in entered {
+            let datagrams = self.inbound[slot]
+                .position
+                .datagrams(TrackId(slot.data().as_ffi()));
+            let mut buf = Vec::new();
+            for d in datagrams {
+                d.encode(&mut buf);
+            }
+            self.send_position_data(peer_slot, &buf);
+        }
+    }
+
+    /// ask whoever is publishing an inbound track for a keyframe
+    fn request_keyframe(
+        &mut self,
+        source: TrackSlot,
+        rid: Option<SRid>,
+        kind: SKeyframeRequestKind,
+    ) {
+        let Some(inbound) = self.inbound.get(source) else {
+            return;
+        };
+
+        match inbound.publisher {
+            Publisher::Local(publisher_id) => {
+                if let Some(publisher) = self.peers.get_mut(publisher_id) {
+                    let _ = publisher.request_keyframe(source, rid, kind);
+                }
+            }
+            Publisher::Remote { sfu_id, .. } => {
+                let Some(remote) = self.mesh.lookup(&sfu_id) else {
+                    return;
+                };
+                for (&(s, id), &slot) in &self.subscribed {
+                    if s == sfu_id && slot == source {
+                        remote.request_keyframe(id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// start recording every local track in this call
+    pub fn start_recording(&mut self) {
+        if self.recorder.is_some() {
+            return;
+        }
+
+        info!(channel_id = %self.channel_id, "start recording");
+        match Recorder::new(self.channel_id) {
+            Ok(recorder) => self.recorder = Some(recorder),
+            Err(err) => {
+                warn!(channel_id = %self.channel_id, "Failed to start recording: {}", err);
+                return;
+            }
+        }
+
+        // video can only be recorded starting from a keyframe
+        let video: Vec<TrackSlot> = self
+            .inbound
+            .iter()
+            .filter(|(_, t)| {
+                t.kind() == MediaKind::Video && matches!(t.publisher, Publisher::Local(_))
+            })
+            .map(|(slot, _)| slot)
+            .collect();
+        for slot in video {
+            self.request_keyframe(slot, None, SKeyframeRequestKind::Pli);
+        }
+    }
+
+    /// stop recording, returning the recorded files once they're written
+    pub fn stop_recording(&mut self) -> Option<oneshot::Receiver<Vec<RecordedFile>>> {
+        let recorder = self.recorder.take()?;
+        info!(channel_id = %self.channel_id, "stop recording");
+        Some(recorder.finish())
+    }
+
+    /// set which sfus this call is routed to
+    pub fn set_routes(&mut self, routes: Vec<SfuId>) {
+        self.routes = routes;
+    }
+
+    /// announce track changes to remote sfus and update subscriptions to remote tracks
+    // PERF: iterates over every track, so this should only be run periodically
+    pub fn sync_mesh(&mut self) {
+        let channel_id = self.channel_id;
+
+        // stop announcing to sfus that are no longer routed
+        let routes = &self.routes;
+        let mesh = &self.mesh;
+        self.announced.retain(|sfu_id, tracks| {
+            if routes.contains(sfu_id) {
+                return true;
+            }
+
+            if let Some(remote) = mesh.lookup(sfu_id) {
+                remote.announce(Announce {
+                    channel_id,
+                    added: vec![],
+                    removed: tracks.iter().map(|t| t.data().as_ffi()).collect(),
+                    positions: vec![],
+                });
+            }
+
+            false
+        });
+
+        for &sfu_id in &self.routes {
+            let Some(remote) = self.mesh.lookup(&sfu_id) else {
+                continue;
+            };
+
+            // never announce a track back to the sfu it came from
+            let desired: HashSet<TrackSlot> = self
+                .inbound
+                .iter()
+                .filter(|(_, t)| t.publisher.sfu_id() != Some(sfu_id))
+                .map(|(slot, _)| slot)
+                .collect();
+
+            let announced = self.announced.entry(sfu_id).or_default();
+            let added: Vec<AnnouncedTrack> = desired
+                .difference(announced)
+                .filter_map(|&slot| {
+                    let t = &self.inbound[slot];
+                    Some(AnnouncedTrack {
+                        id: slot.data().as_ffi(),
+                        user_id: t.user_id,
+                        metadata: serde_json::to_string(&t.metadata).ok()?,
+                    })
+                })
+                .collect();
+            let removed: Vec<u64> = announced
+                .difference(&desired)
+                .map(|slot| slot.data().as_ffi())
+                .collect();
+
+            // newly announced tracks need their current position too
+            let positions: Vec<AnnouncedPosition> = desired
+                .iter()
+                .filter(|slot| self.moved.contains(*slot) || !announced.contains(*slot))
+                .filter_map(|&slot| {
+                    let p = self.inbound[slot].position;
+                    (p != TrackPosition::default()).then(|| AnnouncedPosition {
+                        id: slot.data().as_ffi(),
+                        position: p.position,
+                        direction: p.direction,
+                    })
+                })
+                .collect();
+
+            if !added.is_empty() || !removed.is_empty() || !positions.is_empty() {
+                remote.announce(Announce {
+                    channel_id,
+                    added,
+                    removed,
+                    positions,
+                });
+            }
+
+            *announced = desired;
+        }
+        self.moved.clear();
+
+        // forget subscriptions that ended or whose track was removed, they will be recreated below if needed
+        let inbound = &self.inbound;
+        self.subscribed.retain(|&(sfu_id, id), slot| {
+            let remote = mesh.lookup(&sfu_id);
+            if inbound.contains_key(*slot) {
+                return remote.is_some_and(|r| r.has_subscription(id));
+            }
+            if let Some(remote) = remote {
+                remote.unsubscribe(id);
+            }
+            false
+        });
+
+        // only receive remote tracks that someone is subscribed to
+        for (slot, t) in &self.inbound {
+            let Publisher::Remote { sfu_id, track_id } = t.publisher else {
+                continue;
+            };
+
+            let wanted = self.outbound.values().any(|o| o.source == slot)
+                || self.remote_outbound.values().any(|&s| s == slot);
+            let existing = self
+                .subscribed
+                .iter()
+                .find(|(_, s)| **s == slot)
+                .map(|(k, _)| *k);
+
+            match (wanted, existing) {
+                (true, None) => {
+                    let Some(remote) = self.mesh.lookup(&sfu_id) else {
+                        continue;
+                    };
+                    let id = remote.subscribe(channel_id, track_id, Arc::clone(&self.sink));
+                    self.subscribed.insert((sfu_id, id), slot);
+                }
+                (false, Some(key)) => {
+                    if let Some(remote) = self.mesh.lookup(&key.0) {
+                        remote.unsubscribe(key.1);
+                    }
+                    self.subscribed.remove(&key);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// a remote sfu changed the tracks it has for this call
+    pub fn handle_remote_announce(
+        &mut self,
+        sfu_id: SfuId,
+        announce: Announce,
+    ) -> Vec<(UserId, SignallingEvent)> {
+        let mut added: HashMap<UserId, Vec<TrackAnnouncement>> = HashMap::new();
+        let mut removed: HashMap<UserId, Vec<TrackId>> = HashMap::new();
+
+        for remote_track_id in announce.removed {
+            let Some(slot) = self.find_remote_track(sfu_id, remote_track_id) else {
+                continue;
+            };
+            if let Some(t) = self.remove_inbound(slot) {
+                removed
+                    .entry(t.user_id)
+                    .or_default()
+                    .push(TrackId(slot.data().as_ffi()));
+            }
+        }
+
+        for track in announce.added {
+            let metadata: TrackMetadata2 = match serde_json::from_str(&track.metadata) {
+                Ok(m) => m,
+                Err(e) => {
+                    warn!(?sfu_id, "invalid remote track metadata: {e}");
+                    continue;
+                }
+            };
+
+            let slot = match self.find_remote_track(sfu_id, track.id) {
+                Some(slot) => {
+                    self.inbound[slot].metadata = metadata.clone();
+                    slot
+                }
+                None => {
+                    let slot = self.inbound.insert(Inbound {
+                        publisher: Publisher::Remote {
+                            sfu_id,
+                            track_id: track.id,
+                        },
+                        user_id: track.user_id,
+                        metadata: metadata.clone(),
+                        state: TrackState::Pending,
+                        layers: Vec::new(),
+                        position: TrackPosition::default(),
+                    });
+
+                    // subscribe local peers to implicit tracks
+                    if self.inbound[slot].is_implicit() {
+                        for peer in self.peers.keys() {
+                            self.outbound.insert(Outbound::new(peer, slot));
+                        }
+                    }
+
+                    slot
+                }
+            };
+
+            added
+                .entry(track.user_id)
+                .or_default()
+                .push(TrackAnnouncement {
+                    inner: metadata,
+                    id: TrackId(slot.data().as_ffi()),
+                });
+        }
+
+        for p in announce.positions {
+            if let Some(slot) = self.find_remote_track(sfu_id, p.id) {
+                let position = TrackPosition {
+                    position: p.position,
+                    direction: p.direction,
+                };
+                self.set_position(slot, position);
+            }
+        }
+
+        self.tracks_events(added, removed)
+    }
+
+    /// a remote sfu subscribed to one of our tracks
+    pub fn handle_remote_subscribe(&mut self, sfu_id: SfuId, subscribe: Subscribe) {
+        let slot = TrackSlot::from(slotmap::KeyData::from_ffi(subscribe.track_id));
+        let Some(track) = self.inbound.get(slot) else {
+            debug!(?sfu_id, "remote subscribed to unknown track");
+            return;
+        };
+
+        if track.publisher.sfu_id() == Some(sfu_id) {
+            warn!(?sfu_id, "remote subscribed to its own track");
+            return;
+        }
+
+        let kind = track.kind();
+        self.remote_outbound.insert((sfu_id, subscribe.id), slot);
+
+        // the new subscriber can't decode anything until the next keyframe
+        if kind == MediaKind::Video {
+            self.request_keyframe(slot, None, SKeyframeRequestKind::Pli);
+        }
+    }
+
+    /// a remote sfu unsubscribed from one of our tracks
+    pub fn handle_remote_unsubscribe(&mut self, sfu_id: SfuId, id: SubscribeId) {
+        self.remote_outbound.remove(&(sfu_id, id));
+    }
+
+    /// a remote sfu wants a keyframe for one of our tracks
+    pub fn handle_remote_keyframe(&mut self, sfu_id: SfuId, id: SubscribeId) {
+        if let Some(&slot) = self.remote_outbound.get(&(sfu_id, id)) {
+            self.request_keyframe(slot, None, SKeyframeRequestKind::Pli);
+        }
+    }
+
+    /// media or speaking flags for a remote track we're subscribed to
+    pub fn handle_remote_datagram(&mut self, sfu_id: SfuId, d: datagram::Datagram) {
+        let Some(&slot) = self.subscribed.get(&(sfu_id, d.subscribe_id())) else {
+            trace!(?sfu_id, "datagram for unknown subscription");
+            return;
+        };
+
+        match d {
+            datagram::Datagram::Media(frame) => {
+                if let Some(frame) = frame.into_frame() {
+                    self.forward_media(slot, &frame);
+                }
+            }
+            datagram::Datagram::Speaking { flags, .. } => {
+                if let Some(flags) = SpeakingFlags::from_bits(flags) {
+                    self.forward_speaking(slot, flags);
+                }
+            }
+        }
+    }
+
+    /// forget everything about a remote sfu, eg. after it disconnected
+    pub fn remove_remote(&mut self, sfu_id: SfuId) -> Vec<(UserId, SignallingEvent)> {
+        let slots: Vec<TrackSlot> = self
+            .inbound
+            .iter()
+            .filter(|(_, t)| t.publisher.sfu_id() == Some(sfu_id))
+            .map(|(slot, _)| slot)
+            .collect();
+
+        let mut removed: HashMap<UserId, Vec<TrackId>> = HashMap::new();
+        for slot in slots {
+            if let Some(t) = self.remove_inbound(slot) {
+                removed
+                    .entry(t.user_id)
+                    .or_default()
+                    .push(TrackId(slot.data().as_ffi()));
+            }
+        }
+
+        self.announced.remove(&sfu_id);
+        self.remote_outbound.retain(|(s, _), _| *s != sfu_id);
+        self.subscribed.retain(|(s, _), _| *s != sfu_id);
+
+        self.tracks_events(HashMap::new(), removed)
+    }
+
+    fn find_remote_track(&self, sfu_id: SfuId, remote_track_id: u64) -> Option<TrackSlot> {
+        let publisher = Publisher::Remote {
+            sfu_id,
+            track_id: remote_track_id,
+        };
+        self.inbound
+            .iter()
+            .find(|(_, t)| t.publisher == publisher)
+            .map(|(slot, _)| slot)
+    }
+
+    /// remove an inbound track and stop forwarding it
+    fn remove_inbound(&mut self, slot: TrackSlot) -> Option<Inbound> {
+        let inbound = self.inbound.remove(slot)?;
+
+        let mut dead_outbound = Vec::new();
+        for (out_id, out) in self.outbound.iter_mut() {
+            if out.source != slot {
+                continue;
+            }
+            if let Some(mid) = out.state.mid() {
+                out.state = TrackState::Closing(mid);
+            } else {
+                dead_outbound.push(out_id);
+            }
+        }
+        for out_id in dead_outbound {
+            self.outbound.remove(out_id);
+        }
+
+        self.remote_outbound.retain(|_, s| *s != slot);
+
+        let mesh = &self.mesh;
+        self.subscribed.retain(|&(sfu_id, id), s| {
+            if *s != slot {
+                return true;
+            }
+            if let Some(remote) = mesh.lookup(&sfu_id) {
+                remote.unsubscribe(id);
+            }
+            false
+        });
+
+        Some(inbound)
+    }
+
+    /// create `Tracks` events for every local peer
+    fn tracks_events(
+        &self,
+        mut added: HashMap<UserId, Vec<TrackAnnouncement>>,
+        mut removed: HashMap<UserId, Vec<TrackId>>,
+    ) -> Vec<(UserId, SignallingEvent)> {
+        let users: HashSet<UserId> = added.keys().chain(removed.keys()).copied().collect();
+        let mut events = Vec::new();
+        for user_id in users {
+            let added = added.remove(&user_id).unwrap_or_default();
+            let removed = removed.remove(&user_id).unwrap_or_default();
+            for peer in self.peers.values() {
+                events.push((
+                    peer.user_id(),
+                    SignallingEvent::Tracks {
+                        user_id,
+                        added: added.clone(),
+                        removed: removed.clone(),
+                    },
+                ));
+            }
+        }
+        events
+    }
+
     /// create a sdp offer for any local track changes on this sfu
     pub fn process_sdp_negotiations(&mut self) -> Vec<(UserId, SignallingEvent)> {
         // PERF: store a list of dirty tracks and/or peers instead of iterating over every outbound track
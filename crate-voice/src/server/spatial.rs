@@ -0,0 +1,169 @@
+//! proximity based subscriptions for calls used as a virtual space
+//!
+//! tracks and listeners only become spatial once they have a position, so
+//! calls that never send `PositionDatagram`s are unaffected.
+
+use common::v1::types::voice::{
+    TrackId,
+    datachannel::{PositionDatagram, PositionDatagramUpdate},
+};
+
+/// media from tracks further away than this isn't forwarded
+pub const AUDIBLE_RADIUS: f32 = 50.0;
+
+/// video from tracks further away than this uses the lowest simulcast layer
+pub const FULL_QUALITY_RADIUS: f32 = 15.0;
+
+/// how far past a boundary something needs to move before it changes level,
+/// so that subscriptions don't flap while someone stands on the boundary
+const HYSTERESIS: f32 = 1.1;
+
+/// where a track is in a virtual space
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TrackPosition {
+    pub position: Option<[f32; 3]>,
+    pub direction: Option<[f32; 3]>,
+}
+
+impl TrackPosition {
+    /// apply an update, returning false if it was invalid
+    pub fn apply(&mut self, update: &PositionDatagramUpdate) -> bool {
+        match *update {
+            PositionDatagramUpdate::Position { x, y, z } => {
+                if !(x.is_finite() && y.is_finite() && z.is_finite()) {
+                    return false;
+                }
+                self.position = Some([x, y, z]);
+            }
+            PositionDatagramUpdate::Direction { x, y, z } => {
+                if !(x.is_finite() && y.is_finite() && z.is_finite()) {
+                    return false;
+                }
+                self.direction = Some([x, y, z]);
+            }
+        }
+        true
+    }
+
+    /// datagrams to send someone who doesn't know this position yet
+    pub fn datagrams(&self, track_id: TrackId) -> Vec<PositionDatagram> {
+        let mut out = Vec::new();
+        if let Some([x, y, z]) = self.position {
+            out.push(PositionDatagram {
+                track_id,
+                update: PositionDatagramUpdate::Position { x, y, z },
+            });
+        }
+        if let Some([x, y, z]) = self.direction {
+            out.push(PositionDatagram {
+                track_id,
+                update: PositionDatagramUpdate::Direction { x, y, z },
+            });
+        }
+        out
+    }
+}
+
+/// how close a track is to someone subscribed to it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Proximity {
+    /// forward the highest simulcast layer
+    #[default]
+    Near,
+
+    /// forward the lowest simulcast layer
+    Mid,
+
+    /// don't forward anything
+    Far,
+}
+
+impl Proximity {
+    /// the proximity of a source and listener, or `Near` if either isn't in the space
+    pub fn between(source: Option<[f32; 3]>, listener: Option<[f32; 3]>) -> Self {
+        match (source, listener) {
+            (Some(a), Some(b)) => Proximity::Far.update(distance(a, b)),
+            _ => Proximity::Near,
+        }
+    }
+
+    /// get the new proximity after the distance changed
+    pub fn update(self, distance: f32) -> Self {
+        // boundaries are pushed outwards for the current level
+        let near = if self == Proximity::Near {
+            FULL_QUALITY_RADIUS * HYSTERESIS
+        } else {
+            FULL_QUALITY_RADIUS
+        };
+        let audible = if self == Proximity::Far {
+            AUDIBLE_RADIUS
+        } else {
+            AUDIBLE_RADIUS * HYSTERESIS
+        };
+
+        if distance <= near {
+            Proximity::Near
+        } else if distance <= audible {
+            Proximity::Mid
+        } else {
+            Proximity::Far
+        }
+    }
+}
+
+/// whether a listener is close enough to a source to care about where it is
+pub fn is_nearby(source: [f32; 3], listener: [f32; 3]) -> bool {
+    distance(source, listener) <= AUDIBLE_RADIUS * HYSTERESIS
+}
+
+/// euclidean distance between two points
+pub fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    let [dx, dy, dz] = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+    (dx * dx + dy * dy + dz * dz).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        assert_eq!(Proximity::Far.update(1.0), Proximity::Near);
+        assert_eq!(Proximity::Far.update(30.0), Proximity::Mid);
+        assert_eq!(Proximity::Near.update(100.0), Proximity::Far);
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let edge = AUDIBLE_RADIUS + 1.0;
+        assert_eq!(Proximity::Mid.update(edge), Proximity::Mid);
+        assert_eq!(Proximity::Far.update(edge), Proximity::Far);
+
+        let edge = FULL_QUALITY_RADIUS + 0.5;
+        assert_eq!(Proximity::Near.update(edge), Proximity::Near);
+        assert_eq!(Proximity::Mid.update(edge), Proximity::Mid);
+    }
+
+    #[test]
+    fn test_not_spatial() {
+        assert_eq!(
+            Proximity::between(None, Some([500.0, 0.0, 0.0])),
+            Proximity::Near
+        );
+        assert_eq!(
+            Proximity::between(Some([0.0, 0.0, 0.0]), Some([0.0, 3.0, 4.0])),
+            Proximity::Near
+        );
+    }
+
+    #[test]
+    fn test_rejects_nan() {
+        let mut pos = TrackPosition::default();
+        assert!(!pos.apply(&PositionDatagramUpdate::Position {
+            x: f32::NAN,
+            y: 0.0,
+            z: 0.0
+        }));
+        assert_eq!(pos.position, None);
+    }
+}
@@ -1,16 +1,911 @@
-// TODO: builtin stun server
-// see https://lib.rs/crates/stun_codec
-// see https://lib.rs/crates/is (what str0m uses)
-
-// use bytes::BytesMut;
-// use tokio::net::UdpSocket;
-
-// async fn aaaa() {
-//     let mut sock: UdpSocket = todo!();
-//     let mut buf = BytesMut::with_capacity(2000);
-
-//     loop {
-//         sock.recv(&mut buf).unwrap();
-//         todo!()
-//     }
-// }
+//! builtin stun (rfc 5389) and turn (rfc 8656) server
+//!
+//! turn allocations can only relay to this sfu's media addresses, so this
+//! can't be used as an open relay.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use lamprey_backend_core::{config::ConfigVoice, types::turn::TurnCredentials};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use tokio::{net::UdpSocket, sync::mpsc, task::AbortHandle};
+use tracing::{debug, info, trace, warn};
+
+use crate::prelude::*;
+use crate::util::stun::{Class, StunMessage, StunWriter, attr, method};
+
+const REALM: &str = "lamprey";
+const SOFTWARE: &str = "lamprey-voice";
+
+/// default and maximum allocation lifetimes
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(600);
+const MAX_LIFETIME: Duration = Duration::from_secs(3600);
+
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+
+/// how long nonces are valid for
+const NONCE_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// maximum number of allocations for a single username
+const MAX_ALLOCATIONS_PER_USER: usize = 8;
+
+/// how often to remove expired allocations
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// iana protocol number for udp
+const TRANSPORT_UDP: u8 = 17;
+
+const CHANNEL_MIN: u16 = 0x4000;
+const CHANNEL_MAX: u16 = 0x4FFF;
+
+/// the result of handling a turn request, either a response or an error code and reason
+type TurnResult = std::result::Result<StunWriter, (u16, &'static str)>;
+
+/// a stun and turn server
+pub struct StunServer {
+    sock_v4: UdpSocket,
+    sock_v6: UdpSocket,
+
+    /// used to derive turn passwords, turn is disabled if this is None
+    secret: Option<Arc<str>>,
+
+    /// used to sign nonces
+    nonce_key: [u8; 32],
+
+    /// the addresses that allocations are allowed to relay to
+    peers: Vec<IpAddr>,
+
+    /// the addresses to bind relay sockets to
+    host_v4: IpAddr,
+    host_v6: IpAddr,
+
+    /// allocations by client address
+    allocations: HashMap<SocketAddr, Allocation>,
+
+    /// data received on relay sockets as (client, peer, data)
+    relayed_tx: mpsc::Sender<(SocketAddr, SocketAddr, Vec<u8>)>,
+    relayed_rx: mpsc::Receiver<(SocketAddr, SocketAddr, Vec<u8>)>,
+}
+
+struct Allocation {
+    relay: Arc<UdpSocket>,
+    relay_addr: SocketAddr,
+    username: String,
+
+    /// the transaction that created this allocation, for retransmissions
+    transaction_id: [u8; 12],
+
+    expires: Instant,
+    permissions: HashMap<IpAddr, Instant>,
+    channels: HashMap<u16, (SocketAddr, Instant)>,
+
+    /// the task reading from the relay socket
+    task: AbortHandle,
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Allocation {
+    fn has_permission(&self, ip: IpAddr, now: Instant) -> bool {
+        self.permissions.get(&ip).is_some_and(|t| *t > now)
+    }
+
+    fn channel_for_peer(&self, peer: SocketAddr, now: Instant) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(_, (p, t))| *p == peer && *t > now)
+            .map(|(c, _)| *c)
+    }
+}
+
+impl StunServer {
+    pub async fn new(config: &ConfigVoice, port: u16) -> Result<Self> {
+        let host_v4: IpAddr = config
+            .host_ipv4
+            .as_deref()
+            .and_then(|h| h.parse().ok())
+            .ok_or_else(|| Error::Channel("host_ipv4 missing in config".into()))?;
+        let host_v6: IpAddr = config
+            .host_ipv6
+            .as_deref()
+            .and_then(|h| h.parse().ok())
+            .ok_or_else(|| Error::Channel("host_ipv6 missing in config".into()))?;
+
+        let sock_v4 = UdpSocket::bind(SocketAddr::new(host_v4, port)).await?;
+        let sock_v6 = UdpSocket::bind(SocketAddr::new(host_v6, port)).await?;
+
+        let secret = match &config.turn_secret {
+            Some(s) => Some(
+                s.load()
+                    .map_err(|e| Error::Channel(format!("failed to load turn secret: {e}")))?,
+            ),
+            None => None,
+        };
+
+        let (relayed_tx, relayed_rx) = mpsc::channel(1024);
+
+        Ok(Self {
+            sock_v4,
+            sock_v6,
+            secret,
+            nonce_key: rand::random(),
+            peers: vec![host_v4, host_v6],
+            host_v4,
+            host_v6,
+            allocations: HashMap::new(),
+            relayed_tx,
+            relayed_rx,
+        })
+    }
+
+    /// whether turn is enabled
+    pub fn turn_enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// the stun and turn urls for this server
+    pub fn urls(&self) -> Vec<String> {
+        let mut urls = Vec::new();
+        for sock in [&self.sock_v4, &self.sock_v6] {
+            let Ok(addr) = sock.local_addr() else {
+                continue;
+            };
+            urls.push(format!("stun:{addr}"));
+            if self.turn_enabled() {
+                urls.push(format!("turn:{addr}?transport=udp"));
+            }
+        }
+        urls
+    }
+
+    pub async fn run(mut self) {
+        info!(urls = ?self.urls(), "Stun server listening");
+
+        let mut buf_v4 = [0u8; 2048];
+        let mut buf_v6 = [0u8; 2048];
+        let mut cleanup = tokio::time::interval(CLEANUP_INTERVAL);
+
+        loop {
+            tokio::select! {
+                Ok((len, src)) = self.sock_v4.recv_from(&mut buf_v4) => {
+                    self.handle_packet(src, &buf_v4[..len]).await;
+                }
+                Ok((len, src)) = self.sock_v6.recv_from(&mut buf_v6) => {
+                    self.handle_packet(src, &buf_v6[..len]).await;
+                }
+                Some((client, peer, data)) = self.relayed_rx.recv() => {
+                    self.handle_relayed(client, peer, &data).await;
+                }
+                _ = cleanup.tick() => {
+                    self.cleanup();
+                }
+            }
+        }
+    }
+
+    fn cleanup(&mut self) {
+        let now = Instant::now();
+        self.allocations.retain(|_, a| a.expires > now);
+        for a in self.allocations.values_mut() {
+            a.permissions.retain(|_, t| *t > now);
+            a.channels.retain(|_, (_, t)| *t > now);
+        }
+    }
+
+    async fn send(&self, dst: SocketAddr, data: &[u8]) {
+        let sock = if dst.is_ipv4() {
+            &self.sock_v4
+        } else {
+            &self.sock_v6
+        };
+        if let Err(e) = sock.send_to(data, dst).await {
+            trace!(%dst, "Failed to send stun packet: {}", e);
+        }
+    }
+
+    async fn handle_packet(&mut self, src: SocketAddr, data: &[u8]) {
+        // channel data messages start with 0b01
+        if data.first().is_some_and(|b| b & 0xc0 == 0x40) {
+            self.handle_channel_data(src, data).await;
+            return;
+        }
+
+        let Some(msg) = StunMessage::parse(data) else {
+            return;
+        };
+
+        let res = match (msg.method, msg.class) {
+            (method::BINDING, Class::Request) => Some(self.handle_binding(src, &msg)),
+            (method::SEND, Class::Indication) if self.turn_enabled() => {
+                self.handle_send(src, &msg).await;
+                None
+            }
+            (_, Class::Request) if self.turn_enabled() => Some(self.handle_turn(src, &msg).await),
+            (_, Class::Request) => Some(StunWriter::error(&msg, 400, "Bad Request").finish(None)),
+            _ => None,
+        };
+
+        if let Some(res) = res {
+            self.send(src, &res).await;
+        }
+    }
+
+    fn handle_binding(&self, src: SocketAddr, msg: &StunMessage<'_>) -> Vec<u8> {
+        let mut res = StunWriter::response(msg, Class::Success);
+        res.xor_address(attr::XOR_MAPPED_ADDRESS, src);
+        res.attr(attr::SOFTWARE, SOFTWARE.as_bytes());
+        res.finish(None)
+    }
+
+    async fn handle_turn(&mut self, src: SocketAddr, msg: &StunMessage<'_>) -> Vec<u8> {
+        let unknown: Vec<u16> = msg
+            .required_attributes()
+            .filter(|t| !is_known_attribute(*t))
+            .collect();
+        if !unknown.is_empty() {
+            let mut res = StunWriter::error(msg, 420, "Unknown Attribute");
+            let value: Vec<u8> = unknown.iter().flat_map(|t| t.to_be_bytes()).collect();
+            res.attr(attr::UNKNOWN_ATTRIBUTES, &value);
+            return res.finish(None);
+        }
+
+        let key = match self.authenticate(src, msg) {
+            Ok(key) => key,
+            Err(res) => return res,
+        };
+
+        let res = match msg.method {
+            method::ALLOCATE => self.handle_allocate(src, msg).await,
+            method::REFRESH => self.handle_refresh(src, msg),
+            method::CREATE_PERMISSION => self.handle_create_permission(src, msg),
+            method::CHANNEL_BIND => self.handle_channel_bind(src, msg),
+            _ => Err((400, "Bad Request")),
+        };
+
+        match res {
+            Ok(res) => res.finish(Some(&key)),
+            Err((code, reason)) => StunWriter::error(msg, code, reason).finish(Some(&key)),
+        }
+    }
+
+    /// check a request's long term credentials, returning the key to sign the response with
+    ///
+    /// returns the response to send if authentication failed
+    fn authenticate(
+        &self,
+        src: SocketAddr,
+        msg: &StunMessage<'_>,
+    ) -> std::result::Result<[u8; 16], Vec<u8>> {
+        let Some(secret) = &self.secret else {
+            return Err(StunWriter::error(msg, 400, "Bad Request").finish(None));
+        };
+
+        let (Some(username), Some(nonce)) = (msg.get_str(attr::USERNAME), msg.get_str(attr::NONCE))
+        else {
+            return Err(self.unauthorized(msg, 401, "Unauthorized"));
+        };
+
+        if msg.get(attr::MESSAGE_INTEGRITY).is_none() || msg.get_str(attr::REALM) != Some(REALM) {
+            return Err(self.unauthorized(msg, 401, "Unauthorized"));
+        }
+
+        if !self.verify_nonce(nonce) {
+            return Err(self.unauthorized(msg, 438, "Stale Nonce"));
+        }
+
+        if TurnCredentials::expires(username).is_none_or(|e| e < unix_now()) {
+            return Err(self.unauthorized(msg, 401, "Unauthorized"));
+        }
+
+        let password = TurnCredentials::password(username, secret.as_bytes());
+        let key = long_term_key(username, &password);
+        if !msg.verify_integrity(&key) {
+            return Err(self.unauthorized(msg, 401, "Unauthorized"));
+        }
+
+        // requests for an existing allocation must use the same credentials
+        if let Some(allocation) = self.allocations.get(&src)
+            && allocation.username != username
+        {
+            return Err(StunWriter::error(msg, 441, "Wrong Credentials").finish(None));
+        }
+
+        Ok(key)
+    }
+
+    fn unauthorized(&self, msg: &StunMessage<'_>, code: u16, reason: &str) -> Vec<u8> {
+        let mut res = StunWriter::error(msg, code, reason);
+        res.attr(attr::REALM, REALM.as_bytes());
+        res.attr(attr::NONCE, self.create_nonce().as_bytes());
+        res.finish(None)
+    }
+
+    /// nonces are stateless, containing their expiry and a signature
+    fn create_nonce(&self) -> String {
+        let expires = unix_now() + NONCE_LIFETIME.as_secs();
+        format!("{expires:x}-{}", self.sign_nonce(expires))
+    }
+
+    fn verify_nonce(&self, nonce: &str) -> bool {
+        let Some((expires, sig)) = nonce.split_once('-') else {
+            return false;
+        };
+        let Ok(expires) = u64::from_str_radix(expires, 16) else {
+            return false;
+        };
+        expires >= unix_now() && self.sign_nonce(expires) == sig
+    }
+
+    fn sign_nonce(&self, expires: u64) -> String {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(&self.nonce_key).expect("hmac accepts any key length");
+        mac.update(&expires.to_be_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .take(8)
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    async fn handle_allocate(&mut self, src: SocketAddr, msg: &StunMessage<'_>) -> TurnResult {
+        if let Some(allocation) = self.allocations.get(&src) {
+            if allocation.transaction_id != msg.transaction_id {
+                return Err((437, "Allocation Mismatch"));
+            }
+
+            // the response was lost, so send it again
+            let lifetime = allocation.expires.saturating_duration_since(Instant::now());
+            let mut res = StunWriter::response(msg, Class::Success);
+            res.xor_address(attr::XOR_RELAYED_ADDRESS, allocation.relay_addr);
+            res.xor_address(attr::XOR_MAPPED_ADDRESS, src);
+            res.u32(attr::LIFETIME, lifetime.as_secs() as u32);
+            return Ok(res);
+        }
+
+        match msg.get(attr::REQUESTED_TRANSPORT) {
+            Some(t) if t.first() == Some(&TRANSPORT_UDP) => {}
+            Some(_) => return Err((442, "Unsupported Transport Protocol")),
+            None => return Err((400, "Bad Request")),
+        }
+
+        if msg.get(attr::RESERVATION_TOKEN).is_some() || msg.get(attr::EVEN_PORT).is_some() {
+            return Err((508, "Insufficient Capacity"));
+        }
+
+        let relay_ip = match msg
+            .get(attr::REQUESTED_ADDRESS_FAMILY)
+            .and_then(|f| f.first())
+        {
+            None | Some(0x01) => self.host_v4,
+            Some(0x02) => self.host_v6,
+            Some(_) => return Err((440, "Address Family not Supported")),
+        };
+
+        let username = msg.get_str(attr::USERNAME).unwrap_or_default().to_owned();
+        let count = self
+            .allocations
+            .values()
+            .filter(|a| a.username == username)
+            .count();
+        if count >= MAX_ALLOCATIONS_PER_USER {
+            return Err((486, "Allocation Quota Reached"));
+        }
+
+        let relay = match UdpSocket::bind(SocketAddr::new(relay_ip, 0)).await {
+            Ok(s) => Arc::new(s),
+            Err(e) => {
+                warn!("Failed to bind relay socket: {}", e);
+                return Err((508, "Insufficient Capacity"));
+            }
+        };
+        let Ok(relay_addr) = relay.local_addr() else {
+            return Err((508, "Insufficient Capacity"));
+        };
+
+        let task = tokio::spawn(relay_task(Arc::clone(&relay), src, self.relayed_tx.clone()))
+            .abort_handle();
+
+        let lifetime = requested_lifetime(msg);
+        debug!(%src, %relay_addr, ?lifetime, %username, "Created turn allocation");
+        self.allocations.insert(
+            src,
+            Allocation {
+                relay,
+                relay_addr,
+                username,
+                transaction_id: msg.transaction_id,
+                expires: Instant::now() + lifetime,
+                permissions: HashMap::new(),
+                channels: HashMap::new(),
+                task,
+            },
+        );
+
+        let mut res = StunWriter::response(msg, Class::Success);
+        res.xor_address(attr::XOR_RELAYED_ADDRESS, relay_addr);
+        res.xor_address(attr::XOR_MAPPED_ADDRESS, src);
+        res.u32(attr::LIFETIME, lifetime.as_secs() as u32);
+        res.attr(attr::SOFTWARE, SOFTWARE.as_bytes());
+        Ok(res)
+    }
+
+    fn handle_refresh(&mut self, src: SocketAddr, msg: &StunMessage<'_>) -> TurnResult {
+        let Some(allocation) = self.allocations.get_mut(&src) else {
+            return Err((437, "Allocation Mismatch"));
+        };
+
+        let lifetime = if msg.get_u32(attr::LIFETIME) == Some(0) {
+            debug!(%src, "Deleted turn allocation");
+            self.allocations.remove(&src);
+            Duration::ZERO
+        } else {
+            let lifetime = requested_lifetime(msg);
+            allocation.expires = Instant::now() + lifetime;
+            lifetime
+        };
+
+        let mut res = StunWriter::response(msg, Class::Success);
+        res.u32(attr::LIFETIME, lifetime.as_secs() as u32);
+        Ok(res)
+    }
+
+    fn handle_create_permission(&mut self, src: SocketAddr, msg: &StunMessage<'_>) -> TurnResult {
+        let Some(allocation) = self.allocations.get(&src) else {
+            return Err((437, "Allocation Mismatch"));
+        };
+
+        let peers = msg.get_all_xor_addresses(attr::XOR_PEER_ADDRESS);
+        if peers.is_empty() {
+            return Err((400, "Bad Request"));
+        }
+
+        let mut ips = Vec::new();
+        for peer in peers {
+            let Some(peer) = peer else {
+                return Err((400, "Bad Request"));
+            };
+            if peer.is_ipv4() != allocation.relay_addr.is_ipv4() {
+                return Err((443, "Peer Address Family Mismatch"));
+            }
+            if !self.peers.contains(&peer.ip()) {
+                return Err((403, "Forbidden"));
+            }
+            ips.push(peer.ip());
+        }
+
+        let expires = Instant::now() + PERMISSION_LIFETIME;
+        let allocation = self.allocations.get_mut(&src).expect("checked above");
+        for ip in ips {
+            allocation.permissions.insert(ip, expires);
+        }
+
+        Ok(StunWriter::response(msg, Class::Success))
+    }
+
+    fn handle_channel_bind(&mut self, src: SocketAddr, msg: &StunMessage<'_>) -> TurnResult {
+        let Some(allocation) = self.allocations.get_mut(&src) else {
+            return Err((437, "Allocation Mismatch"));
+        };
+
+        let channel = msg
+            .get(attr::CHANNEL_NUMBER)
+            .and_then(|c| Some(u16::from_be_bytes(c.get(..2)?.try_into().ok()?)))
+            .filter(|c| (CHANNEL_MIN..=CHANNEL_MAX).contains(c))
+            .ok_or((400, "Bad Request"))?;
+        let peer = msg
+            .get_xor_address(attr::XOR_PEER_ADDRESS)
+            .ok_or((400, "Bad Request"))?;
+
+        if peer.is_ipv4() != allocation.relay_addr.is_ipv4() {
+            return Err((443, "Peer Address Family Mismatch"));
+        }
+        if !self.peers.contains(&peer.ip()) {
+            return Err((403, "Forbidden"));
+        }
+
+        // channels can't be rebound to a different peer, and peers can't have multiple channels
+        let now = Instant::now();
+        if allocation
+            .channels
+            .get(&channel)
+            .is_some_and(|(p, t)| *p != peer && *t > now)
+            || allocation
+                .channel_for_peer(peer, now)
+                .is_some_and(|c| c != channel)
+        {
+            return Err((400, "Bad Request"));
+        }
+
+        allocation
+            .channels
+            .insert(channel, (peer, now + CHANNEL_LIFETIME));
+        allocation
+            .permissions
+            .insert(peer.ip(), now + PERMISSION_LIFETIME);
+
+        Ok(StunWriter::response(msg, Class::Success))
+    }
+
+    /// data from a client to a peer, wrapped in a send indication
+    async fn handle_send(&self, src: SocketAddr, msg: &StunMessage<'_>) {
+        let Some(allocation) = self.allocations.get(&src) else {
+            return;
+        };
+        let (Some(peer), Some(data)) = (
+            msg.get_xor_address(attr::XOR_PEER_ADDRESS),
+            msg.get(attr::DATA),
+        ) else {
+            return;
+        };
+
+        if allocation.has_permission(peer.ip(), Instant::now()) {
+            let _ = allocation.relay.send_to(data, peer).await;
+        }
+    }
+
+    /// data from a client to a peer over a channel
+    async fn handle_channel_data(&self, src: SocketAddr, data: &[u8]) {
+        let Some(allocation) = self.allocations.get(&src) else {
+            return;
+        };
+        if data.len() < 4 {
+            return;
+        }
+
+        let channel = u16::from_be_bytes([data[0], data[1]]);
+        let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let Some(payload) = data.get(4..4 + len) else {
+            return;
+        };
+
+        let now = Instant::now();
+        let Some(&(peer, expires)) = allocation.channels.get(&channel) else {
+            return;
+        };
+        if expires > now && allocation.has_permission(peer.ip(), now) {
+            let _ = allocation.relay.send_to(payload, peer).await;
+        }
+    }
+
+    /// data from a peer to a client
+    async fn handle_relayed(&self, client: SocketAddr, peer: SocketAddr, data: &[u8]) {
+        let Some(allocation) = self.allocations.get(&client) else {
+            return;
+        };
+
+        let now = Instant::now();
+        if !allocation.has_permission(peer.ip(), now) {
+            return;
+        }
+
+        let packet = match allocation.channel_for_peer(peer, now) {
+            Some(channel) => {
+                let mut packet = Vec::with_capacity(4 + data.len());
+                packet.extend_from_slice(&channel.to_be_bytes());
+                packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                packet.extend_from_slice(data);
+                packet
+            }
+            None => {
+                let mut ind = StunWriter::new(method::DATA, Class::Indication, &rand::random());
+                ind.xor_address(attr::XOR_PEER_ADDRESS, peer);
+                ind.attr(attr::DATA, data);
+                ind.finish(None)
+            }
+        };
+
+        self.send(client, &packet).await;
+    }
+}
+
+/// read from a relay socket and send everything to the server
+async fn relay_task(
+    relay: Arc<UdpSocket>,
+    client: SocketAddr,
+    tx: mpsc::Sender<(SocketAddr, SocketAddr, Vec<u8>)>,
+) {
+    let mut buf = [0u8; 2048];
+    loop {
+        let Ok((len, peer)) = relay.recv_from(&mut buf).await else {
+            return;
+        };
+        // drop packets instead of blocking if the server is falling behind
+        let _ = tx.try_send((client, peer, buf[..len].to_vec()));
+    }
+}
+
+fn requested_lifetime(msg: &StunMessage<'_>) -> Duration {
+    msg.get_u32(attr::LIFETIME)
+        .map(|s| Duration::from_secs(s as u64).clamp(DEFAULT_LIFETIME, MAX_LIFETIME))
+        .unwrap_or(DEFAULT_LIFETIME)
+}
+
+/// the key for long term credentials is md5(username:realm:password)
+fn long_term_key(username: &str, password: &str) -> [u8; 16] {
+    Md5::digest(format!("{username}:{REALM}:{password}").as_bytes()).into()
+}
+
+fn is_known_attribute(ty: u16) -> bool {
+    matches!(
+        ty,
+        attr::USERNAME
+            | attr::MESSAGE_INTEGRITY
+            | attr::CHANNEL_NUMBER
+            | attr::LIFETIME
+            | attr::XOR_PEER_ADDRESS
+            | attr::DATA
+            | attr::REALM
+            | attr::NONCE
+            | attr::REQUESTED_ADDRESS_FAMILY
+            | attr::EVEN_PORT
+            | attr::REQUESTED_TRANSPORT
+            | attr::DONT_FRAGMENT
+            | attr::RESERVATION_TOKEN
+    )
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use common::v2::types::UserId;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    async fn server() -> StunServer {
+        let config: ConfigVoice = serde_json::from_value(serde_json::json!({
+            "token": "token",
+            "host_ipv4": "127.0.0.1",
+            "host_ipv6": "::1",
+            "turn_secret": "secret",
+        }))
+        .unwrap();
+        StunServer::new(&config, 0).await.unwrap()
+    }
+
+    fn client() -> SocketAddr {
+        "127.0.0.1:40000".parse().unwrap()
+    }
+
+    fn creds() -> TurnCredentials {
+        TurnCredentials::mint(UserId::new(), unix_now() + 3600, SECRET)
+    }
+
+    /// build an authenticated request
+    fn request(
+        method: u16,
+        creds: &TurnCredentials,
+        nonce: &str,
+        build: impl FnOnce(&mut StunWriter),
+    ) -> Vec<u8> {
+        let mut w = StunWriter::new(method, Class::Request, &rand::random());
+        w.attr(attr::USERNAME, creds.username.as_bytes());
+        w.attr(attr::REALM, REALM.as_bytes());
+        w.attr(attr::NONCE, nonce.as_bytes());
+        build(&mut w);
+        let key = long_term_key(&creds.username, &creds.password);
+        w.finish(Some(&key))
+    }
+
+    async fn turn(server: &mut StunServer, data: &[u8]) -> Vec<u8> {
+        let msg = StunMessage::parse(data).unwrap();
+        server.handle_turn(client(), &msg).await
+    }
+
+    fn error_code(res: &[u8]) -> Option<u16> {
+        let msg = StunMessage::parse(res).unwrap();
+        let value = msg.get(attr::ERROR_CODE)?;
+        Some(value[2] as u16 * 100 + value[3] as u16)
+    }
+
+    fn udp(w: &mut StunWriter) {
+        w.attr(attr::REQUESTED_TRANSPORT, &[TRANSPORT_UDP, 0, 0, 0]);
+    }
+
+    fn peer(addr: &str) -> impl FnOnce(&mut StunWriter) {
+        let addr: SocketAddr = addr.parse().unwrap();
+        move |w| {
+            w.xor_address(attr::XOR_PEER_ADDRESS, addr);
+        }
+    }
+
+    fn bind(channel: u16, addr: &str) -> impl FnOnce(&mut StunWriter) {
+        let addr: SocketAddr = addr.parse().unwrap();
+        move |w| {
+            w.attr(
+                attr::CHANNEL_NUMBER,
+                &[(channel >> 8) as u8, channel as u8, 0, 0],
+            );
+            w.xor_address(attr::XOR_PEER_ADDRESS, addr);
+        }
+    }
+
+    /// create an allocation and return the nonce to use for later requests
+    async fn allocate(server: &mut StunServer, creds: &TurnCredentials) -> String {
+        let nonce = server.create_nonce();
+        let res = turn(server, &request(method::ALLOCATE, creds, &nonce, udp)).await;
+        assert_eq!(error_code(&res), None);
+        nonce
+    }
+
+    #[tokio::test]
+    async fn test_allocate() {
+        let mut server = server().await;
+        let creds = creds();
+
+        // the first request has no credentials, and gets a nonce to use
+        let mut w = StunWriter::new(method::ALLOCATE, Class::Request, &rand::random());
+        udp(&mut w);
+        let res = turn(&mut server, &w.finish(None)).await;
+        assert_eq!(error_code(&res), Some(401));
+        let res = StunMessage::parse(&res).unwrap();
+        let nonce = res.get_str(attr::NONCE).unwrap().to_owned();
+        assert!(server.verify_nonce(&nonce));
+
+        let req = request(method::ALLOCATE, &creds, &nonce, udp);
+        let res = turn(&mut server, &req).await;
+        assert_eq!(error_code(&res), None);
+        let msg = StunMessage::parse(&res).unwrap();
+        let key = long_term_key(&creds.username, &creds.password);
+        assert!(msg.verify_integrity(&key));
+        let relay = msg.get_xor_address(attr::XOR_RELAYED_ADDRESS).unwrap();
+        assert_eq!(relay.ip(), server.host_v4);
+        assert_eq!(
+            msg.get_xor_address(attr::XOR_MAPPED_ADDRESS),
+            Some(client())
+        );
+
+        // retransmissions get the same allocation, new requests don't
+        let res = turn(&mut server, &req).await;
+        let msg = StunMessage::parse(&res).unwrap();
+        assert_eq!(msg.get_xor_address(attr::XOR_RELAYED_ADDRESS), Some(relay));
+        let res = turn(&mut server, &request(method::ALLOCATE, &creds, &nonce, udp)).await;
+        assert_eq!(error_code(&res), Some(437));
+
+        // the allocation belongs to these credentials
+        let other = request(method::REFRESH, &self::creds(), &nonce, |_| {});
+        assert_eq!(error_code(&turn(&mut server, &other).await), Some(441));
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let mut server = server().await;
+        let nonce = server.create_nonce();
+
+        let wrong_password = TurnCredentials {
+            password: "wrong".into(),
+            ..creds()
+        };
+        let req = request(method::ALLOCATE, &wrong_password, &nonce, udp);
+        assert_eq!(error_code(&turn(&mut server, &req).await), Some(401));
+
+        let expired = TurnCredentials::mint(UserId::new(), unix_now() - 1, SECRET);
+        let req = request(method::ALLOCATE, &expired, &nonce, udp);
+        assert_eq!(error_code(&turn(&mut server, &req).await), Some(401));
+
+        // nonces can't be forged or used after they expire
+        let forged = format!("{:x}-0000000000000000", unix_now() + 60);
+        let req = request(method::ALLOCATE, &creds(), &forged, udp);
+        assert_eq!(error_code(&turn(&mut server, &req).await), Some(438));
+        let expired = format!("{:x}-{}", unix_now() - 1, server.sign_nonce(unix_now() - 1));
+        let req = request(method::ALLOCATE, &creds(), &expired, udp);
+        assert_eq!(error_code(&turn(&mut server, &req).await), Some(438));
+
+        assert!(server.allocations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_peer_filtering() {
+        let mut server = server().await;
+        let creds = creds();
+        let nonce = allocate(&mut server, &creds).await;
+
+        // only this sfu can be relayed to
+        let req = request(
+            method::CREATE_PERMISSION,
+            &creds,
+            &nonce,
+            peer("192.0.2.1:5000"),
+        );
+        assert_eq!(error_code(&turn(&mut server, &req).await), Some(403));
+        let req = request(
+            method::CHANNEL_BIND,
+            &creds,
+            &nonce,
+            bind(0x4000, "10.0.0.1:5000"),
+        );
+        assert_eq!(error_code(&turn(&mut server, &req).await), Some(403));
+
+        let req = request(
+            method::CREATE_PERMISSION,
+            &creds,
+            &nonce,
+            peer("[::1]:5000"),
+        );
+        assert_eq!(error_code(&turn(&mut server, &req).await), Some(443));
+
+        let req = request(
+            method::CREATE_PERMISSION,
+            &creds,
+            &nonce,
+            peer("127.0.0.1:5000"),
+        );
+        assert_eq!(error_code(&turn(&mut server, &req).await), None);
+        let allocation = &server.allocations[&client()];
+        let ip = "127.0.0.1".parse().unwrap();
+        assert!(allocation.has_permission(ip, Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn test_channel_bind() {
+        let mut server = server().await;
+        let creds = creds();
+        let nonce = allocate(&mut server, &creds).await;
+
+        let req = request(
+            method::CHANNEL_BIND,
+            &creds,
+            &nonce,
+            bind(0x4000, "127.0.0.1:5000"),
+        );
+        assert_eq!(error_code(&turn(&mut server, &req).await), None);
+
+        // refreshing a binding is fine
+        let req = request(
+            method::CHANNEL_BIND,
+            &creds,
+            &nonce,
+            bind(0x4000, "127.0.0.1:5000"),
+        );
+        assert_eq!(error_code(&turn(&mut server, &req).await), None);
+
+        // but channels and peers can't be rebound
+        let req = request(
+            method::CHANNEL_BIND,
+            &creds,
+            &nonce,
+            bind(0x4000, "127.0.0.1:5001"),
+        );
+        assert_eq!(error_code(&turn(&mut server, &req).await), Some(400));
+        let req = request(
+            method::CHANNEL_BIND,
+            &creds,
+            &nonce,
+            bind(0x4001, "127.0.0.1:5000"),
+        );
+        assert_eq!(error_code(&turn(&mut server, &req).await), Some(400));
+
+        // channel numbers must be in range
+        let req = request(
+            method::CHANNEL_BIND,
+            &creds,
+            &nonce,
+            bind(0x3fff, "127.0.0.1:5001"),
+        );
+        assert_eq!(error_code(&turn(&mut server, &req).await), Some(400));
+
+        let allocation = &server.allocations[&client()];
+        let now = Instant::now();
+        let peer = "127.0.0.1:5000".parse().unwrap();
+        assert_eq!(allocation.channel_for_peer(peer, now), Some(0x4000));
+        assert!(allocation.has_permission(peer.ip(), now));
+    }
+}
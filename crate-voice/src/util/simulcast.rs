@@ -35,3 +35,16 @@ pub fn get_simulcast_layer(encoding: &TrackEncoding) -> SimulcastLayer {
             .build(),
     }
 }
+
+/// sort key for a simulcast layer's rid, lower is higher quality
+///
+/// unknown rids sort after every known layer
+pub fn layer_rank(rid: &str) -> u8 {
+    match rid {
+        "s" => 0,
+        "f" => 1,
+        "r" => 2,
+        "t" => 3,
+        _ => u8::MAX,
+    }
+}
@@ -1,46 +1,437 @@
-/// Parse a raw UDP packet to extract the STUN USERNAME attribute (if present)
-///
-/// This is used to map an unknown source address to a peer during the ICE handshake.
-// TODO: check if this is correct
-pub fn extract_local_ufrag(data: &[u8]) -> Option<String> {
-    if data.len() < 20 {
-        return None;
+//! stun message parsing and encoding (rfc 5389, rfc 8489)
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+/// xored into the crc32 of a message for the fingerprint attribute
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+
+const HEADER_LEN: usize = 20;
+
+pub mod method {
+    pub const BINDING: u16 = 0x001;
+    pub const ALLOCATE: u16 = 0x003;
+    pub const REFRESH: u16 = 0x004;
+    pub const SEND: u16 = 0x006;
+    pub const DATA: u16 = 0x007;
+    pub const CREATE_PERMISSION: u16 = 0x008;
+    pub const CHANNEL_BIND: u16 = 0x009;
+}
+
+pub mod attr {
+    pub const MAPPED_ADDRESS: u16 = 0x0001;
+    pub const USERNAME: u16 = 0x0006;
+    pub const MESSAGE_INTEGRITY: u16 = 0x0008;
+    pub const ERROR_CODE: u16 = 0x0009;
+    pub const UNKNOWN_ATTRIBUTES: u16 = 0x000A;
+    pub const CHANNEL_NUMBER: u16 = 0x000C;
+    pub const LIFETIME: u16 = 0x000D;
+    pub const XOR_PEER_ADDRESS: u16 = 0x0012;
+    pub const DATA: u16 = 0x0013;
+    pub const REALM: u16 = 0x0014;
+    pub const NONCE: u16 = 0x0015;
+    pub const XOR_RELAYED_ADDRESS: u16 = 0x0016;
+    pub const REQUESTED_ADDRESS_FAMILY: u16 = 0x0017;
+    pub const EVEN_PORT: u16 = 0x0018;
+    pub const REQUESTED_TRANSPORT: u16 = 0x0019;
+    pub const DONT_FRAGMENT: u16 = 0x001A;
+    pub const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+    pub const RESERVATION_TOKEN: u16 = 0x0022;
+    pub const SOFTWARE: u16 = 0x8022;
+    pub const FINGERPRINT: u16 = 0x8028;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Request,
+    Indication,
+    Success,
+    Error,
+}
+
+impl Class {
+    fn bits(self) -> u16 {
+        match self {
+            Class::Request => 0x000,
+            Class::Indication => 0x010,
+            Class::Success => 0x100,
+            Class::Error => 0x110,
+        }
     }
 
-    // STUN magic cookie must be 0x2112A442
-    if data[4..8] != [0x21, 0x12, 0xA4, 0x42] {
-        return None;
+    fn from_type(ty: u16) -> Self {
+        match ty & 0x110 {
+            0x000 => Class::Request,
+            0x010 => Class::Indication,
+            0x100 => Class::Success,
+            _ => Class::Error,
+        }
     }
+}
 
-    let mut offset = 20; // Skip 20-byte STUN header
-    while offset + 4 <= data.len() {
-        let attr_type = u16::from_be_bytes([data[offset], data[offset + 1]]);
-        let attr_len = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
-        offset += 4;
+/// a parsed stun message that borrows from a packet
+#[derive(Debug)]
+pub struct StunMessage<'a> {
+    pub method: u16,
+    pub class: Class,
+    pub transaction_id: [u8; 12],
+
+    /// attributes in the order that they appear
+    attrs: Vec<(u16, &'a [u8])>,
+
+    /// offset of the message integrity attribute, if it exists
+    integrity_offset: Option<usize>,
+
+    raw: &'a [u8],
+}
+
+impl<'a> StunMessage<'a> {
+    /// parse a stun message, returning None if the data isn't a valid stun message
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || data[0] & 0xc0 != 0 {
+            return None;
+        }
 
-        if offset + attr_len > data.len() {
+        let ty = u16::from_be_bytes([data[0], data[1]]);
+        let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let cookie = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        if cookie != MAGIC_COOKIE || !len.is_multiple_of(4) || HEADER_LEN + len != data.len() {
             return None;
         }
 
-        if attr_type == 0x0006 {
-            // USERNAME attribute
-            let username_bytes = &data[offset..offset + attr_len];
-            if let Ok(username_str) = std::str::from_utf8(username_bytes) {
-                // The STUN username is formatted as "local_ufrag:remote_ufrag"
-                // We extract and return the local_ufrag (our server's ufrag)
-                return username_str.split(':').next().map(|s| s.to_string());
+        let method = (ty & 0x000f) | ((ty & 0x00e0) >> 1) | ((ty & 0x3e00) >> 2);
+        let mut transaction_id = [0; 12];
+        transaction_id.copy_from_slice(&data[8..20]);
+
+        let mut attrs = Vec::new();
+        let mut integrity_offset = None;
+        let mut offset = HEADER_LEN;
+        while offset + 4 <= data.len() {
+            let attr_type = u16::from_be_bytes([data[offset], data[offset + 1]]);
+            let attr_len = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+            let value = data.get(offset + 4..offset + 4 + attr_len)?;
+
+            // everything after message integrity is ignored, except for the fingerprint
+            if integrity_offset.is_none() || attr_type == attr::FINGERPRINT {
+                attrs.push((attr_type, value));
+            }
+            if attr_type == attr::MESSAGE_INTEGRITY && integrity_offset.is_none() {
+                integrity_offset = Some(offset);
+            }
+
+            // attributes are padded to 4 byte boundaries
+            offset += 4 + ((attr_len + 3) & !3);
+        }
+
+        Some(Self {
+            method,
+            class: Class::from_type(ty),
+            transaction_id,
+            attrs,
+            integrity_offset,
+            raw: data,
+        })
+    }
+
+    /// get the first attribute of a type
+    pub fn get(&self, ty: u16) -> Option<&'a [u8]> {
+        self.attrs.iter().find(|(t, _)| *t == ty).map(|(_, v)| *v)
+    }
+
+    /// get every attribute of a type
+    pub fn get_all(&self, ty: u16) -> impl Iterator<Item = &'a [u8]> + '_ {
+        self.attrs
+            .iter()
+            .filter(move |(t, _)| *t == ty)
+            .map(|(_, v)| *v)
+    }
+
+    /// get an attribute as a utf-8 string
+    pub fn get_str(&self, ty: u16) -> Option<&'a str> {
+        std::str::from_utf8(self.get(ty)?).ok()
+    }
+
+    /// get an attribute as a u32
+    pub fn get_u32(&self, ty: u16) -> Option<u32> {
+        let value = self.get(ty)?;
+        Some(u32::from_be_bytes(value.get(..4)?.try_into().ok()?))
+    }
+
+    /// get an xor address attribute
+    pub fn get_xor_address(&self, ty: u16) -> Option<SocketAddr> {
+        decode_xor_address(self.get(ty)?, &self.transaction_id)
+    }
+
+    /// get every xor address attribute of a type
+    pub fn get_all_xor_addresses(&self, ty: u16) -> Vec<Option<SocketAddr>> {
+        self.get_all(ty)
+            .map(|v| decode_xor_address(v, &self.transaction_id))
+            .collect()
+    }
+
+    /// attribute types in the comprehension-required range, which must be understood
+    pub fn required_attributes(&self) -> impl Iterator<Item = u16> + '_ {
+        self.attrs.iter().map(|(t, _)| *t).filter(|t| *t < 0x8000)
+    }
+
+    /// check the message integrity attribute with a key
+    ///
+    /// returns false if the message has no message integrity
+    pub fn verify_integrity(&self, key: &[u8]) -> bool {
+        let Some(offset) = self.integrity_offset else {
+            return false;
+        };
+        let Some(expected) = self.raw.get(offset + 4..offset + 24) else {
+            return false;
+        };
+
+        // the length in the header covers everything up to and including message integrity
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&self.raw[..HEADER_LEN]);
+        header[2..4].copy_from_slice(&((offset + 24 - HEADER_LEN) as u16).to_be_bytes());
+
+        let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac accepts any key length");
+        mac.update(&header);
+        mac.update(&self.raw[HEADER_LEN..offset]);
+        mac.verify_slice(expected).is_ok()
+    }
+}
+
+/// builds a stun message
+pub struct StunWriter {
+    buf: Vec<u8>,
+}
+
+impl StunWriter {
+    pub fn new(method: u16, class: Class, transaction_id: &[u8; 12]) -> Self {
+        let ty = (method & 0x000f) | ((method & 0x0070) << 1) | ((method & 0x0f80) << 2);
+        let mut buf = Vec::with_capacity(128);
+        buf.extend_from_slice(&(ty | class.bits()).to_be_bytes());
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(transaction_id);
+        Self { buf }
+    }
+
+    /// create a response to a request
+    pub fn response(req: &StunMessage, class: Class) -> Self {
+        Self::new(req.method, class, &req.transaction_id)
+    }
+
+    /// create an error response to a request
+    pub fn error(req: &StunMessage, code: u16, reason: &str) -> Self {
+        let mut me = Self::response(req, Class::Error);
+        me.error_code(code, reason);
+        me
+    }
+
+    pub fn attr(&mut self, ty: u16, value: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(&ty.to_be_bytes());
+        self.buf
+            .extend_from_slice(&(value.len() as u16).to_be_bytes());
+        self.buf.extend_from_slice(value);
+        let padding = (4 - value.len() % 4) % 4;
+        self.buf.extend(std::iter::repeat_n(0, padding));
+        self
+    }
+
+    pub fn u32(&mut self, ty: u16, value: u32) -> &mut Self {
+        self.attr(ty, &value.to_be_bytes())
+    }
+
+    pub fn xor_address(&mut self, ty: u16, addr: SocketAddr) -> &mut Self {
+        let mut transaction_id = [0; 12];
+        transaction_id.copy_from_slice(&self.buf[8..20]);
+        let value = encode_xor_address(addr, &transaction_id);
+        self.attr(ty, &value)
+    }
+
+    pub fn error_code(&mut self, code: u16, reason: &str) -> &mut Self {
+        let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+        value.extend_from_slice(reason.as_bytes());
+        self.attr(attr::ERROR_CODE, &value)
+    }
+
+    /// finish this message, adding message integrity if a key is provided and a fingerprint
+    pub fn finish(mut self, key: Option<&[u8]>) -> Vec<u8> {
+        if let Some(key) = key {
+            self.set_len(self.buf.len() - HEADER_LEN + 24);
+            let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac accepts any key length");
+            mac.update(&self.buf);
+            let tag = mac.finalize().into_bytes();
+            self.attr(attr::MESSAGE_INTEGRITY, &tag);
+        }
+
+        self.set_len(self.buf.len() - HEADER_LEN + 8);
+        let crc = crc32fast::hash(&self.buf) ^ FINGERPRINT_XOR;
+        self.attr(attr::FINGERPRINT, &crc.to_be_bytes());
+        self.buf
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.buf[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    }
+}
+
+fn decode_xor_address(value: &[u8], transaction_id: &[u8; 12]) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match value[1] {
+        0x01 => {
+            let bytes: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            IpAddr::V4(Ipv4Addr::from(u32::from_be_bytes(bytes) ^ MAGIC_COOKIE))
+        }
+        0x02 => {
+            let mut bytes: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            for (b, k) in bytes.iter_mut().zip(xor_key(transaction_id)) {
+                *b ^= k;
             }
+            IpAddr::V6(Ipv6Addr::from(bytes))
         }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
 
-        // STUN attributes are padded to 4-byte boundaries
-        let padded_len = (attr_len + 3) & !3;
-        offset += padded_len;
+fn encode_xor_address(addr: SocketAddr, transaction_id: &[u8; 12]) -> Vec<u8> {
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let mut value = Vec::with_capacity(20);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value.extend_from_slice(&[0, 0x01]);
+            value.extend_from_slice(&port.to_be_bytes());
+            value.extend_from_slice(&(u32::from(ip) ^ MAGIC_COOKIE).to_be_bytes());
+        }
+        IpAddr::V6(ip) => {
+            value.extend_from_slice(&[0, 0x02]);
+            value.extend_from_slice(&port.to_be_bytes());
+            value.extend(
+                ip.octets()
+                    .iter()
+                    .zip(xor_key(transaction_id))
+                    .map(|(b, k)| b ^ k),
+            );
+        }
     }
+    value
+}
+
+/// ipv6 addresses are xored with the magic cookie followed by the transaction id
+fn xor_key(transaction_id: &[u8; 12]) -> impl Iterator<Item = u8> + '_ {
+    MAGIC_COOKIE
+        .to_be_bytes()
+        .into_iter()
+        .chain(transaction_id.iter().copied())
+}
+
+/// Parse a raw UDP packet to extract the STUN USERNAME attribute (if present)
+///
+/// This is used to map an unknown source address to a peer during the ICE handshake.
+pub fn extract_local_ufrag(data: &[u8]) -> Option<String> {
+    let msg = StunMessage::parse(data)?;
 
-    None
+    // The STUN username is formatted as "local_ufrag:remote_ufrag"
+    // We extract and return the local_ufrag (our server's ufrag)
+    let username = msg.get_str(attr::USERNAME)?;
+    username.split(':').next().map(|s| s.to_string())
 }
 
 #[cfg(test)]
 mod tests {
-    // TODO: add tests
+    use super::*;
+
+    const TXID: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+    #[test]
+    fn test_roundtrip() {
+        let addr: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let mut w = StunWriter::new(method::BINDING, Class::Success, &TXID);
+        w.xor_address(attr::XOR_MAPPED_ADDRESS, addr);
+        let data = w.finish(None);
+
+        let msg = StunMessage::parse(&data).unwrap();
+        assert_eq!(msg.method, method::BINDING);
+        assert_eq!(msg.class, Class::Success);
+        assert_eq!(msg.transaction_id, TXID);
+        assert_eq!(msg.get_xor_address(attr::XOR_MAPPED_ADDRESS), Some(addr));
+        assert!(msg.get(attr::FINGERPRINT).is_some());
+    }
+
+    #[test]
+    fn test_ipv6_address() {
+        let addr: SocketAddr = "[2001:db8::1]:3478".parse().unwrap();
+        let value = encode_xor_address(addr, &TXID);
+        assert_eq!(decode_xor_address(&value, &TXID), Some(addr));
+    }
+
+    #[test]
+    fn test_method_encoding() {
+        for m in [
+            method::BINDING,
+            method::ALLOCATE,
+            method::CREATE_PERMISSION,
+            method::CHANNEL_BIND,
+        ] {
+            for class in [
+                Class::Request,
+                Class::Indication,
+                Class::Success,
+                Class::Error,
+            ] {
+                let data = StunWriter::new(m, class, &TXID).finish(None);
+                let msg = StunMessage::parse(&data).unwrap();
+                assert_eq!((msg.method, msg.class), (m, class));
+            }
+        }
+    }
+
+    #[test]
+    fn test_integrity() {
+        let mut w = StunWriter::new(method::ALLOCATE, Class::Request, &TXID);
+        w.attr(attr::USERNAME, b"user");
+        let data = w.finish(Some(b"key"));
+
+        let msg = StunMessage::parse(&data).unwrap();
+        assert!(msg.verify_integrity(b"key"));
+        assert!(!msg.verify_integrity(b"wrong"));
+    }
+
+    #[test]
+    fn test_extract_ufrag() {
+        let mut w = StunWriter::new(method::BINDING, Class::Request, &TXID);
+        w.attr(attr::USERNAME, b"local:remote");
+        let data = w.finish(None);
+        assert_eq!(extract_local_ufrag(&data).as_deref(), Some("local"));
+
+        // not stun
+        assert_eq!(extract_local_ufrag(&[0x80; 40]), None);
+    }
+
+    #[test]
+    fn test_rfc5769_request() {
+        // rfc 5769 section 2.1
+        let data: [u8; 108] = [
+            0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34,
+            0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x10, 0x53, 0x54, 0x55, 0x4e,
+            0x20, 0x74, 0x65, 0x73, 0x74, 0x20, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x00, 0x24,
+            0x00, 0x04, 0x6e, 0x00, 0x01, 0xff, 0x80, 0x29, 0x00, 0x08, 0x93, 0x2f, 0xf9, 0xb1,
+            0x51, 0x26, 0x3b, 0x36, 0x00, 0x06, 0x00, 0x09, 0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68,
+            0x36, 0x76, 0x59, 0x20, 0x20, 0x20, 0x00, 0x08, 0x00, 0x14, 0x9a, 0xea, 0xa7, 0x0c,
+            0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e, 0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49, 0xc1, 0xb5,
+            0x71, 0xa2, 0x80, 0x28, 0x00, 0x04, 0xe5, 0x7a, 0x3b, 0xcf,
+        ];
+        let msg = StunMessage::parse(&data).unwrap();
+        assert_eq!(msg.method, method::BINDING);
+        assert_eq!(msg.class, Class::Request);
+        assert_eq!(msg.get_str(attr::USERNAME), Some("evtj:h6vY"));
+        assert!(msg.verify_integrity(b"VOkJxbRl1RmTxUk/WvJxBt"));
+        assert_eq!(extract_local_ufrag(&data).as_deref(), Some("evtj"));
+    }
 }
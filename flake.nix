@@ -144,6 +144,7 @@
         voice = mkCrate "lamprey-voice" [ "crate-voice" ];
         media = mkCrate "lamprey-media" [ "crate-media" "crate-backend-core" "crate-script" ];
         scanner-malware = mkCrate "scanner-malware" [ "scanner-malware" ];
+        scanner-yara = mkCrate "scanner-yara" [ "scanner-yara" ];
 
         wasm-cargo-artifacts = craneLib.buildDepsOnly (common // {
           pname = "lamprey-markdown-wasm-deps";
@@ -310,7 +311,7 @@
         });
       in {
         packages = rec {
-          inherit backend bridge voice media frontend scanner-malware wasm-markdown emoji emoji-spritesheets agent-sandbox spawn-sandbox;
+          inherit backend bridge voice media frontend scanner-malware scanner-yara wasm-markdown emoji emoji-spritesheets agent-sandbox spawn-sandbox;
 
           scanner-nsfw = pkgs.writeShellApplication {
             name = "run-scanner-nsfw";
@@ -329,6 +330,8 @@
             contents = [
               pkgs.dockerTools.caCertificates
               pkgs.ffmpeg-headless
+              pkgs.poppler-utils
+              pkgs.libreoffice
               pkgs.file
               pkgs.curl
             ];
@@ -405,6 +405,18 @@ export class VoiceClient {
 
 		switch (msg.type) {
 			case "Connected": {
+				if (msg.ice_servers?.length) {
+					const servers = msg.ice_servers.map((s) => ({
+						urls: s.urls,
+						username: s.username ?? undefined,
+						credential: s.credential ?? undefined,
+					}));
+					this.rtc.setConfiguration({
+						...RTC_CONFIG,
+						iceServers: [...(RTC_CONFIG.iceServers ?? []), ...servers],
+					});
+					if (this.rtc.iceGatheringState !== "new") this.rtc.restartIce();
+				}
 				this.setConnectionState("connected");
 				this.drainSendQueue();
 				// TODO: send initial subscriptions
@@ -1,4 +1,4 @@
-// TODO: allow server specified ice servers
+// the sfu may provide its own stun/turn servers, which are added to these
 export const RTC_CONFIG: RTCConfiguration = {
 	iceServers: [
 		{ urls: "stun:stun.l.google.com:19302" },
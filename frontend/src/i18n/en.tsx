@@ -287,6 +287,10 @@ export default {
 			name: "Broadcast voice",
 			description: "(todo) Can broadcast voice to all channels in a category",
 		},
+		VoiceRecord: {
+			name: "Record calls",
+			description: "Can start and stop recording calls in voice threads",
+		},
 		ChannelSlowmodeBypass: {
 			name: "Bypass slowmode",
 			description: "Unaffected by slowmode",
@@ -500,6 +504,10 @@ export default {
 			name: "Broadcast voice",
 			description: "(todo) Can broadcast voice to all channels in a category",
 		},
+		VoiceRecord: {
+			name: "Record calls",
+			description: "Can start and stop recording calls in voice threads",
+		},
 		ChannelSlowmodeBypass: {
 			name: "Bypass slowmode",
 			description: "Unaffected by slowmode",
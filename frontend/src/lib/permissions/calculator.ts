@@ -147,6 +147,7 @@ export const ADMIN_PERMS: Permission[] = [
 	"VoiceSpeak",
 	"VoiceVad",
 	"VoiceVideo",
+	"VoiceRecord",
 ];
 
 export const ADMIN_PERMS_SET = new Set<Permission>(ADMIN_PERMS);
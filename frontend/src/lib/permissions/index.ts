@@ -503,6 +503,13 @@ export const permissions: Array<Perm> = [
 		types: ["Room", "Voice", "Broadcast", "Category"],
 		moderator: false,
 	},
+	{
+		id: "VoiceRecord",
+		group: "voice",
+		overwrite_group: "voice",
+		types: ["Room", "Voice", "Broadcast", "Category"],
+		moderator: true,
+	},
 	{
 		id: "ChannelSlowmodeBypass",
 		group: "messages",
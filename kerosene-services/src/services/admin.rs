@@ -104,6 +104,7 @@ impl ServiceAdmin {
     async fn gc_media(&self, mode: AdminCollectGarbageMode) -> Result<(u64, u64)> {
         let mut data = self.state.begin().await?;
         let blobs = self.state.blobs();
+        let paths = self.state.config().media.paths();
         let res = match mode {
             AdminCollectGarbageMode::Mark => {
                 let rows = data.gc_media_mark().await?;
@@ -119,7 +120,14 @@ impl ServiceAdmin {
                     }
 
                     for media_id in &media_to_delete {
-                        let path = format!("media/{}/", media_id);
+                        let storage_id = match data.gc_media_release_blob(*media_id).await? {
+                            // this was the last reference to a shared blob
+                            Some((owner_id, 0)) => owner_id,
+                            // other media still use this blob
+                            Some(_) => continue,
+                            None => *media_id,
+                        };
+                        let path = paths.dir(storage_id);
                         let items = blobs.list_with(&path).recursive(true).await?;
                         for item in items {
                             if item.metadata().is_file() {
@@ -7,7 +7,10 @@ use common::{
         AutomodMatchFragment, AutomodMatchKind, AutomodMatches, AutomodMediaLocation, AutomodRule,
         AutomodTarget, AutomodTextLocation, AutomodTrigger,
     },
-    v2::types::{AutomodRuleId, MediaId, media::Media},
+    v2::types::{
+        AutomodRuleId, MediaId,
+        media::{Media, scanner::ScanVerdict},
+    },
 };
 use kerosene_core::config::Config;
 use regex::{Regex, RegexSet};
@@ -280,16 +283,19 @@ impl Compiled {
                 continue;
             }
 
-            if let AutomodTrigger::MediaScan { scanner } = &rule.trigger {
-                if let Some(threshold) = self.media_thresholds.get(scanner) {
-                    // PERF: maybe i should store scans as a HashMap instead of a Vec?
-                    if let Some(result) = media.scans.iter().find(|s| &s.key == scanner) {
-                        if result.result >= *threshold {
-                            scan.rule_ids.push(rule.id);
-                            for action in &rule.actions {
-                                scan.actions.add(action);
-                            }
-                        }
+            // PERF: maybe i should store scans as a HashMap instead of a Vec?
+            if let AutomodTrigger::MediaScan { scanner } = &rule.trigger
+                && let Some(result) = media.scans.iter().find(|s| &s.key == scanner)
+            {
+                // malicious media always triggers, even without a threshold
+                let over_threshold = self
+                    .media_thresholds
+                    .get(scanner)
+                    .is_some_and(|threshold| result.result >= *threshold);
+                if over_threshold || result.verdict == ScanVerdict::Malicious {
+                    scan.rule_ids.push(rule.id);
+                    for action in &rule.actions {
+                        scan.actions.add(action);
                     }
                 }
             }
@@ -4,7 +4,7 @@ use std::time::Duration;
 use common::v1::types::presence::Status;
 use common::v1::types::util::{Changes, Diff, Time};
 use common::v1::types::{
-    AuditLogEntryType, Channel, ChannelCreate, ChannelId, ChannelPatch, ChannelType, Message,
+    AuditLogEntryType, Channel, ChannelCreate, ChannelId, ChannelPatch, ChannelType,
     MessageChannelIcon, MessageChannelMoved, MessageChannelRename, MessageChannelTagged, MessageId,
     MessageSync, MessageThreadCreated, MessageType, Permission, PermissionOverwrite, RoomId,
     ThreadMemberPut, User, UserId,
@@ -143,6 +143,32 @@ impl ServiceChannels {
         Ok(())
     }
 
+    /// whether a channel is only visible to some users
+    ///
+    /// dms, group dms, private threads, and channels in rooms that aren't public are private
+    pub async fn is_private(&self, channel_id: ChannelId) -> Result<bool> {
+        let channel = self
+            .cache_thread
+            .try_get_with(channel_id, async move {
+                let mut data = self.state.begin_read().await?;
+                data.channel_get(channel_id).await
+            })
+            .await
+            .map_err(|err| err.fake_clone())?;
+
+        if matches!(
+            channel.ty,
+            ChannelType::Dm | ChannelType::Gdm | ChannelType::ThreadPrivate
+        ) {
+            return Ok(true);
+        }
+
+        match channel.room_id {
+            Some(room_id) => Ok(!self.state.services().rooms.get(room_id, None).await?.public),
+            None => Ok(true),
+        }
+    }
+
     pub async fn get(&self, channel_id: ChannelId, user_id: Option<UserId>) -> Result<Channel> {
         let mut thread = self
             .cache_thread
@@ -1557,11 +1583,6 @@ impl ServiceChannels {
     }
 }
 
-pub fn calculate_hotness(_channel: &Channel, _first_message: &Message) -> f64 {
-    // TODO
-    0.0
-}
-
 #[cfg(any())]
 pub fn _calculate_hotness(channel: &Channel, first_message: &Message) -> f64 {
     let gravity: f64 = 1.8;
@@ -1,9 +1,14 @@
 use std::{sync::Arc, time::Duration};
 
-use common::v1::types::{MessageAttachmentType, MessageSync, MessageType, UserId};
+use common::v1::types::{
+    ApplicationId, EmbedId, MessageAttachmentType, MessageSync, MessageType, Permission, UserId,
+    application::Application,
+};
 use common::v2::types::embed::Embed;
-use common::v2::types::media::{MediaCreate, MediaCreateSource};
-use lamprey_unfurl::{DirectMediaPlugin, HtmlStreamPlugin, Unfurler};
+use common::v2::types::media::{Media, MediaCreate, MediaCreateSource};
+use futures::future::join_all;
+use lamprey_unfurl::util::glob_match;
+use lamprey_unfurl::{DirectMediaPlugin, HtmlStreamPlugin, OEmbedPlugin, OEmbedProvider, Unfurler};
 use moka::future::Cache;
 use tokio::sync::{Mutex, broadcast};
 use tokio::task::JoinHandle;
@@ -17,10 +22,22 @@ use crate::types::{DbMessageUpdate, MediaLinkType, MessageRef};
 /// how long can embeds be reused for
 const MAX_EMBED_AGE: Duration = Duration::from_secs(60 * 5);
 
+/// how long to remember which applications unfurl urls
+const UNFURLERS_TTL: Duration = Duration::from_secs(60);
+
+/// the maximum number of embeds an application can unfurl a url into
+const MAX_APP_EMBEDS: usize = 4;
+
+/// the maximum number of embeds in a message
+const MAX_MESSAGE_EMBEDS: usize = 32;
+
 pub struct ServiceEmbed {
     state: Globals,
     unfurler: Arc<Unfurler>,
     cache: Cache<Url, Embed>,
+
+    /// applications with registered unfurl patterns
+    unfurlers: Cache<(), Arc<Vec<Application>>>,
     stop: broadcast::Sender<()>,
     workers: Mutex<Vec<JoinHandle<()>>>,
 }
@@ -28,6 +45,17 @@ pub struct ServiceEmbed {
 impl ServiceEmbed {
     pub fn new(state: Globals) -> Self {
         let (tx, _) = broadcast::channel(1);
+        let oembed_config = state.config().url_preview.oembed.clone();
+        let oembed = oembed_config.providers.into_iter().fold(
+            OEmbedPlugin::new(1024 * 1024 * 4).discovery(oembed_config.discovery),
+            |plugin, p| {
+                plugin.provider(OEmbedProvider {
+                    name: p.name,
+                    schemes: p.schemes,
+                    endpoint: p.endpoint,
+                })
+            },
+        );
         let unfurler = Arc::new(
             Unfurler::builder()
                 .client_config(|builder| {
@@ -41,7 +69,25 @@ impl ServiceEmbed {
                                 .expect("should always be valid user agent"),
                         )
                 })
+                .deny(state.config().http.deny.iter().cloned())
+                .host_limit(
+                    state.config().url_preview.host_concurrency,
+                    Duration::from_millis(state.config().url_preview.host_interval),
+                )
+                .robots(
+                    state
+                        .config()
+                        .url_preview
+                        .robots
+                        .then(|| "Lamprey".to_owned()),
+                )
+                .cache(
+                    state.config().url_preview.cache_size,
+                    Duration::from_secs(60),
+                    Duration::from_secs(60 * 60 * 24),
+                )
                 .add_plugin(DirectMediaPlugin)
+                .add_plugin(oembed)
                 .add_plugin(HtmlStreamPlugin {
                     max_bytes: 1024 * 1024 * 4,
                 })
@@ -56,6 +102,7 @@ impl ServiceEmbed {
                 .max_capacity(1000)
                 .time_to_live(MAX_EMBED_AGE)
                 .build(),
+            unfurlers: Cache::builder().time_to_live(UNFURLERS_TTL).build(),
             stop: tx,
             workers: Mutex::new(Vec::new()),
         }
@@ -102,6 +149,8 @@ impl ServiceEmbed {
 
     pub fn purge_cache(&self) {
         self.cache.invalidate_all();
+        self.unfurlers.invalidate_all();
+        self.unfurler.purge_cache();
     }
 
     async fn worker(state: &Globals) -> Result<()> {
@@ -112,48 +161,66 @@ impl ServiceEmbed {
         };
 
         let url: Url = job.url.parse()?;
+        let message_ref: Option<MessageRef> =
+            job.message_ref.map(|v| serde_json::from_value(v).unwrap());
+        let user_id: UserId = job.user_id.into();
+        let srv = state.services();
+
+        let (include_default, app_embeds) = match &message_ref {
+            Some(mref) => srv
+                .embed
+                .unfurl_with_apps(mref, user_id, &url)
+                .await
+                .unwrap_or_else(|e| {
+                    error!("failed to unfurl with applications: {e:?}");
+                    (true, vec![])
+                }),
+            None => (true, vec![]),
+        };
 
-        let embed = match state
-            .services()
-            .embed
-            .cache
-            .try_get_with(url.clone(), async {
-                debug!("generating embed for {}", url);
-                state
-                    .services()
-                    .embed
-                    .generate_inner(job.user_id.into(), url)
-                    .await
-                    .map_err(Arc::new)
-            })
-            .await
-        {
-            Ok(embed) => embed,
-            Err(e_arc) => {
-                if let Err(e) = txn.url_embed_queue_finish(job.id, None).await {
-                    error!("failed to finish url embed queue job with error: {e:?}");
-                }
-                txn.commit().await?;
-                return Err(e_arc.fake_clone());
-            }
+        let default = if include_default {
+            Some(
+                srv.embed
+                    .cache
+                    .try_get_with(url.clone(), async {
+                        debug!("generating embed for {}", url);
+                        srv.embed
+                            .generate_inner(user_id, url.clone())
+                            .await
+                            .map_err(Arc::new)
+                    })
+                    .await,
+            )
+        } else {
+            None
         };
 
-        if let Err(e) = txn.url_embed_queue_finish(job.id, Some(&embed)).await {
+        let finished = match &default {
+            Some(Ok(embed)) => Some(embed),
+            _ => None,
+        };
+        if let Err(e) = txn.url_embed_queue_finish(job.id, finished).await {
             error!("failed to finish url embed queue job: {e:?}");
         }
         txn.commit().await?;
 
-        if let Err(e) = Self::attach_embed(
-            state,
-            job.message_ref.map(|v| serde_json::from_value(v).unwrap()),
-            Some(job.user_id.into()),
-            embed,
-        )
-        .await
-        {
+        let mut embeds = vec![];
+        let mut error = None;
+        match default {
+            Some(Ok(embed)) => embeds.push(embed),
+            Some(Err(e)) => error = Some(e.fake_clone()),
+            None => {}
+        }
+        embeds.extend(app_embeds);
+
+        if let Err(e) = Self::attach_embeds(state, message_ref, Some(user_id), embeds).await {
             error!("failed to attach embed: {e:?}");
         }
-        Ok(())
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 
     pub async fn queue(
@@ -162,7 +229,9 @@ impl ServiceEmbed {
         user_id: Option<UserId>,
         url: Url,
     ) -> Result<()> {
-        if let Some(embed) = self.cache.get(&url).await {
+        // applications need to be asked every time, so don't reuse embeds for their urls
+        let has_unfurlers = message_ref.is_some() && !self.unfurlers_for(&url).await?.is_empty();
+        if !has_unfurlers && let Some(embed) = self.cache.get(&url).await {
             if let Some(message_ref) = message_ref {
                 info!(
                     "reuse embed message: version_id = {} url = {:?}",
@@ -170,7 +239,7 @@ impl ServiceEmbed {
                     url.as_str()
                 );
                 if let Err(e) =
-                    Self::attach_embed(&self.state, Some(message_ref), user_id, embed).await
+                    Self::attach_embeds(&self.state, Some(message_ref), user_id, vec![embed]).await
                 {
                     error!("failed to attach embed from cache: {e:?}");
                 }
@@ -209,6 +278,109 @@ impl ServiceEmbed {
             .map_err(|e| Error::UrlEmbedOther(e.to_string()))
     }
 
+    /// get the applications that have registered unfurl patterns
+    async fn unfurlers(&self) -> Result<Arc<Vec<Application>>> {
+        self.unfurlers
+            .try_get_with((), async {
+                let mut data = self.state.begin_read().await?;
+                Ok::<_, Error>(Arc::new(data.application_list_unfurlers().await?))
+            })
+            .await
+            .map_err(|e| e.fake_clone())
+    }
+
+    /// get the applications with a pattern matching this url
+    async fn unfurlers_for(&self, url: &Url) -> Result<Vec<ApplicationId>> {
+        Ok(self
+            .unfurlers()
+            .await?
+            .iter()
+            .filter(|app| {
+                app.unfurl_patterns
+                    .iter()
+                    .any(|p| glob_match(p, url.as_str()))
+            })
+            .map(|app| app.id)
+            .collect())
+    }
+
+    /// ask applications to unfurl a url in a message
+    ///
+    /// returns whether the default embed should also be generated and the
+    /// embeds the applications responded with
+    async fn unfurl_with_apps(
+        &self,
+        mref: &MessageRef,
+        user_id: UserId,
+        url: &Url,
+    ) -> Result<(bool, Vec<Embed>)> {
+        let srv = self.state.services();
+        let mut pending = vec![];
+        for application_id in self.unfurlers_for(url).await? {
+            // applications can only unfurl links in channels their bot can see
+            let bot_id: UserId = (*application_id).into();
+            match srv.perms.for_channel(bot_id, mref.thread_id).await {
+                Ok(perms) if perms.has(Permission::ChannelView) => {}
+                _ => continue,
+            }
+
+            match srv
+                .interactions
+                .create_unfurl(
+                    application_id,
+                    mref.thread_id,
+                    mref.message_id,
+                    user_id,
+                    url.clone(),
+                )
+                .await
+            {
+                Ok(rx) => pending.push(async move { (bot_id, rx.await) }),
+                Err(e) => warn!("failed to create unfurl interaction for {application_id}: {e:?}"),
+            }
+        }
+
+        // interactions expire on their own, so this doesn't wait forever
+        let mut responded = false;
+        let mut include_default = false;
+        let mut embeds = vec![];
+        for (bot_id, res) in join_all(pending).await {
+            let Ok((include, app_embeds)) = res else {
+                continue;
+            };
+            responded = true;
+            include_default |= include;
+            for embed in app_embeds.into_iter().take(MAX_APP_EMBEDS) {
+                embeds.push(self.sanitize_app_embed(bot_id, url, embed).await);
+            }
+        }
+
+        Ok((include_default || !responded, embeds))
+    }
+
+    /// make an embed from an application safe to attach to a message
+    async fn sanitize_app_embed(&self, bot_id: UserId, url: &Url, embed: Embed) -> Embed {
+        Embed {
+            id: EmbedId::new(),
+            url: Some(url.clone()),
+            media: self.app_media(bot_id, embed.media).await,
+            thumbnail: self.app_media(bot_id, embed.thumbnail).await,
+            author_avatar: self.app_media(bot_id, embed.author_avatar).await,
+            site_avatar: self.app_media(bot_id, embed.site_avatar).await,
+            player: embed.player.filter(|p| p.url.scheme() == "https"),
+            ..embed
+        }
+        .truncate()
+    }
+
+    /// look up media from an application, dropping it if the application didn't upload it
+    async fn app_media(&self, bot_id: UserId, media: Option<Media>) -> Option<Media> {
+        let media_id = media?.id;
+        let mut data = self.state.begin_read().await.ok()?;
+        let media = data.media_select(media_id).await.ok()?;
+        (media.user_id == Some(bot_id)).then_some(media)
+    }
+
     #[tracing::instrument(level = "info", skip(self))]
     pub(crate) async fn generate_inner(&self, user_id: UserId, url: Url) -> Result<Embed> {
         // Use unfurler to generate embed
@@ -251,15 +423,18 @@ impl ServiceEmbed {
         Ok(embed)
     }
 
-    async fn attach_embed(
+    async fn attach_embeds(
         state: &Globals,
         message_ref: Option<MessageRef>,
         user_id: Option<UserId>,
-        embed: Embed,
+        new_embeds: Vec<Embed>,
     ) -> Result<()> {
         let Some(mref) = message_ref else {
             return Ok(());
         };
+        if new_embeds.is_empty() {
+            return Ok(());
+        }
         let mut txn = state.begin().await?;
         let mut message = txn.message_get(mref.thread_id, mref.message_id).await?;
         let ver = txn
@@ -270,42 +445,50 @@ impl ServiceEmbed {
         let mut message_type = message.latest_version.message_type;
         let (embeds, attachments, components) = match &mut message_type {
             MessageType::DefaultMarkdown(m) => {
-                if m.embeds
-                    .iter()
-                    .any(|e| e.url.as_ref() == embed.url.as_ref())
-                {
+                // only skip urls that were already embedded, since applications and
+                // the default preview can both embed the same url
+                let existing: Vec<Option<Url>> = m.embeds.iter().map(|e| e.url.clone()).collect();
+                let mut added = false;
+                for embed in new_embeds {
+                    if existing.contains(&embed.url) {
+                        info!(
+                            "skip embed message: version_id = {} url = {:?}",
+                            mref.version_id,
+                            embed.url.as_ref().map(|u| u.as_str())
+                        );
+                        continue;
+                    }
+                    if m.embeds.len() >= MAX_MESSAGE_EMBEDS {
+                        break;
+                    }
+
+                    for media in [
+                        &embed.media,
+                        &embed.thumbnail,
+                        &embed.author_avatar,
+                        &embed.site_avatar,
+                    ]
+                    .into_iter()
+                    .flatten()
+                    {
+                        txn.media_link_insert(media.id, *mref.version_id, MediaLinkType::Embed)
+                            .await?;
+                    }
+
                     info!(
-                        "skip embed message: version_id = {} url = {:?}",
+                        "add embed message: version_id = {} url = {:?}",
                         mref.version_id,
                         embed.url.as_ref().map(|u| u.as_str())
                     );
-                    return Ok(());
-                }
 
-                if let Some(media) = &embed.media {
-                    txn.media_link_insert(media.id, *mref.version_id, MediaLinkType::Embed)
-                        .await?;
-                }
-                if let Some(media) = &embed.thumbnail {
-                    txn.media_link_insert(media.id, *mref.version_id, MediaLinkType::Embed)
-                        .await?;
-                }
-                if let Some(media) = &embed.author_avatar {
-                    txn.media_link_insert(media.id, *mref.version_id, MediaLinkType::Embed)
-                        .await?;
-                }
-                if let Some(media) = &embed.site_avatar {
-                    txn.media_link_insert(media.id, *mref.version_id, MediaLinkType::Embed)
-                        .await?;
+                    m.embeds.push(embed);
+                    added = true;
                 }
 
-                info!(
-                    "add embed message: version_id = {} url = {:?}",
-                    mref.version_id,
-                    embed.url.as_ref().map(|u| u.as_str())
-                );
+                if !added {
+                    return Ok(());
+                }
 
-                m.embeds.push(embed);
                 (
                     m.embeds.clone(),
                     m.attachments
@@ -69,6 +69,9 @@ impl ServiceEmoji {
             return Err(ApiError::from_code(ErrorCode::MediaNotAnImage).into());
         }
 
+        srv.media
+            .ensure_room_quota_for(&mut data, room_id, json.media_id)
+            .await?;
         let emoji = data.emoji_create(user_id, room_id, json.clone()).await?;
 
         let changes = Changes::new()
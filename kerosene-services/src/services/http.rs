@@ -1,7 +1,12 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 // use common::util::routes::{Endpoint, Request};
-use reqwest::{Client, Response};
+use lamprey_unfurl::AddrFilter;
+use reqwest::{
+    Client, Response,
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect::Policy,
+};
 use url::Url;
 
 use crate::{
@@ -12,41 +17,61 @@ use crate::{
 pub struct ServiceHttp {
     // TEMP: make client public
     pub(crate) client: Client,
-    state: Globals,
+
+    /// the client for urls from users, which can't reach internal addresses
+    external: Client,
+    filter: Arc<AddrFilter>,
 }
 
 impl ServiceHttp {
     pub fn new(state: Globals) -> Self {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(15))
-            .connect_timeout(Duration::from_secs(5))
-            .redirect(reqwest::redirect::Policy::limited(10))
-            .user_agent(
-                state
-                    .config()
-                    .user_agent_header_value()
-                    .expect("should always be valid user agent"),
-            )
-            .https_only(true)
+        let builder = || {
+            Client::builder()
+                .timeout(Duration::from_secs(15))
+                .connect_timeout(Duration::from_secs(5))
+                .user_agent(
+                    state
+                        .config()
+                        .user_agent_header_value()
+                        .expect("should always be valid user agent"),
+                )
+                .https_only(true)
+        };
+        let filter = Arc::new(AddrFilter::new().deny(state.config().http.deny.iter().cloned()));
+        let client = builder()
+            .redirect(Policy::limited(10))
+            .build()
+            .expect("failed to build http client");
+        let redirect_filter = filter.clone();
+        let external = builder()
+            .dns_resolver(Arc::new(FilteredResolver(filter.clone())))
+            .redirect(Policy::custom(move |attempt| {
+                if attempt.previous().len() >= 10 {
+                    attempt.error("too many redirects")
+                } else if let Err(err) = redirect_filter.check_url(attempt.url()) {
+                    attempt.error(err)
+                } else {
+                    attempt.follow()
+                }
+            }))
             .build()
             .expect("failed to build http client");
-        Self { client, state }
+        Self {
+            client,
+            external,
+            filter,
+        }
     }
 
     /// make a http GET request to this url
+    ///
+    /// this refuses to connect to internal addresses, so it's safe to use with urls from users
     pub async fn get(&self, url: Url) -> Result<Response> {
-        let res = self.client.get(url).send().await?;
-
-        if let Some(addr) = res.remote_addr() {
-            for denied in &self.state.config().http.deny {
-                if denied.contains(&addr.ip()) {
-                    return Err(Error::BadStatic("url blacklisted"));
-                }
-            }
-        } else {
-            tracing::warn!("Could not get remote address for request.");
+        if self.filter.check_url(&url).is_err() {
+            return Err(Error::BadStatic("url blacklisted"));
         }
 
+        let res = self.external.get(url).send().await?;
         Ok(res.error_for_status()?)
     }
 
@@ -70,3 +95,16 @@ impl ServiceHttp {
     //     todo!()
     // }
 }
+
+/// resolves domains through an [`AddrFilter`], so urls can't point to internal addresses
+struct FilteredResolver(Arc<AddrFilter>);
+
+impl Resolve for FilteredResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let filter = self.0.clone();
+        Box::pin(async move {
+            let addrs = filter.resolve(name.as_str()).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
@@ -9,13 +9,16 @@ use common::v1::types::interactions::{
     InteractionResponse, InteractionResponseCreate, InteractionResponseCreateType, InteractionType,
 };
 use common::v1::types::{
-    InteractionId, Message, MessageCreate, MessageInteraction, MessageSync, Permission, UserId,
+    Channel, ChannelId, Embed, InteractionId, Message, MessageCreate, MessageInteraction,
+    MessageSync, Permission, Room, RoomMember, User, UserId,
 };
 use common::v2::types::{ApplicationId, MessageId};
 use dashmap::DashMap;
 use kerosene_core::types::auth::Auth5;
 use lamprey_backend_core::Error;
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
+use url::Url;
 use uuid::Uuid;
 
 use crate::{Result, prelude::*};
@@ -36,6 +39,20 @@ pub struct ServiceInteractions {
     // maybe use nats jetstream or redis or whatever for this
     interactions: DashMap<InteractionId, Arc<InteractionEntry>>,
     interaction_nonce_to_id: DashMap<String, InteractionId>,
+
+    /// the embed service waiting for applications to unfurl urls
+    unfurls: DashMap<InteractionId, oneshot::Sender<(bool, Vec<Embed>)>>,
+}
+
+/// the context shared by interactions on a message
+struct InteractionContext {
+    room: Option<Room>,
+    channel: Channel,
+    message: Message,
+    user: User,
+    room_member: Option<RoomMember>,
+    user_permissions: Vec<Permission>,
+    application_permissions: Vec<Permission>,
 }
 
 /// an interaction on the server
@@ -92,6 +109,7 @@ impl ServiceInteractions {
             state,
             interactions: DashMap::new(),
             interaction_nonce_to_id: DashMap::new(),
+            unfurls: DashMap::new(),
         }
     }
 
@@ -117,44 +135,17 @@ impl ServiceInteractions {
                     message_id,
                     custom_id,
                 } => {
-                    let channel = srv.channels.get(channel_id, Some(user_id)).await?;
-                    let room = if let Some(room_id) = channel.room_id {
-                        Some(srv.rooms.get(room_id, Some(user_id)).await?)
-                    } else {
-                        None
-                    };
-                    let message = srv
-                        .messages
-                        .get(channel_id, message_id, Some(user_id))
+                    let ctx = self
+                        .context(create.application_id, channel_id, message_id, user_id)
                         .await?;
-                    let user = srv.users.get(user_id, Some(user_id)).await?;
-                    let room_member = if let Some(room_id) = room.as_ref().map(|r| r.id) {
-                        let mut data = self.state.begin_read().await?;
-                        Some(data.room_member_get(room_id, user_id).await?)
-                    } else {
-                        None
-                    };
-                    let user_permissions: Vec<Permission> = srv
-                        .perms
-                        .for_channel(user_id, channel_id)
-                        .await?
-                        .perms()
-                        .into();
-                    let application_permissions: Vec<Permission> = srv
-                        .perms
-                        .for_channel((*create.application_id).into(), channel_id)
-                        .await?
-                        .perms()
-                        .into();
-
                     InteractionType::Button {
-                        room,
-                        channel,
-                        message,
-                        user,
-                        room_member,
-                        user_permissions,
-                        application_permissions,
+                        room: ctx.room,
+                        channel: ctx.channel,
+                        message: ctx.message,
+                        user: ctx.user,
+                        room_member: ctx.room_member,
+                        user_permissions: ctx.user_permissions,
+                        application_permissions: ctx.application_permissions,
                         custom_id,
                     }
                 }
@@ -201,6 +192,131 @@ impl ServiceInteractions {
         Ok(inter)
     }
 
+    /// load the channel, message, and user an interaction is about
+    async fn context(
+        &self,
+        application_id: ApplicationId,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        user_id: UserId,
+    ) -> Result<InteractionContext> {
+        let srv = self.state.services();
+        let channel = srv.channels.get(channel_id, Some(user_id)).await?;
+        let room = if let Some(room_id) = channel.room_id {
+            Some(srv.rooms.get(room_id, Some(user_id)).await?)
+        } else {
+            None
+        };
+        let message = srv
+            .messages
+            .get(channel_id, message_id, Some(user_id))
+            .await?;
+        let user = srv.users.get(user_id, Some(user_id)).await?;
+        let room_member = if let Some(room_id) = room.as_ref().map(|r| r.id) {
+            let mut data = self.state.begin_read().await?;
+            Some(data.room_member_get(room_id, user_id).await?)
+        } else {
+            None
+        };
+        let user_permissions: Vec<Permission> = srv
+            .perms
+            .for_channel(user_id, channel_id)
+            .await?
+            .perms()
+            .into();
+        let application_permissions: Vec<Permission> = srv
+            .perms
+            .for_channel((*application_id).into(), channel_id)
+            .await?
+            .perms()
+            .into();
+
+        Ok(InteractionContext {
+            room,
+            channel,
+            message,
+            user,
+            room_member,
+            user_permissions,
+            application_permissions,
+        })
+    }
+
+    /// ask an application to unfurl a url in a message
+    ///
+    /// the receiver resolves to `(include_default, embeds)` if the application
+    /// responds, and is closed if it doesn't respond in time.
+    pub async fn create_unfurl(
+        &self,
+        application_id: ApplicationId,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        user_id: UserId,
+        url: Url,
+    ) -> Result<oneshot::Receiver<(bool, Vec<Embed>)>> {
+        let id = InteractionId::new();
+        let srv = self.state.services();
+        let ctx = self
+            .context(application_id, channel_id, message_id, user_id)
+            .await?;
+
+        let inter = Interaction {
+            id,
+            application_id,
+            token: Some(Uuid::new_v4().to_string()),
+            version: 1,
+            ty: InteractionType::Unfurl {
+                url,
+                room: ctx.room,
+                channel: ctx.channel,
+                message: ctx.message,
+                user: ctx.user,
+                room_member: ctx.room_member,
+                user_permissions: ctx.user_permissions,
+                application_permissions: ctx.application_permissions,
+            },
+        };
+
+        // the application may respond before broadcast_global returns
+        let (tx, rx) = oneshot::channel();
+        let expire_handle = tokio::spawn(async move {
+            tokio::time::sleep(INTERACTION_LIFETIME).await;
+            srv.interactions
+                .fail(id, InteractionErrorCode::Timeout)
+                .await?;
+            Result::Ok(())
+        });
+        let expire_abort = expire_handle.abort_handle();
+
+        self.unfurls.insert(id, tx);
+        self.interactions.insert(
+            id,
+            Arc::new(InteractionEntry {
+                interaction: inter.clone(),
+                nonce: None,
+                state: InteractionEntryState::Created { expire_handle },
+            }),
+        );
+
+        // only sent to the application, the author of the message didn't do anything
+        let res = self
+            .state
+            .messaging()
+            .broadcast_global(MessageSync::InteractionCreate {
+                interaction: Box::new(inter),
+                user_id: (*application_id).into(),
+                nonce: None,
+            })
+            .await;
+        if let Err(err) = res {
+            expire_abort.abort();
+            self.remove(id);
+            return Err(err);
+        }
+
+        Ok(rx)
+    }
+
     /// create a new ping interaction for a webhook
     pub async fn create_ping(&self, application_id: ApplicationId) -> Result<Interaction> {
         let _inter = Interaction {
@@ -240,6 +356,14 @@ impl ServiceInteractions {
             return Err(Error::BadStatic("invalid token"));
         }
 
+        let is_unfurl = matches!(entry.interaction.ty, InteractionType::Unfurl { .. });
+        if is_unfurl != matches!(respond.ty, InteractionResponseCreateType::Unfurl { .. }) {
+            self.interactions.insert(id, entry);
+            return Err(Error::BadStatic(
+                "unfurl interactions must be responded to with an unfurl",
+            ));
+        }
+
         let srv = self.state.services();
         let deferred = match respond.ty {
             InteractionResponseCreateType::Pong => return Err(Error::Unimplemented),
@@ -329,7 +453,18 @@ impl ServiceInteractions {
             // InteractionResponseCreateType::Defer => true,
             InteractionResponseCreateType::ReplyDefer => return Err(Error::Unimplemented),
             InteractionResponseCreateType::Defer => return Err(Error::Unimplemented),
-            InteractionResponseCreateType::Unfurl { .. } => return Err(Error::Unimplemented),
+            InteractionResponseCreateType::Unfurl {
+                include_default,
+                embeds,
+            } => {
+                if let Some((_, tx)) = self.unfurls.remove(&id) {
+                    // the embed service may have already given up
+                    let _ = tx.send((include_default, embeds));
+                }
+
+                // unfurls can't have followups, so there's nothing left to track
+                return Ok(InteractionResponse {});
+            }
         };
 
         let interaction_user_id = match &entry.interaction.ty {
@@ -389,7 +524,8 @@ impl ServiceInteractions {
         let interaction_user_id = match &i.interaction.ty {
             InteractionType::Button { user, .. } => user.id,
             InteractionType::Ping => return Err(Error::BadStatic("what do i do here?")),
-            InteractionType::Unfurl { user, .. } => user.id,
+            // nobody is waiting on the client for unfurls
+            InteractionType::Unfurl { .. } => return Ok(()),
         };
 
         self.state
@@ -406,6 +542,7 @@ impl ServiceInteractions {
     }
 
     fn remove(&self, id: InteractionId) -> Option<Arc<InteractionEntry>> {
+        self.unfurls.remove(&id);
         let it = self.interactions.remove(&id);
         if let Some(nonce) = it.as_ref().and_then(|(_, i)| i.nonce.as_ref()) {
             self.interaction_nonce_to_id.remove(nonce);
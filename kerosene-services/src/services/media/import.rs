@@ -1,5 +1,6 @@
 use std::str::FromStr;
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use async_tempfile::TempFile;
 use common::v1::types::{Mime, SessionId, UserId};
@@ -7,6 +8,7 @@ use common::v2::types::MediaId;
 use common::v2::types::media::MediaMetadata;
 use futures::StreamExt;
 use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Mutex;
 use tokio::task::JoinHandle;
 use url::Url;
 
@@ -16,9 +18,6 @@ use crate::services::media::ServiceMedia;
 use crate::services::media::util::{Import, MediaItem, MediaItemWriter};
 use tracing::instrument;
 
-// TODO: remove created media after 5 minutes, reset timer after any update
-// TODO: remove uploaded media after 5 minutes
-
 /// a piece of media being uploaded
 // TODO: make fields not pub?
 pub struct Upload {
@@ -35,7 +34,8 @@ pub struct Upload {
     pub temp_writer: BufWriter<TempFile>,
     pub current_size: u64,
 
-    pub finished_at: Instant,
+    /// the last time this upload was touched, for expiring stale uploads
+    pub updated_at: Instant,
 
     pub expire_handle: JoinHandle<()>,
 }
@@ -58,7 +58,7 @@ impl Upload {
 
         self.temp_writer.write_all(bytes).await?;
         self.current_size += len;
-        self.finished_at = Instant::now();
+        self.updated_at = Instant::now();
         Ok(())
     }
 
@@ -82,6 +82,16 @@ impl Upload {
     pub fn expects_more(&self) -> bool {
         self.offset() < self.expected_size()
     }
+
+    /// how long this upload can go without being touched before it's discarded
+    pub fn expiry(&self) -> Duration {
+        Duration::from_secs(self.s.config().media.upload_expiry)
+    }
+
+    /// when this upload will be discarded if it isn't touched
+    pub fn expires_at(&self) -> SystemTime {
+        SystemTime::now() + self.expiry().saturating_sub(self.updated_at.elapsed())
+    }
 }
 
 impl ServiceMedia {
@@ -99,6 +109,17 @@ impl ServiceMedia {
         let writer = MediaItem::new_writer(self.state.clone(), media);
         let item = writer.reader();
 
+        // insert initial media record into DB, reserving its declared size
+        let mut txn = self.state.begin().await?;
+        self.ensure_user_quota(
+            &mut txn,
+            import.user_id,
+            import.max_size.unwrap_or_default(),
+        )
+        .await?;
+        txn.media_insert((*item.media()).clone()).await?;
+        txn.commit().await?;
+
         let temp_file = TempFile::new().await.expect("failed to create temp file!");
         let temp_writer = BufWriter::new(temp_file.open_rw().await?);
 
@@ -112,19 +133,13 @@ impl ServiceMedia {
             temp_file,
             temp_writer,
             current_size: 0,
-            finished_at: Instant::now(),
+            updated_at: Instant::now(),
             expire_handle,
         };
 
-        self.uploads.insert(media_id, upload);
+        self.uploads.insert(media_id, Arc::new(Mutex::new(upload)));
         self.cache.insert(media_id, item.clone()).await;
 
-        // insert initial media record into DB
-        let media = item.media();
-        let mut txn = self.state.begin().await?;
-        txn.media_insert((*media).clone()).await?;
-        txn.commit().await?;
-
         Ok(item)
     }
 
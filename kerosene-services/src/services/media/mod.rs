@@ -1,34 +1,54 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use common::v1::types::UserId;
 use common::v1::types::federation::RemoteReq;
+use common::v1::types::{Permission, RoomId, UserId};
 use common::{
     v1::types::{
         MediaId,
-        error::{ApiError, ErrorCode},
+        error::{ApiError, ErrorCode, QuotaExceeded, QuotaScope},
     },
-    v2::types::media::MediaPatch,
+    v2::types::media::{Media, MediaPatch, MediaUsage, scanner::ScannerInfo},
 };
 use dashmap::DashMap;
-use lamprey_backend_core::ffmpeg::Ffmpeg;
+use lamprey_backend_core::{ffmpeg::Ffmpeg, types::media::MediaVariant};
+use lamprey_backend_data_postgres::data::AnyData;
 use moka::future::Cache;
-use tokio::io::AsyncWriteExt;
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{Mutex, OwnedMutexGuard},
+};
 use tracing::{debug, error};
 
 use crate::{prelude::*, services::media::util::MediaItemState};
 
 mod import;
+mod placeholder;
 mod process;
+mod strip;
 mod util;
 
 pub use import::Upload;
 pub use util::{Import, MediaItem, get_s3_url};
 
+/// how long to remember what a scanner accepts
+const SCANNER_INFO_TTL: Duration = Duration::from_secs(60 * 5);
+
 pub struct ServiceMedia {
     state: Globals,
     cache: Cache<MediaId, MediaItem>,
-    uploads: Arc<DashMap<MediaId, Upload>>,
+
+    /// unfinished uploads
+    ///
+    /// these (and their temp files) are only kept in memory, so an upload can
+    /// only be resumed on the same instance and is lost on restart
+    uploads: Arc<DashMap<MediaId, Arc<Mutex<Upload>>>>,
     ffmpeg: Ffmpeg,
+
+    /// scanner capabilities by key, so `/info` isn't fetched for every scan
+    scanner_info: Cache<String, ScannerInfo>,
 }
 
 impl ServiceMedia {
@@ -39,6 +59,7 @@ impl ServiceMedia {
             cache: Cache::new(1000), // TODO: make configurable
             uploads: Arc::new(DashMap::new()),
             ffmpeg,
+            scanner_info: Cache::builder().time_to_live(SCANNER_INFO_TTL).build(),
         }
     }
 
@@ -58,6 +79,23 @@ impl ServiceMedia {
         Ok(item)
     }
 
+    /// get the text extracted from a document
+    ///
+    /// returns None if this media isn't a document or has no text
+    pub async fn get_text(&self, media: &Media) -> Result<Option<String>> {
+        if !media.metadata.is_document() {
+            return Ok(None);
+        }
+
+        let path = self.state.config().media.paths().text(media.storage_id());
+        let url = get_s3_url(self.state.config(), &path)?;
+        match self.state.blobs().read(url.path()).await {
+            Ok(data) => Ok(Some(String::from_utf8_lossy(&data.to_vec()).into_owned())),
+            Err(err) if err.kind() == opendal::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     pub async fn get_remote(&self, remote: &RemoteReq<MediaId>) -> Result<MediaItem> {
         // PERF: cache Remote -> MediaId?
         let media = self
@@ -136,8 +174,9 @@ impl ServiceMedia {
     pub async fn delete(&self, _user_id: UserId, media_id: MediaId) -> Result<()> {
         // FIXME: check user_id
 
-        if let Some(up) = self.uploads.remove(&media_id) {
-            up.1.expire_handle.abort();
+        if let Some((_, up)) = self.uploads.remove(&media_id) {
+            up.lock().await.expire_handle.abort();
+            self.upload_discard(media_id).await?;
             return Ok(());
         }
 
@@ -153,21 +192,200 @@ impl ServiceMedia {
         }
     }
 
-    /// get an upload to update it
-    pub async fn upload_get(
+    /// get how much storage a user's media uses
+    pub async fn usage_user(&self, user_id: UserId) -> Result<MediaUsage> {
+        let mut usage = self
+            .state
+            .begin_read()
+            .await?
+            .media_usage_user(user_id)
+            .await?;
+        usage.quota = self.state.config().limits.user.max_media_storage;
+        Ok(usage)
+    }
+
+    /// get how much storage the media linked to a room uses
+    pub async fn usage_room(&self, room_id: RoomId) -> Result<MediaUsage> {
+        let mut usage = self
+            .state
+            .begin_read()
+            .await?
+            .media_usage_room(room_id)
+            .await?;
+        usage.quota = self.state.config().limits.room.max_media_storage;
+        Ok(usage)
+    }
+
+    /// return an error if a user uploading `size` more bytes would exceed their quota
+    ///
+    /// locks the user's usage, so do the write in the same transaction
+    pub async fn ensure_user_quota(
+        &self,
+        txn: &mut AnyData,
+        user_id: UserId,
+        size: u64,
+    ) -> Result<()> {
+        let Some(quota) = self.state.config().limits.user.max_media_storage else {
+            return Ok(());
+        };
+        txn.media_usage_user_lock(user_id).await?;
+        let mut usage = txn.media_usage_user(user_id).await?;
+        usage.quota = Some(quota);
+        ensure_quota(QuotaScope::User, &usage, size)
+    }
+
+    /// return an error if linking `size` more bytes to a room would exceed its quota
+    ///
+    /// locks the room's usage, so do the write in the same transaction
+    pub async fn ensure_room_quota(
         &self,
+        txn: &mut AnyData,
+        room_id: RoomId,
+        size: u64,
+    ) -> Result<()> {
+        let Some(quota) = self.state.config().limits.room.max_media_storage else {
+            return Ok(());
+        };
+        if size == 0 {
+            return Ok(());
+        }
+        txn.media_usage_room_lock(room_id).await?;
+        let mut usage = txn.media_usage_room(room_id).await?;
+        usage.quota = Some(quota);
+        ensure_quota(QuotaScope::Room, &usage, size)
+    }
+
+    /// return an error if linking this media to a room would exceed its quota
+    ///
+    /// media that's already linked somewhere doesn't count again
+    pub async fn ensure_room_quota_for(
+        &self,
+        txn: &mut AnyData,
+        room_id: RoomId,
         media_id: MediaId,
-    ) -> Option<dashmap::mapref::one::RefMut<'_, MediaId, Upload>> {
-        self.bump(media_id);
-        self.uploads.get_mut(&media_id)
+    ) -> Result<()> {
+        if self.state.config().limits.room.max_media_storage.is_none()
+            || !txn.media_link_select(media_id).await?.is_empty()
+        {
+            return Ok(());
+        }
+        let size = txn.media_select(media_id).await?.size;
+        self.ensure_room_quota(txn, room_id, size).await
+    }
+
+    /// reserve storage for the bytes written past an upload's declared size
+    async fn reserve_upload(&self, up: &Upload) -> Result<()> {
+        let reserved = up.import.max_size.unwrap_or_default();
+        let mut txn = self.state.begin().await?;
+        self.ensure_user_quota(&mut txn, up.user_id(), up.current_size - reserved)
+            .await?;
+        let mut media = (*up.writer.reader().media()).clone();
+        media.size = up.current_size;
+        txn.media_replace(media).await?;
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// sign cdn urls for a piece of private media, if the server requires signed urls
+    pub fn sign(&self, media: &mut Media) -> Result<()> {
+        let Some(key) = &self.state.config().media.url_key else {
+            return Ok(());
+        };
+
+        media.signed = Some(MediaVariant::sign_all(
+            media.id,
+            self.url_expires(),
+            key.load()?.as_bytes(),
+        ));
+        Ok(())
+    }
+
+    /// when signed urls created now should expire
+    pub fn url_expires(&self) -> u64 {
+        // round the expiry so urls stay the same (and cacheable) for a while
+        let half = (self.state.config().media.url_expiry / 2).max(1);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        (now / half + 2) * half
+    }
+
+    /// return an error if a user can't see a piece of media, otherwise return whether it's private
+    ///
+    /// media is private if it's linked to something in a private channel. it can be seen by
+    /// whoever uploaded it and anyone who can view one of those channels.
+    pub async fn ensure_visible(&self, user_id: UserId, media: &Media) -> Result<bool> {
+        let channel_ids = self
+            .state
+            .begin_read()
+            .await?
+            .media_link_channels(media.id)
+            .await?;
+
+        let srv = self.state.services();
+        let mut private = Vec::new();
+        for channel_id in channel_ids {
+            if srv.channels.is_private(channel_id).await? {
+                private.push(channel_id);
+            }
+        }
+
+        if private.is_empty() || media.user_id == Some(user_id) {
+            return Ok(!private.is_empty());
+        }
+
+        for channel_id in private {
+            let perms = srv.perms.for_channel(user_id, channel_id).await?;
+            if perms.has(Permission::ChannelView) {
+                return Ok(true);
+            }
+        }
+
+        Err(Error::ApiError(ApiError::from_code(
+            ErrorCode::UnknownMedia,
+        )))
+    }
+
+    #[inline]
+    pub fn ffmpeg(&self) -> &Ffmpeg {
+        &self.ffmpeg
+    }
+
+    /// get an upload to update it
+    ///
+    /// waits for any other writes to this upload to finish
+    pub async fn upload_get(&self, media_id: MediaId) -> Option<OwnedMutexGuard<Upload>> {
+        let up = Arc::clone(&*self.uploads.get(&media_id)?);
+        let mut up = up.lock_owned().await;
+        up.updated_at = Instant::now();
+        Some(up)
     }
 
     /// finish an upload and begin processing
     pub async fn upload_done(&self, media_id: MediaId) -> Result<MediaItem> {
-        if let Some((_, mut up)) = self.uploads.remove(&media_id) {
+        if let Some((_, up)) = self.uploads.remove(&media_id) {
+            let mut up = match Arc::try_unwrap(up) {
+                Ok(up) => up.into_inner(),
+                Err(up) => {
+                    // something is still writing to this upload
+                    self.uploads.insert(media_id, up);
+                    return Err(Error::CantOverwrite);
+                }
+            };
             up.expire_handle.abort();
             up.temp_writer.flush().await?;
 
+            // the declared size was already reserved when the upload was created, so only
+            // check whatever was written past it
+            let reserved = up.import.max_size.unwrap_or_default();
+            if up.current_size > reserved
+                && let Err(err) = self.reserve_upload(&up).await
+            {
+                self.upload_discard(media_id).await?;
+                return Err(err);
+            }
+
             let item = up.writer.reader();
             let state = self.state.clone();
             tokio::spawn(async move {
@@ -200,25 +418,66 @@ impl ServiceMedia {
         }
     }
 
-    /// reset expiration timer for an upload
-    fn bump(&self, media_id: MediaId) {
-        if let Some(mut up) = self.uploads.get_mut(&media_id) {
-            up.expire_handle.abort();
-            up.expire_handle = self.spawn_expiration_task(media_id);
-        }
+    /// delete the media for an upload that will never finish
+    async fn upload_discard(&self, media_id: MediaId) -> Result<()> {
+        self.cache.invalidate(&media_id).await;
+        let mut data = self.state.begin().await?;
+        data.media_delete(media_id).await?;
+        data.commit().await?;
+        Ok(())
     }
 
+    /// discard an upload once it goes too long without being touched
     fn spawn_expiration_task(&self, media_id: MediaId) -> tokio::task::JoinHandle<()> {
-        let uploads = Arc::clone(&self.uploads);
+        let state = self.state.clone();
+        let expiry = Duration::from_secs(state.config().media.upload_expiry);
         tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_secs(300)).await;
-            if uploads.remove(&media_id).is_some() {
+            let mut deadline = Instant::now() + expiry;
+            loop {
+                tokio::time::sleep_until(deadline.into()).await;
+
+                let srv = state.services();
+                let Some(up) = srv.media.uploads.get(&media_id).map(|up| Arc::clone(&up)) else {
+                    return;
+                };
+
+                // uploads that are currently receiving data aren't stale
+                let Ok(up) = up.try_lock() else {
+                    deadline = Instant::now() + expiry;
+                    continue;
+                };
+
+                if up.updated_at.elapsed() < expiry {
+                    deadline = up.updated_at + expiry;
+                    continue;
+                }
+
+                srv.media.uploads.remove(&media_id);
+                drop(up);
+                if let Err(err) = srv.media.upload_discard(media_id).await {
+                    error!("failed to discard expired upload {}: {}", media_id, err);
+                }
                 debug!("expired upload {}", media_id);
+                return;
             }
         })
     }
 }
 
+fn ensure_quota(scope: QuotaScope, usage: &MediaUsage, size: u64) -> Result<()> {
+    match usage.quota {
+        Some(quota) if usage.bytes.saturating_add(size) > quota => {
+            Err(Error::ApiError(ApiError::quota_exceeded(QuotaExceeded {
+                scope,
+                used: usage.bytes,
+                quota,
+                requested: size,
+            })))
+        }
+        _ => Ok(()),
+    }
+}
+
 #[cfg(any())]
 pub struct ServiceMediaOld {
     // TODO: make not pub
@@ -296,11 +555,15 @@ impl ServiceMediaOld {
             scans: vec![],
             has_thumbnail: false,
             has_gifv: false,
+            placeholder: None,
+            audio: None,
+            blob_id: None,
             links: vec![],
             room_id: None,
             channel_id: None,
             hashes: Hashes::new(),
             strip_exif: create.strip_exif,
+            strip_exif_failed: false,
             remote,
         };
         self.state.data().media_insert(media).await?;
@@ -345,11 +608,15 @@ impl ServiceMediaOld {
             scans: vec![],
             has_thumbnail: false,
             has_gifv: false,
+            placeholder: None,
+            audio: None,
+            blob_id: None,
             links: vec![],
             room_id: None,
             channel_id: None,
             hashes: Hashes::new(),
             strip_exif: create.strip_exif,
+            strip_exif_failed: false,
             remote: None,
         };
         self.state.data().media_replace(media_processing).await?;
@@ -621,57 +888,33 @@ impl ServiceMediaOld {
         self.process_upload(up, media_id, user_id, "remote_media", None)
             .await
     }
+}
 
-    /// Strip EXIF metadata from an image file.
-    ///
-    /// Downloads the image, strips EXIF data, and re-uploads it.
-    #[tracing::instrument(skip(self, media_id))]
-    pub async fn strip_exif(&self, media_id: MediaId) -> Result<()> {
-        let media = self.state.data().media_select(media_id).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // NOTE: maybe i want to strip geolocation and other sensitive metadata from video, audio, etc?
-        if !media.content_type.as_str().starts_with("image/") {
-            return Ok(());
+    fn usage(bytes: u64, quota: Option<u64>) -> MediaUsage {
+        MediaUsage {
+            bytes,
+            count: 1,
+            quota,
         }
+    }
 
-        let url = self.state.get_s3_url(&format!("media/{media_id}/file"))?;
-        let data = self.state.blobs.read(url.path()).await?;
-
-        // PERF: don't do this! this clones the buffer.
-        let data = data.to_vec();
+    #[test]
+    fn test_ensure_quota() {
+        assert!(ensure_quota(QuotaScope::User, &usage(100, None), u64::MAX).is_ok());
+        assert!(ensure_quota(QuotaScope::User, &usage(60, Some(100)), 40).is_ok());
+        assert!(ensure_quota(QuotaScope::User, &usage(100, Some(100)), 0).is_ok());
 
-        let temp_file = TempFile::new().await?;
-        {
-            let mut temp_writer = BufWriter::new(temp_file.open_rw().await?);
-            temp_writer.write_all(&data).await?;
-            temp_writer.flush().await?;
-        }
-        let path = temp_file.file_path();
-
-        let format = match media.content_type.as_str() {
-            "image/jpeg" => "mjpeg",
-            "image/png" => "png",
-            "image/webp" => "webp",
-            "image/gif" => "gif",
-            _ => "image2",
+        let Err(Error::ApiError(err)) = ensure_quota(QuotaScope::Room, &usage(60, Some(100)), 41)
+        else {
+            panic!("quota should be exceeded");
         };
-
-        // strip exif metadata here
-        // this also "bakes in" rotation because ffmpeg applies it by default
-        let output = ffmpeg::strip_metadata(path, format).await?;
-
-        // reupload stripped image
-        let mut w = self
-            .state
-            .blobs
-            .writer_with(url.path())
-            .cache_control("public, max-age=604800, immutable, stale-while-revalidate=86400")
-            .content_type(media.content_type.as_str())
-            .await?;
-        w.write(output).await?;
-        w.close().await?;
-
-        info!("stripped EXIF from media {}", media_id);
-        Ok(())
+        assert_eq!(err.code, ErrorCode::QuotaExceeded);
+        let quota = err.quota.expect("error should include the quota");
+        assert_eq!(quota.scope, QuotaScope::Room);
+        assert_eq!((quota.used, quota.quota, quota.requested), (60, 100, 41));
     }
 }
@@ -0,0 +1,205 @@
+//! tiny previews shown while thumbnails load
+
+use std::f64::consts::PI;
+
+use base64::Engine;
+use common::v1::types::misc::{Color, color::ColorSrgb};
+use common::v2::types::media::MediaPlaceholder;
+use image::DynamicImage;
+
+/// create a placeholder for an image
+pub fn placeholder(img: &DynamicImage) -> MediaPlaceholder {
+    // thumbhash doesn't benefit from anything larger than 100x100
+    let small = img.thumbnail(100, 100).to_rgba8();
+    let (w, h) = small.dimensions();
+    let rgba = small.as_raw();
+    let hash = thumbhash(w as usize, h as usize, rgba);
+    let [r, g, b] = dominant_color(rgba);
+    MediaPlaceholder {
+        thumbhash: base64::engine::general_purpose::STANDARD.encode(hash),
+        color: Color::Srgb(ColorSrgb {
+            r: r as f32 / 255.0,
+            g: g as f32 / 255.0,
+            b: b as f32 / 255.0,
+            alpha: None,
+        }),
+    }
+}
+
+/// find the most common color, ignoring transparent pixels
+///
+/// colors are bucketed to 4 bits per channel, then averaged within the largest bucket
+fn dominant_color(rgba: &[u8]) -> [u8; 3] {
+    let mut buckets = vec![(0u32, [0u64; 3]); 4096];
+    for px in rgba.chunks_exact(4) {
+        if px[3] < 128 {
+            continue;
+        }
+        let idx =
+            ((px[0] as usize >> 4) << 8) | ((px[1] as usize >> 4) << 4) | (px[2] as usize >> 4);
+        let bucket = &mut buckets[idx];
+        bucket.0 += 1;
+        for (sum, c) in bucket.1.iter_mut().zip(px) {
+            *sum += *c as u64;
+        }
+    }
+
+    let (count, sum) = buckets
+        .into_iter()
+        .max_by_key(|b| b.0)
+        .expect("there are always buckets");
+    if count == 0 {
+        return [0, 0, 0];
+    }
+    sum.map(|c| (c / count as u64) as u8)
+}
+
+/// encode an image as a [thumbhash](https://evanw.github.io/thumbhash/)
+///
+/// the image must be at most 100x100
+fn thumbhash(w: usize, h: usize, rgba: &[u8]) -> Vec<u8> {
+    debug_assert!(w <= 100 && h <= 100);
+    let n = w * h;
+
+    // average color, weighted by alpha
+    let (mut avg_r, mut avg_g, mut avg_b, mut avg_a) = (0.0, 0.0, 0.0, 0.0);
+    for px in rgba.chunks_exact(4).take(n) {
+        let alpha = px[3] as f64 / 255.0;
+        avg_r += alpha / 255.0 * px[0] as f64;
+        avg_g += alpha / 255.0 * px[1] as f64;
+        avg_b += alpha / 255.0 * px[2] as f64;
+        avg_a += alpha;
+    }
+    if avg_a > 0.0 {
+        avg_r /= avg_a;
+        avg_g /= avg_a;
+        avg_b /= avg_a;
+    }
+
+    let has_alpha = avg_a < n as f64;
+    // use fewer luminance bits if there's alpha
+    let l_limit = if has_alpha { 5.0 } else { 7.0 };
+    let max_dim = w.max(h) as f64;
+    let lx = ((l_limit * w as f64 / max_dim).round() as usize).max(1);
+    let ly = ((l_limit * h as f64 / max_dim).round() as usize).max(1);
+
+    // convert to luminance, yellow-blue, red-green, and alpha, composited over the average color
+    let mut l = Vec::with_capacity(n);
+    let mut p = Vec::with_capacity(n);
+    let mut q = Vec::with_capacity(n);
+    let mut a = Vec::with_capacity(n);
+    for px in rgba.chunks_exact(4).take(n) {
+        let alpha = px[3] as f64 / 255.0;
+        let r = avg_r * (1.0 - alpha) + alpha / 255.0 * px[0] as f64;
+        let g = avg_g * (1.0 - alpha) + alpha / 255.0 * px[1] as f64;
+        let b = avg_b * (1.0 - alpha) + alpha / 255.0 * px[2] as f64;
+        l.push((r + g + b) / 3.0);
+        p.push((r + g) / 2.0 - b);
+        q.push(r - g);
+        a.push(alpha);
+    }
+
+    // dct into a constant term and normalized varying terms
+    let encode_channel = |channel: &[f64], nx: usize, ny: usize| {
+        let mut dc = 0.0;
+        let mut ac = vec![];
+        let mut scale: f64 = 0.0;
+        let mut fx = vec![0.0; w];
+        for cy in 0..ny {
+            let mut cx = 0;
+            while cx * ny < nx * (ny - cy) {
+                for (x, f) in fx.iter_mut().enumerate() {
+                    *f = (PI / w as f64 * cx as f64 * (x as f64 + 0.5)).cos();
+                }
+                let mut f = 0.0;
+                for y in 0..h {
+                    let fy = (PI / h as f64 * cy as f64 * (y as f64 + 0.5)).cos();
+                    for x in 0..w {
+                        f += channel[x + y * w] * fx[x] * fy;
+                    }
+                }
+                f /= n as f64;
+                if cx > 0 || cy > 0 {
+                    ac.push(f);
+                    scale = scale.max(f.abs());
+                } else {
+                    dc = f;
+                }
+                cx += 1;
+            }
+        }
+        if scale > 0.0 {
+            for f in &mut ac {
+                *f = 0.5 + 0.5 / scale * *f;
+            }
+        }
+        (dc, ac, scale)
+    };
+
+    let (l_dc, l_ac, l_scale) = encode_channel(&l, lx.max(3), ly.max(3));
+    let (p_dc, p_ac, p_scale) = encode_channel(&p, 3, 3);
+    let (q_dc, q_ac, q_scale) = encode_channel(&q, 3, 3);
+    let alpha = has_alpha.then(|| encode_channel(&a, 5, 5));
+
+    let is_landscape = w > h;
+    let header24 = (63.0 * l_dc).round() as u32
+        | ((31.5 + 31.5 * p_dc).round() as u32) << 6
+        | ((31.5 + 31.5 * q_dc).round() as u32) << 12
+        | ((31.0 * l_scale).round() as u32) << 18
+        | (has_alpha as u32) << 23;
+    let header16 = (if is_landscape { ly } else { lx }) as u32
+        | ((63.0 * p_scale).round() as u32) << 3
+        | ((63.0 * q_scale).round() as u32) << 9
+        | (is_landscape as u32) << 15;
+
+    let mut hash = vec![
+        (header24 & 255) as u8,
+        ((header24 >> 8) & 255) as u8,
+        (header24 >> 16) as u8,
+        (header16 & 255) as u8,
+        (header16 >> 8) as u8,
+    ];
+    if let Some((a_dc, _, a_scale)) = &alpha {
+        hash.push((15.0 * a_dc).round() as u8 | ((15.0 * a_scale).round() as u8) << 4);
+    }
+
+    let ac_start = hash.len();
+    let mut channels = vec![l_ac, p_ac, q_ac];
+    if let Some((_, a_ac, _)) = alpha {
+        channels.push(a_ac);
+    }
+    for (i, f) in channels.iter().flatten().enumerate() {
+        let idx = ac_start + (i >> 1);
+        if idx >= hash.len() {
+            hash.push(0);
+        }
+        hash[idx] |= ((15.0 * f).round() as u8) << ((i & 1) << 2);
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solid_color() {
+        let rgba: Vec<u8> = [200, 100, 50, 255].repeat(16 * 8);
+        assert_eq!(dominant_color(&rgba), [200, 100, 50]);
+
+        // 5 header bytes, then 4 bits per ac coefficient
+        let hash = thumbhash(16, 8, &rgba);
+        assert_eq!(hash[4] >> 7, 1, "landscape flag should be set");
+        assert!(hash.len() > 5);
+    }
+
+    #[test]
+    fn transparent_has_alpha() {
+        let mut rgba: Vec<u8> = [0, 0, 0, 0].repeat(10 * 10);
+        rgba[..4].copy_from_slice(&[255, 255, 255, 255]);
+        assert_eq!(dominant_color(&rgba), [255, 255, 255]);
+        let hash = thumbhash(10, 10, &rgba);
+        assert_eq!(hash[2] >> 7, 1, "alpha flag should be set");
+    }
+}
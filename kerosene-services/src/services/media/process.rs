@@ -1,41 +1,53 @@
-use std::{collections::HashMap, io::Cursor, sync::Arc, time::Duration};
+use std::{collections::HashMap, io::Cursor, path::PathBuf, sync::Arc, time::Duration};
 
-use async_tempfile::TempFile;
+use async_tempfile::{TempDir, TempFile};
 use bytes::BytesMut;
 use common::{
     v1::types::{
-        MessageSync, Mime,
+        MediaId, MessageSync, Mime,
         misc::hashes::{HashData, HashType, Hashes},
     },
     v2::types::media::{
-        Media, MediaMetadata, MediaScan, MediaStatus,
-        scanner::{MediaScanResponse, ScanRequest},
+        Media, MediaAudio, MediaMetadata, MediaPlaceholder, MediaScan, MediaStatus,
+        scanner::{MediaScanResponse, ScanRequest, ScanVerdict, ScannerInfo},
     },
 };
 use futures::stream::FuturesUnordered;
 use image::ImageReader;
 use lamprey_backend_core::{
-    ffmpeg::metadata::{MediaMetadata as FfprobeMetadata, MediaType},
-    types::media::MediaPaths,
+    config::ConfigMediaScanner,
+    ffmpeg::{
+        document,
+        metadata::{MediaMetadata as FfprobeMetadata, MediaType},
+    },
+    types::media::{MediaPaths, ThumbFormat, can_animate_thumb},
 };
 use mediatype::MediaTypeBuf;
+use moka::future::Cache;
 use sha2::{Digest, Sha512_256};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio_stream::StreamExt;
-use tracing::{Instrument, Level, debug, span, trace};
+use tracing::{Instrument, Level, debug, span, trace, warn};
 
 use crate::{
     prelude::*,
     services::media::{
         ServiceMedia,
         import::Upload,
-        util::{Import, MediaItemState, get_s3_url},
+        placeholder, strip,
+        util::{Import, MediaError, MediaItemState, get_s3_url},
     },
 };
 
 /// cache control header for immutable
 const IMMUTABLE: &'static str = "public, max-age=604800, immutable, stale-while-revalidate=86400";
 
+/// how many thumbnails to generate at once
+const THUMBNAIL_CONCURRENCY: usize = 4;
+
+/// how many peaks to include in audio waveforms
+const WAVEFORM_BUCKETS: usize = 256;
+
 struct MediaPipeline {
     s: Globals,
     import: Import,
@@ -48,11 +60,29 @@ struct MediaPipeline {
     mime: Option<MediaTypeBuf>,
     hashes: Option<Hashes>,
     poster: Option<Option<Poster>>,
+    blob_id: Option<Option<MediaId>>,
+    pdf: Option<Option<PathBuf>>,
+
+    /// holds the pdf converted from an office document
+    pdf_dir: Option<TempDir>,
+}
+
+/// the result of stripping metadata from a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StripOutcome {
+    /// metadata stripping wasn't requested or isn't relevant for this file
+    Unchanged,
+
+    /// metadata was stripped, and the file needs to be (re)uploaded
+    Stripped,
+
+    /// this file may still contain metadata
+    Failed,
 }
 
 #[derive(Debug, Clone)]
 struct Poster {
-    _bytes: Bytes,
+    bytes: Bytes,
 }
 
 // // TODO: split out context/cache into separate struct?
@@ -67,7 +97,7 @@ struct Poster {
 
 impl MediaPipeline {
     fn from_upload(s: Globals, import: Import, file: TempFile) -> Self {
-        let paths = MediaPaths::new("media/");
+        let paths = s.config().media.paths();
         Self {
             s,
             import,
@@ -78,6 +108,9 @@ impl MediaPipeline {
             mime: None,
             hashes: None,
             poster: None,
+            blob_id: None,
+            pdf: None,
+            pdf_dir: None,
         }
     }
 
@@ -142,11 +175,60 @@ impl MediaPipeline {
         Ok(mt)
     }
 
+    /// get a pdf for this document, converting office documents if needed
+    ///
+    /// returns None if this isn't a document
+    async fn document_pdf(&mut self) -> Result<Option<PathBuf>> {
+        if let Some(pdf) = &self.pdf {
+            return Ok(pdf.clone());
+        }
+
+        let mime = self.sniff_mime().await?;
+        let essence = mime.essence().to_string();
+        let pdf = if essence == "application/pdf" {
+            Some(self.file.file_path().to_owned())
+        } else if document::is_office_document(&essence) {
+            debug!("convert document to pdf");
+            let dir = TempDir::new().await?;
+            let ff = &self.s.services().media.ffmpeg;
+            match ff
+                .document_to_pdf(self.file.file_path(), dir.dir_path())
+                .await
+            {
+                Ok(path) => {
+                    self.pdf_dir = Some(dir);
+                    Some(path)
+                }
+                Err(err) => {
+                    warn!(?err, "failed to convert document to pdf");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        self.pdf = Some(pdf.clone());
+        Ok(pdf)
+    }
+
     async fn get_metadata(&mut self) -> Result<MediaMetadata> {
         if let Some(meta) = &self.media_metadata {
             return Ok(meta.clone());
         };
 
+        if let Some(pdf) = self.document_pdf().await? {
+            let ff = &self.s.services().media.ffmpeg;
+            match ff.pdf_page_count(&pdf).await {
+                Ok(pages) => {
+                    let meta = MediaMetadata::Document { pages };
+                    self.media_metadata = Some(meta.clone());
+                    return Ok(meta);
+                }
+                Err(err) => warn!(?err, "failed to count document pages"),
+            }
+        }
+
         let mime = self.sniff_mime().await?;
         let ffmeta = self.get_ffprobe_metadata().await?;
         let meta = match mime.ty().as_str() {
@@ -167,7 +249,13 @@ impl MediaPipeline {
                     }),
                 }
             }
-            "audio" | "video" => MediaMetadata::Video {
+            "audio" => MediaMetadata::Audio {
+                duration: ffmeta
+                    .as_ref()
+                    .and_then(|m| m.duration().map(|d| d as u64))
+                    .unwrap_or(0),
+            },
+            "video" => MediaMetadata::Video {
                 height: ffmeta.as_ref().and_then(|m| m.height()).unwrap_or(0),
                 width: ffmeta.as_ref().and_then(|m| m.width()).unwrap_or(0),
                 duration: ffmeta
@@ -222,6 +310,42 @@ impl MediaPipeline {
         Ok(hashes)
     }
 
+    /// find an existing blob with the same content, if there is one
+    ///
+    /// returns the id of the media that owns the blob. the blob is only referenced
+    /// once processing succeeds.
+    async fn dedupe(&mut self) -> Result<Option<MediaId>> {
+        if let Some(blob_id) = self.blob_id {
+            return Ok(blob_id);
+        }
+
+        let hashes = self.calculate_hashes().await?;
+        let blob_id = match hashes.0.get(&HashType::Sha512_256) {
+            Some(hash) => {
+                let mut data = self.s.begin_read().await?;
+                let owner_id = data.media_blob_select(&hash.0.0).await?;
+                owner_id.filter(|id| *id != self.import.media_id)
+            }
+            None => None,
+        };
+
+        if let Some(owner_id) = blob_id {
+            debug!(%owner_id, "deduplicated media blob");
+        }
+
+        self.blob_id = Some(blob_id);
+        Ok(blob_id)
+    }
+
+    /// stop using a deduplicated blob and upload everything for this media instead
+    async fn upload_own_blob(&mut self) -> Result<()> {
+        self.blob_id = Some(None);
+        self.poster = None;
+        self.process_poster().await?;
+        self.process_text().await?;
+        self.upload().await
+    }
+
     /// extract and upload the poster
     ///
     /// returns `true` if there was a poster and `false` otherwise
@@ -237,6 +361,20 @@ impl MediaPipeline {
             return Ok(None);
         }
 
+        // documents use their first page
+        if let Some(pdf) = self.document_pdf().await? {
+            debug!("render first page of document");
+            let ff = &self.s.services().media.ffmpeg;
+            return match ff.pdf_render_first_page(&pdf).await {
+                Ok(bytes) => self.upload_poster(Bytes::from(bytes)).await,
+                Err(err) => {
+                    warn!(?err, "failed to render document");
+                    self.poster = Some(None);
+                    Ok(None)
+                }
+            };
+        }
+
         let meta = match self.get_ffprobe_metadata().await {
             Ok(Some(meta)) => meta,
             Ok(None) => return Ok(None),
@@ -266,7 +404,11 @@ impl MediaPipeline {
             return Ok(None);
         };
 
-        let bytes = Bytes::from(bytes);
+        self.upload_poster(Bytes::from(bytes)).await
+    }
+
+    /// upload an extracted or rendered poster
+    async fn upload_poster(&mut self, bytes: Bytes) -> Result<Option<Poster>> {
         let url = get_s3_url(self.s.config(), &self.paths.poster(self.import.media_id))?;
 
         let span_probe = span!(Level::DEBUG, "probe thumbnail image mime");
@@ -283,8 +425,14 @@ impl MediaPipeline {
         .instrument(span_probe)
         .await?;
 
+        // the poster already exists if this is a duplicate
+        let deduped = self.dedupe().await?.is_some();
         let span_upload = span!(Level::DEBUG, "upload thumb");
         async {
+            if deduped {
+                return Result::Ok(());
+            }
+
             let mut w = self
                 .s
                 .blobs()
@@ -299,7 +447,7 @@ impl MediaPipeline {
         .instrument(span_upload)
         .await?;
 
-        let poster = Poster { _bytes: bytes };
+        let poster = Poster { bytes };
         self.poster = Some(Some(poster.clone()));
         Ok(Some(poster))
     }
@@ -316,60 +464,219 @@ impl MediaPipeline {
         Ok(has_thumbnail)
     }
 
-    /// (re)generate thumbnails for this
+    /// generate a placeholder from the poster or the image itself
+    async fn generate_placeholder(&mut self) -> Result<Option<MediaPlaceholder>> {
+        let poster = self.process_poster().await?;
+        let mime = self.sniff_mime().await?;
+        let bytes = match poster {
+            Some(poster) => poster.bytes,
+            None if mime.ty().as_str() == "image" => {
+                Bytes::from(tokio::fs::read(self.file.file_path()).await?)
+            }
+            None => return Ok(None),
+        };
+
+        // decoding and hashing is cpu bound
+        let placeholder = tokio::task::spawn_blocking(move || {
+            let img = image::load_from_memory(&bytes);
+            match img {
+                Ok(img) => Some(placeholder::placeholder(&img)),
+                Err(err) => {
+                    debug!(?err, "failed to decode image for placeholder");
+                    None
+                }
+            }
+        })
+        .await
+        .ok()
+        .flatten();
+
+        Ok(placeholder)
+    }
+
+    /// calculate the waveform, loudness, and channel layout of audio
+    ///
+    /// failures are logged, since a missing waveform shouldn't fail the upload
+    async fn analyze_audio(&mut self) -> Result<Option<MediaAudio>> {
+        let mime = self.sniff_mime().await?;
+        if mime.ty().as_str() != "audio" {
+            return Ok(None);
+        }
+
+        let ffmeta = self.get_ffprobe_metadata().await?;
+        let Some(stream) = ffmeta.as_ref().and_then(|m| m.get_main_audio()) else {
+            return Ok(None);
+        };
+
+        let ff = &self.s.services().media.ffmpeg;
+        let analysis = match ff
+            .analyze_audio(self.file.file_path(), WAVEFORM_BUCKETS)
+            .await
+        {
+            Ok(analysis) => analysis,
+            Err(err) => {
+                warn!(?err, "failed to analyze audio");
+                return Ok(None);
+            }
+        };
+
+        Ok(Some(MediaAudio {
+            waveform: analysis.waveform,
+            loudness: analysis.loudness,
+            channels: stream.channels.unwrap_or(0),
+            channel_layout: stream.channel_layout.clone(),
+        }))
+    }
+
+    /// (re)generate thumbnails for every configured size and format
+    ///
+    /// this runs in the background after the media is ready. failures are
+    /// logged and skipped, since crate-media generates missing thumbnails when
+    /// they're requested anyways
     async fn generate_thumbnails(&mut self) -> Result<()> {
-        // NOTE: thumbnails are generally generated in crate-media, not here
+        if self.dedupe().await?.is_some() {
+            return Ok(());
+        }
+
+        let poster = self.process_poster().await?;
+        let mime = self.sniff_mime().await?;
+        let metadata = self.get_metadata().await?;
+        let media_id = self.import.media_id;
+
+        // static thumbnails are generated from the poster if it exists
+        let poster_file = match poster {
+            Some(poster) => {
+                let f = TempFile::new().await?;
+                let mut w = f.open_rw().await?;
+                w.write_all(&poster.bytes).await?;
+                w.flush().await?;
+                Some(f)
+            }
+            None if mime.ty().as_str() == "image" => None,
+            None => return Ok(()),
+        };
+        let static_source = poster_file
+            .as_ref()
+            .map(|f| f.file_path())
+            .unwrap_or(self.file.file_path());
+
+        let animated = can_animate_thumb(&mime.essence().to_string(), &metadata);
+        let mut jobs = vec![];
+        for &size in &self.s.config().media.thumb_sizes {
+            for format in ThumbFormat::ALL {
+                jobs.push((size, format, false));
+            }
+
+            if animated {
+                for format in ThumbFormat::ANIMATED {
+                    jobs.push((size, format, true));
+                }
+            }
+        }
+
+        let this = &*self;
+        let ff = &this.s.services().media.ffmpeg;
+        let futs = jobs.into_iter().map(|(size, format, animate)| {
+            let source = if animate {
+                this.file.file_path()
+            } else {
+                static_source
+            };
+            let path = if animate {
+                this.paths.thumb(media_id, size, format.ext())
+            } else {
+                this.paths.thumb_static(media_id, size, format.ext())
+            };
+
+            async move {
+                let res = async {
+                    let out = TempFile::new().await?;
+                    ff.generate_thumbnail(source, out.file_path(), size, format, animate)
+                        .await?;
+                    let mut bytes = vec![];
+                    out.open_ro().await?.read_to_end(&mut bytes).await?;
+
+                    let url = get_s3_url(this.s.config(), &path)?;
+                    let mut w = this
+                        .s
+                        .blobs()
+                        .writer_with(url.path())
+                        .cache_control(IMMUTABLE)
+                        .content_type(format.mime())
+                        .await?;
+                    w.write(bytes).await?;
+                    w.close().await?;
+                    Result::Ok(())
+                }
+                .await;
+
+                match res {
+                    Ok(()) => debug!(?size, ?format, ?animate, "generated thumbnail"),
+                    Err(err) => warn!(
+                        ?size,
+                        ?format,
+                        ?animate,
+                        ?err,
+                        "failed to generate thumbnail"
+                    ),
+                }
+            }
+        });
+
+        // don't spawn too many ffmpeg processes at once
+        let mut results = futures::StreamExt::buffer_unordered(
+            futures::stream::iter(futs),
+            THUMBNAIL_CONCURRENCY,
+        );
+        while results.next().await.is_some() {}
+
         Ok(())
     }
 
-    // async fn generate_thumbnails(&mut self) -> Result<()> {
-    //     let poster = self.process_poster().await?;
-    //     let mime = self.get_mime().await?;
-
-    //     let bytes = match poster {
-    //         Some(p) => todo!("generate from poster"),
-    //         None if mime.ty().as_str() == "image" => todo!("generate from file itself"),
-    //         None => return Ok(()),
-    //     };
-
-    //     // whether this media can have an animated thumbnail
-    //     let animated = match mime.ty().as_str() {
-    //         "video" => true,
-    //         "image" => todo!("check if gif, apng, webp is animated"),
-    //         _ => false,
-    //     };
-
-    //     let mut fut =
-    //         FuturesUnordered::<Pin<Box<dyn Future<Output = Result<(u32, bool)>> + Send>>>::new();
-    //     for &size in &self.s.config.media.thumb_sizes {
-    //         fut.push(Box::pin(async move {
-    //             // TODO: generate thumbnail
-    //             Result::Ok((size, false))
-    //         }));
-
-    //         if animated {
-    //             fut.push(Box::pin(async move {
-    //                 // TODO: generate animated thumbnail
-    //                 Result::Ok((size, true))
-    //             }));
-    //         }
-    //     }
-
-    //     while let Some(_) = fut.next().await {}
-
-    //     // TODO: tracing
-    //     // while let Some(res) = fut.next().await {
-    //     //     match res {
-    //     //         Ok((size, animated)) => debug!(?size, ?animated, "generated thumbnail"),
-    //     //         Err(err) => error!(?size, ?animated, "failed to generate thumbnail"),
-    //     //     }
-    //     // }
-
-    //     Ok(())
-    // }
+    /// extract and upload the text in a document so it can be searched
+    ///
+    /// failures are logged, since missing text shouldn't fail the upload
+    async fn process_text(&mut self) -> Result<()> {
+        // the text already exists if this is a duplicate
+        if self.dedupe().await?.is_some() {
+            return Ok(());
+        }
+
+        let Some(pdf) = self.document_pdf().await? else {
+            return Ok(());
+        };
+
+        let ff = &self.s.services().media.ffmpeg;
+        let text = match ff.pdf_extract_text(&pdf).await {
+            Ok(text) if !text.trim().is_empty() => text,
+            Ok(_) => return Ok(()),
+            Err(err) => {
+                warn!(?err, "failed to extract text from document");
+                return Ok(());
+            }
+        };
+
+        let url = get_s3_url(self.s.config(), &self.paths.text(self.import.media_id))?;
+        let mut w = self
+            .s
+            .blobs()
+            .writer_with(url.path())
+            .cache_control(IMMUTABLE)
+            .content_type("text/plain; charset=utf-8")
+            .await?;
+        w.write(text).await?;
+        w.close().await?;
+        Ok(())
+    }
 
     /// (re)upload media to s3
+    ///
+    /// the uploaded file is registered as a blob other media can share
     async fn upload(&mut self) -> Result<()> {
+        if self.dedupe().await?.is_some() {
+            return Ok(());
+        }
+
         let mut file = self.file.open_ro().await?;
         let mime = self.sniff_mime().await?;
 
@@ -392,6 +699,7 @@ impl MediaPipeline {
             w.write(buf.split_to(n).freeze()).await?;
         }
         w.close().await?;
+
         Ok(())
     }
 
@@ -408,11 +716,16 @@ impl MediaPipeline {
             Some(p) => p.to_string(),
             None => return Ok(vec![]),
         };
+        let content_type = self
+            .mime
+            .as_ref()
+            .map(|m| m.essence().to_string())
+            .unwrap_or_else(|| "application/octet-stream".to_owned());
+        let size = self.file.metadata().await?.len();
 
         // TODO: use ServiceHttp
         // note that ServiceHttp's client won't work here since it requires https
         let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(15))
             .connect_timeout(Duration::from_secs(5))
             .redirect(reqwest::redirect::Policy::limited(10))
             .user_agent(
@@ -424,26 +737,35 @@ impl MediaPipeline {
             .build()
             .expect("failed to build http client");
 
+        let scanner_info = self.s.services().media.scanner_info.clone();
         let mut futs = FuturesUnordered::new();
 
         for scanner in scanners {
-            let scan_url = scanner.scan_url.clone();
-            let key = scanner.key.clone();
-            let version = scanner.version;
-            let path = path.clone();
+            let req = ScanRequest {
+                path: path.clone(),
+                content_type: Some(content_type.clone()),
+                size: Some(size),
+            };
             let client = client.clone();
+            let scanner_info = scanner_info.clone();
 
             futs.push(async move {
-                let req = ScanRequest { path };
-                let res = client.post(scan_url).json(&req).send().await.ok()?;
-
-                let scan_res: MediaScanResponse = res.json().await.ok()?;
-
-                Some(MediaScan {
-                    key,
-                    result: scan_res.score as f32,
-                    version,
-                })
+                match scan_with(&client, &scanner_info, scanner, req).await {
+                    Ok(Some(res)) => Some(MediaScan {
+                        key: scanner.key.clone(),
+                        result: res.score as f32,
+                        version: scanner.version,
+                        verdict: res.verdict(),
+                    }),
+                    Ok(None) => {
+                        debug!(scanner = scanner.key, "scanner doesn't accept this media");
+                        None
+                    }
+                    Err(err) => {
+                        warn!(scanner = scanner.key, "failed to scan media: {err}");
+                        None
+                    }
+                }
             });
         }
 
@@ -458,43 +780,140 @@ impl MediaPipeline {
         Ok(scans)
     }
 
-    /// strip exif metadata from an image file
-    ///
-    /// returns true if the file is modified and needs to be reuploaded
-    async fn strip_exif(&mut self) -> Result<bool> {
+    /// strip sensitive metadata (location, device, etc) from this file
+    async fn strip_exif(&mut self) -> Result<StripOutcome> {
         if !self.import.strip_exif {
-            return Ok(false);
+            return Ok(StripOutcome::Unchanged);
         }
 
         let mime = self.sniff_mime().await?;
-        if mime.ty().as_str() != "image" {
-            return Ok(false);
+        let path = self.file.file_path().to_owned();
+        let ff = &self.s.services().media.ffmpeg;
+
+        // containers are remuxed by ffmpeg, which never re-encodes
+        let remux_format = match mime.essence().to_string().as_str() {
+            "video/mp4" | "audio/mp4" | "audio/m4a" | "audio/x-m4a" => Some("mp4"),
+            "video/quicktime" => Some("mov"),
+            "video/x-matroska" | "audio/x-matroska" => Some("matroska"),
+            "video/webm" | "audio/webm" => Some("webm"),
+            "audio/mpeg" => Some("mp3"),
+            "audio/ogg" => Some("ogg"),
+            "audio/flac" | "audio/x-flac" => Some("flac"),
+            _ => None,
+        };
+
+        if let Some(format) = remux_format {
+            let out = TempFile::new().await?;
+            if let Err(err) = ff
+                .remux_without_metadata(&path, out.file_path(), format)
+                .await
+            {
+                debug!(?err, "failed to remux without metadata");
+                return Ok(StripOutcome::Failed);
+            }
+            tokio::fs::copy(out.file_path(), &path).await?;
+            return Ok(StripOutcome::Stripped);
         }
 
-        let format = match mime.essence().to_string().as_str() {
-            "image/jpeg" => "mjpeg",
-            "image/png" => "png",
-            "image/webp" => "webp",
-            "image/gif" => "gif",
-            // TODO: return a warning/error/something if we can't strip exif for this image
-            // TODO: support avif
-            _ => return Ok(false),
+        let data = tokio::fs::read(&path).await?;
+        let stripped = match mime.essence().to_string().as_str() {
+            "image/jpeg" => strip::strip_jpeg(&data),
+            "image/png" | "image/apng" => strip::strip_png(&data),
+            "image/webp" => strip::strip_webp(&data),
+            "image/avif" | "image/heic" | "image/heif" => {
+                let mut data = data;
+                strip::scrub_heif(&mut data).map(|_| data)
+            }
+            // TODO: strip gif comments without re-encoding
+            "image/gif" => ff.strip_metadata(&path, "gif").await.ok(),
+            _ if matches!(mime.ty().as_str(), "image" | "video" | "audio") => None,
+            // other files are left as is
+            _ => return Ok(StripOutcome::Unchanged),
         };
 
-        let path = self.file.file_path();
-        let ff = &self.s.services().media.ffmpeg;
-        let output = ff.strip_metadata(path, format).await?;
+        let Some(stripped) = stripped else {
+            debug!("cannot strip metadata from this file");
+            return Ok(StripOutcome::Failed);
+        };
 
         // replace the temp file content with stripped bytes
         let mut f = self.file.open_rw().await?;
         f.set_len(0).await?;
-        f.write_all(&output).await?;
+        f.write_all(&stripped).await?;
         f.flush().await?;
 
-        Ok(true)
+        Ok(StripOutcome::Stripped)
     }
 }
 
+/// scan a file with a single scanner
+///
+/// returns None if the scanner doesn't accept this file
+async fn scan_with(
+    client: &reqwest::Client,
+    scanner_info: &Cache<String, ScannerInfo>,
+    scanner: &ConfigMediaScanner,
+    req: ScanRequest,
+) -> std::result::Result<Option<MediaScanResponse>, reqwest::Error> {
+    let timeout = Duration::from_secs(scanner.timeout);
+
+    let info = match &scanner.info_url {
+        Some(url) => match scanner_info.get(&scanner.key).await {
+            Some(info) => Some(info),
+            None => {
+                let info: ScannerInfo = client
+                    .get(url.clone())
+                    .timeout(timeout)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                scanner_info.insert(scanner.key.clone(), info.clone()).await;
+                Some(info)
+            }
+        },
+        None => None,
+    };
+
+    let content_type = req.content_type.as_deref().unwrap_or_default();
+    if let Some(info) = &info
+        && !info.accepts(content_type, req.size.unwrap_or_default())
+    {
+        return Ok(None);
+    }
+
+    let res = match &scanner.stream_url {
+        // fall back to the shared filesystem if the scanner can't stream
+        Some(url) if info.as_ref().is_none_or(|i| i.streaming) => {
+            let file = match tokio::fs::File::open(&req.path).await {
+                Ok(file) => file,
+                Err(err) => {
+                    warn!("failed to open file for streaming: {err}");
+                    return Ok(None);
+                }
+            };
+            client
+                .post(url.clone())
+                .timeout(timeout)
+                .header(reqwest::header::CONTENT_TYPE, content_type)
+                .body(file)
+                .send()
+                .await?
+        }
+        _ => {
+            client
+                .post(scanner.scan_url.clone())
+                .timeout(timeout)
+                .json(&req)
+                .send()
+                .await?
+        }
+    };
+
+    Ok(Some(res.error_for_status()?.json().await?))
+}
+
 impl ServiceMedia {
     /// Run the media processing pipeine for an `Upload`
     #[tracing::instrument(skip(self, upload), fields(media_id = %upload.media_id()))]
@@ -507,17 +926,39 @@ impl ServiceMedia {
             import: pipe.import.clone(),
         });
 
-        let _transformed = pipe.strip_exif().await?;
+        let stripped = pipe.strip_exif().await?;
 
         let _ffprobe_metadata = pipe.get_ffprobe_metadata().await?;
         let mime = pipe.sniff_mime().await?;
         let hashes = pipe.calculate_hashes().await?;
+        let blob_id = pipe.dedupe().await?;
         let scans = pipe.scan_media().await?;
+
+        // reject malicious media before it's uploaded anywhere
+        if scans.iter().any(|s| s.verdict == ScanVerdict::Malicious) {
+            warn!(?scans, "rejecting malicious media");
+            let mut media: Media = (*writer.reader().media()).clone();
+            media.status = MediaStatus::Errored;
+            media.scans = scans;
+
+            let mut txn = self.state.begin().await?;
+            txn.media_replace(media.clone()).await?;
+            txn.commit().await?;
+
+            writer.set_media(Arc::new(media));
+            writer.set_state(MediaItemState::Errored {
+                error: MediaError::Malicious,
+            });
+            return Err(Error::BadStatic("media was flagged as malicious"));
+        }
+
         let metadata = pipe.get_metadata().await?;
         let _poster = pipe.process_poster().await?;
         let has_thumbnail = pipe.has_thumbnail().await?;
+        let placeholder = pipe.generate_placeholder().await?;
+        let audio = pipe.analyze_audio().await?;
 
-        pipe.generate_thumbnails().await?;
+        pipe.process_text().await?;
         pipe.upload().await?;
 
         let mut media: Media = (*writer.reader().media()).clone();
@@ -527,15 +968,44 @@ impl ServiceMedia {
         media.hashes = hashes;
         media.scans = scans;
         media.has_thumbnail = has_thumbnail;
+        media.placeholder = placeholder;
+        media.audio = audio;
+        media.blob_id = blob_id;
+        media.strip_exif_failed = stripped == StripOutcome::Failed;
         media.size = pipe.file.metadata().await?.len();
 
         let mut txn = self.state.begin().await?;
+        if let Some(hash) = media.hashes.0.get(&HashType::Sha512_256) {
+            if media.blob_id.is_some() && txn.media_blob_ref(media.id, &hash.0.0).await?.is_none() {
+                // the blob was released after dedupe, so this media needs its own copy
+                debug!("deduplicated media blob was deleted, uploading a copy");
+                txn.rollback().await?;
+                pipe.upload_own_blob().await?;
+                media.blob_id = None;
+                txn = self.state.begin().await?;
+            }
+
+            if media.blob_id.is_none() {
+                txn.media_blob_insert(media.id, &hash.0.0, media.size)
+                    .await?;
+            }
+        }
         txn.media_replace(media.clone()).await?;
         txn.commit().await?;
 
         writer.set_media(Arc::new(media.clone()));
         writer.set_ready();
 
+        // crate-media generates thumbnails on request, so the media doesn't need to wait for these
+        tokio::spawn(
+            async move {
+                if let Err(err) = pipe.generate_thumbnails().await {
+                    warn!(?err, "failed to generate thumbnails");
+                }
+            }
+            .in_current_span(),
+        );
+
         if let Some(session_id) = upload.session_id {
             self.state
                 .messaging()
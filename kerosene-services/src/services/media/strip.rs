@@ -0,0 +1,592 @@
+//! strip sensitive metadata from files without re-encoding them
+//!
+//! all functions return `None` if the file couldn't be parsed
+
+/// strip exif, xmp, iptc, and comments from a jpeg
+///
+/// the exif orientation is kept so photos aren't displayed sideways
+pub fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..2]);
+    let mut orientation = None;
+    let mut pos = 2;
+
+    loop {
+        if data.get(pos)? != &0xff {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+
+        // padding
+        if marker == 0xff {
+            pos += 1;
+            continue;
+        }
+
+        // markers without a length
+        if matches!(marker, 0x01 | 0xd0..=0xd7) {
+            out.extend_from_slice(&data[pos..pos + 2]);
+            pos += 2;
+            continue;
+        }
+
+        if marker == 0xd9 {
+            out.extend_from_slice(&data[pos..pos + 2]);
+            return Some(out);
+        }
+
+        let len = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+        if len < 2 {
+            return None;
+        }
+        let segment = data.get(pos..pos + 2 + len)?;
+        let payload = &segment[4..];
+
+        // start of scan, followed by image data up to the next marker
+        if marker == 0xda {
+            if let Some(orientation) = orientation.take() {
+                insert_orientation(&mut out, orientation);
+            }
+            let end = scan_end(data, pos + 2 + len).unwrap_or(data.len());
+            out.extend_from_slice(&data[pos..end]);
+            if end == data.len() {
+                return Some(out);
+            }
+            pos = end;
+            continue;
+        }
+
+        let strip = match marker {
+            // exif or xmp
+            0xe1 => {
+                if let Some(tiff) = payload.strip_prefix(b"Exif\0\0") {
+                    orientation = orientation.or_else(|| exif_orientation(tiff));
+                }
+                true
+            }
+            // multi picture format, can contain more exif
+            0xe2 => payload.starts_with(b"MPF\0"),
+            // iptc
+            0xed => true,
+            // comment
+            0xfe => true,
+            _ => false,
+        };
+
+        if !strip {
+            out.extend_from_slice(segment);
+        }
+
+        pos += 2 + len;
+    }
+}
+
+/// find the marker that ends the entropy coded data starting at `pos`
+fn scan_end(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let at = pos + data.get(pos..)?.iter().position(|&b| b == 0xff)?;
+        match *data.get(at + 1)? {
+            // stuffed zero and restart markers are part of the data
+            0x00 | 0xd0..=0xd7 => pos = at + 2,
+            // fill bytes before a marker
+            0xff => pos = at + 1,
+            _ => return Some(at),
+        }
+    }
+}
+
+/// insert a minimal exif segment containing only the orientation after the jfif header
+fn insert_orientation(out: &mut Vec<u8>, orientation: u16) {
+    let mut app1 = vec![0xff, 0xe1];
+    let mut payload = b"Exif\0\0".to_vec();
+    // big endian tiff header, first ifd at offset 8
+    payload.extend_from_slice(b"MM\0\x2a\0\0\0\x08");
+    // one entry: orientation, SHORT, count 1
+    payload.extend_from_slice(&1u16.to_be_bytes());
+    payload.extend_from_slice(&0x0112u16.to_be_bytes());
+    payload.extend_from_slice(&3u16.to_be_bytes());
+    payload.extend_from_slice(&1u32.to_be_bytes());
+    payload.extend_from_slice(&orientation.to_be_bytes());
+    payload.extend_from_slice(&[0, 0]);
+    // no next ifd
+    payload.extend_from_slice(&0u32.to_be_bytes());
+    app1.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+    app1.extend_from_slice(&payload);
+
+    // exif should come right after soi and app0
+    let mut at = 2;
+    if out.get(2..4) == Some(&[0xff, 0xe0])
+        && let Some(len) = out.get(4..6)
+    {
+        at = 4 + u16::from_be_bytes([len[0], len[1]]) as usize;
+    }
+    out.splice(at.min(out.len())..at.min(out.len()), app1);
+}
+
+/// read the orientation from ifd0 of a tiff header
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let le = match tiff.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| -> Option<u16> {
+        let b = [*tiff.get(at)?, *tiff.get(at + 1)?];
+        Some(if le {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        })
+    };
+    let u32_at = |at: usize| -> Option<u32> {
+        let b: [u8; 4] = tiff.get(at..at + 4)?.try_into().ok()?;
+        Some(if le {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let count = u16_at(ifd)? as usize;
+    (0..count).find_map(|i| {
+        let entry = ifd + 2 + i * 12;
+        (u16_at(entry)? == 0x0112)
+            .then(|| u16_at(entry + 8))
+            .flatten()
+            .filter(|o| (1..=8).contains(o))
+    })
+}
+
+/// strip text, exif, and timestamp chunks from a png
+pub fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !data.starts_with(SIGNATURE) {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(SIGNATURE);
+    let mut pos = SIGNATURE.len();
+
+    while pos < data.len() {
+        let len = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let ty = data.get(pos + 4..pos + 8)?;
+        // length, type, data, crc
+        let chunk = data.get(pos..pos + 12 + len)?;
+        if !matches!(ty, b"tEXt" | b"zTXt" | b"iTXt" | b"eXIf" | b"tIME") {
+            out.extend_from_slice(chunk);
+        }
+        pos += 12 + len;
+        if ty == b"IEND" {
+            break;
+        }
+    }
+
+    Some(out)
+}
+
+/// strip exif and xmp chunks from a webp
+pub fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..12]);
+    let mut pos = 12;
+
+    while pos < data.len() {
+        let ty = data.get(pos..pos + 4)?;
+        let len = u32::from_le_bytes(data.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        // chunks are padded to an even length
+        let end = (pos + 8 + len + (len & 1)).min(data.len());
+        let chunk = data.get(pos..end)?;
+
+        match ty {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let start = out.len();
+                out.extend_from_slice(chunk);
+                // clear the exif and xmp flags
+                *out.get_mut(start + 8)? &= !0x0c;
+            }
+            _ => out.extend_from_slice(chunk),
+        }
+
+        pos = end;
+    }
+
+    let riff_len = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    Some(out)
+}
+
+/// overwrite exif and xmp items in a heif/avif file with zeros
+///
+/// this keeps every offset in the file valid, so nothing needs to be rewritten.
+/// returns the number of items that were scrubbed.
+pub fn scrub_heif(data: &mut [u8]) -> Option<usize> {
+    let meta = find_box(data, 0, data.len(), b"meta")?;
+    // meta is a full box
+    let children = meta.0 + 4;
+
+    let iinf = find_box(data, children, meta.1, b"iinf")?;
+    let iloc = find_box(data, children, meta.1, b"iloc")?;
+    let idat = find_box(data, children, meta.1, b"idat");
+
+    let items = metadata_items(data, iinf)?;
+    if items.is_empty() {
+        return Some(0);
+    }
+
+    let mut ranges = vec![];
+    let mut r = Reader::new(data, iloc.0, iloc.1);
+    let version = r.u8()?;
+    r.skip(3)?;
+    let sizes = r.u8()?;
+    let (offset_size, length_size) = (sizes >> 4, sizes & 0xf);
+    let sizes = r.u8()?;
+    let base_offset_size = sizes >> 4;
+    let index_size = if version == 0 { 0 } else { sizes & 0xf };
+    let item_count = if version < 2 {
+        r.u16()? as u32
+    } else {
+        r.u32()?
+    };
+
+    for _ in 0..item_count {
+        let item_id = if version < 2 {
+            r.u16()? as u32
+        } else {
+            r.u32()?
+        };
+        let construction_method = if version == 0 { 0 } else { r.u16()? & 0xf };
+        r.skip(2)?;
+        let base_offset = r.sized(base_offset_size)?;
+        let extent_count = r.u16()?;
+        for _ in 0..extent_count {
+            r.sized(index_size)?;
+            let offset = r.sized(offset_size)?;
+            let length = r.sized(length_size)?;
+            if !items.contains(&item_id) {
+                continue;
+            }
+
+            let offset = usize::try_from(base_offset.checked_add(offset)?).ok()?;
+            let start = match construction_method {
+                0 => offset,
+                1 => idat?.0.checked_add(offset)?,
+                // item references can't contain metadata themselves
+                _ => continue,
+            };
+            ranges.push((start, start.checked_add(usize::try_from(length).ok()?)?));
+        }
+    }
+
+    for (start, end) in &ranges {
+        data.get_mut(*start..*end)?.fill(0);
+    }
+
+    Some(items.len())
+}
+
+/// get the ids of every exif or xmp item in an `iinf` box
+fn metadata_items(data: &[u8], iinf: (usize, usize)) -> Option<Vec<u32>> {
+    let mut r = Reader::new(data, iinf.0, iinf.1);
+    let version = r.u8()?;
+    r.skip(3)?;
+    let count = if version == 0 {
+        r.u16()? as u32
+    } else {
+        r.u32()?
+    };
+
+    let mut items = vec![];
+    let mut pos = r.pos;
+    for _ in 0..count {
+        let (start, end) = find_box(data, pos, iinf.1, b"infe")?;
+        let mut r = Reader::new(data, start, end);
+        let version = r.u8()?;
+        r.skip(3)?;
+        if version >= 2 {
+            let item_id = if version == 2 {
+                r.u16()? as u32
+            } else {
+                r.u32()?
+            };
+            r.skip(2)?;
+            let ty = r.bytes(4)?;
+            // xmp is stored as a mime item
+            if ty == b"Exif" || ty == b"mime" {
+                items.push(item_id);
+            }
+        }
+        pos = end;
+    }
+
+    Some(items)
+}
+
+/// find the first box of a type in a range, returning the range of its contents
+fn find_box(data: &[u8], mut pos: usize, end: usize, ty: &[u8; 4]) -> Option<(usize, usize)> {
+    while end.saturating_sub(pos) >= 8 {
+        let size = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?);
+        let box_ty = data.get(pos + 4..pos + 8)?;
+        let (header, size) = match size {
+            0 => (8, end - pos),
+            1 => {
+                let size = u64::from_be_bytes(data.get(pos + 8..pos + 16)?.try_into().ok()?);
+                (16, usize::try_from(size).ok()?)
+            }
+            size => (8, usize::try_from(size).ok()?),
+        };
+        let box_end = pos.checked_add(size)?;
+        if size < header || box_end > end {
+            return None;
+        }
+        if box_ty == ty {
+            return Some((pos + header, box_end));
+        }
+        pos = box_end;
+    }
+    None
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize, end: usize) -> Self {
+        Self { data, pos, end }
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.pos + n > self.end {
+            return None;
+        }
+        let b = self.data.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(b)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// read a 0, 4, or 8 byte integer
+    fn sized(&mut self, size: u8) -> Option<u64> {
+        match size {
+            0 => Some(0),
+            4 => self.u32().map(|n| n as u64),
+            8 => self
+                .bytes(8)
+                .map(|b| u64::from_be_bytes(b.try_into().expect("read 8 bytes"))),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut s = vec![0xff, marker];
+        s.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        s.extend_from_slice(payload);
+        s
+    }
+
+    #[test]
+    fn jpeg_keeps_orientation() {
+        // little endian tiff with orientation = 6 and a gps ifd pointer
+        let mut tiff = b"II\x2a\0\x08\0\0\0".to_vec();
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        tiff.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+        tiff.extend_from_slice(&[0x25, 0x88, 4, 0, 1, 0, 0, 0, 0x40, 0, 0, 0]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        let mut exif = b"Exif\0\0".to_vec();
+        exif.extend_from_slice(&tiff);
+
+        let mut jpeg = vec![0xff, 0xd8];
+        jpeg.extend(jpeg_segment(0xe0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0"));
+        jpeg.extend(jpeg_segment(0xe1, &exif));
+        jpeg.extend(jpeg_segment(0xfe, b"secret comment"));
+        jpeg.extend(jpeg_segment(0xda, &[1, 2, 3]));
+        jpeg.extend_from_slice(&[0x12, 0x34, 0xff, 0xd9]);
+
+        let out = strip_jpeg(&jpeg).unwrap();
+        assert!(!out.windows(14).any(|w| w == b"secret comment"));
+        assert!(!out.windows(2).any(|w| w == [0x25, 0x88]));
+        assert!(out.ends_with(&[0x12, 0x34, 0xff, 0xd9]));
+
+        let exif_at = out.windows(6).position(|w| w == b"Exif\0\0").unwrap();
+        assert_eq!(exif_orientation(&out[exif_at + 6..]), Some(6));
+    }
+
+    #[test]
+    fn jpeg_drops_mpf_images() {
+        let secondary = |exif: &[u8]| {
+            let mut jpeg = vec![0xff, 0xd8];
+            jpeg.extend(jpeg_segment(0xe1, exif));
+            jpeg.extend(jpeg_segment(0xda, &[1, 2, 3]));
+            jpeg.extend_from_slice(&[0x56, 0x78, 0xff, 0xd9]);
+            jpeg
+        };
+
+        // a progressive scan with stuffed bytes and restart markers, then a
+        // secondary image appended after the end of the primary one
+        let mut jpeg = vec![0xff, 0xd8];
+        jpeg.extend(jpeg_segment(0xe2, b"MPF II* "));
+        jpeg.extend(jpeg_segment(0xda, &[1, 2, 3]));
+        jpeg.extend_from_slice(&[0x12, 0xff, 0x00, 0x34, 0xff, 0xd0, 0x56]);
+        jpeg.extend(jpeg_segment(0xc4, &[4, 5, 6]));
+        jpeg.extend(jpeg_segment(0xda, &[1, 2, 3]));
+        jpeg.extend_from_slice(&[0x9a, 0xff, 0xff, 0xd9]);
+        let primary = jpeg.len();
+        jpeg.extend(secondary(b"Exif  MM *   GPS"));
+
+        let out = strip_jpeg(&jpeg).unwrap();
+        assert!(!out.windows(4).any(|w| w == b"MPF "));
+        assert!(!out.windows(4).any(|w| w == b"Exif"));
+
+        let mut expected = vec![0xff, 0xd8];
+        expected.extend_from_slice(&jpeg[14..primary]);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn png_strips_text() {
+        let chunk = |ty: &[u8], data: &[u8]| {
+            let mut c = (data.len() as u32).to_be_bytes().to_vec();
+            c.extend_from_slice(ty);
+            c.extend_from_slice(data);
+            c.extend_from_slice(&[0, 0, 0, 0]);
+            c
+        };
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(chunk(b"IHDR", &[0; 13]));
+        png.extend(chunk(b"tEXt", b"Location\0home"));
+        png.extend(chunk(b"IDAT", &[1, 2, 3]));
+        png.extend(chunk(b"IEND", &[]));
+
+        let out = strip_png(&png).unwrap();
+        assert_eq!(out.len(), png.len() - (12 + 13));
+        assert!(!out.windows(4).any(|w| w == b"tEXt"));
+    }
+
+    #[test]
+    fn webp_strips_exif() {
+        let mut webp = b"RIFF\0\0\0\0WEBP".to_vec();
+        webp.extend_from_slice(b"VP8X\x0a\0\0\0");
+        webp.extend_from_slice(&[0x08, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        webp.extend_from_slice(b"VP8L\x02\0\0\0\x01\x02");
+        webp.extend_from_slice(b"EXIF\x03\0\0\0abc\0");
+        let len = (webp.len() - 8) as u32;
+        webp[4..8].copy_from_slice(&len.to_le_bytes());
+
+        let out = strip_webp(&webp).unwrap();
+        assert!(!out.windows(4).any(|w| w == b"EXIF"));
+        assert_eq!(out[20] & 0x08, 0);
+        assert_eq!(
+            u32::from_le_bytes(out[4..8].try_into().unwrap()) as usize,
+            out.len() - 8
+        );
+    }
+
+    fn bx(ty: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut b = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(ty);
+        b.extend_from_slice(body);
+        b
+    }
+
+    #[test]
+    fn heif_scrubs_exif_item() {
+        let ftyp = bx(b"ftyp", b"avif\0\0\0\0");
+        let mut infe = vec![2, 0, 0, 0];
+        infe.extend_from_slice(&[0, 1, 0, 0]);
+        infe.extend_from_slice(b"Exif");
+        let mut iinf_body = vec![0, 0, 0, 0, 0, 1];
+        iinf_body.extend(bx(b"infe", &infe));
+        let iinf = bx(b"iinf", &iinf_body);
+
+        // the exif payload is placed right after the meta box
+        let build = |offset: u32| {
+            let mut iloc_body = vec![0, 0, 0, 0, 0x44, 0x00, 0, 1];
+            iloc_body.extend_from_slice(&[0, 1, 0, 0, 0, 1]);
+            iloc_body.extend_from_slice(&offset.to_be_bytes());
+            iloc_body.extend_from_slice(&8u32.to_be_bytes());
+            let mut meta_body = vec![0, 0, 0, 0];
+            meta_body.extend(iinf.clone());
+            meta_body.extend(bx(b"iloc", &iloc_body));
+            let mut file = ftyp.clone();
+            file.extend(bx(b"meta", &meta_body));
+            file
+        };
+        let offset = build(0).len() as u32 + 8;
+        let mut file = build(offset);
+        file.extend(bx(b"mdat", b"GPSDATA!"));
+
+        assert_eq!(scrub_heif(&mut file), Some(1));
+        assert!(file.ends_with(&[0; 8]));
+
+        // an extent that runs past the end of the address space
+        let mut iloc_body = vec![0, 0, 0, 0, 0x48, 0x00, 0, 1];
+        iloc_body.extend_from_slice(&[0, 1, 0, 0, 0, 1]);
+        iloc_body.extend_from_slice(&offset.to_be_bytes());
+        iloc_body.extend_from_slice(&u64::MAX.to_be_bytes());
+        let mut meta_body = vec![0, 0, 0, 0];
+        meta_body.extend(iinf.clone());
+        meta_body.extend(bx(b"iloc", &iloc_body));
+        let mut file = ftyp.clone();
+        file.extend(bx(b"meta", &meta_body));
+        file.extend(bx(b"mdat", b"GPSDATA!"));
+        assert_eq!(scrub_heif(&mut file), None);
+    }
+
+    #[test]
+    fn heif_rejects_huge_boxes() {
+        // 64-bit size that overflows
+        let mut huge = 1u32.to_be_bytes().to_vec();
+        huge.extend_from_slice(b"free");
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        huge.extend(bx(b"meta", &[0; 4]));
+        assert_eq!(find_box(&huge, 0, huge.len(), b"meta"), None);
+        assert_eq!(scrub_heif(&mut huge), None);
+
+        // 32-bit size past the end of the file
+        let mut oversized = u32::MAX.to_be_bytes().to_vec();
+        oversized.extend_from_slice(b"meta");
+        oversized.extend_from_slice(&[0; 8]);
+        assert_eq!(find_box(&oversized, 0, oversized.len(), b"meta"), None);
+        assert_eq!(scrub_heif(&mut oversized), None);
+
+        // size smaller than the header
+        let mut short = 4u32.to_be_bytes().to_vec();
+        short.extend_from_slice(b"meta");
+        assert_eq!(find_box(&short, 0, short.len(), b"meta"), None);
+    }
+}
@@ -28,9 +28,6 @@ pub fn get_s3_url(config: &Config, path: &str) -> Result<Url> {
     Ok(u)
 }
 
-// TODO: remove created media after 5 minutes, reset timer after any update
-// TODO: remove uploaded media after 5 minutes
-
 // NOTE: if i end up presigning media, i should do it in MediaItem
 
 /// a piece of media on this server
@@ -82,6 +79,9 @@ pub enum MediaError {
     Processing,
     Timeout,
 
+    /// a scanner found something malicious
+    Malicious,
+
     /// unknown/other error
     // TODO: remove, i should be able to get the actual error from the Media itself
     Unknown,
@@ -192,7 +192,8 @@ impl MediaItem {
 
                 // fetch from cdn
                 let media = self.media();
-                let url = get_s3_url(self.inner.s.config(), &format!("media/{}/file", media.id))?;
+                let config = self.inner.s.config();
+                let url = get_s3_url(config, &config.media.paths().file(media.storage_id()))?;
                 let data = self.inner.s.blobs().read(url.path()).await?;
                 Result::Ok(data.to_bytes())
             })
@@ -217,7 +218,8 @@ impl MediaItem {
 
                 // fetch from cdn
                 let media = self.media();
-                let url = get_s3_url(self.inner.s.config(), &format!("media/{}/file", media.id))?;
+                let config = self.inner.s.config();
+                let url = get_s3_url(config, &config.media.paths().file(media.storage_id()))?;
                 let reader = self.inner.s.blobs().reader_with(url.path()).await?;
                 let mut reader = reader.into_futures_async_read(0..).await?.compat();
                 tokio::io::copy(&mut reader, &mut writer).await?;
@@ -329,6 +331,7 @@ impl Import {
             strip_exif: self.strip_exif,
             user_id: Some(self.user_id),
             remote: self.remote,
+            signed: None,
 
             source_url: None,
             deleted_at: None,
@@ -336,6 +339,10 @@ impl Import {
             scans: vec![],
             has_thumbnail: false,
             has_gifv: false,
+            placeholder: None,
+            audio: None,
+            blob_id: None,
+            strip_exif_failed: false,
             links: vec![],
             room_id: None,
             channel_id: None,
@@ -7,7 +7,7 @@ use common::v1::types::misc::Time;
 use common::v1::types::{
     Channel, ChannelId, Mentions, Message, MessageAttachmentType, MessageCreate, MessageId,
     MessageInteraction, MessagePatch, MessageSync, MessageType, MessageVersion, ParseMentions,
-    Permission, ThreadMemberPut, User, UserId,
+    Permission, RoomId, ThreadMemberPut, User, UserId,
 };
 use common::v2::types::media::MediaReference;
 use common::v2::types::{MediaId, SERVER_USER_ID};
@@ -576,14 +576,24 @@ impl ServiceMessages {
         //     .await?;
         // txn.commit().await?;
 
-        self.validate_media(&op.stage.all_media_ids, message_id, author_id)
-            .await?;
+        self.validate_media(
+            &op.stage.all_media_ids,
+            message_id,
+            author_id,
+            op.channel.room_id,
+        )
+        .await?;
 
         // TODO: skip all of these for ephemeral messages
         let message = self.persist_to_database(&mut op).await?;
         let version_id = *message.latest_version.version_id;
-        self.claim_media(&mut op.stage.all_media_ids, message_id, version_id)
-            .await?;
+        self.claim_media(
+            &mut op.stage.all_media_ids,
+            message_id,
+            version_id,
+            op.channel.room_id,
+        )
+        .await?;
         self.update_slowmode_timeout(&mut op).await?;
 
         Ok(op.transition(|old| Committed {
@@ -611,6 +621,8 @@ impl ServiceMessages {
         self.ensure_thread_membership(&mut op).await?;
         self.spawn_unfurler_tasks(&mut op).await?;
         self.spawn_notification_tasks(&mut op).await?;
+        self.sign_attachments(op.channel.id, std::slice::from_mut(&mut op.stage.message))
+            .await?;
 
         let sync = match &op.kind {
             MessageOperationKind::MessageCreate(_) => MessageSync::MessageCreate {
@@ -1065,10 +1077,12 @@ impl ServiceMessages {
         all_media_ids: &MediaRegistry,
         message_id: MessageId,
         author_id: UserId,
+        room_id: Option<RoomId>,
     ) -> Result<()> {
         all_media_ids.check()?;
 
         let mut txn = self.globals.begin_read().await?;
+        let mut new_size = 0u64;
         for &id in &all_media_ids.known {
             // PERF: this should probably be batched
             let media = txn.media_select(id).await?;
@@ -1089,6 +1103,20 @@ impl ServiceMessages {
                     ErrorCode::MediaAlreadyUsed,
                 )));
             }
+
+            if existing.is_empty() {
+                new_size = new_size.saturating_add(media.size);
+            }
+        }
+
+        // 3. quota check: newly linked media counts towards the room's storage
+        // claim_media checks again, but failing here avoids persisting the message
+        if let Some(room_id) = room_id {
+            self.globals
+                .services()
+                .media
+                .ensure_room_quota(&mut txn, room_id, new_size)
+                .await?;
         }
 
         Ok(())
@@ -1101,8 +1129,22 @@ impl ServiceMessages {
         all_media_ids: &mut MediaRegistry,
         message_id: MessageId,
         version_id: Uuid,
+        room_id: Option<RoomId>,
     ) -> Result<()> {
         let mut txn = self.globals.begin().await?;
+        if let Some(room_id) = room_id {
+            let mut new_size = 0u64;
+            for &id in &all_media_ids.known {
+                if txn.media_link_select(id).await?.is_empty() {
+                    new_size = new_size.saturating_add(txn.media_select(id).await?.size);
+                }
+            }
+            self.globals
+                .services()
+                .media
+                .ensure_room_quota(&mut txn, room_id, new_size)
+                .await?;
+        }
         for &id in &all_media_ids.known {
             // 3. insert media links
             txn.media_link_insert(id, message_id.into_inner(), MediaLinkType::Message)
@@ -148,10 +148,10 @@ impl ServiceMessages {
         txn.commit().await?;
 
         // 4. validate media ownership
-        self.validate_media(&all_media_ids, message_id, user_id)
+        self.validate_media(&all_media_ids, message_id, user_id, channel.room_id)
             .await?;
         // 5. insert media links
-        self.claim_media(&mut all_media_ids, message_id, version_id)
+        self.claim_media(&mut all_media_ids, message_id, version_id, channel.room_id)
             .await?;
 
         let message = self.get(channel_id, message_id, None).await?;
@@ -244,6 +244,16 @@ impl ServiceMessages {
                         cache.insert(mid, media);
                     }
                 }
+
+                // deltas are broadcast as is, so sign them like attachments
+                let srv = self.globals.services();
+                if self.globals.config().media.url_key.is_some()
+                    && srv.channels.is_private(channel_id).await?
+                {
+                    for media in cache.values_mut() {
+                        srv.media.sign(media)?;
+                    }
+                }
                 cache
             };
 
@@ -262,9 +272,11 @@ impl ServiceMessages {
         // 4. validate media ownership if there are new media IDs
         if !all_media_ids.known.is_empty() {
             let version_id = (*message_id).into();
-            self.validate_media(&all_media_ids, message_id, user_id)
+            let srv = self.globals.services();
+            let channel = srv.channels.get(channel_id, Some(user_id)).await?;
+            self.validate_media(&all_media_ids, message_id, user_id, channel.room_id)
                 .await?;
-            self.claim_media(&mut all_media_ids, message_id, version_id)
+            self.claim_media(&mut all_media_ids, message_id, version_id, channel.room_id)
                 .await?;
         }
 
@@ -12,7 +12,9 @@ use std::time::Duration;
 use tracing::{error, warn};
 use uuid::Uuid;
 
-use common::v1::types::message::{Message, MessageType, MessageVersion, RepliesResponse};
+use common::v1::types::message::{
+    Message, MessageAttachmentType, MessageType, MessageVersion, RepliesResponse,
+};
 use common::v1::types::misc::Color;
 use common::v1::types::{
     Channel, ChannelId, ContextQuery, ContextResponse, EmbedCreate, EmbedId, Mentions,
@@ -501,6 +503,37 @@ impl ServiceMessages {
             }
         }
 
+        self.sign_attachments(channel_id, messages).await?;
+
+        Ok(())
+    }
+
+    /// sign attachment urls for messages in private channels, so leaked links expire
+    async fn sign_attachments(
+        &self,
+        channel_id: ChannelId,
+        messages: &mut [Message],
+    ) -> Result<()> {
+        if self.globals.config().media.url_key.is_none() {
+            return Ok(());
+        }
+
+        let srv = self.globals.services();
+        if !srv.channels.is_private(channel_id).await? {
+            return Ok(());
+        }
+
+        for message in messages {
+            let MessageType::DefaultMarkdown(m) = &mut message.latest_version.message_type else {
+                continue;
+            };
+            for attachment in &mut m.attachments {
+                if let MessageAttachmentType::Media { media } = &mut attachment.ty {
+                    srv.media.sign(media)?;
+                }
+            }
+        }
+
         Ok(())
     }
 
@@ -834,6 +867,10 @@ impl ServiceMessages {
             author_avatar: author_avatar.map(|m| m.into()),
             site_name: None,
             site_avatar: None,
+            player: None,
+            published_at: None,
+            price: None,
+            event: None,
         })
     }
 }
@@ -431,6 +431,9 @@ impl ServiceRooms {
                 txn.media_link_delete_all(*room_id).await?;
             }
             if let Some(media_id) = icon {
+                srv.media
+                    .ensure_room_quota_for(&mut txn, room_id, *media_id)
+                    .await?;
                 txn.media_link_insert(*media_id, *room_id, MediaLinkType::RoomIcon)
                     .await?;
             }
@@ -441,6 +444,9 @@ impl ServiceRooms {
                 txn.media_link_delete_all(*room_id).await?;
             }
             if let Some(media_id) = banner {
+                srv.media
+                    .ensure_room_quota_for(&mut txn, room_id, *media_id)
+                    .await?;
                 txn.media_link_insert(*media_id, *room_id, MediaLinkType::RoomBanner)
                     .await?;
             }
@@ -15,7 +15,6 @@ use tracing::error;
 use uuid::Uuid;
 
 use crate::prelude::*;
-use crate::services::channel::calculate_hotness;
 use crate::services::search::{index::AsyncIndexHandle, util::SCHEMA};
 
 #[derive(Clone)]
@@ -261,7 +260,14 @@ impl BackfillEtlInner {
 
             let mut batch = Vec::with_capacity(media_list.len());
             for media in &media_list {
-                let doc = SearchMedia::transform(media);
+                let text = match self.s.services().media.get_text(media).await {
+                    Ok(text) => text,
+                    Err(err) => {
+                        error!("failed to fetch media text: {err}");
+                        None
+                    }
+                };
+                let doc = SearchMedia::transform(media, text.as_deref());
                 let term = Term::from_field_text(SCHEMA.id, &media.id.to_string());
                 batch.push((term, doc));
             }
@@ -388,13 +394,7 @@ impl BackfillEtlInner {
             for channel in &res.items {
                 let srv = self.s.services();
                 let first_message = srv.messages.get_first(channel.id, None).await.ok();
-                let doc = SearchChannel::transform(
-                    channel,
-                    first_message.as_ref(),
-                    first_message
-                        .as_ref()
-                        .map(|m| calculate_hotness(channel, m)),
-                );
+                let doc = SearchChannel::transform(channel, first_message.as_ref());
                 let term = Term::from_field_text(SCHEMA.id, &channel.id.to_string());
                 batch.push((term, doc));
             }
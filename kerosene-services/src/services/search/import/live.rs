@@ -1,7 +1,10 @@
 use std::{sync::Arc, time::Duration};
 
-use common::v1::types::{AuditLogEntry, Channel, Message, MessageSync, Room, RoomMember, User};
+use common::v1::types::{
+    AuditLogEntry, Channel, ChannelId, Message, MessageSync, Room, RoomMember, User,
+};
 use common::v2::types::media::Media;
+use dashmap::DashSet;
 use lamprey_search::transform::{
     SearchAuditLogEntry, SearchChannel, SearchMedia, SearchMessage, SearchRoom, SearchRoomMember,
     SearchUser,
@@ -12,17 +15,27 @@ use tracing::{error, info};
 
 use crate::globals::messaging::Broadcast;
 use crate::prelude::*;
-use crate::services::channel::calculate_hotness;
 use crate::services::search::{index::AsyncIndexHandle, util::SCHEMA};
 
+/// how often threads with new messages or reactions are reindexed
+const THREAD_REINDEX_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
 pub struct LiveEtl {
     s: Globals,
     index: AsyncIndexHandle,
+
+    /// threads that got messages or reactions since they were last indexed
+    dirty_threads: Arc<DashSet<ChannelId>>,
 }
 
 impl LiveEtl {
     pub fn new(s: Globals, index: AsyncIndexHandle) -> Self {
-        Self { s, index }
+        Self {
+            s,
+            index,
+            dirty_threads: Arc::new(DashSet::new()),
+        }
     }
 
     fn srv(&self) -> Arc<Services> {
@@ -30,6 +43,8 @@ impl LiveEtl {
     }
 
     pub async fn spawn(self) {
+        tokio::spawn(self.clone().spawn_thread_reindexer());
+
         loop {
             match self.s.messaging().subscribe().await {
                 Ok(mut stream) => {
@@ -52,11 +67,26 @@ impl LiveEtl {
 
     async fn handle_sync(&self, sync: MessageSync) -> Result<()> {
         match sync {
-            MessageSync::MessageCreate { message } => self.index_message(message).await?,
+            MessageSync::MessageCreate { message } => {
+                let channel_id = message.channel_id;
+                self.index_message(message).await?;
+                self.dirty_threads.insert(channel_id);
+            }
             MessageSync::MessageUpdate { message } => self.index_message(message).await?,
-            MessageSync::MessageDelete { message_id, .. } => {
+            MessageSync::MessageDelete {
+                channel_id,
+                message_id,
+                ..
+            } => {
                 let term = Term::from_field_text(SCHEMA.id, &message_id.to_string());
                 self.index.delete_term(term).await?;
+                self.dirty_threads.insert(channel_id);
+            }
+            MessageSync::ReactionCreate { channel_id, .. }
+            | MessageSync::ReactionDelete { channel_id, .. }
+            | MessageSync::ReactionDeleteKey { channel_id, .. }
+            | MessageSync::ReactionDeleteAll { channel_id, .. } => {
+                self.dirty_threads.insert(channel_id);
             }
             MessageSync::ChannelCreate { channel } => self.index_channel(*channel).await?,
             MessageSync::ChannelUpdate { channel } => self.index_channel(*channel).await?,
@@ -94,11 +124,45 @@ impl LiveEtl {
 
     async fn index_channel(&self, channel: Channel) -> Result<()> {
         let first_message = self.srv().messages.get_first(channel.id, None).await.ok();
+        self.index_channel_with(channel, first_message).await
+    }
+
+    /// periodically reindex dirty threads
+    ///
+    /// busy threads get a message or reaction every few seconds, so this batches
+    /// their reindexes instead of doing one for every event
+    async fn spawn_thread_reindexer(self) {
+        let mut interval = tokio::time::interval(THREAD_REINDEX_INTERVAL);
+        loop {
+            interval.tick().await;
+            let channel_ids: Vec<ChannelId> = self.dirty_threads.iter().map(|id| *id).collect();
+            for channel_id in channel_ids {
+                self.dirty_threads.remove(&channel_id);
+                if let Err(err) = self.reindex_thread(channel_id).await {
+                    error!("error while reindexing thread {channel_id}: {err}");
+                }
+            }
+        }
+    }
+
+    /// reindex a thread so its message count and score stay up to date
+    async fn reindex_thread(&self, channel_id: ChannelId) -> Result<()> {
+        // read from the database directly, the channel cache doesn't track message counts
+        let channel = self.s.begin_read().await?.channel_get(channel_id).await?;
+        if channel.parent_id.is_none() {
+            return Ok(());
+        }
+
+        self.index_channel(channel).await
+    }
+
+    async fn index_channel_with(
+        &self,
+        channel: Channel,
+        first_message: Option<Message>,
+    ) -> Result<()> {
         let term = Term::from_field_text(SCHEMA.id, &channel.id.to_string());
-        let hotness = first_message
-            .as_ref()
-            .map(|m| calculate_hotness(&channel, m));
-        let doc = SearchChannel::transform(&channel, first_message.as_ref(), hotness);
+        let doc = SearchChannel::transform(&channel, first_message.as_ref());
         self.index.update_document(term, doc).await?;
         Ok(())
     }
@@ -119,7 +183,8 @@ impl LiveEtl {
 
     async fn index_media(&self, media: Media) -> Result<()> {
         let term = Term::from_field_text(SCHEMA.id, &media.id.to_string());
-        let doc = SearchMedia::transform(&media);
+        let text = self.srv().media.get_text(&media).await?;
+        let doc = SearchMedia::transform(&media, text.as_deref());
         self.index.update_document(term, doc).await?;
         Ok(())
     }
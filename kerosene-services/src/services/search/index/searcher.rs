@@ -15,6 +15,7 @@ use common::v1::types::{
         RoomMemberSearchOrderField, RoomMemberSearchRequest, RoomSearchOrderField,
         RoomSearchRequest, UserSearchOrderField, UserSearchRequest,
     },
+    util::Time,
 };
 
 use crate::services::search::{
@@ -27,7 +28,7 @@ use crate::services::search::{
 };
 use crate::services::search::{
     index::{AsyncSearcher, glue::TantivyMessage},
-    util::IntoTantivyOrder,
+    util::{IntoTantivyOrder, escape_json_path_segment, order_by_hotness, order_by_json_number},
 };
 use crate::{Error, Result};
 use lamprey_search::visibility::{
@@ -267,11 +268,34 @@ impl ContentSearcher {
                     count as u64,
                 )
             }
-            // TODO: impl sort by Score
-            // TODO: impl sort by Reactions
-            (ChannelSearchOrderField::Id, ord)
-            | (ChannelSearchOrderField::Score, ord)
-            | (ChannelSearchOrderField::Reactions { .. }, ord) => {
+            (ChannelSearchOrderField::Score, ord) => {
+                let top_docs = order_by_hotness(
+                    TopDocs::with_limit(limit).and_offset(cursor),
+                    ord,
+                    Time::now_utc().unix_timestamp(),
+                );
+                let (docs, count): (Vec<(f64, DocAddress)>, usize) =
+                    self.searcher.search(&query, &(top_docs, Count)).await?;
+                (
+                    docs.into_iter().map(|(_, addr)| addr).collect(),
+                    count as u64,
+                )
+            }
+            (ChannelSearchOrderField::Reactions { reaction }, ord) => {
+                let key = escape_json_path_segment(&reaction.to_key_str());
+                let top_docs = order_by_json_number(
+                    TopDocs::with_limit(limit).and_offset(cursor),
+                    format!("metadata_fast.reactions.{key}"),
+                    ord,
+                );
+                let (docs, count): (Vec<(f64, DocAddress)>, usize) =
+                    self.searcher.search(&query, &(top_docs, Count)).await?;
+                (
+                    docs.into_iter().map(|(_, addr)| addr).collect(),
+                    count as u64,
+                )
+            }
+            (ChannelSearchOrderField::Id, ord) => {
                 let top_docs = TopDocs::with_limit(limit)
                     .and_offset(cursor)
                     .order_by_string_fast_field("id", ord.tantivy());
@@ -320,11 +344,12 @@ impl ContentSearcher {
 
         let (items_raw, count): (Vec<_>, _) = match msg.req.sort_field {
             RoomSearchOrderField::Members => {
-                // FIXME: order by member count
-                let top_docs = TopDocs::with_limit(limit)
-                    .and_offset(cursor)
-                    .order_by_score();
-                let (docs, count): (Vec<(Score, DocAddress)>, usize) =
+                let top_docs = order_by_json_number(
+                    TopDocs::with_limit(limit).and_offset(cursor),
+                    "metadata_fast.member_count".to_owned(),
+                    msg.req.inner.sort_order,
+                );
+                let (docs, count): (Vec<(f64, DocAddress)>, usize) =
                     self.searcher.search(&query, &(top_docs, Count)).await?;
                 (
                     docs.into_iter().map(|(_, addr)| addr).collect(),
@@ -626,16 +651,36 @@ impl ContentSearcher {
         let limit = msg.req.inner.limit as usize;
         let cursor = msg.req.inner.offset as usize;
 
-        // TODO: sort by msg.req.sort_field
-        let top_docs = TopDocs::with_limit(limit)
-            .and_offset(cursor)
-            .order_by_fast_field("created_at", msg.req.inner.sort_order.tantivy());
-
-        let (docs, count): (Vec<(Option<tantivy::DateTime>, DocAddress)>, usize) =
-            self.searcher.search(&query, &(top_docs, Count)).await?;
+        let (items_raw, count): (Vec<_>, _) = match msg.req.sort_field {
+            RoomMemberSearchOrderField::Joined => {
+                let top_docs = TopDocs::with_limit(limit)
+                    .and_offset(cursor)
+                    .order_by_fast_field::<tantivy::DateTime>(
+                        "created_at",
+                        msg.req.inner.sort_order.tantivy(),
+                    );
+                let (docs, count): (Vec<(Option<tantivy::DateTime>, DocAddress)>, usize) =
+                    self.searcher.search(&query, &(top_docs, Count)).await?;
+                (
+                    docs.into_iter().map(|(_, addr)| addr).collect(),
+                    count as u64,
+                )
+            }
+            RoomMemberSearchOrderField::UserId => {
+                let top_docs = TopDocs::with_limit(limit)
+                    .and_offset(cursor)
+                    .order_by_string_fast_field("author_id", msg.req.inner.sort_order.tantivy());
+                let (docs, count): (Vec<(Option<String>, DocAddress)>, usize) =
+                    self.searcher.search(&query, &(top_docs, Count)).await?;
+                (
+                    docs.into_iter().map(|(_, addr)| addr).collect(),
+                    count as u64,
+                )
+            }
+        };
 
-        let mut items: Vec<TantivyRoomMember> = Vec::with_capacity(docs.len());
-        for (_, doc_address) in docs {
+        let mut items: Vec<TantivyRoomMember> = Vec::with_capacity(items_raw.len());
+        for doc_address in items_raw {
             items.push(self.searcher.doc(doc_address).await?);
         }
 
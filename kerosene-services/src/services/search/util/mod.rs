@@ -1,5 +1,8 @@
 // TEMP: re-export
 pub use lamprey_search::{
     schema::SCHEMA,
-    util::{BqBuilder, IntoTantivyOrder},
+    util::{
+        BqBuilder, IntoTantivyOrder, escape_json_path_segment, order_by_hotness,
+        order_by_json_number,
+    },
 };
@@ -2,7 +2,8 @@ use crate::Result;
 use crate::services::voice::ServiceVoice;
 use crate::services::voice::voice_state::VoiceStateHandle;
 use common::v1::types::error::{ApiError, ErrorCode};
-use common::v1::types::voice::CallMetadata;
+use common::v1::types::voice::messages::SfuCommand;
+use common::v1::types::voice::{CallMetadata, CallRecording};
 use common::v1::types::{
     ChannelId, ChannelType, MessageId, SfuId, UserId,
     util::Time,
@@ -21,6 +22,9 @@ const CALL_CLEANUP_TIMEOUT: Duration = Duration::from_secs(300);
 pub struct CallHandleInner {
     pub call: Call,
     pub sfus: DashSet<SfuId>,
+
+    /// the destinations last sent to each sfu in a `RouteUpdate`
+    pub routes: DashMap<SfuId, Vec<SfuId>>,
     pub cleanup_task: Option<tokio::task::AbortHandle>,
     pub voice_states: DashMap<UserId, VoiceStateHandle>,
     pub message_id: Option<MessageId>,
@@ -78,6 +82,7 @@ impl ServiceVoice {
                 topic: params.topic,
                 created_at: Time::now_utc(),
                 audience_count: Some(0),
+                recording: None,
             },
         };
 
@@ -90,6 +95,7 @@ impl ServiceVoice {
         let handle = Arc::new(CallHandleInner {
             call: call.clone(),
             sfus: DashSet::new(),
+            routes: DashMap::new(),
             cleanup_task,
             voice_states: DashMap::new(),
             message_id: None,
@@ -147,6 +153,7 @@ impl ServiceVoice {
                     let updated_handle = Arc::new(CallHandleInner {
                         call: handle.call.clone(),
                         sfus: handle.sfus.clone(),
+                        routes: handle.routes.clone(),
                         cleanup_task: handle.cleanup_task.clone(),
                         voice_states: handle.voice_states.clone(),
                         message_id: Some(message_id),
@@ -183,6 +190,19 @@ impl ServiceVoice {
                 task.abort();
             }
 
+            // whatever was recorded so far is still posted
+            let _ = self.recording_stop(channel_id);
+
+            // tear down links between sfus
+            for entry in handle.routes.iter() {
+                if let Some(sfu) = self.sfu_get(*entry.key()) {
+                    sfu.send(SfuCommand::RouteUpdate {
+                        channel_id,
+                        destinations: vec![],
+                    });
+                }
+            }
+
             if let Some(message_id) = handle.message_id {
                 // TODO: split out this code
                 let globals = self.state.clone();
@@ -258,12 +278,48 @@ impl ServiceVoice {
         let updated_handle = Arc::new(CallHandleInner {
             call: new_call.clone(),
             sfus: handle.sfus.clone(),
+            routes: handle.routes.clone(),
+            cleanup_task: handle.cleanup_task.clone(),
+            voice_states: handle.voice_states.clone(),
+            message_id: handle.message_id,
+        });
+
+        *entry.value_mut() = Arc::clone(&updated_handle);
+
+        self.state
+            .messaging()
+            .broadcast_channel(channel_id, MessageSync::CallUpdate { call: new_call })
+            .await?;
+
+        Ok(updated_handle)
+    }
+
+    /// set or clear the recording in progress for a call
+    pub(crate) async fn call_set_recording(
+        &self,
+        channel_id: ChannelId,
+        recording: Option<CallRecording>,
+    ) -> Result<CallHandle> {
+        let mut entry = self
+            .calls
+            .get_mut(&channel_id)
+            .ok_or_else(|| ApiError::from_code(ErrorCode::UnknownVoiceChannel))?;
+
+        let handle = entry.value();
+        let mut new_call = handle.call.clone();
+        new_call.inner.recording = recording;
+
+        let updated_handle = Arc::new(CallHandleInner {
+            call: new_call.clone(),
+            sfus: handle.sfus.clone(),
+            routes: handle.routes.clone(),
             cleanup_task: handle.cleanup_task.clone(),
             voice_states: handle.voice_states.clone(),
             message_id: handle.message_id,
         });
 
         *entry.value_mut() = Arc::clone(&updated_handle);
+        drop(entry);
 
         self.state
             .messaging()
@@ -326,6 +382,7 @@ impl ServiceVoice {
                 let updated_handle = Arc::new(CallHandleInner {
                     call: handle.call.clone(),
                     sfus: handle.sfus.clone(),
+                    routes: handle.routes.clone(),
                     cleanup_task: Some(new_cleanup_task),
                     voice_states: handle.voice_states.clone(),
                     message_id: handle.message_id,
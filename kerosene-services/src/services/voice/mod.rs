@@ -1,5 +1,6 @@
 use crate::prelude::*;
 use crate::services::voice::calls::CallHandle;
+use crate::services::voice::recording::RecordingHandle;
 use crate::services::voice::sfus::SfuHandle;
 use common::v1::types::voice::messages::SfuCommand;
 use common::v1::types::voice::router::{VoiceRouter, VoiceRouterConfig};
@@ -8,6 +9,7 @@ use dashmap::DashMap;
 use tokio::sync::RwLock;
 
 pub mod calls;
+pub mod recording;
 // pub mod ring;
 pub mod sfus;
 pub mod sync;
@@ -18,6 +20,7 @@ pub struct ServiceVoice {
     pub calls: DashMap<ChannelId, CallHandle>,
     pub sfus: DashMap<SfuId, SfuHandle>,
     pub router: RwLock<VoiceRouter>,
+    pub recordings: DashMap<ChannelId, RecordingHandle>,
 }
 
 impl ServiceVoice {
@@ -28,6 +31,7 @@ impl ServiceVoice {
             calls: DashMap::new(),
             sfus: DashMap::new(),
             router: RwLock::new(router),
+            recordings: DashMap::new(),
         }
     }
 }
@@ -0,0 +1,315 @@
+use crate::Result;
+use crate::services::media::{Import, MediaItem};
+use crate::services::voice::ServiceVoice;
+use crate::services::voice::calls::CallHandle;
+use crate::types::{DbMessageAttachment, DbMessageCreate, MediaLinkType};
+use async_tempfile::TempFile;
+use bytes::Bytes;
+use common::v1::types::error::{ApiError, ErrorCode};
+use common::v1::types::voice::messages::SfuCommand;
+use common::v1::types::voice::{CallRecording, MediaKind};
+use common::v1::types::{
+    ChannelId, MessageCall, MessageSync, MessageType, SfuId, UserId, util::Time,
+};
+use common::v2::types::MediaId;
+use dashmap::DashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Mutex;
+use tracing::{debug, error, warn};
+
+/// the maximum number of attachments on a recording message
+const MAX_RECORDING_ATTACHMENTS: usize = 32;
+
+/// the maximum number of participants listed on a recording message
+const MAX_RECORDING_PARTICIPANTS: usize = 128;
+
+/// a call recording, either in progress or waiting for sfus to upload it
+pub struct Recording {
+    pub started_by: UserId,
+    pub started_at: Time,
+
+    /// whether this recording was stopped
+    pub stopped: AtomicBool,
+
+    /// sfus that are recording this call and haven't finished uploading yet
+    pub sfus: DashSet<SfuId>,
+
+    /// tracks that have been uploaded so far
+    pub tracks: Mutex<Vec<RecordedTrack>>,
+}
+
+/// a single track that an sfu recorded and uploaded
+pub struct RecordedTrack {
+    /// the user who published this track
+    pub user_id: UserId,
+
+    pub kind: MediaKind,
+
+    /// how many milliseconds after the recording started this track begins
+    pub offset: u64,
+
+    pub media: MediaItem,
+}
+
+pub type RecordingHandle = Arc<Recording>;
+
+impl Recording {
+    pub fn to_api(&self) -> CallRecording {
+        CallRecording {
+            started_by: self.started_by,
+            started_at: self.started_at,
+        }
+    }
+
+    #[inline]
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Acquire)
+    }
+}
+
+impl ServiceVoice {
+    /// get a call's recording, including recordings that are still being uploaded
+    pub fn recording_get(&self, channel_id: ChannelId) -> Option<RecordingHandle> {
+        self.recordings.get(&channel_id).map(|r| r.value().clone())
+    }
+
+    /// start recording a call
+    pub async fn call_recording_start(
+        &self,
+        channel_id: ChannelId,
+        user_id: UserId,
+    ) -> Result<CallHandle> {
+        let call = self
+            .call_get(channel_id)
+            .ok_or_else(|| ApiError::from_code(ErrorCode::UnknownVoiceChannel))?;
+
+        // the previous recording needs to finish uploading before a new one can start
+        if self.recordings.contains_key(&channel_id) {
+            return Err(ApiError::from_code(ErrorCode::AlreadyRecording).into());
+        }
+
+        let recording = Arc::new(Recording {
+            started_by: user_id,
+            started_at: Time::now_utc(),
+            stopped: AtomicBool::new(false),
+            sfus: call.sfus.clone(),
+            tracks: Mutex::new(Vec::new()),
+        });
+        self.recordings.insert(channel_id, Arc::clone(&recording));
+
+        for sfu_id in recording.sfus.iter() {
+            if let Some(sfu) = self.sfu_get(*sfu_id) {
+                sfu.send(SfuCommand::RecordingStart { channel_id });
+            }
+        }
+
+        self.call_set_recording(channel_id, Some(recording.to_api()))
+            .await
+    }
+
+    /// stop recording a call
+    ///
+    /// the recording is posted once every sfu has uploaded its tracks
+    pub async fn call_recording_stop(&self, channel_id: ChannelId) -> Result<()> {
+        self.recording_stop(channel_id)?;
+        self.call_set_recording(channel_id, None).await?;
+        Ok(())
+    }
+
+    /// tell every sfu recording this call to stop and upload
+    pub(crate) fn recording_stop(&self, channel_id: ChannelId) -> Result<()> {
+        let recording = self
+            .recording_get(channel_id)
+            .filter(|r| !r.is_stopped())
+            .ok_or_else(|| ApiError::from_code(ErrorCode::NotRecording))?;
+        recording.stopped.store(true, Ordering::Release);
+
+        for sfu_id in recording.sfus.iter() {
+            if let Some(sfu) = self.sfu_get(*sfu_id) {
+                sfu.send(SfuCommand::RecordingStop { channel_id });
+            }
+        }
+
+        self.recording_maybe_finish(channel_id, &recording);
+        Ok(())
+    }
+
+    /// start recording on a sfu that was just added to a call
+    pub(crate) fn recording_sfu_added(&self, channel_id: ChannelId, sfu_id: SfuId) {
+        let Some(recording) = self.recording_get(channel_id) else {
+            return;
+        };
+
+        if recording.is_stopped() || !recording.sfus.insert(sfu_id) {
+            return;
+        }
+
+        if let Some(sfu) = self.sfu_get(sfu_id) {
+            sfu.send(SfuCommand::RecordingStart { channel_id });
+        }
+    }
+
+    /// a sfu finished uploading its tracks for a recording, or went away
+    pub(crate) fn recording_sfu_finished(&self, channel_id: ChannelId, sfu_id: SfuId) {
+        let Some(recording) = self.recording_get(channel_id) else {
+            return;
+        };
+
+        recording.sfus.remove(&sfu_id);
+        self.recording_maybe_finish(channel_id, &recording);
+    }
+
+    /// add a track that was uploaded by a sfu
+    pub async fn recording_track_add(
+        &self,
+        channel_id: ChannelId,
+        track: RecordedTrack,
+    ) -> Result<()> {
+        let recording = self
+            .recording_get(channel_id)
+            .ok_or_else(|| ApiError::from_code(ErrorCode::NotRecording))?;
+        recording.tracks.lock().await.push(track);
+        Ok(())
+    }
+
+    fn recording_maybe_finish(&self, channel_id: ChannelId, recording: &RecordingHandle) {
+        if !recording.is_stopped() || !recording.sfus.is_empty() {
+            return;
+        }
+
+        // only finish once, even if multiple sfus finish at the same time
+        if self.recordings.remove(&channel_id).is_none() {
+            return;
+        }
+
+        let recording = Arc::clone(recording);
+        let globals = self.state.clone();
+        tokio::spawn(async move {
+            let srv = globals.services();
+            let tracks = std::mem::take(&mut *recording.tracks.lock().await);
+            if let Err(err) = srv
+                .voice
+                .recording_post(channel_id, recording.started_by, tracks)
+                .await
+            {
+                error!(%channel_id, "couldn't post recording: {err}");
+            }
+        });
+    }
+
+    /// post a finished recording to its channel
+    async fn recording_post(
+        &self,
+        channel_id: ChannelId,
+        started_by: UserId,
+        mut tracks: Vec<RecordedTrack>,
+    ) -> Result<()> {
+        if tracks.is_empty() {
+            debug!(%channel_id, "recording has no tracks");
+            return Ok(());
+        }
+
+        // tracks are processed like any other upload
+        for track in &mut tracks {
+            track.media.ready().await;
+        }
+
+        let mut attachments = Vec::new();
+        match self.recording_mix(started_by, &tracks).await {
+            Ok(Some(media_id)) => attachments.push(media_id),
+            Ok(None) => {}
+            Err(err) => warn!(%channel_id, "couldn't mix recording: {err}"),
+        }
+        attachments.extend(tracks.iter().map(|t| t.media.media().id));
+        attachments.truncate(MAX_RECORDING_ATTACHMENTS);
+
+        let mut participants: Vec<UserId> = tracks.iter().map(|t| t.user_id).collect();
+        participants.sort();
+        participants.dedup();
+        participants.truncate(MAX_RECORDING_PARTICIPANTS);
+
+        // TODO(?): move this logic to messages service
+        let mut txn = self.state.begin().await?;
+        let message_id = txn
+            .message_create(DbMessageCreate {
+                id: None,
+                channel_id,
+                attachments: attachments
+                    .iter()
+                    .map(|&media_id| DbMessageAttachment {
+                        media_id,
+                        spoiler: false,
+                    })
+                    .collect(),
+                author_id: started_by,
+                embeds: vec![],
+                components: vec![],
+                message_type: MessageType::Call(MessageCall {
+                    ended_at: Some(Time::now_utc()),
+                    participants,
+                }),
+                created_at: None,
+                removed_at: None,
+                flume: None,
+                mentions: Default::default(),
+                interaction: None,
+                ephemeral: false,
+            })
+            .await?;
+        let message = txn.message_get(channel_id, message_id).await?;
+        let version_id = *message.latest_version.version_id;
+        for &media_id in &attachments {
+            txn.media_link_insert(media_id, message_id.into_inner(), MediaLinkType::Message)
+                .await?;
+            txn.media_link_insert(media_id, version_id, MediaLinkType::MessageVersion)
+                .await?;
+        }
+        txn.commit().await?;
+
+        self.state
+            .messaging()
+            .broadcast_channel(channel_id, MessageSync::MessageCreate { message })
+            .await?;
+
+        Ok(())
+    }
+
+    /// mix every recorded audio track into a single track
+    ///
+    /// returns None if there aren't enough audio tracks to mix
+    async fn recording_mix(
+        &self,
+        started_by: UserId,
+        tracks: &[RecordedTrack],
+    ) -> Result<Option<MediaId>> {
+        let srv = self.state.services();
+
+        let mut files = Vec::new();
+        for track in tracks.iter().filter(|t| t.kind == MediaKind::Audio) {
+            files.push((track.media.download_tempfile().await?, track.offset));
+        }
+
+        if files.len() < 2 {
+            return Ok(None);
+        }
+
+        let inputs: Vec<(&Path, u64)> = files
+            .iter()
+            .map(|(file, offset)| (file.file_path().as_path(), *offset))
+            .collect();
+        let out = TempFile::new().await?;
+        srv.media
+            .ffmpeg()
+            .mix_audio(&inputs, out.file_path())
+            .await?;
+
+        let bytes = Bytes::from(tokio::fs::read(out.file_path()).await?);
+        let mut import = Import::new(started_by);
+        import.filename = Some("recording.ogg".to_owned());
+        import.max_size = Some(bytes.len() as u64);
+        let mut item = srv.media.import_from_bytes(import, bytes).await?;
+        Ok(Some(item.ready().await.id))
+    }
+}
@@ -3,12 +3,15 @@ use crate::services::voice::voice_state::{VoiceStateHandle, VoiceStateHandleInne
 use crate::services::voice::{ServiceVoice, SfuCommand};
 use axum::extract::ws::WebSocket;
 use common::v1::types::error::{ApiError, ErrorCode};
-use common::v1::types::voice::SfuStats;
-use common::v1::types::voice::messages::{SfuEvent, SignallingEvent};
-use common::v1::types::{ChannelId, MessageSync, SfuId, UserId, util::Time};
+use common::v1::types::voice::internal::SfuVoiceState;
+use common::v1::types::voice::messages::{SfuEvent, SignallingCommand, SignallingEvent};
+use common::v1::types::voice::{IceServer, SfuStats};
+use common::v1::types::{ChannelId, MessageSync, Permission, SfuId, UserId, util::Time};
 use lamprey_backend_core::Error;
-use std::collections::HashMap;
+use lamprey_backend_core::types::turn::TurnCredentials;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::{RwLock, mpsc};
 use tracing::{debug, error, info, warn};
 
@@ -17,8 +20,14 @@ pub struct SfuHandleInner {
     pub connected_at: Time,
     pub stats: RwLock<SfuStats>,
     pub tx: mpsc::UnboundedSender<SfuCommand>,
+
+    /// urls for this sfu's builtin stun/turn server
+    pub ice_urls: RwLock<Vec<String>>,
 }
 
+/// how long turn credentials are valid for
+const TURN_CREDENTIALS_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24);
+
 pub type SfuHandle = Arc<SfuHandleInner>;
 
 impl SfuHandleInner {
@@ -28,6 +37,7 @@ impl SfuHandleInner {
             connected_at,
             stats: RwLock::new(SfuStats::default()),
             tx,
+            ice_urls: RwLock::new(Vec::new()),
         }
     }
 
@@ -48,19 +58,21 @@ impl SfuHandleInner {
     }
 
     pub fn has_capacity(&self) -> bool {
-        // TODO: use stats to calculate capacity
-        // let stats = self.stats.blocking_read();
-        // if stats.peer_count >= 500 {
-        //     return false;
-        // }
-
-        // if stats.peer_count > 0 && stats.bandwidth_max > 0 {
-        //     let bandwidth_per_peer = stats.bandwidth_usage / stats.peer_count;
-        //     // check if adding another peer would exceed 80% of maximum bandwidth
-        //     return (stats.bandwidth_usage + bandwidth_per_peer) < (stats.bandwidth_max * 8 / 10);
-        // }
-
-        true
+        self.capacity() != Some(0)
+    }
+
+    /// how many more users this sfu can take, if it's known
+    pub fn capacity(&self) -> Option<usize> {
+        // stats are only locked briefly while being replaced
+        let stats = self.stats.try_read().ok()?;
+        if stats.peer_count == 0 || stats.bandwidth_max == 0 {
+            return None;
+        }
+
+        // keep 20% of the maximum bandwidth free
+        let bandwidth_per_peer = (stats.bandwidth_usage / stats.peer_count).max(1);
+        let free = (stats.bandwidth_max * 8 / 10).saturating_sub(stats.bandwidth_usage);
+        Some((free / bandwidth_per_peer) as usize)
     }
 }
 
@@ -210,8 +222,23 @@ impl ServiceVoice {
                 srv.voice.state_destroy(channel_id, user_id).await?;
             }
             SfuEvent::Latency { target_sfu, rtt } => {
-                let mut router = self.router.write().await;
-                router.update_latency(sfu_id, target_sfu, rtt);
+                self.router
+                    .write()
+                    .await
+                    .update_latency(sfu_id, target_sfu, rtt);
+
+                // the best path may have changed for calls using this link
+                let channel_ids: Vec<ChannelId> = self
+                    .calls
+                    .iter()
+                    .filter(|c| {
+                        c.routes.contains_key(&sfu_id) || c.routes.contains_key(&target_sfu)
+                    })
+                    .map(|c| *c.key())
+                    .collect();
+                for channel_id in channel_ids {
+                    self.sfu_update_routes(channel_id).await;
+                }
             }
             SfuEvent::Stats { stats } => {
                 if let Some(sfu) = self.sfu_get(sfu_id) {
@@ -224,25 +251,52 @@ impl ServiceVoice {
                 channel_id,
             } => {
                 info!(%user_id, %channel_id, "Peer created on SFU");
+                let ice_servers = self.sfu_ice_servers(sfu_id, user_id).await?;
+                self.state
+                    .messaging()
+                    .broadcast_channel(
+                        channel_id,
+                        MessageSync::VoiceDispatch {
+                            user_id,
+                            channel_id,
+                            payload: SignallingEvent::Connected {
+                                sfu_id,
+                                ice_servers,
+                            },
+                        },
+                    )
+                    .await?;
+            }
+            SfuEvent::IceServers { urls } => {
+                if let Some(sfu) = self.sfu_get(sfu_id) {
+                    debug!(%sfu_id, ?urls, "SFU ice servers updated");
+                    *sfu.ice_urls.write().await = urls;
+                }
             }
             SfuEvent::CascadeCreated { sfu_id, channel_id } => {
                 info!(%sfu_id, %channel_id, "Cascade created on SFU");
             }
+            SfuEvent::RecordingFinished { channel_id } => {
+                debug!(%sfu_id, %channel_id, "SFU finished uploading recording");
+                self.recording_sfu_finished(channel_id, sfu_id);
+            }
             SfuEvent::CascadePrepared {
-                sfu_id,
+                sfu_id: connecting_sfu_id,
                 token,
                 addr,
+                fingerprint,
             } => {
                 info!(%addr, "Cascade prepared");
-                // finish creating cascade (send command to sfu at sfu_id)
-                if let Some(sfu) = self.sfu_get(sfu_id) {
+                // finish creating cascade (tell the connecting sfu to connect to this one)
+                if let Some(sfu) = self.sfu_get(connecting_sfu_id) {
                     sfu.send(SfuCommand::CreateCascade {
                         sfu_id,
                         token,
                         addr,
+                        fingerprint,
                     });
                 } else {
-                    error!(%sfu_id, "SFU not found for cascade preparation");
+                    error!(%connecting_sfu_id, "SFU not found for cascade preparation");
                 }
             }
         }
@@ -250,35 +304,75 @@ impl ServiceVoice {
         Ok(())
     }
 
+    /// get the ice servers a user should use to connect to a sfu
+    ///
+    /// turn credentials are minted for this user
+    async fn sfu_ice_servers(&self, sfu_id: SfuId, user_id: UserId) -> Result<Vec<IceServer>> {
+        let Some(sfu) = self.sfu_get(sfu_id) else {
+            return Ok(vec![]);
+        };
+
+        let urls = sfu.ice_urls.read().await.clone();
+        if urls.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let secret = self
+            .state
+            .config()
+            .voice
+            .as_ref()
+            .and_then(|v| v.turn_secret.as_ref());
+        let Some(secret) = secret else {
+            return Ok(vec![IceServer {
+                urls,
+                username: None,
+                credential: None,
+            }]);
+        };
+
+        let expires = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_add(TURN_CREDENTIALS_LIFETIME)
+            .as_secs();
+        let creds = TurnCredentials::mint(user_id, expires, secret.load()?.as_bytes());
+
+        // stun servers ignore credentials, so everything can be in one entry
+        Ok(vec![IceServer {
+            urls,
+            username: Some(creds.username),
+            credential: Some(creds.password),
+        }])
+    }
+
     pub async fn sfu_alloc(&self, channel_id: ChannelId, user_id: UserId) -> Result<SfuHandle> {
-        let sfu =
-            match self.sfu_alloc_user(channel_id, user_id).await? {
-                Allocation::JoinExisting(sfu_id) => self
-                    .sfus
-                    .get(&sfu_id)
+        let sfu = match self.sfu_alloc_user(channel_id, user_id).await? {
+            Allocation::JoinExisting(sfu_id) => self
+                .sfus
+                .get(&sfu_id)
+                .map(|s| Arc::clone(s.value()))
+                .ok_or_else(|| Error::ApiError(ApiError::from_code(ErrorCode::UnknownSfu)))?,
+            Allocation::CascadeToNew {
+                existing_sfu_id,
+                new_sfu_id,
+            } => {
+                debug!(%existing_sfu_id, %new_sfu_id, "cascading call to new sfu");
+
+                // the link between the sfus is created by `sfu_update_routes` once the
+                // user's voice state is on the new sfu
+                self.sfus
+                    .get(&new_sfu_id)
                     .map(|s| Arc::clone(s.value()))
-                    .ok_or_else(|| Error::ApiError(ApiError::from_code(ErrorCode::UnknownSfu)))?,
-                Allocation::CascadeToNew {
-                    existing_sfu_id,
-                    new_sfu_id,
-                } => {
-                    let existing_sfu = self.sfus.get(&existing_sfu_id).ok_or_else(|| {
-                        Error::ApiError(ApiError::from_code(ErrorCode::UnknownSfu))
-                    })?;
-                    let new_sfu = self.sfus.get(&new_sfu_id).ok_or_else(|| {
-                        Error::ApiError(ApiError::from_code(ErrorCode::UnknownSfu))
-                    })?;
-
-                    // TODO
-                    // existing_sfu.send(SfuCommand::PrepareCascade { sfu_id: new_sfu_id });
-                    Arc::clone(&new_sfu)
-                }
-            };
+                    .ok_or_else(|| Error::ApiError(ApiError::from_code(ErrorCode::UnknownSfu)))?
+            }
+        };
 
         // ensure this sfu is registered in the call's active sfus set
         if let Some(call) = self.calls.get(&channel_id) {
             call.sfus.insert(sfu.id());
         }
+        self.recording_sfu_added(channel_id, sfu.id());
 
         Ok(sfu)
     }
@@ -294,8 +388,20 @@ impl ServiceVoice {
             call.value().sfus.remove(&sfu_id);
         }
 
+        // anything this sfu didn't upload yet is lost
+        let recordings: Vec<ChannelId> = self
+            .recordings
+            .iter()
+            .filter(|r| r.sfus.contains(&sfu_id))
+            .map(|r| *r.key())
+            .collect();
+        for channel_id in recordings {
+            self.recording_sfu_finished(channel_id, sfu_id);
+        }
+
         // migrate all voice states on this sfu
         let affected_states = self.state_list_by_sfu(sfu_id);
+        let mut affected_channels = HashSet::new();
 
         for handle in affected_states {
             let channel_id = handle.inner().channel_id;
@@ -308,41 +414,167 @@ impl ServiceVoice {
                     continue;
                 }
             };
-            let new_sfu_id = new_sfu.id();
 
-            // update the voice state
-            if let Some(call) = self.call_get(channel_id) {
-                // PERF: maybe `.remove()` instead to avoid cloning?
-                if let Some(mut entry) = call.voice_states.get_mut(&user_id) {
-                    let old_handle = entry.value();
-
-                    let new_handle = Arc::new(VoiceStateHandleInner {
-                        inner: old_handle.inner().clone(),
-                        // NOTE: do i always want to reset state here?
-                        // state: VoiceStateState::Connected,
-                        state: old_handle.state.clone(),
-                        sfu_id: new_sfu_id,
-                    });
+            if let Err(err) = self
+                .sfu_migrate_user(channel_id, user_id, new_sfu.id())
+                .await
+            {
+                warn!(%user_id, %channel_id, "couldn't migrate user: {err}");
+            }
+            affected_channels.insert(channel_id);
+        }
 
-                    *entry.value_mut() = new_handle;
+        // also reroute calls that were relaying through this sfu
+        for call in self.calls.iter() {
+            if call.routes.contains_key(&sfu_id) {
+                affected_channels.insert(*call.key());
+            }
+        }
 
-                    // PERF(?): maybe don't insert new_sfu_id for every voice state?
-                    call.sfus.insert(new_sfu_id);
-                }
+        for channel_id in affected_channels {
+            self.sfu_update_routes(channel_id).await;
+        }
+    }
+
+    /// move a user's voice state to another sfu
+    ///
+    /// a peer is created on the new sfu and the user is told to renegotiate with it
+    pub async fn sfu_migrate_user(
+        &self,
+        channel_id: ChannelId,
+        user_id: UserId,
+        new_sfu_id: SfuId,
+    ) -> Result<()> {
+        let srv = self.state.services();
+        let call = self
+            .call_get(channel_id)
+            .ok_or_else(|| Error::ApiError(ApiError::from_code(ErrorCode::UnknownCall)))?;
+        let new_sfu = self
+            .sfu_get(new_sfu_id)
+            .ok_or_else(|| Error::ApiError(ApiError::from_code(ErrorCode::UnknownSfu)))?;
+
+        // PERF: maybe `.remove()` instead to avoid cloning?
+        let (old_sfu_id, new_handle) = {
+            let Some(mut entry) = call.voice_states.get_mut(&user_id) else {
+                return Ok(());
+            };
+
+            let old_handle = entry.value();
+            let old_sfu_id = old_handle.sfu_id;
+            if old_sfu_id == new_sfu_id {
+                return Ok(());
             }
 
-            let _ = self
-                .state
-                .messaging()
-                .broadcast_channel(
+            let new_handle = Arc::new(VoiceStateHandleInner {
+                inner: old_handle.inner().clone(),
+                // NOTE: do i always want to reset state here?
+                // state: VoiceStateState::Connected,
+                state: old_handle.state.clone(),
+                sfu_id: new_sfu_id,
+            });
+
+            *entry.value_mut() = Arc::clone(&new_handle);
+            (old_sfu_id, new_handle)
+        };
+        call.sfus.insert(new_sfu_id);
+        self.recording_sfu_added(channel_id, new_sfu_id);
+
+        let perms = srv.perms.for_channel3(Some(user_id), channel_id).await?;
+        new_sfu.send(SfuCommand::CreatePeer {
+            channel_id,
+            state: SfuVoiceState::from_api_state(
+                new_handle.inner(),
+                perms.has(Permission::VoicePriority),
+            )
+            .map_err(|err| Error::Internal(err.to_string()))?,
+        });
+
+        if let Some(old_sfu) = self.sfu_get(old_sfu_id) {
+            old_sfu.send(SfuCommand::Signalling {
+                user_id,
+                channel_id,
+                inner: SignallingCommand::Disconnect,
+            });
+        }
+
+        self.state
+            .messaging()
+            .broadcast_channel(
+                channel_id,
+                MessageSync::VoiceDispatch {
+                    user_id,
                     channel_id,
-                    MessageSync::VoiceDispatch {
-                        user_id,
-                        channel_id,
-                        payload: SignallingEvent::Migrate { new_sfu_id },
-                    },
-                )
-                .await;
+                    payload: SignallingEvent::Migrate { new_sfu_id },
+                },
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// recalculate the links between the sfus hosting a call
+    ///
+    /// sends a `RouteUpdate` to every sfu whose destinations changed, including sfus that
+    /// are no longer part of the call
+    pub async fn sfu_update_routes(&self, channel_id: ChannelId) {
+        let Some(call) = self.call_get(channel_id) else {
+            return;
+        };
+
+        let active: HashSet<SfuId> = call
+            .voice_states
+            .iter()
+            .map(|s| s.sfu_id)
+            .filter(|sfu_id| self.sfus.contains_key(sfu_id))
+            .collect();
+        let available: HashSet<SfuId> = self
+            .sfus
+            .iter()
+            .filter(|s| s.has_capacity())
+            .map(|s| s.id())
+            .collect();
+        let topology = self
+            .router
+            .read()
+            .await
+            .calculate_routes(&active, &available);
+
+        let mut routes: HashMap<SfuId, Vec<SfuId>> = topology
+            .nodes()
+            .into_iter()
+            .map(|sfu_id| (sfu_id, topology.neighbors(sfu_id)))
+            .collect();
+        let previous: Vec<SfuId> = call.routes.iter().map(|r| *r.key()).collect();
+        for sfu_id in previous {
+            routes.entry(sfu_id).or_default();
+        }
+
+        for (sfu_id, mut destinations) in routes {
+            destinations.sort();
+            if call
+                .routes
+                .get(&sfu_id)
+                .is_some_and(|old| *old == destinations)
+            {
+                continue;
+            }
+
+            if let Some(sfu) = self.sfu_get(sfu_id) {
+                sfu.send(SfuCommand::RouteUpdate {
+                    channel_id,
+                    destinations: destinations.clone(),
+                });
+            }
+
+            if destinations.is_empty() {
+                call.routes.remove(&sfu_id);
+            } else {
+                call.routes.insert(sfu_id, destinations);
+            }
+        }
+
+        if !topology.relays.is_empty() {
+            debug!(%channel_id, relays = ?topology.relays, "relaying call");
         }
     }
 
@@ -471,11 +703,8 @@ impl ServiceVoice {
 
     /// recalculate the topology of a channel
     pub async fn sfu_rebalance(&self, channel_id: ChannelId) -> Vec<RebalanceAction> {
-        // TODO: implement this
-
         let mut actions = Vec::new();
         let router = self.router.read().await;
-        let merge_threshold = router.config.merge_threshold;
 
         // 0. partition voice states by sfu
         let mut voice_states_by_sfu: HashMap<SfuId, Vec<VoiceStateHandle>> = HashMap::new();
@@ -486,70 +715,62 @@ impl ServiceVoice {
                 .push(Arc::clone(&s));
         }
 
-        // let mut sfus_to_remove = HashSet::new();
-
         // 1. evaluate merging (cleanup under-utilized shards)
-        for (&sfu_id, local_users) in &voice_states_by_sfu {
-            // if local_users.len() <= merge_threshold && channel.active_nodes.len() > 1 {
-            //     // find the biggest "center of gravity" node to absorb these users
-            //     if let Some((&absorb_node, _)) = voice_states_by_sfu
-            //         .iter()
-            //         .filter(|(&id, _)| id != sfu_id && !sfus_to_remove.contains(&id))
-            //         .max_by_key(|(_, users)| users.len())
-            //     {
-            //         let user_ids: Vec<UserId> = local_users.iter().map(|u| u.user_id).collect();
-            //         actions.push(RebalanceAction::MigrateUsers {
-            //             users: user_ids,
-            //             target_sfu: absorb_node,
-            //         });
-            //         actions.push(RebalanceAction::Shutdown(sfu_id));
-            //         sfus_to_remove.insert(sfu_id);
-            //     }
-            // }
+        let users = voice_states_by_sfu
+            .iter()
+            .map(|(&sfu_id, states)| (sfu_id, states.len()))
+            .collect();
+        let capacity = voice_states_by_sfu
+            .keys()
+            .filter_map(|&sfu_id| Some((sfu_id, self.sfu_get(sfu_id)?.capacity()?)))
+            .collect();
+        for merge in router.calculate_merges(&users, &capacity) {
+            let users = voice_states_by_sfu[&merge.from]
+                .iter()
+                .map(|s| s.inner().user_id)
+                .collect();
+            actions.push(RebalanceAction::MigrateUsers {
+                users,
+                target_sfu: merge.into,
+            });
+            actions.push(RebalanceAction::Shutdown {
+                target_sfu: merge.from,
+            });
         }
 
         // 2. evaluate splitting / migration (fix bad placements)
-        for (&sfu_id, local_users) in &voice_states_by_sfu {
-            // if sfus_to_remove.contains(&sfu_id) {
-            //     continue;
-            // }
-
-            // NOTE: this pseudocode won't work for me, as regions don't exist in lamprey. i'll need to maybe create something like VoiceRouter but fuzzier for user locations.
-            // // Group local users by their physical geographical region
-            // let mut users_by_region: HashMap<RegionId, Vec<UserId>> = HashMap::new();
-            // for user in local_users {
-            //     users_by_region
-            //         .entry(user.region)
-            //         .or_default()
-            //         .push(user.user_id);
-            // }
-
-            // let node_region = graph.nodes[&node_id].region;
-
-            // for (user_region, users_in_region) in users_by_region {
-            //     let latency = graph.get_latency(node_region, user_region);
-
-            //     // If there's a large cluster of users experiencing high latency
-            //     if latency > 100 && users_in_region.len() > 5 {
-            //         // Find a better node closer to them
-            //         if let Some(better_node) = graph.find_closest_available_node(user_region) {
-            //             let new_latency =
-            //                 graph.get_latency(user_region, graph.nodes[&better_node].region);
-
-            //             // If the new node is significantly better, migrate them!
-            //             if new_latency < latency - 40 {
-            //                 actions.push(RebalanceAction::MigrateUsers {
-            //                     users: users_in_region,
-            //                     target_sfu: better_node,
-            //                 });
-            //             }
-            //         }
-            //     }
-            // }
-        }
+        // TODO: needs user locations, which don't exist yet (see `UserLocation`)
+        // users with high latency to their sfu should be moved to a closer one, cascading
+        // if needed
 
         actions
     }
+
+    /// rebalance a call and apply the result
+    pub async fn sfu_rebalance_apply(&self, channel_id: ChannelId) {
+        for action in self.sfu_rebalance(channel_id).await {
+            match action {
+                RebalanceAction::MigrateUsers { users, target_sfu } => {
+                    for user_id in users {
+                        if let Err(err) =
+                            self.sfu_migrate_user(channel_id, user_id, target_sfu).await
+                        {
+                            warn!(%user_id, %channel_id, "couldn't migrate user: {err}");
+                        }
+                    }
+                }
+                RebalanceAction::Shutdown { target_sfu } => {
+                    // the sfu gets an empty RouteUpdate below once its users are gone
+                    debug!(%channel_id, %target_sfu, "merged sfu out of call");
+                    if let Some(call) = self.call_get(channel_id) {
+                        call.sfus.remove(&target_sfu);
+                    }
+                }
+            }
+        }
+
+        self.sfu_update_routes(channel_id).await;
+    }
 }
 
 // TODO: deduplicate with crate-backend/src/sync/transport.rs
@@ -230,6 +230,9 @@ impl ServiceVoice {
             },
         });
 
+        // link this sfu to the rest of the call
+        self.sfu_update_routes(update.channel_id).await;
+
         self.state
             .messaging()
             .broadcast_channel(
@@ -366,9 +369,13 @@ impl ServiceVoice {
             let channel = srv.channels.get(channel_id, None).await?;
             if channel.ty != ChannelType::Broadcast {
                 Box::pin(self.call_delete(channel_id, false)).await;
+                return Ok(());
             }
         }
 
+        // merge sfus that don't have many users left
+        Box::pin(self.sfu_rebalance_apply(channel_id)).await;
+
         Ok(())
     }
 
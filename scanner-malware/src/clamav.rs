@@ -1,6 +1,6 @@
 use std::path::{Path, PathBuf};
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpStream, UnixStream};
 use tokio::time::{Duration, timeout};
 use tracing::{debug, info, trace};
@@ -14,6 +14,9 @@ const CLAMAV_TIMEOUT: Duration = Duration::from_secs(30);
 pub struct ScanResult {
     pub score: f64,
     pub message: Option<String>,
+
+    /// the names of the signatures that matched
+    pub signatures: Vec<String>,
 }
 
 /// Connection target for ClamAV
@@ -184,6 +187,15 @@ impl ClamAV {
         let mut conn = self.connect().await?;
         conn.scan_buffer(bytes).await
     }
+
+    /// Scan everything read from a reader (stream to clamd)
+    pub async fn scan_reader(
+        &self,
+        reader: impl AsyncRead + Unpin,
+    ) -> Result<ScanResult, ClamAVError> {
+        let mut conn = self.connect().await?;
+        conn.scan_reader(reader).await
+    }
 }
 
 impl ClamAVConnection {
@@ -214,20 +226,28 @@ impl ClamAVConnection {
     pub async fn scan_path(&mut self, path: &Path) -> Result<ScanResult, ClamAVError> {
         debug!("scanning file: {:?}", path);
 
+        let file = tokio::fs::File::open(path)
+            .await
+            .map_err(|_| ClamAVError::Scan("file not found".to_string()))?;
+
+        self.scan_reader(file).await
+    }
+
+    /// Scan everything read from a reader (stream to clamd)
+    pub async fn scan_reader(
+        &mut self,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<ScanResult, ClamAVError> {
         debug!("sending zINSTREAM command");
         self.socket
             .write_all(b"zINSTREAM\0")
             .await
             .map_err(|e| ClamAVError::Connection(format!("failed to send command: {e}")))?;
 
-        let mut file = tokio::fs::File::open(path)
-            .await
-            .map_err(|_| ClamAVError::Scan("file not found".to_string()))?;
-
         let mut buf = vec![0u8; 65536];
         let mut total_bytes = 0u64;
         loop {
-            let n = AsyncReadExt::read(&mut file, &mut buf)
+            let n = AsyncReadExt::read(&mut reader, &mut buf)
                 .await
                 .map_err(|e| ClamAVError::Scan(format!("failed to read file: {e}")))?;
 
@@ -247,7 +267,7 @@ impl ClamAVConnection {
                 .map_err(|e| ClamAVError::Connection(format!("failed to write data: {e}")))?;
         }
 
-        debug!("streamed {} bytes from file", total_bytes);
+        debug!("streamed {} bytes", total_bytes);
 
         debug!("sending EOF marker");
         self.socket
@@ -315,12 +335,19 @@ fn parse_clamd_response(response: &str) -> Result<ScanResult, ClamAVError> {
         Ok(ScanResult {
             score: 0.0,
             message: Some("No malware detected".to_string()),
+            signatures: vec![],
         })
     } else if response.contains("FOUND") {
         debug!("scan result: malware found - {}", response);
+        // responses look like `stream: Eicar-Signature FOUND`
+        let signature = response
+            .trim_start_matches("stream:")
+            .trim_end_matches("FOUND")
+            .trim();
         Ok(ScanResult {
             score: 1.0,
             message: Some(format!("Malware detected: {response}")),
+            signatures: vec![signature.to_owned()],
         })
     } else {
         debug!("scan result: error or unknown response");
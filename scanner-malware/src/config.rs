@@ -18,6 +18,16 @@ pub struct Config {
     /// Listen configuration
     #[serde(default = "default_listen")]
     pub listen: ListenConfig,
+
+    /// the version advertised to the backend, bump when changing signatures
+    #[serde(default = "default_version")]
+    pub version: u16,
+
+    /// the largest file in bytes that will be accepted for scanning
+    ///
+    /// this should be at most clamd's StreamMaxLength, which defaults to 25mb
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -54,6 +64,14 @@ fn default_clamav_max_connections() -> usize {
     8
 }
 
+fn default_version() -> u16 {
+    1
+}
+
+fn default_max_size() -> u64 {
+    25 * 1024 * 1024
+}
+
 fn default_clamav_tcp_host() -> String {
     "127.0.0.1".to_string()
 }
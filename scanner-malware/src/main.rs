@@ -4,16 +4,19 @@
 
 use axum::{
     Json,
+    body::Body,
     extract::State,
     routing::{get, post},
 };
 use clap::Parser;
-use serde::{Deserialize, Serialize};
+use common::v2::types::media::scanner::{MediaScanResponse, ScanRequest, ScanVerdict, ScannerInfo};
+use futures_util::TryStreamExt;
 use std::{net::SocketAddr, path::PathBuf, sync::Arc};
-use tower_http::{cors::CorsLayer, trace::TraceLayer};
+use tokio_util::io::StreamReader;
+use tower_http::{cors::CorsLayer, limit::RequestBodyLimitLayer, trace::TraceLayer};
 use tracing::info;
 use tracing_subscriber::{EnvFilter, Registry, layer::SubscriberExt};
-use utoipa::{OpenApi, ToSchema};
+use utoipa::OpenApi;
 use utoipa_axum::router::OpenApiRouter;
 
 use crate::{
@@ -33,29 +36,29 @@ use clamav_runner::{ClamAVRunner, ClamAVRunnerBuilder};
 struct AppState {
     clamav: ClamAV,
     _clamav_runner: Option<ClamAVRunner>,
+    info: ScannerInfo,
 }
 
-#[derive(Debug, Serialize, Deserialize, ToSchema)]
-struct ScanRequest {
-    path: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, ToSchema)]
-struct ScanResponse {
-    score: f64,
-    message: Option<String>,
-}
-
-impl From<ScanResult> for ScanResponse {
+impl From<ScanResult> for MediaScanResponse {
     fn from(result: ScanResult) -> Self {
-        ScanResponse {
+        let verdict = if result.signatures.is_empty() {
+            ScanVerdict::Clean
+        } else {
+            ScanVerdict::Malicious
+        };
+        MediaScanResponse {
             score: result.score,
             message: result.message,
+            verdict: Some(verdict),
+            matches: result.signatures,
         }
     }
 }
 
-async fn scan_with_clamav(clamav: &ClamAV, file_path: &str) -> Result<ScanResponse, ScanError> {
+async fn scan_with_clamav(
+    clamav: &ClamAV,
+    file_path: &str,
+) -> Result<MediaScanResponse, ScanError> {
     let result = clamav.scan_path(PathBuf::from(file_path).as_path()).await?;
     Ok(result.into())
 }
@@ -72,12 +75,24 @@ async fn health() -> Json<serde_json::Value> {
     Json(serde_json::json!({ "ok": true }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/info",
+    responses(
+        (status = 200, description = "Scanner capabilities", body = ScannerInfo),
+    ),
+    tag = "scan",
+)]
+async fn info(State(state): State<Arc<AppState>>) -> Json<ScannerInfo> {
+    Json(state.info.clone())
+}
+
 #[utoipa::path(
     post,
     path = "/scan",
     request_body = ScanRequest,
     responses(
-        (status = 200, description = "Scan completed", body = ScanResponse),
+        (status = 200, description = "Scan completed", body = MediaScanResponse),
         (status = 400, description = "Scan failed"),
         (status = 404, description = "File not found"),
         (status = 502, description = "ClamAV connection error"),
@@ -87,20 +102,41 @@ async fn health() -> Json<serde_json::Value> {
 async fn scan(
     State(state): State<Arc<AppState>>,
     Json(req): Json<ScanRequest>,
-) -> Result<Json<ScanResponse>, ScanError> {
+) -> Result<Json<MediaScanResponse>, ScanError> {
     info!("scanning file: {}", req.path);
     let result = scan_with_clamav(&state.clamav, &req.path).await?;
     Ok(Json(result))
 }
 
+#[utoipa::path(
+    post,
+    path = "/scan/stream",
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Scan completed", body = MediaScanResponse),
+        (status = 400, description = "Scan failed"),
+        (status = 502, description = "ClamAV connection error"),
+    ),
+    tag = "scan",
+)]
+async fn scan_stream(
+    State(state): State<Arc<AppState>>,
+    body: Body,
+) -> Result<Json<MediaScanResponse>, ScanError> {
+    info!("scanning streamed file");
+    let reader = StreamReader::new(body.into_data_stream().map_err(std::io::Error::other));
+    let result = state.clamav.scan_reader(reader).await?;
+    Ok(Json(result.into()))
+}
+
 #[derive(OpenApi)]
 #[openapi(
     info(
         title = "Malware Scanner API",
         description = "Scan files for malware using ClamAV",
     ),
-    paths(health, scan),
-    components(schemas(ScanRequest, ScanResponse)),
+    paths(health, info, scan, scan_stream),
+    components(schemas(ScannerInfo, ScanRequest, MediaScanResponse, ScanVerdict)),
     tags(
         (name = "health", description = "Health check endpoints"),
         (name = "scan", description = "File scanning endpoints"),
@@ -159,14 +195,27 @@ async fn main() -> anyhow::Result<()> {
         .build()
         .await?;
 
+    let max_size = config.max_size as usize;
     let state = Arc::new(AppState {
         clamav,
         _clamav_runner: clamav_runner,
+        info: ScannerInfo {
+            name: "clamav".to_owned(),
+            version: config.version,
+            accepts: vec![],
+            max_size: Some(config.max_size),
+            streaming: true,
+        },
     });
 
     let (router, api) = OpenApiRouter::with_openapi(ApiDoc::openapi())
         .route("/health", get(health))
+        .route("/info", get(info))
         .route("/scan", post(scan))
+        .route(
+            "/scan/stream",
+            post(scan_stream).layer(RequestBodyLimitLayer::new(max_size)),
+        )
         .with_state(state)
         .split_for_parts();
 
@@ -0,0 +1,11 @@
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(name = "scanner-yara")]
+#[command(about = "Media scanner using local YARA rules", long_about = None)]
+pub struct Cli {
+    /// Path to the configuration file
+    #[arg(short, long, value_name = "FILE", default_value = "scanner-yara.toml")]
+    pub config: PathBuf,
+}
@@ -0,0 +1,71 @@
+use std::path::{Path, PathBuf};
+
+use figment::providers::Format;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// RUST_LOG configuration
+    pub rust_log: String,
+
+    /// directory to load .yar and .yara rule files from
+    pub rules_directory: PathBuf,
+
+    /// the version advertised to the backend, bump when changing rules
+    #[serde(default = "default_version")]
+    pub version: u16,
+
+    /// the largest file in bytes that will be accepted for scanning
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+
+    /// how long a single scan may take (in seconds)
+    #[serde(default = "default_scan_timeout")]
+    pub scan_timeout: u64,
+
+    /// Listen configuration
+    #[serde(default = "default_listen")]
+    pub listen: ListenConfig,
+}
+
+fn default_version() -> u16 {
+    1
+}
+
+fn default_max_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_scan_timeout() -> u64 {
+    10
+}
+
+fn default_listen() -> ListenConfig {
+    ListenConfig::Tcp {
+        address: "127.0.0.1".parse().unwrap(),
+        port: 4102,
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ListenConfig {
+    Tcp {
+        address: std::net::IpAddr,
+        port: u16,
+    },
+    Unix {
+        path: PathBuf,
+    },
+}
+
+impl Config {
+    pub fn load(config_path: &Path) -> Result<Self, Box<figment::Error>> {
+        figment::Figment::new()
+            .merge(figment::providers::Toml::file(config_path))
+            .merge(figment::providers::Env::raw().only(&["RUST_LOG"]))
+            .merge(figment::providers::Env::prefixed("SCANNER_YARA_"))
+            .extract()
+            .map_err(Box::new)
+    }
+}
@@ -0,0 +1,37 @@
+use axum::{http::StatusCode, response::IntoResponse};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RulesError {
+    #[error("failed to read rules directory: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("failed to compile rules: {0}")]
+    Compile(#[from] boreal::compiler::AddRuleError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScanError {
+    #[error("file not found")]
+    NotFound,
+
+    #[error("file too large")]
+    TooLarge,
+
+    #[error("scan timed out")]
+    TimedOut,
+
+    #[error("scan error: {0}")]
+    Scan(String),
+}
+
+impl IntoResponse for ScanError {
+    fn into_response(self) -> axum::response::Response {
+        let (status, body) = match self {
+            ScanError::NotFound => (StatusCode::NOT_FOUND, "File not found".to_owned()),
+            ScanError::TooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "File too large".to_owned()),
+            ScanError::TimedOut => (StatusCode::GATEWAY_TIMEOUT, "Scan timed out".to_owned()),
+            ScanError::Scan(e) => (StatusCode::BAD_REQUEST, format!("Scan error: {e}")),
+        };
+        (status, body).into_response()
+    }
+}
@@ -0,0 +1,267 @@
+use axum::{
+    Json,
+    body::Body,
+    extract::State,
+    routing::{get, post},
+};
+use clap::Parser;
+use common::v2::types::media::scanner::{MediaScanResponse, ScanRequest, ScanVerdict, ScannerInfo};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    io::AsyncReadExt,
+    signal::unix::{SignalKind, signal},
+};
+use tower_http::{cors::CorsLayer, trace::TraceLayer};
+use tracing::{error, info};
+use tracing_subscriber::{EnvFilter, Registry, layer::SubscriberExt};
+use utoipa::OpenApi;
+use utoipa_axum::router::OpenApiRouter;
+
+use crate::{
+    config::{Config, ListenConfig},
+    error::ScanError,
+    rules::{Rules, ScanResult, Severity},
+};
+
+mod cli;
+mod config;
+mod error;
+mod rules;
+
+struct AppState {
+    rules: RwLock<Arc<Rules>>,
+    info: ScannerInfo,
+}
+
+impl AppState {
+    fn rules(&self) -> Arc<Rules> {
+        self.rules.read().expect("rules lock poisoned").clone()
+    }
+
+    async fn scan(&self, bytes: Vec<u8>) -> Result<MediaScanResponse, ScanError> {
+        let rules = self.rules();
+        let result = tokio::task::spawn_blocking(move || rules.scan(&bytes))
+            .await
+            .map_err(|e| ScanError::Scan(e.to_string()))??;
+        info!(matches = ?result.matches, "scan complete");
+        Ok(result.into())
+    }
+}
+
+impl From<ScanResult> for MediaScanResponse {
+    fn from(result: ScanResult) -> Self {
+        let (score, verdict) = match result.severity {
+            Severity::Clean => (0.0, ScanVerdict::Clean),
+            Severity::Suspicious => (0.5, ScanVerdict::Suspicious),
+            Severity::Malicious => (1.0, ScanVerdict::Malicious),
+        };
+        let message = if result.matches.is_empty() {
+            None
+        } else {
+            Some(format!("Matched rules: {}", result.matches.join(", ")))
+        };
+        MediaScanResponse {
+            score,
+            message,
+            verdict: Some(verdict),
+            matches: result.matches,
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses(
+        (status = 200, description = "Service is healthy", body = serde_json::Value),
+    ),
+    tag = "health",
+)]
+async fn health(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "ok": true, "rules": state.rules().count() }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/info",
+    responses(
+        (status = 200, description = "Scanner capabilities", body = ScannerInfo),
+    ),
+    tag = "scan",
+)]
+async fn info(State(state): State<Arc<AppState>>) -> Json<ScannerInfo> {
+    Json(state.info.clone())
+}
+
+#[utoipa::path(
+    post,
+    path = "/scan",
+    request_body = ScanRequest,
+    responses(
+        (status = 200, description = "Scan completed", body = MediaScanResponse),
+        (status = 400, description = "Scan failed"),
+        (status = 404, description = "File not found"),
+        (status = 413, description = "File too large"),
+        (status = 504, description = "Scan timed out"),
+    ),
+    tag = "scan",
+)]
+async fn scan(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ScanRequest>,
+) -> Result<Json<MediaScanResponse>, ScanError> {
+    info!("scanning file: {}", req.path);
+    let file = tokio::fs::File::open(&req.path)
+        .await
+        .map_err(|_| ScanError::NotFound)?;
+    let meta = file
+        .metadata()
+        .await
+        .map_err(|e| ScanError::Scan(format!("failed to read file: {e}")))?;
+    let limit = state.info.max_size.unwrap_or(u64::MAX);
+    if meta.len() > limit {
+        return Err(ScanError::TooLarge);
+    }
+
+    // the file could grow after the size check
+    let mut bytes = Vec::with_capacity(meta.len() as usize);
+    file.take(limit.saturating_add(1))
+        .read_to_end(&mut bytes)
+        .await
+        .map_err(|e| ScanError::Scan(format!("failed to read file: {e}")))?;
+    if bytes.len() as u64 > limit {
+        return Err(ScanError::TooLarge);
+    }
+    Ok(Json(state.scan(bytes).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/scan/stream",
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Scan completed", body = MediaScanResponse),
+        (status = 400, description = "Scan failed"),
+        (status = 413, description = "File too large"),
+        (status = 504, description = "Scan timed out"),
+    ),
+    tag = "scan",
+)]
+async fn scan_stream(
+    State(state): State<Arc<AppState>>,
+    body: Body,
+) -> Result<Json<MediaScanResponse>, ScanError> {
+    info!("scanning streamed file");
+    // the whole body is buffered, which is why /info doesn't advertise streaming
+    let limit = state.info.max_size.unwrap_or(u64::MAX) as usize;
+    let bytes = axum::body::to_bytes(body, limit)
+        .await
+        .map_err(|_| ScanError::TooLarge)?;
+    Ok(Json(state.scan(bytes.into()).await?))
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "YARA Scanner API",
+        description = "Scan files with local YARA rules",
+    ),
+    paths(health, info, scan, scan_stream),
+    components(schemas(ScannerInfo, ScanRequest, MediaScanResponse, ScanVerdict)),
+    tags(
+        (name = "health", description = "Health check endpoints"),
+        (name = "scan", description = "File scanning endpoints"),
+    ),
+)]
+struct ApiDoc;
+
+async fn serve_transport(listen: ListenConfig, router: axum::Router) -> anyhow::Result<()> {
+    match listen {
+        ListenConfig::Tcp { address, port } => {
+            let addr = SocketAddr::new(address, port);
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            info!("listening on http://{addr}");
+            axum::serve(listener, router).await?;
+        }
+        ListenConfig::Unix { path } => {
+            if let Some(p) = path.parent() {
+                tokio::fs::create_dir_all(p).await?;
+            }
+            if path.exists() {
+                tracing::warn!("deleting existing socket {}", path.display());
+                tokio::fs::remove_file(&path).await?;
+            }
+            let listener = tokio::net::UnixListener::bind(&path)?;
+            info!("listening on unix:{}", path.display());
+            axum::serve(listener, router).await?;
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = cli::Cli::parse();
+    let config = Config::load(&cli.config)?;
+
+    let subscriber = Registry::default()
+        .with(EnvFilter::try_new(&config.rust_log)?)
+        .with(tracing_subscriber::fmt::layer());
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    info!("starting scanner-yara with RUST_LOG={}", config.rust_log);
+
+    let scan_timeout = Duration::from_secs(config.scan_timeout);
+    let rules = Rules::load(&config.rules_directory, scan_timeout)?;
+
+    let state = Arc::new(AppState {
+        rules: RwLock::new(Arc::new(rules)),
+        info: ScannerInfo {
+            name: "yara".to_owned(),
+            version: config.version,
+            accepts: vec![],
+            max_size: Some(config.max_size),
+            // rules match against the whole file, so it can't be scanned while it's streamed
+            streaming: false,
+        },
+    });
+
+    // reload rules on SIGHUP, keeping the old rules if they fail to compile
+    let mut hangup = signal(SignalKind::hangup())?;
+    let reload_state = state.clone();
+    let rules_directory = config.rules_directory.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("reloading rules from {}", rules_directory.display());
+            let dir = rules_directory.clone();
+            match tokio::task::spawn_blocking(move || Rules::load(&dir, scan_timeout)).await {
+                Ok(Ok(rules)) => {
+                    *reload_state.rules.write().expect("rules lock poisoned") = Arc::new(rules);
+                }
+                Ok(Err(err)) => error!("failed to reload rules: {err}"),
+                Err(err) => error!("failed to reload rules: {err}"),
+            }
+        }
+    });
+
+    let (router, api) = OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .route("/health", get(health))
+        .route("/info", get(info))
+        .route("/scan", post(scan))
+        .route("/scan/stream", post(scan_stream))
+        .with_state(state)
+        .split_for_parts();
+
+    let router = router
+        .route("/api/docs.json", get(|| async { Json(api) }))
+        .layer(TraceLayer::new_for_http())
+        .layer(CorsLayer::very_permissive());
+
+    serve_transport(config.listen, router).await?;
+
+    Ok(())
+}
@@ -0,0 +1,159 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use boreal::{Compiler, MetadataValue, Scanner, scanner::ScanParams};
+use tracing::{debug, info, warn};
+
+use crate::error::{RulesError, ScanError};
+
+/// how severe a matched rule is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Clean,
+    Suspicious,
+    Malicious,
+}
+
+#[derive(Debug)]
+pub struct ScanResult {
+    pub severity: Severity,
+
+    /// the names of the rules that matched, as `namespace.rule`
+    pub matches: Vec<String>,
+}
+
+/// a compiled set of yara rules
+pub struct Rules {
+    scanner: Scanner,
+    count: usize,
+}
+
+impl Rules {
+    /// compile every rule file in a directory
+    ///
+    /// each file gets its own namespace, named after its path relative to the directory
+    pub fn load(dir: &Path, timeout: Duration) -> Result<Self, RulesError> {
+        let mut files = Vec::new();
+        find_rule_files(dir, &mut files)?;
+        files.sort();
+
+        let mut compiler = Compiler::new();
+        for file in &files {
+            let namespace = file
+                .strip_prefix(dir)
+                .unwrap_or(file)
+                .with_extension("")
+                .to_string_lossy()
+                .replace(std::path::MAIN_SEPARATOR, "_");
+            debug!("compiling {} into namespace {namespace}", file.display());
+            let status = compiler.add_rules_file_in_namespace(file, &namespace)?;
+            for warning in status.warnings() {
+                warn!("{}: {warning}", file.display());
+            }
+        }
+
+        let rules = Self::from_compiler(compiler, timeout);
+        info!("loaded {} rules from {} files", rules.count, files.len());
+        Ok(rules)
+    }
+
+    fn from_compiler(compiler: Compiler, timeout: Duration) -> Self {
+        let mut scanner = compiler.finalize();
+        scanner.set_scan_params(ScanParams::default().timeout_duration(Some(timeout)));
+        let count = scanner.rules().count();
+        Self { scanner, count }
+    }
+
+    /// the number of compiled rules
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// scan a buffer with every rule
+    pub fn scan(&self, bytes: &[u8]) -> Result<ScanResult, ScanError> {
+        let res = match self.scanner.scan_mem(bytes) {
+            Ok(res) => res,
+            Err((boreal::scanner::ScanError::Timeout, _)) => return Err(ScanError::TimedOut),
+            Err((err, _)) => return Err(ScanError::Scan(err.to_string())),
+        };
+
+        let mut severity = Severity::Clean;
+        let mut matches = Vec::new();
+        for rule in res.rules.iter().filter(|r| r.matched) {
+            severity = severity.max(self.rule_severity(rule.metadatas));
+            matches.push(format!("{}.{}", rule.namespace, rule.name));
+        }
+
+        Ok(ScanResult { severity, matches })
+    }
+
+    /// get the severity from a rule's `severity` metadata
+    ///
+    /// rules are malicious unless they say otherwise
+    fn rule_severity(&self, metadatas: &[boreal::Metadata]) -> Severity {
+        let severity = metadatas.iter().find_map(|m| {
+            if self.scanner.get_string_symbol(m.name) != "severity" {
+                return None;
+            }
+            match m.value {
+                MetadataValue::Bytes(b) => Some(self.scanner.get_bytes_symbol(b)),
+                _ => None,
+            }
+        });
+
+        match severity {
+            Some(b"low" | b"suspicious") => Severity::Suspicious,
+            _ => Severity::Malicious,
+        }
+    }
+}
+
+/// recursively find every .yar and .yara file in a directory
+fn find_rule_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), RulesError> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_rule_files(&path, files)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext == "yar" || ext == "yara")
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> Rules {
+        let mut compiler = Compiler::new();
+        compiler.add_rules_str_in_namespace(source, "test").unwrap();
+        Rules::from_compiler(compiler, Duration::from_secs(5))
+    }
+
+    #[test]
+    fn severity() {
+        let rules = compile(
+            r#"
+            rule bad { strings: $a = "evil" condition: $a }
+            rule meh { meta: severity = "suspicious" strings: $a = "odd" condition: $a }
+            "#,
+        );
+        assert_eq!(rules.count(), 2);
+
+        let res = rules.scan(b"nothing to see here").unwrap();
+        assert_eq!(res.severity, Severity::Clean);
+        assert!(res.matches.is_empty());
+
+        let res = rules.scan(b"this is odd").unwrap();
+        assert_eq!(res.severity, Severity::Suspicious);
+        assert_eq!(res.matches, ["test.meh"]);
+
+        let res = rules.scan(b"this is odd and evil").unwrap();
+        assert_eq!(res.severity, Severity::Malicious);
+        assert_eq!(res.matches.len(), 2);
+    }
+}
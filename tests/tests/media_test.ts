@@ -162,3 +162,133 @@ Deno.test("Media Uploads", async (t) => {
 		}
 	});
 });
+
+Deno.test("Resumable Media Uploads", async (t) => {
+	const alice = await createTester("alice-tus");
+	const bob = await createTester("bob-tus");
+
+	let uploadUrl: string;
+
+	await t.step("Alice creates a media upload", async () => {
+		const res = await fetch(`${BASE_URL}/api/v1/media`, {
+			method: "POST",
+			headers: {
+				"Authorization": `Bearer ${alice.token}`,
+				"Content-Type": "application/json",
+			},
+			body: JSON.stringify({ filename: "resume.txt", size: 11 }),
+		});
+		assertEquals(res.status, 201);
+		uploadUrl = (await res.json()).upload_url;
+	});
+
+	await t.step("Upload url advertises tus support", async () => {
+		const res = await fetch(uploadUrl, { method: "OPTIONS" });
+		assertEquals(res.status, 204);
+		assertEquals(res.headers.get("tus-resumable"), "1.0.0");
+		assertEquals(res.headers.get("tus-version"), "1.0.0");
+		await res.body?.cancel();
+	});
+
+	await t.step("Alice uploads the first chunk", async () => {
+		const res = await fetch(uploadUrl, {
+			method: "PATCH",
+			headers: {
+				"Authorization": `Bearer ${alice.token}`,
+				"Tus-Resumable": "1.0.0",
+				"Content-Type": "application/offset+octet-stream",
+				"Upload-Offset": "0",
+			},
+			body: "hello",
+		});
+		assertEquals(res.status, 204);
+		assertEquals(res.headers.get("upload-offset"), "5");
+		assertEquals(typeof res.headers.get("upload-expires"), "string");
+		await res.body?.cancel();
+	});
+
+	await t.step("Bob can't see Alice's upload", async () => {
+		const res = await fetch(uploadUrl, {
+			method: "HEAD",
+			headers: { "Authorization": `Bearer ${bob.token}` },
+		});
+		assertEquals(res.status, 404);
+		await res.body?.cancel();
+	});
+
+	await t.step("Alice resumes from the current offset", async () => {
+		const head = await fetch(uploadUrl, {
+			method: "HEAD",
+			headers: {
+				"Authorization": `Bearer ${alice.token}`,
+				"Tus-Resumable": "1.0.0",
+			},
+		});
+		assertEquals(head.status, 204);
+		assertEquals(head.headers.get("upload-offset"), "5");
+		assertEquals(head.headers.get("upload-length"), "11");
+		await head.body?.cancel();
+
+		const conflict = await fetch(uploadUrl, {
+			method: "PATCH",
+			headers: {
+				"Authorization": `Bearer ${alice.token}`,
+				"Tus-Resumable": "1.0.0",
+				"Content-Type": "application/offset+octet-stream",
+				"Upload-Offset": "0",
+			},
+			body: "hello",
+		});
+		assertEquals(conflict.status, 409);
+		await conflict.body?.cancel();
+
+		const res = await fetch(uploadUrl, {
+			method: "PATCH",
+			headers: {
+				"Authorization": `Bearer ${alice.token}`,
+				"Tus-Resumable": "1.0.0",
+				"Content-Type": "application/offset+octet-stream",
+				"Upload-Offset": "5",
+			},
+			body: " world",
+		});
+		assertEquals(res.status, 204);
+		assertEquals(res.headers.get("upload-offset"), "11");
+		await res.body?.cancel();
+	});
+
+	await t.step("Tus clients must use the offset content type", async () => {
+		const res = await fetch(`${BASE_URL}/api/v1/media`, {
+			method: "POST",
+			headers: {
+				"Authorization": `Bearer ${alice.token}`,
+				"Content-Type": "application/json",
+			},
+			body: JSON.stringify({ filename: "wrong.txt", size: 4 }),
+		});
+		const { upload_url } = await res.json();
+
+		const patch = await fetch(upload_url, {
+			method: "PATCH",
+			headers: {
+				"Authorization": `Bearer ${alice.token}`,
+				"Tus-Resumable": "1.0.0",
+				"Content-Type": "text/plain",
+				"Upload-Offset": "0",
+			},
+			body: "test",
+		});
+		assertEquals(patch.status, 415);
+		await patch.body?.cancel();
+
+		const del = await fetch(upload_url, {
+			method: "DELETE",
+			headers: {
+				"Authorization": `Bearer ${alice.token}`,
+				"Tus-Resumable": "1.0.0",
+			},
+		});
+		assertEquals(del.status, 204);
+		await del.body?.cancel();
+	});
+});
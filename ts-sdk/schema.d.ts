@@ -9506,8 +9506,16 @@ export interface components {
 			 *     roughly corresponds to the time that the first user joined
 			 */
 			created_at: components["schemas"]["Time"];
+			recording?: null | components["schemas"]["CallRecording"];
 			topic?: string | null;
 		};
+		/** @description a recording of a call */
+		CallRecording: {
+			/** @description when this recording was started */
+			started_at: components["schemas"]["Time"];
+			/** @description the user who started this recording */
+			started_by: components["schemas"]["Id"];
+		};
 		Captcha: Record<string, never>;
 		/** @description a set of changes made to a document */
 		Changeset: {
@@ -10876,6 +10884,15 @@ export interface components {
 		};
 		/** @description webrtc ice candidate */
 		IceCandidate: string;
+		/** @description a stun or turn server that a client can use to connect to a sfu */
+		IceServer: {
+			/** @description the password to authenticate with, only used for turn */
+			credential?: string | null;
+			/** @description stun or turn urls for this server */
+			urls: string[];
+			/** @description the username to authenticate with, only used for turn */
+			username?: string | null;
+		};
 		/**
 		 * Uuid
 		 * Format: uuid
@@ -12900,7 +12917,8 @@ export interface components {
 			| "CallUpdate"
 			| "RoomJoinForce"
 			| "ScriptManage"
-			| "ScriptInspect";
+			| "ScriptInspect"
+			| "VoiceRecord";
 		PermissionOverwrite: {
 			/** @description extra permissions allowed here */
 			allow: components["schemas"]["Permission"][];
@@ -13912,6 +13930,12 @@ export interface components {
 		/** @description an event sent from the backend to the peer's sync connection */
 		SignallingEvent:
 			| {
+					/**
+					 * @description extra ice servers to use for this connection
+					 *
+					 *     turn credentials are only valid for this voice state
+					 */
+					ice_servers?: components["schemas"]["IceServer"][];
 					/**
 					 * @description the id of the selected sfu
 					 *
@@ -14136,6 +14160,12 @@ export interface components {
 		};
 		/** @description the metadata for a track */
 		TrackMetadata2: {
+			/**
+			 * @description whether this track's media is end to end encrypted with sframe
+			 *
+			 *     the sfu still forwards encrypted tracks, but can't record them.
+			 */
+			encrypted?: boolean;
 			/**
 			 * @description key to group tracks together into streams
 			 *